@@ -0,0 +1,77 @@
+//! Merge two squashfs images into one
+//!
+//! Usage: merge [--prefix DIR] [--conflicts error|first|second] FIRST SECOND -o OUTPUT
+
+use std::env;
+use std::fs::File;
+use std::process;
+
+use sqfs::config::ConflictPolicy;
+use sqfs::read::Archive;
+use sqfs::write::{self, ArchiveBuilder, MergeOptions};
+
+const USAGE: &str =
+    "usage: merge [--prefix DIR] [--conflicts error|first|second] FIRST SECOND -o OUTPUT";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn main() {
+    let mut options = MergeOptions::default();
+    let mut output = None;
+    let mut paths = Vec::new();
+    let mut args = env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("-o") => output = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--prefix") => {
+                let prefix = args.next().and_then(|prefix| prefix.into_string().ok());
+                options.prefix = prefix.unwrap_or_else(|| usage()).into();
+            }
+            Some("--conflicts") => {
+                options.conflicts = match args.next().as_ref().and_then(|arg| arg.to_str()) {
+                    Some("error") => ConflictPolicy::Error,
+                    Some("first") => ConflictPolicy::KeepFirst,
+                    Some("second") => ConflictPolicy::KeepSecond,
+                    _ => usage(),
+                }
+            }
+            _ => paths.push(arg),
+        }
+    }
+    let (first, second, output) = match (&paths[..], output) {
+        ([first, second], Some(output)) => (first, second, output),
+        _ => usage(),
+    };
+
+    let open = |path| {
+        Archive::open(path).unwrap_or_else(|e| {
+            eprintln!("unable to open {:?}: {}", path, e);
+            process::exit(2);
+        })
+    };
+    let (first, second) = (open(first), open(second));
+    let output = File::create(output).unwrap_or_else(|e| {
+        eprintln!("unable to create output: {}", e);
+        process::exit(2);
+    });
+    let mut builder = ArchiveBuilder::new();
+    builder.block_size = first.block_size();
+    builder.compressor_kind = first.compression().kind();
+    let mut archive = builder.build(output);
+    let result =
+        write::merge(&mut archive, &first, &second, &options).and_then(|()| archive.flush());
+    match result {
+        Ok(report) => {
+            for issue in report {
+                eprintln!("warning: {}", issue);
+            }
+        }
+        Err(e) => {
+            eprintln!("unable to merge images: {}", e);
+            process::exit(1);
+        }
+    }
+}
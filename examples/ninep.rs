@@ -0,0 +1,557 @@
+//! Serve a squashfs image read-only over 9P2000.L
+//!
+//! Usage: ninep IMAGE [ADDRESS]
+//!
+//! Mount it with `mount -t 9p -o trans=tcp,port=5640,version=9p2000.L,ro 127.0.0.1 /mnt`.
+//!
+//! Clients identify each file by its inode number, which 9P calls the qid path. A fid holds
+//! only that number, and every request looks the inode up again with
+//! [`Archive::inode_by_number`], so handles stay valid across reconnects and restarts of the
+//! server for as long as the image is the same, as NFS file handles must. Lookups are quick on
+//! exportable images, which have a table of every inode by number.
+
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::env;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process;
+use std::thread;
+
+use sqfs::read::{Archive, Inode, InodeData, ReadAt};
+use sqfs::FileType;
+
+const USAGE: &str = "usage: ninep IMAGE [ADDRESS]";
+const DEFAULT_ADDRESS: &str = "127.0.0.1:5640";
+
+const VERSION: &[u8] = b"9P2000.L";
+/// The largest message accepted, the most a client can negotiate
+const MSIZE: u32 = 1 << 17;
+/// The size of the header of a read reply, which the data must fit in `msize` after
+const IOHDRSZ: u32 = 24;
+
+// Request types, each reply's type is one more than its request's
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TREADDIR: u8 = 40;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TCLUNK: u8 = 120;
+
+const QTDIR: u8 = 0x80;
+const QTSYMLINK: u8 = 0x02;
+const QTFILE: u8 = 0;
+
+/// The attributes getattr fills in, all of the basic ones
+const GETATTR_BASIC: u64 = 0x7ff;
+const SQUASHFS_MAGIC: u32 = 0x7371_7368;
+
+// The errors replies carry, as linux errno values
+const ENOENT: u32 = 2;
+const EIO: u32 = 5;
+const EBADF: u32 = 9;
+const ENOTDIR: u32 = 20;
+const EINVAL: u32 = 22;
+const EROFS: u32 = 30;
+const EPROTO: u32 = 71;
+const EOPNOTSUPP: u32 = 95;
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn main() {
+    let mut args = env::args_os().skip(1);
+    let image = args.next().unwrap_or_else(|| usage());
+    let address = match args.next() {
+        Some(address) => address.into_string().unwrap_or_else(|_| usage()),
+        None => DEFAULT_ADDRESS.to_owned(),
+    };
+    if args.next().is_some() {
+        usage();
+    }
+
+    let archive = Archive::open(&image).unwrap_or_else(|e| {
+        eprintln!("unable to open {:?}: {}", image, e);
+        process::exit(2);
+    });
+    if !archive.features().export_table {
+        eprintln!("warning: the image has no export table, lookups will walk the tree");
+    }
+    let listener = TcpListener::bind(&address).unwrap_or_else(|e| {
+        eprintln!("unable to listen on {}: {}", address, e);
+        process::exit(2);
+    });
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("unable to accept a connection: {}", e);
+                continue;
+            }
+        };
+        let archive = archive.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve(archive, stream) {
+                eprintln!("connection failed: {}", e);
+            }
+        });
+    }
+}
+
+/// Answer the requests of one client until it disconnects
+fn serve<R: ReadAt>(archive: Archive<R>, mut stream: TcpStream) -> io::Result<()> {
+    let mut session = Session::new(archive);
+    loop {
+        let mut size = [0; 4];
+        match stream.read_exact(&mut size) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        }
+        let size = u32::from_le_bytes(size);
+        if !(7..=MSIZE).contains(&size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid message size {}", size),
+            ));
+        }
+        let mut message = vec![0; size as usize - 4];
+        stream.read_exact(&mut message)?;
+        let reply = session.handle(&message);
+        let size = u32::try_from(reply.len() + 4).unwrap();
+        stream.write_all(&size.to_le_bytes())?;
+        stream.write_all(&reply)?;
+    }
+}
+
+/// The fields of a request, taken in order
+struct Request<'a>(&'a [u8]);
+
+impl<'a> Request<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], u32> {
+        if self.0.len() < len {
+            return Err(EPROTO);
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, u32> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, u32> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<&'a [u8], u32> {
+        let len = self.u16()?;
+        self.bytes(len.into())
+    }
+}
+
+/// The fields of a reply, added in order
+#[derive(Default)]
+struct Reply(Vec<u8>);
+
+impl Reply {
+    fn u8(&mut self, value: u8) -> &mut Self {
+        self.0.push(value);
+        self
+    }
+
+    fn u16(&mut self, value: u16) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, value: u64) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn string(&mut self, value: &[u8]) -> &mut Self {
+        self.u16(value.len() as u16).u8s(value)
+    }
+
+    fn u8s(&mut self, value: &[u8]) -> &mut Self {
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    /// The unique id of a file: its type, a version which never changes in a read-only
+    /// image, and its inode number
+    fn qid(&mut self, file_type: FileType, inode_number: u32) -> &mut Self {
+        let ty = match file_type {
+            FileType::Dir => QTDIR,
+            FileType::Symlink => QTSYMLINK,
+            _ => QTFILE,
+        };
+        self.u8(ty).u32(0).u64(inode_number.into())
+    }
+}
+
+/// The state of one client's connection
+struct Session<R> {
+    archive: Archive<R>,
+    msize: u32,
+    /// The inode number each fid refers to
+    fids: HashMap<u32, u32>,
+}
+
+impl<R: ReadAt> Session<R> {
+    fn new(archive: Archive<R>) -> Self {
+        Session {
+            archive,
+            msize: MSIZE,
+            fids: HashMap::new(),
+        }
+    }
+
+    /// Answer one message, given and returned without its leading size
+    fn handle(&mut self, message: &[u8]) -> Vec<u8> {
+        let mut request = Request(message);
+        let (ty, tag) = match (request.bytes(1), request.u16()) {
+            (Ok(ty), Ok(tag)) => (ty[0], tag),
+            _ => (0, !0),
+        };
+        let mut reply = Reply::default();
+        match self.answer(ty, &mut request) {
+            Ok(body) => {
+                reply.u8(ty + 1).u16(tag).u8s(&body.0);
+            }
+            Err(errno) => {
+                reply.u8(RLERROR).u16(tag).u32(errno);
+            }
+        }
+        reply.0
+    }
+
+    fn answer(&mut self, ty: u8, request: &mut Request<'_>) -> Result<Reply, u32> {
+        let mut reply = Reply::default();
+        match ty {
+            TVERSION => {
+                let msize = request.u32()?;
+                let version = request.string()?;
+                self.msize = msize.clamp(IOHDRSZ + 1, MSIZE);
+                self.fids.clear();
+                let version = if version.starts_with(VERSION) {
+                    VERSION
+                } else {
+                    b"unknown"
+                };
+                reply.u32(self.msize).string(version);
+            }
+            TATTACH => {
+                let fid = request.u32()?;
+                let root = self.archive.root().map_err(|_| EIO)?;
+                self.fids.insert(fid, root.inode_number());
+                reply.qid(root.file_type(), root.inode_number());
+            }
+            TWALK => {
+                let inode = self.fid(request.u32()?)?;
+                let newfid = request.u32()?;
+                let count = request.u16()?;
+                let mut current = inode.inode_number();
+                let mut qids = Reply::default();
+                for i in 0..count {
+                    let name = request.string()?;
+                    match self.walk(current, name) {
+                        Ok((file_type, inode_number)) => {
+                            qids.qid(file_type, inode_number);
+                            current = inode_number;
+                        }
+                        Err(errno) if i == 0 => return Err(errno),
+                        // Walks which fail part way return the files found so far
+                        Err(_) => {
+                            reply.u16(i).u8s(&qids.0);
+                            return Ok(reply);
+                        }
+                    }
+                }
+                self.fids.insert(newfid, current);
+                reply.u16(count).u8s(&qids.0);
+            }
+            TLOPEN => {
+                let inode = self.fid(request.u32()?)?;
+                if request.u32()? & 3 != 0 {
+                    return Err(EROFS);
+                }
+                reply
+                    .qid(inode.file_type(), inode.inode_number())
+                    .u32(self.msize - IOHDRSZ);
+            }
+            TREAD => {
+                let inode = self.fid(request.u32()?)?;
+                let offset = request.u64()?;
+                let count = request.u32()?.min(self.msize - IOHDRSZ);
+                let data = self.read(&inode, offset, count).map_err(|_| EIO)?;
+                reply.u32(data.len() as u32).u8s(&data);
+            }
+            TREADDIR => {
+                let inode = self.fid(request.u32()?)?;
+                let offset = request.u64()?;
+                let count = request.u32()?.min(self.msize - IOHDRSZ);
+                let entries = self.read_dir(&inode)?;
+                let mut data = Reply::default();
+                for (i, (name, file_type, inode_number)) in entries.iter().enumerate() {
+                    if (i as u64) < offset {
+                        continue;
+                    }
+                    // qid, offset, type and name
+                    if data.0.len() + 24 + name.len() > count as usize {
+                        break;
+                    }
+                    data.qid(*file_type, *inode_number)
+                        .u64(i as u64 + 1)
+                        .u8(dirent_type(*file_type))
+                        .string(name);
+                }
+                reply.u32(data.0.len() as u32).u8s(&data.0);
+            }
+            TGETATTR => {
+                let inode = self.fid(request.u32()?)?;
+                self.getattr(&inode, &mut reply);
+            }
+            TREADLINK => {
+                let inode = self.fid(request.u32()?)?;
+                let target = inode.symlink_target().ok_or(EINVAL)?;
+                reply.string(target);
+            }
+            TSTATFS => {
+                self.fid(request.u32()?)?;
+                let block_size = self.archive.block_size();
+                let blocks = self.archive.bytes_used().div_ceil(block_size.into());
+                reply
+                    .u32(SQUASHFS_MAGIC)
+                    .u32(block_size)
+                    .u64(blocks)
+                    .u64(0)
+                    .u64(0)
+                    .u64(self.archive.superblock().inode_count.into())
+                    .u64(0)
+                    .u64(0)
+                    .u32(256);
+            }
+            TCLUNK => {
+                self.fids.remove(&request.u32()?).ok_or(EBADF)?;
+            }
+            // Requests are answered in order, so there's never one to flush
+            TFLUSH => {}
+            _ => return Err(EOPNOTSUPP),
+        }
+        Ok(reply)
+    }
+
+    /// The inode `fid` refers to, looked up by number
+    fn fid(&self, fid: u32) -> Result<Inode, u32> {
+        let inode_number = *self.fids.get(&fid).ok_or(EBADF)?;
+        self.archive.inode_by_number(inode_number).map_err(|_| EIO)
+    }
+
+    /// The inode number of the parent of `dir`, or of `dir` itself if it's the root
+    fn parent(&self, dir: &Inode) -> Result<u32, u32> {
+        let parent = dir.parent_inode_number().ok_or(ENOTDIR)?;
+        if parent > self.archive.superblock().inode_count {
+            return Ok(dir.inode_number());
+        }
+        Ok(parent)
+    }
+
+    /// The type and number of the entry `name` in the directory numbered `inode_number`
+    fn walk(&self, inode_number: u32, name: &[u8]) -> Result<(FileType, u32), u32> {
+        let dir = self
+            .archive
+            .inode_by_number(inode_number)
+            .map_err(|_| EIO)?;
+        if !dir.is_dir() {
+            return Err(ENOTDIR);
+        }
+        if name == b".." {
+            return Ok((FileType::Dir, self.parent(&dir)?));
+        }
+        let entries = self.archive.read_dir(&dir).map_err(|_| EIO)?;
+        entries
+            .iter()
+            .find(|entry| entry.name() == name)
+            .map(|entry| (entry.file_type(), entry.inode_number()))
+            .ok_or(ENOENT)
+    }
+
+    /// The name, type and number of each entry of `dir`, starting with `.` and `..`
+    fn read_dir(&self, dir: &Inode) -> Result<Vec<(Vec<u8>, FileType, u32)>, u32> {
+        if !dir.is_dir() {
+            return Err(ENOTDIR);
+        }
+        let mut entries = vec![
+            (b".".to_vec(), FileType::Dir, dir.inode_number()),
+            (b"..".to_vec(), FileType::Dir, self.parent(dir)?),
+        ];
+        let listing = self.archive.read_dir(dir).map_err(|_| EIO)?;
+        entries.extend(listing.iter().map(|entry| {
+            let name = entry.name().to_vec();
+            (name, entry.file_type(), entry.inode_number())
+        }));
+        Ok(entries)
+    }
+
+    /// Up to `count` bytes of the contents of `file`, from `offset`
+    fn read(&self, file: &Inode, offset: u64, count: u32) -> sqfs::Result<Vec<u8>> {
+        let mut reader = self.archive.open_file(file)?;
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::new();
+        reader.take(count.into()).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn getattr(&self, inode: &Inode, reply: &mut Reply) {
+        let size = match inode.data() {
+            InodeData::File(_) => inode.file_size().unwrap(),
+            InodeData::Symlink(target) => target.len() as u64,
+            _ => 0,
+        };
+        let rdev = match inode.data() {
+            InodeData::BlockDevice(device) | InodeData::CharDevice(device) => {
+                let (major, minor) = (u64::from(device.major()), u64::from(device.minor()));
+                // As linux's makedev
+                (major & 0xfffff000) << 32
+                    | (major & 0xfff) << 8
+                    | (minor & 0xffffff00) << 12
+                    | (minor & 0xff)
+            }
+            _ => 0,
+        };
+        let mtime = u64::from(inode.mtime());
+        reply
+            .u64(GETATTR_BASIC)
+            .qid(inode.file_type(), inode.inode_number())
+            .u32(inode.mode().to_unix())
+            .u32(inode.uid())
+            .u32(inode.gid())
+            .u64(inode.hard_link_count().into())
+            .u64(rdev)
+            .u64(size)
+            .u64(self.archive.block_size().into())
+            .u64(size.div_ceil(512));
+        // Access, modification and change times, then birth time, generation and data version
+        for _ in 0..3 {
+            reply.u64(mtime).u64(0);
+        }
+        reply.u64(0).u64(0).u64(0).u64(0);
+    }
+}
+
+/// The `d_type` of a directory entry of type `file_type`
+fn dirent_type(file_type: FileType) -> u8 {
+    match file_type {
+        FileType::Fifo => 1,
+        FileType::CharDev => 2,
+        FileType::Dir => 4,
+        FileType::BlockDev => 6,
+        FileType::File => 8,
+        FileType::Symlink => 10,
+        FileType::Socket => 12,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqfs::write::ArchiveBuilder;
+
+    /// An image of `/sub/file`
+    fn image() -> Archive<Vec<u8>> {
+        let (mut archive, image) = ArchiveBuilder::new().build_in_memory();
+        let mut file = archive.create_file();
+        file.set_contents(Box::new(io::Cursor::new("hello")));
+        let file = file.finish(&mut archive).unwrap();
+        let mut sub = archive.create_dir();
+        sub.add_item("file", file);
+        let sub = sub.finish(&mut archive);
+        let mut root = archive.create_dir();
+        root.add_item("sub", sub);
+        let root = root.finish(&mut archive);
+        archive.set_root(root);
+        archive.flush().unwrap();
+        image.open().unwrap()
+    }
+
+    /// Send a request, returning the reply's type and fields
+    fn call<R: ReadAt>(session: &mut Session<R>, ty: u8, fields: &Reply) -> (u8, Vec<u8>) {
+        let mut message = Reply::default();
+        message.u8(ty).u16(1).u8s(&fields.0);
+        let reply = session.handle(&message.0);
+        assert_eq!(reply[1..3], [1, 0]);
+        (reply[0], reply[3..].to_vec())
+    }
+
+    /// Attach fid 0 to the root, and walk fid 1 to `names`, returning the qid paths walked
+    fn walk<R: ReadAt>(session: &mut Session<R>, names: &[&str]) -> Vec<u64> {
+        let mut attach = Reply::default();
+        attach.u32(0).u32(!0).string(b"").string(b"").u32(0);
+        assert_eq!(call(session, TATTACH, &attach).0, TATTACH + 1);
+        let mut walk = Reply::default();
+        walk.u32(0).u32(1).u16(names.len() as u16);
+        for name in names {
+            walk.string(name.as_bytes());
+        }
+        let (ty, reply) = call(session, TWALK, &walk);
+        assert_eq!(ty, TWALK + 1);
+        let mut reply = Request(&reply);
+        (0..reply.u16().unwrap())
+            .map(|_| {
+                reply.bytes(5).unwrap();
+                reply.u64().unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stable_handles() {
+        let archive = image();
+        let mut first = Session::new(archive.clone());
+        let paths = walk(&mut first, &["sub", "file"]);
+        let file = archive.lookup("sub/file").unwrap();
+        assert_eq!(paths[1], u64::from(file.inode_number()));
+        assert_eq!(archive.inode_by_number(paths[1] as u32).unwrap(), file);
+
+        // Another connection, as after the server restarts, finds the same handles
+        let mut second = Session::new(archive.clone());
+        assert_eq!(walk(&mut second, &["sub", "file"]), paths);
+        let root = u64::from(archive.root().unwrap().inode_number());
+        assert_eq!(
+            walk(&mut second, &["sub", "..", ".."]),
+            [paths[0], root, root]
+        );
+
+        let mut read = Reply::default();
+        read.u32(1).u64(1).u32(100);
+        assert_eq!(walk(&mut second, &["sub", "file"]), paths);
+        let (ty, data) = call(&mut second, TREAD, &read);
+        assert_eq!(ty, TREAD + 1);
+        assert_eq!(data[4..], *b"ello");
+
+        let mut clunk = Reply::default();
+        clunk.u32(1);
+        assert_eq!(call(&mut second, TCLUNK, &clunk).0, TCLUNK + 1);
+        let (ty, errno) = call(&mut second, TREAD, &read);
+        assert_eq!((ty, errno), (RLERROR, EBADF.to_le_bytes().to_vec()));
+    }
+}
@@ -0,0 +1,64 @@
+//! Rewrite a squashfs image without timestamps, owners or extended attributes
+//!
+//! Usage: strip [--keep-times] [--keep-ids] [--compression NAME] IMAGE OUTPUT
+
+use std::env;
+use std::fs::File;
+use std::process;
+
+use sqfs::compression::Kind;
+use sqfs::read::Archive;
+use sqfs::write::{self, StripOptions};
+
+const USAGE: &str = "usage: strip [--keep-times] [--keep-ids] [--compression NAME] IMAGE OUTPUT";
+
+fn main() {
+    let mut options = StripOptions::default();
+    let mut paths = Vec::new();
+    let mut args = env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--keep-times") => options.times = false,
+            Some("--keep-ids") => options.ids = false,
+            Some("--compression") => {
+                let name = args.next().unwrap_or_default();
+                let kind = Kind::from_name(&name.to_string_lossy());
+                if !kind.supported() {
+                    eprintln!("unsupported compression: {:?}", name);
+                    process::exit(2);
+                }
+                options.compression = Some(kind);
+            }
+            _ => paths.push(arg),
+        }
+    }
+    let (image, output) = match &paths[..] {
+        [image, output] => (image, output),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    let source = Archive::open(image).unwrap_or_else(|e| {
+        eprintln!("unable to open image: {}", e);
+        process::exit(2);
+    });
+    let output = File::create(output).unwrap_or_else(|e| {
+        eprintln!("unable to create output: {}", e);
+        process::exit(2);
+    });
+    let mut archive = options.archive_builder(&source).build(output);
+    let result = write::strip(&mut archive, &source, &options).and_then(|()| archive.flush());
+    match result {
+        Ok(report) => {
+            for issue in report {
+                eprintln!("warning: {}", issue);
+            }
+        }
+        Err(e) => {
+            eprintln!("unable to strip image: {}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! Compare a squashfs image against a directory on disk
+//!
+//! Usage: verify_tree IMAGE DIR
+
+use std::env;
+use std::process;
+
+use sqfs::read::Archive;
+
+fn main() {
+    let args: Vec<_> = env::args_os().skip(1).collect();
+    let (image, dir) = match &args[..] {
+        [image, dir] => (image, dir),
+        _ => {
+            eprintln!("usage: verify_tree IMAGE DIR");
+            process::exit(2);
+        }
+    };
+
+    let archive = Archive::open(image).unwrap_or_else(|e| {
+        eprintln!("unable to open image: {}", e);
+        process::exit(2);
+    });
+    let mismatches = archive.verify_tree(dir).unwrap_or_else(|e| {
+        eprintln!("unable to compare trees: {}", e);
+        process::exit(2);
+    });
+    for mismatch in &mismatches {
+        println!("{}", mismatch);
+    }
+    if !mismatches.is_empty() {
+        process::exit(1);
+    }
+}
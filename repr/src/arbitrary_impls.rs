@@ -0,0 +1,84 @@
+//! `Arbitrary` implementations for types with invariants a plain derive would not uphold
+//!
+//! Generated values are only "valid-ish": they respect the invariants which are checked before
+//! anything else is looked at (e.g. the superblock magic), so fuzzers spend their time past the
+//! first sanity check.
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+
+use crate::compression::options::{GzipStrategies, Lz4Flags, XzFilters};
+use crate::superblock::{self, Flags, Superblock};
+use crate::{compression, datablock, Mode, Reserved};
+
+macro_rules! arbitrary_bitflags {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl<'a> Arbitrary<'a> for $ty {
+                fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+                    Ok(Self::from_bits_truncate(Arbitrary::arbitrary(u)?))
+                }
+            }
+        )*
+    };
+}
+
+arbitrary_bitflags!(Mode, Flags, GzipStrategies, XzFilters, Lz4Flags);
+
+impl<'a, const N: usize> Arbitrary<'a> for Reserved<N> {
+    fn arbitrary(_: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Reserved::ZERO)
+    }
+}
+
+impl<'a> Arbitrary<'a> for datablock::Size {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let size = u.int_in_range(0..=datablock::MAX_SIZE as u32)?;
+        Ok(datablock::Size::new(size, u.arbitrary()?))
+    }
+}
+
+impl<'a> Arbitrary<'a> for Superblock {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let block_log = u.int_in_range(crate::BLOCK_LOG_MIN..=crate::BLOCK_LOG_MAX)?;
+        let compression_id = u.int_in_range(compression::Id::GZIP.0..=compression::Id::MAX.0)?;
+        Ok(Superblock {
+            magic: superblock::MAGIC,
+            inode_count: u.arbitrary()?,
+            modification_time: u.arbitrary()?,
+            block_size: 1 << block_log,
+            fragment_entry_count: u.arbitrary()?,
+            compression_id: compression::Id(compression_id),
+            block_log,
+            flags: u.arbitrary()?,
+            id_count: u.arbitrary()?,
+            version_major: superblock::VERSION_MAJOR,
+            version_minor: superblock::VERSION_MINOR,
+            root_inode_ref: u.arbitrary()?,
+            bytes_used: u.arbitrary()?,
+            id_table_start: u.arbitrary()?,
+            xattr_id_table_start: u.arbitrary()?,
+            inode_table_start: u.arbitrary()?,
+            directory_table_start: u.arbitrary()?,
+            fragment_table_start: u.arbitrary()?,
+            export_table_start: u.arbitrary()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn superblock_invariants() {
+        let data: Vec<u8> = (0..=255).cycle().take(4096).collect();
+        let mut u = Unstructured::new(&data);
+        while !u.is_empty() {
+            let superblock = Superblock::arbitrary(&mut u).unwrap();
+            let (block_size, block_log) = (superblock.block_size, superblock.block_log);
+            assert_eq!(block_size, 1 << block_log);
+            assert!((crate::BLOCK_SIZE_MIN..=crate::BLOCK_SIZE_MAX).contains(&block_size));
+            assert_eq!({ superblock.magic }, superblock::MAGIC);
+        }
+    }
+}
@@ -2,9 +2,15 @@
 //!
 use zerocopy::{AsBytes, FromBytes};
 
+assert_size! {
+    Id => 2,
+}
+
 pub mod options;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(transparent)]
 pub struct Id(pub u16);
 
@@ -5,8 +5,18 @@
 use bitflags::bitflags;
 use zerocopy::{AsBytes, FromBytes, Unaligned};
 
+assert_size! {
+    Gzip => 8,
+    Xz => 8,
+    Lz4 => 8,
+    Zstd => 4,
+    Lzo => 8,
+}
+
 /// Compression options for the gzip compressor
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct Gzip {
     /// Should be in range 1…9 (inclusive). Defaults to 9.
@@ -35,6 +45,7 @@ bitflags! {
     ///
     /// If no flags are set, the default strategy is implicitly used.
     #[derive(FromBytes, AsBytes)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     #[repr(transparent)]
     pub struct GzipStrategies: u16 {
         const DEFAULT = 0x01;
@@ -53,6 +64,8 @@ impl Default for GzipStrategies {
 
 /// Compression options for the xz compressor
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct Xz {
     /// Should be > 8KiB, and must be either the sum of a power of two,
@@ -63,10 +76,22 @@ pub struct Xz {
     pub executable_filters: XzFilters,
 }
 
+/// The options implied by an archive with the default block size, as `mksquashfs` sizes the
+/// dictionary to match the block size
+impl Default for Xz {
+    fn default() -> Self {
+        Self {
+            dictionary_size: crate::BLOCK_SIZE_DEFAULT,
+            executable_filters: XzFilters::empty(),
+        }
+    }
+}
+
 bitflags! {
     /// A bitfield describing the additional enabled filters attempted to
     /// better compress executable code.
     #[derive(AsBytes, FromBytes)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     #[repr(transparent)]
     pub struct XzFilters: u32 {
         const X86 = 0x01;
@@ -80,6 +105,8 @@ bitflags! {
 
 /// Compression options for the lz4 compressor
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct Lz4 {
     /// The only supported value is 1 (`LZ4_LEGACY`)
@@ -101,6 +128,7 @@ bitflags! {
     /// A bitfield describing the additional enabled filters attempted to
     /// better compress executable code.
     #[derive(Default, AsBytes, FromBytes)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     #[repr(transparent)]
     pub struct Lz4Flags: u32 {
         /// Use LZ4 High Compression(HC) mode
@@ -110,6 +138,8 @@ bitflags! {
 
 /// Compression options for the zstd compressor
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct Zstd {
     /// Should be in range 1..22 (inclusive).
@@ -127,6 +157,8 @@ impl Default for Zstd {
 
 /// Compression options for the lzo compressor
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct Lzo {
     /// Should be in range 1..22 (inclusive).
@@ -151,6 +183,8 @@ impl Default for Lzo {
 
 /// Which variant of LZO to use
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(transparent)]
 pub struct LzoAlgorithm(pub u32);
 
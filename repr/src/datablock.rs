@@ -1,6 +1,11 @@
 use std::fmt;
 use zerocopy::{AsBytes, FromBytes, Unaligned};
 
+assert_size! {
+    Size => 4,
+    Ref => 8,
+}
+
 /// The max size of a datablock: 1 MiB
 pub const MAX_SIZE: usize = 1024 * 1024;
 
@@ -40,5 +45,7 @@ impl fmt::Debug for Size {
 
 /// Number of bytes from the start of the archive where the block starts
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct Ref(pub u64);
@@ -17,6 +17,12 @@ use crate::inode;
 
 pub use crate::metablock::Ref;
 
+assert_size! {
+    Header => 12,
+    Entry => 8,
+    Index => 12,
+}
+
 /// A header which precedes a list of directory entries
 ///
 ///Every time, the inode block changes or the difference of the inode number cannot be encoded in
@@ -25,6 +31,8 @@ pub use crate::metablock::Ref;
 ///A header must not be followed by more than 256 entries. If there are more entries,
 /// a new header is emitted.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct Header {
     /// Number of entries following the header
@@ -54,6 +62,8 @@ pub struct Header {
 ///The file names are stored without trailing null bytes. Since a zero length name makes no sense,
 /// the name length is stored off-by-one, i.e. the value 0 cannot be encoded
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct Entry {
     /// An offset into the uncompressed inode metadata block
@@ -79,6 +89,8 @@ pub struct Entry {
 ///
 /// A directory index is followed by string name of `name_size + 1` bytes
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct Index {
     /// A byte offset from the first directory header to the current header, as if the uncompressed
@@ -17,10 +17,17 @@
 
 use zerocopy::{AsBytes, FromBytes, Unaligned};
 
-use crate::datablock;
+use crate::{datablock, Reserved};
+
+assert_size! {
+    Entry => 16,
+    Idx => 4,
+}
 
 /// Fragment block entry
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct Entry {
     /// The offset within the archive where the fragment block starts
@@ -33,9 +40,22 @@ pub struct Entry {
     /// uncompressed bit will never be set by the size.
     pub size: datablock::Size,
     /// This field is unused
-    pub _unused: u32,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub _unused: Reserved<4>,
+}
+
+impl Entry {
+    pub fn new(start: datablock::Ref, size: datablock::Size) -> Self {
+        Self {
+            start,
+            size,
+            _unused: Reserved::ZERO,
+        }
+    }
 }
 
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct Idx(pub u32);
@@ -8,11 +8,31 @@ use zerocopy::{AsBytes, FromBytes, Unaligned};
 
 pub use crate::metablock::Ref;
 
+assert_size! {
+    Idx => 4,
+    Kind => 2,
+    Header => 16,
+    BasicDir => 16,
+    ExtendedDir => 24,
+    BasicFile => 16,
+    ExtendedFile => 40,
+    Symlink => 8,
+    BasicDevice => 8,
+    ExtendedDevice => 12,
+    DeviceNumber => 4,
+    BasicIpc => 4,
+    ExtendedIpc => 8,
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct Idx(pub u32);
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct Kind(pub u16);
 
@@ -58,6 +78,8 @@ impl Kind {
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct Header {
     /// The type of item described by the inode which follows this header
@@ -83,6 +105,8 @@ pub struct Header {
 
 /// A basic directory inode structure
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct BasicDir {
     /// The location of the block in the Directory Table where the directory entry information starts
@@ -108,6 +132,8 @@ pub struct BasicDir {
 /// This inode is followed by `index_count + 1` directory index entries for faster
 /// lookup in the directory table
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct ExtendedDir {
     /// The number of hard links to this directory
@@ -146,6 +172,8 @@ pub const fn dir_stored_size(total_size: u32) -> u32 {
 /// the number of blocks needed to store file_size bytes, rounded up. Each item in the list
 /// describes the (possibly compressed) size of a block.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct BasicFile {
     /// The offset from the start of the archive where the data blocks are stored
@@ -173,6 +201,8 @@ pub struct BasicFile {
 /// the number of blocks needed to store file_size bytes, rounded up. Each item in the list
 /// describes the (possibly compressed) size of a block.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct ExtendedFile {
     /// The offset from the start of the archive where the data blocks are stored
@@ -208,6 +238,8 @@ pub struct ExtendedFile {
 /// If the header had a kind `EXT_SYMLINK`, the path string is followed by an xattr_idx u32, which
 /// is an index into the xattr lookup table. Set to 0xFFFFFFFF if the inode has no extended attributes
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct Symlink {
     /// The number of hard links to this symlink
@@ -219,6 +251,8 @@ pub struct Symlink {
 
 /// A basic device inode structure
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct BasicDevice {
     /// The number of hard links to this device
@@ -229,6 +263,8 @@ pub struct BasicDevice {
 
 /// A full extended device inode structure
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct ExtendedDevice {
     /// The number of hard links to this device
@@ -239,14 +275,36 @@ pub struct ExtendedDevice {
     pub xattr_idx: xattr::Idx,
 }
 
+/// A device number, in the encoding used by the linux `new_encode_dev`
+///
+/// Only 12 bits of major and 20 bits of minor number can be represented.
 #[derive(Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct DeviceNumber(pub u32);
 
 impl DeviceNumber {
-    pub fn new(major: u32, minor: u32) -> Self {
-        assert!(major <= 0x0_0FFF);
-        assert!(minor <= 0xF_FFFF);
+    /// The largest major number which can be stored
+    pub const MAJOR_MAX: u32 = 0x0_0FFF;
+    /// The largest minor number which can be stored
+    pub const MINOR_MAX: u32 = 0xF_FFFF;
+
+    /// Encode a major/minor pair
+    ///
+    /// Returns an error if either number is too large to be stored
+    pub fn new(major: u32, minor: u32) -> Result<Self, DeviceNumberError> {
+        if major > Self::MAJOR_MAX || minor > Self::MINOR_MAX {
+            return Err(DeviceNumberError { major, minor });
+        }
+        Ok(Self::encode(major, minor))
+    }
+
+    /// Encode a major/minor pair, saturating each number at the largest representable value
+    pub fn new_clamped(major: u32, minor: u32) -> Self {
+        Self::encode(major.min(Self::MAJOR_MAX), minor.min(Self::MINOR_MAX))
+    }
+
+    const fn encode(major: u32, minor: u32) -> Self {
         DeviceNumber(major << 8 | minor & 0xFF | (minor & !0xFF) << 12)
     }
 
@@ -259,6 +317,28 @@ impl DeviceNumber {
     }
 }
 
+/// A major/minor pair which does not fit in a [`DeviceNumber`](struct.DeviceNumber.html)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DeviceNumberError {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl fmt::Display for DeviceNumberError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "device number {}:{} out of range (max {}:{})",
+            self.major,
+            self.minor,
+            DeviceNumber::MAJOR_MAX,
+            DeviceNumber::MINOR_MAX
+        )
+    }
+}
+
+impl std::error::Error for DeviceNumberError {}
+
 impl fmt::Debug for DeviceNumber {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("DeviceNumber")
@@ -270,6 +350,8 @@ impl fmt::Debug for DeviceNumber {
 
 /// A basic IPC (fifo/socket) inode structure
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct BasicIpc {
     /// The number of hard links to this device
@@ -278,6 +360,8 @@ pub struct BasicIpc {
 
 /// A full extended IPC (fifo/socket) inode structure
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct ExtendedIpc {
     /// The number of hard links to this device
@@ -285,3 +369,19 @@ pub struct ExtendedIpc {
     /// An index into the xattr lookup table. Set to 0xFFFFFFFF if the inode has no extended attributes
     pub xattr_idx: xattr::Idx,
 }
+
+#[test]
+fn device_number_tests() {
+    let dev = DeviceNumber::new(8, 0x12345).unwrap();
+    assert_eq!((dev.major(), dev.minor()), (8, 0x12345));
+
+    let err = DeviceNumber::new(0x1000, 0).unwrap_err();
+    assert_eq!((err.major, err.minor), (0x1000, 0));
+    assert!(DeviceNumber::new(0, 0x10_0000).is_err());
+
+    let dev = DeviceNumber::new_clamped(u32::MAX, u32::MAX);
+    assert_eq!(
+        (dev.major(), dev.minor()),
+        (DeviceNumber::MAJOR_MAX, DeviceNumber::MINOR_MAX)
+    );
+}
@@ -0,0 +1,177 @@
+//! Field layout reflection
+//!
+//! Lists the name, byte offset and size of each field of the on-disk structures, so tools can
+//! annotate raw bytes without hardcoding the squashfs format a second time.
+
+use std::mem;
+
+use crate::compression::options;
+use crate::{directory, fragment, inode, superblock, xattr};
+
+/// A single field of a packed on-disk structure
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Field {
+    /// The name of the field in the rust structure
+    pub name: &'static str,
+    /// The offset of the field from the start of the structure, in bytes
+    pub offset: usize,
+    /// The size of the field, in bytes
+    pub size: usize,
+}
+
+impl Field {
+    /// The bytes of this field, from the bytes of the whole structure
+    ///
+    /// Returns `None` if `bytes` is too short to contain the field
+    pub fn slice<'a>(&self, bytes: &'a [u8]) -> Option<&'a [u8]> {
+        bytes.get(self.offset..self.offset + self.size)
+    }
+}
+
+/// A packed on-disk structure with a known field layout
+pub trait Layout: Sized {
+    /// The size of the structure on disk, in bytes
+    const PACKED_SIZE: usize = mem::size_of::<Self>();
+
+    /// The fields of the structure, in the order they are stored on disk
+    fn fields() -> Vec<Field>;
+}
+
+fn pointee_size<T>(_: *const T) -> usize {
+    mem::size_of::<T>()
+}
+
+macro_rules! impl_layout {
+    ($($ty:ty { $($field:ident),* $(,)? })*) => {
+        $(
+            impl Layout for $ty {
+                fn fields() -> Vec<Field> {
+                    let uninit = mem::MaybeUninit::<$ty>::uninit();
+                    let base = uninit.as_ptr();
+                    vec![$({
+                        // Safe: only the address of the field is computed, nothing is read
+                        let field = unsafe { std::ptr::addr_of!((*base).$field) };
+                        Field {
+                            name: stringify!($field),
+                            offset: field as usize - base as usize,
+                            size: pointee_size(field),
+                        }
+                    }),*]
+                }
+            }
+        )*
+    };
+}
+
+impl_layout! {
+    superblock::Superblock {
+        magic,
+        inode_count,
+        modification_time,
+        block_size,
+        fragment_entry_count,
+        compression_id,
+        block_log,
+        flags,
+        id_count,
+        version_major,
+        version_minor,
+        root_inode_ref,
+        bytes_used,
+        id_table_start,
+        xattr_id_table_start,
+        inode_table_start,
+        directory_table_start,
+        fragment_table_start,
+        export_table_start,
+    }
+    inode::Header { inode_type, permissions, uid_idx, gid_idx, modified_time, inode_number }
+    inode::BasicDir { dir_block_start, hard_link_count, file_size, block_offset, parent_inode_number }
+    inode::ExtendedDir {
+        hard_link_count,
+        file_size,
+        dir_block_start,
+        parent_inode_number,
+        index_count,
+        block_offset,
+        xattr_idx,
+    }
+    inode::BasicFile { blocks_start, fragment_block_index, block_offset, file_size }
+    inode::ExtendedFile {
+        blocks_start,
+        file_size,
+        sparse,
+        hard_link_count,
+        fragment_block_index,
+        block_offset,
+        xattr_idx,
+    }
+    inode::Symlink { hard_link_count, target_size }
+    inode::BasicDevice { hard_link_count, device }
+    inode::ExtendedDevice { hard_link_count, device, xattr_idx }
+    inode::BasicIpc { hard_link_count }
+    inode::ExtendedIpc { hard_link_count, xattr_idx }
+    directory::Header { count, start, inode_number }
+    directory::Entry { offset, inode_offset, kind, name_size }
+    directory::Index { index, start, name_size }
+    fragment::Entry { start, size, _unused }
+    xattr::Key { kind, name_size }
+    xattr::Value { value_size }
+    xattr::LookupTable { xattr_table_start, xattr_entry_count, _unused }
+    xattr::LookupEntry { xattr_ref, count, size }
+    options::Gzip { compression_level, window_size, strategies }
+    options::Xz { dictionary_size, executable_filters }
+    options::Lz4 { version, flags }
+    options::Zstd { compression_level }
+    options::Lzo { algorithm, level }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_complete<T: Layout>() {
+        let mut offset = 0;
+        for field in T::fields() {
+            assert_eq!(field.offset, offset, "gap before {}", field.name);
+            offset += field.size;
+        }
+        assert_eq!(offset, T::PACKED_SIZE, "fields missing at end");
+    }
+
+    #[test]
+    fn fields_cover_structs() {
+        assert_complete::<superblock::Superblock>();
+        assert_complete::<inode::Header>();
+        assert_complete::<inode::BasicDir>();
+        assert_complete::<inode::ExtendedDir>();
+        assert_complete::<inode::BasicFile>();
+        assert_complete::<inode::ExtendedFile>();
+        assert_complete::<inode::Symlink>();
+        assert_complete::<inode::BasicDevice>();
+        assert_complete::<inode::ExtendedDevice>();
+        assert_complete::<inode::BasicIpc>();
+        assert_complete::<inode::ExtendedIpc>();
+        assert_complete::<directory::Header>();
+        assert_complete::<directory::Entry>();
+        assert_complete::<directory::Index>();
+        assert_complete::<fragment::Entry>();
+        assert_complete::<xattr::Key>();
+        assert_complete::<xattr::Value>();
+        assert_complete::<xattr::LookupTable>();
+        assert_complete::<xattr::LookupEntry>();
+        assert_complete::<options::Gzip>();
+        assert_complete::<options::Xz>();
+        assert_complete::<options::Lz4>();
+        assert_complete::<options::Zstd>();
+        assert_complete::<options::Lzo>();
+    }
+
+    #[test]
+    fn superblock_offsets() {
+        let fields = superblock::Superblock::fields();
+        let bytes_used = fields.iter().find(|f| f.name == "bytes_used").unwrap();
+        assert_eq!((bytes_used.offset, bytes_used.size), (40, 8));
+        assert_eq!(superblock::Superblock::PACKED_SIZE, 96);
+    }
+}
@@ -9,6 +9,8 @@
 //! * [Export Table]
 //! * [UID/GID Lookup Table](uid_gid/index.html)
 //! * [Xattr Table](xattr/index.html)
+//!
+//! The byte layout of each structure can be inspected at runtime through [`layout`](layout/index.html)
 
 use bitflags::bitflags;
 use zerocopy::{AsBytes, FromBytes, Unaligned};
@@ -18,17 +20,42 @@ use std::fmt::Write;
 use std::io;
 use std::mem;
 use std::mem::MaybeUninit;
+use std::str::FromStr;
+
+#[cfg(not(target_endian = "little"))]
+compile_error!("squashfs structures are little endian, and are read in place as native integers");
+
+/// Assert at compile time that the packed size of a structure matches the squashfs format
+macro_rules! assert_size {
+    ($($ty:ty => $size:expr),* $(,)?) => {
+        $(const _: () = assert!(::std::mem::size_of::<$ty>() == $size);)*
+    };
+}
 
 pub mod compression;
 pub mod datablock;
 pub mod directory;
 pub mod fragment;
 pub mod inode;
+pub mod layout;
+pub mod limits;
 pub mod metablock;
+pub mod offset;
 pub mod superblock;
 pub mod uid_gid;
 pub mod xattr;
 
+#[cfg(feature = "arbitrary")]
+mod arbitrary_impls;
+#[cfg(feature = "serde")]
+mod serde_impls;
+
+assert_size! {
+    MetablockHeader => 2,
+    Mode => 2,
+    Time => 4,
+}
+
 pub const BLOCK_LOG_MIN: u16 = 12;
 pub const BLOCK_LOG_MAX: u16 = 20;
 pub const BLOCK_LOG_DEFAULT: u16 = 17;
@@ -39,13 +66,15 @@ pub const BLOCK_SIZE_DEFAULT: u32 = 1 << BLOCK_LOG_DEFAULT as u32;
 
 /// The header stored before a metadata block
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct MetablockHeader(pub u16);
 
 impl MetablockHeader {
     /// Return true if the following block is compressed
     pub fn is_compressed(self) -> bool {
-        (self.0 & 0x8000) != 0
+        (self.0 & 0x8000) == 0
     }
 
     /// The size in bytes (on disk) of the following metadata block
@@ -117,6 +146,45 @@ impl Mode {
             bits: self.bits & Self::TYPE_MASK.bits,
         }
     }
+
+    /// Convert from a unix `mode_t` value
+    ///
+    /// Bits which do not correspond to a permission or file type are dropped
+    pub const fn from_unix(mode: u32) -> Self {
+        Self::from_bits_truncate(mode as u16)
+    }
+
+    /// Convert to a unix `mode_t` value
+    pub const fn to_unix(self) -> u32 {
+        self.bits as u32
+    }
+}
+
+impl From<std::fs::Permissions> for Mode {
+    #[cfg(unix)]
+    fn from(permissions: std::fs::Permissions) -> Self {
+        use std::os::unix::fs::PermissionsExt;
+
+        Mode::from_unix(permissions.mode())
+    }
+
+    #[cfg(not(unix))]
+    fn from(permissions: std::fs::Permissions) -> Self {
+        if permissions.readonly() {
+            Mode::from_unix(0o444)
+        } else {
+            Mode::O644
+        }
+    }
+}
+
+#[cfg(unix)]
+impl From<Mode> for std::fs::Permissions {
+    fn from(mode: Mode) -> Self {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::Permissions::from_mode(mode.to_unix())
+    }
 }
 
 impl fmt::Display for Mode {
@@ -200,7 +268,127 @@ impl fmt::Display for Mode {
     }
 }
 
+/// Reserved or unused bytes in an on-disk structure
+///
+/// Ignored when read: any two values compare equal, so structures containing garbage in
+/// reserved fields still compare equal to their zero-filled counterparts.
+///
+/// Structures built with their constructors hold zeros. The bytes of a structure which was
+/// read are kept as they were though, so writing it back out copies them: build a new
+/// structure from its fields to write zeros instead.
+#[derive(Copy, Clone)]
+#[repr(transparent)]
+pub struct Reserved<const N: usize>([u8; N]);
+
+// Safe: Reserved is a transparent wrapper around a byte array. zerocopy's derives don't accept
+// const generic parameters, so these can't be derived.
+unsafe impl<const N: usize> AsBytes for Reserved<N> {
+    fn only_derive_is_allowed_to_implement_this_trait() {}
+}
+unsafe impl<const N: usize> FromBytes for Reserved<N> {
+    fn only_derive_is_allowed_to_implement_this_trait() {}
+}
+unsafe impl<const N: usize> Unaligned for Reserved<N> {
+    fn only_derive_is_allowed_to_implement_this_trait() {}
+}
+
+impl<const N: usize> Reserved<N> {
+    pub const ZERO: Self = Reserved([0; N]);
+}
+
+impl<const N: usize> Default for Reserved<N> {
+    fn default() -> Self {
+        Self::ZERO
+    }
+}
+
+impl<const N: usize> PartialEq for Reserved<N> {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl<const N: usize> Eq for Reserved<N> {}
+
+impl<const N: usize> fmt::Debug for Reserved<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Reserved<{}>", N)
+    }
+}
+
+/// Parse a mode either from its symbolic form (`"drwxr-xr-x"` or `"rwxr-xr-x"`), or as an
+/// octal number (`"0755"`, `"755"` or `"40755"`)
+impl FromStr for Mode {
+    type Err = ParseModeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.is_empty() && s.bytes().all(|b| (b'0'..=b'7').contains(&b)) {
+            return u16::from_str_radix(s, 8)
+                .map(|bits| Mode::from_unix(bits.into()))
+                .map_err(|_| ParseModeError(()));
+        }
+
+        let bytes = s.as_bytes();
+        let (ty, perms) = match bytes.len() {
+            9 => (Mode::NONE, bytes),
+            10 => {
+                let ty = match bytes[0] {
+                    b'd' => Mode::TYPE_DIR,
+                    b'c' => Mode::TYPE_CHAR,
+                    b'b' => Mode::TYPE_BLOCK,
+                    b'-' => Mode::TYPE_FILE,
+                    b'l' => Mode::TYPE_LINK,
+                    b's' => Mode::TYPE_SOCKET,
+                    b'p' => Mode::TYPE_FIFO,
+                    _ => return Err(ParseModeError(())),
+                };
+                (ty, &bytes[1..])
+            }
+            _ => return Err(ParseModeError(())),
+        };
+
+        let mut mode = ty;
+        let flag = |c: u8, expected: u8, flag: Mode| match c {
+            b'-' => Ok(Mode::NONE),
+            c if c == expected => Ok(flag),
+            _ => Err(ParseModeError(())),
+        };
+        let exec = |c: u8, exec: Mode, special: Mode, special_char: u8| match c {
+            b'-' => Ok(Mode::NONE),
+            b'x' => Ok(exec),
+            c if c == special_char.to_ascii_uppercase() => Ok(special),
+            c if c == special_char => Ok(exec | special),
+            _ => Err(ParseModeError(())),
+        };
+        mode |= flag(perms[0], b'r', Mode::USER_READ)?;
+        mode |= flag(perms[1], b'w', Mode::USER_WRITE)?;
+        mode |= exec(perms[2], Mode::USER_EXEC, Mode::BIT_SUID, b's')?;
+        mode |= flag(perms[3], b'r', Mode::GROUP_READ)?;
+        mode |= flag(perms[4], b'w', Mode::GROUP_WRITE)?;
+        mode |= exec(perms[5], Mode::GROUP_EXEC, Mode::BIT_SGID, b's')?;
+        mode |= flag(perms[6], b'r', Mode::OTHER_READ)?;
+        mode |= flag(perms[7], b'w', Mode::OTHER_WRITE)?;
+        mode |= exec(perms[8], Mode::OTHER_EXEC, Mode::BIT_STICKY, b't')?;
+
+        Ok(mode)
+    }
+}
+
+/// An error returned when parsing a [`Mode`](struct.Mode.html) fails
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseModeError(());
+
+impl fmt::Display for ParseModeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("invalid mode: expected an octal mode or a string like \"rwxr-xr-x\"")
+    }
+}
+
+impl std::error::Error for ParseModeError {}
+
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct Time(pub u32);
 
@@ -211,3 +399,130 @@ fn mode_tests() {
     let mode = mode | Mode::BIT_STICKY;
     assert_eq!(&format!("{}", mode), "-rwxr-xr-T");
 }
+
+#[test]
+fn mode_unix_conversions() {
+    let mode = Mode::from_unix(0o100_755);
+    assert_eq!(mode, Mode::TYPE_FILE | Mode::O755);
+    assert_eq!(mode.to_unix(), 0o100_755);
+
+    // Bits outside of mode_t's type and permission bits are dropped
+    assert_eq!(Mode::from_unix(0o1_000_644), Mode::O644);
+}
+
+#[cfg(unix)]
+#[test]
+fn mode_permissions_conversions() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let permissions = std::fs::Permissions::from(Mode::O755);
+    assert_eq!(permissions.mode(), 0o755);
+    assert_eq!(Mode::from(permissions), Mode::O755);
+}
+
+#[test]
+fn mode_parse_tests() {
+    let mode: Mode = "-rwxr-xr--".parse().unwrap();
+    assert_eq!(mode, Mode::TYPE_FILE | Mode { bits: 0o754 });
+    assert_eq!("rwxr-xr-x".parse(), Ok(Mode::O755));
+    assert_eq!(
+        "drwxrwxrwt".parse(),
+        Ok(Mode::TYPE_DIR | Mode::O777 | Mode::BIT_STICKY)
+    );
+    assert_eq!("rwSr-sr-T".parse(), Ok(Mode { bits: 0o7654 }));
+
+    assert_eq!("0755".parse(), Ok(Mode::O755));
+    assert_eq!("644".parse(), Ok(Mode::O644));
+    assert_eq!("40755".parse(), Ok(Mode::TYPE_DIR | Mode::O755));
+
+    for bad in &[
+        "",
+        "0o755",
+        "1777777",
+        "rwxr-xr-",
+        "xrwxr-xr-x",
+        "rwxrwxrwz",
+        "rwtr-xr-x",
+    ] {
+        assert!(bad.parse::<Mode>().is_err(), "{:?} should not parse", bad);
+    }
+
+    for &mode in &[
+        Mode::TYPE_LINK | Mode::O777,
+        Mode::TYPE_FIFO | Mode { bits: 0o6640 },
+    ] {
+        assert_eq!(mode.to_string().parse(), Ok(mode));
+    }
+}
+
+#[test]
+fn reserved_ignored() {
+    let entry: fragment::Entry =
+        read(&[1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0xAA, 0xBB, 0xCC, 0xDD][..]).unwrap();
+    assert_eq!(
+        entry,
+        fragment::Entry::new(datablock::Ref(1), datablock::Size::new(2, false))
+    );
+    // Kept as read, only constructors zero the reserved bytes
+    assert_eq!(&entry.as_bytes()[12..], &[0xAA, 0xBB, 0xCC, 0xDD]);
+    let rebuilt = fragment::Entry::new(entry.start, entry.size);
+    assert_eq!(&rebuilt.as_bytes()[12..], &[0; 4]);
+    assert_eq!(
+        fragment::Entry::new(datablock::Ref(1), datablock::Size::ZERO).as_bytes()[12..],
+        [0; 4]
+    );
+}
+
+#[test]
+fn padded_sizes() {
+    use superblock::padded_size;
+
+    assert_eq!(padded_size(0), 0);
+    assert_eq!(padded_size(1), 4096);
+    assert_eq!(padded_size(4096), 4096);
+    assert_eq!(padded_size(4097), 8192);
+}
+
+#[test]
+fn superblock_layout() {
+    let superblock = superblock::Superblock {
+        magic: superblock::MAGIC,
+        inode_count: 0x0102_0304,
+        modification_time: Time(0),
+        block_size: BLOCK_SIZE_DEFAULT,
+        fragment_entry_count: 0,
+        compression_id: compression::Id::ZSTD,
+        block_log: BLOCK_LOG_DEFAULT,
+        flags: superblock::Flags::empty(),
+        id_count: 0,
+        version_major: superblock::VERSION_MAJOR,
+        version_minor: superblock::VERSION_MINOR,
+        root_inode_ref: metablock::Ref::new(0, 0),
+        bytes_used: 0x0102_0304_0506_0708,
+        id_table_start: 0,
+        xattr_id_table_start: 0,
+        inode_table_start: 0,
+        directory_table_start: 0,
+        fragment_table_start: 0,
+        export_table_start: u64::MAX,
+    };
+    let bytes = superblock.as_bytes();
+    assert_eq!(&bytes[0..4], b"hsqs");
+    assert_eq!(&bytes[4..8], &[4, 3, 2, 1]);
+    assert_eq!(&bytes[12..16], &BLOCK_SIZE_DEFAULT.to_le_bytes());
+    assert_eq!(&bytes[20..22], &[6, 0]);
+    assert_eq!(&bytes[28..32], &[4, 0, 0, 0]);
+    assert_eq!(&bytes[40..48], &[8, 7, 6, 5, 4, 3, 2, 1]);
+    assert_eq!(&bytes[88..96], &[0xFF; 8]);
+}
+
+#[test]
+fn directory_entry_layout() {
+    let entry = directory::Entry {
+        offset: 0x0102,
+        inode_offset: -1,
+        kind: inode::Kind::BASIC_FILE,
+        name_size: 3,
+    };
+    assert_eq!(entry.as_bytes(), &[2, 1, 0xFF, 0xFF, 2, 0, 3, 0]);
+}
@@ -0,0 +1,56 @@
+//! Limits of the format, and of what the linux kernel will read
+//!
+//! Readers should reject structures beyond these limits before allocating anything for them, so
+//! a corrupt size field can't exhaust memory. Writers should never produce them.
+
+/// The longest name of a directory entry, in bytes (the kernel's `SQUASHFS_NAME_LEN`)
+///
+/// Directory entries store the length minus one in 16 bits, but the kernel refuses anything
+/// longer. Most linux filesystems only allow 255 bytes (`NAME_MAX`).
+pub const MAX_NAME_LEN: usize = 256;
+
+/// The longest symlink target, in bytes
+///
+/// Symlink inodes store the length in 32 bits. This is far beyond `PATH_MAX`, which is all the
+/// kernel will follow.
+pub const MAX_SYMLINK_TARGET: usize = u16::MAX as usize;
+
+/// The most distinct uids and gids in an archive, which is also one more than the largest id
+/// index
+pub const MAX_IDS: usize = u16::MAX as usize;
+
+/// The most entries following one directory header
+pub const MAX_DIR_ENTRIES_PER_HEADER: u32 = 256;
+
+/// The largest file the kernel can represent (`MAX_LFS_FILESIZE`)
+pub const MAX_FILE_SIZE: u64 = i64::MAX as u64;
+
+/// The number of entries in the block list of a file of `file_size` bytes
+///
+/// Every full block has an entry, and so does a partial last block, unless it is stored in a
+/// fragment.
+pub const fn block_count(file_size: u64, block_size: u32, has_fragment: bool) -> u64 {
+    let block_size = block_size as u64;
+    let full = file_size / block_size;
+    let tail = file_size - full * block_size;
+    if !has_fragment && tail > 0 {
+        full + 1
+    } else {
+        full
+    }
+}
+
+/// The most entries in the block list of a file, with blocks of `block_size` bytes
+pub const fn max_blocks(block_size: u32) -> u64 {
+    block_count(MAX_FILE_SIZE, block_size, false)
+}
+
+#[test]
+fn block_counts() {
+    assert_eq!(block_count(0, 4096, false), 0);
+    assert_eq!(block_count(4096, 4096, false), 1);
+    assert_eq!(block_count(4097, 4096, false), 2);
+    assert_eq!(block_count(4097, 4096, true), 1);
+    assert_eq!(block_count(100, 4096, true), 0);
+    assert_eq!(max_blocks(crate::BLOCK_SIZE_MAX), 1 << 43);
+}
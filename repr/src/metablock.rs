@@ -31,13 +31,60 @@
 use std::fmt;
 use zerocopy::{AsBytes, FromBytes, Unaligned};
 
-pub const SIZE: usize = 8 * 1024;
+assert_size! {
+    Ref => 8,
+    Header => 2,
+}
+
+/// The uncompressed size of a metadata block required by the format
+pub const STANDARD_SIZE: usize = 8 * 1024;
+
+/// The largest metadata block the size in a header can describe, if stored uncompressed
+pub const MAX_SIZE: usize = 16 * 1024;
+
+/// The uncompressed size of the metadata blocks read and written
+///
+/// This is always [`STANDARD_SIZE`], unless the `nonstandard-metablock-size` feature is
+/// enabled, and the `SQFS_METABLOCK_SIZE` environment variable is set at compile time to
+/// another power of two from 1KiB up to [`MAX_SIZE`]. That is only useful for experiments: no
+/// other implementation can read the images written.
+pub const SIZE: usize = configured_size();
+
+#[cfg(not(feature = "nonstandard-metablock-size"))]
+const fn configured_size() -> usize {
+    STANDARD_SIZE
+}
+
+#[cfg(feature = "nonstandard-metablock-size")]
+const fn configured_size() -> usize {
+    let value = match option_env!("SQFS_METABLOCK_SIZE") {
+        Some(value) => value.as_bytes(),
+        None => return STANDARD_SIZE,
+    };
+    let mut size = 0;
+    let mut i = 0;
+    while i < value.len() {
+        assert!(
+            value[i].is_ascii_digit(),
+            "SQFS_METABLOCK_SIZE must be a number"
+        );
+        size = size * 10 + (value[i] - b'0') as usize;
+        i += 1;
+    }
+    assert!(
+        size.is_power_of_two() && size >= 1024 && size <= MAX_SIZE,
+        "SQFS_METABLOCK_SIZE must be a power of two from 1024 to 16384"
+    );
+    size
+}
 
-pub const COMPRESSED_FLAG: u16 = 0x8000;
+/// Set in a metablock header if the block is stored uncompressed
+pub const UNCOMPRESSED_FLAG: u16 = 0x8000;
 
 pub type Metablock = [u8; SIZE];
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct Ref(pub u64);
 
@@ -77,20 +124,22 @@ impl fmt::Debug for Ref {
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct Header(pub u16);
 
 impl Header {
     pub fn new(size: u16, compressed: bool) -> Self {
         debug_assert!(usize::from(size) <= SIZE);
-        Self(size | (if compressed { COMPRESSED_FLAG } else { 0 }))
+        Self(size | (if compressed { 0 } else { UNCOMPRESSED_FLAG }))
     }
 
     pub fn compressed(self) -> bool {
-        self.0 & COMPRESSED_FLAG == COMPRESSED_FLAG
+        self.0 & UNCOMPRESSED_FLAG == 0
     }
 
     pub fn size(self) -> u16 {
-        self.0 & !COMPRESSED_FLAG
+        self.0 & !UNCOMPRESSED_FLAG
     }
 }
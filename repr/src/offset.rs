@@ -0,0 +1,124 @@
+//! Byte offsets, distinguished by what they count from
+//!
+//! Positions in an image come in three units which are all plain byte counts, and mixing them
+//! up is easy: an offset from the start of the archive, an offset into the compressed
+//! metablocks of a table (the `block_start` of a [`metablock::Ref`](crate::metablock::Ref)),
+//! and an offset into the uncompressed contents of a table, as if its metablocks were
+//! decompressed back to back. Each gets its own type, and only the meaningful conversions
+//! between them exist.
+
+use std::fmt;
+use std::ops::{Add, AddAssign, Sub};
+
+use crate::metablock;
+
+/// A byte offset from the start of the archive
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ArchiveOffset(pub u64);
+
+/// A byte offset into the compressed metablocks of a table, from the start of the table
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TableOffset(pub u64);
+
+/// A byte offset into the uncompressed contents of a table, from the start of the table
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct UncompressedOffset(pub u64);
+
+/// The table starting at an archive offset, plus an offset into it
+impl Add<TableOffset> for ArchiveOffset {
+    type Output = ArchiveOffset;
+
+    fn add(self, rhs: TableOffset) -> ArchiveOffset {
+        ArchiveOffset(self.0 + rhs.0)
+    }
+}
+
+/// A number of bytes after the offset
+impl Add<u64> for ArchiveOffset {
+    type Output = ArchiveOffset;
+
+    fn add(self, rhs: u64) -> ArchiveOffset {
+        ArchiveOffset(self.0 + rhs)
+    }
+}
+
+impl AddAssign<u64> for ArchiveOffset {
+    fn add_assign(&mut self, rhs: u64) {
+        self.0 += rhs;
+    }
+}
+
+/// The number of bytes between two offsets
+impl Sub for ArchiveOffset {
+    type Output = u64;
+
+    fn sub(self, rhs: ArchiveOffset) -> u64 {
+        self.0 - rhs.0
+    }
+}
+
+impl Add<u64> for UncompressedOffset {
+    type Output = UncompressedOffset;
+
+    fn add(self, rhs: u64) -> UncompressedOffset {
+        UncompressedOffset(self.0 + rhs)
+    }
+}
+
+impl AddAssign<u64> for UncompressedOffset {
+    fn add_assign(&mut self, rhs: u64) {
+        self.0 += rhs;
+    }
+}
+
+impl Sub for UncompressedOffset {
+    type Output = u64;
+
+    fn sub(self, rhs: UncompressedOffset) -> u64 {
+        self.0 - rhs.0
+    }
+}
+
+impl UncompressedOffset {
+    /// The index of the metablock holding the byte at this offset, counting from the first
+    /// metablock of the table
+    pub fn metablock_index(self) -> u64 {
+        self.0 / metablock::SIZE as u64
+    }
+
+    /// The offset of the byte within its metablock
+    pub fn metablock_offset(self) -> u16 {
+        (self.0 % metablock::SIZE as u64) as u16
+    }
+}
+
+impl metablock::Ref {
+    /// The offset of the referenced metablock in its table
+    #[inline]
+    pub fn table_offset(self) -> TableOffset {
+        TableOffset(self.block_start().into())
+    }
+}
+
+impl fmt::Display for ArchiveOffset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversions() {
+        let table = ArchiveOffset(1000);
+        let r = metablock::Ref::new(20, 5);
+        assert_eq!(table + r.table_offset(), ArchiveOffset(1020));
+        assert_eq!((table + 24) - table, 24);
+
+        let offset = UncompressedOffset(metablock::SIZE as u64 * 2 + 7);
+        assert_eq!(offset.metablock_index(), 2);
+        assert_eq!(offset.metablock_offset(), 7);
+    }
+}
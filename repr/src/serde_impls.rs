@@ -0,0 +1,165 @@
+//! Human readable serde implementations for types whose packed representation is not meaningful
+//! on its own
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{datablock, inode, metablock, Mode};
+
+/// Serialized as the symbolic string shown by `ls -l` (e.g. `"drwxr-xr-x"`)
+///
+/// Modes holding only permissions, like inode headers', leave out the type character (e.g.
+/// `"rwxr-xr-x"`). Modes with a type `ls` has no character for are serialized in octal.
+impl Serialize for Mode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let symbolic = self.to_string();
+        match self.ty() {
+            Mode::NONE => serializer.serialize_str(&symbolic[1..]),
+            _ if symbolic.starts_with('?') => {
+                serializer.collect_str(&format_args!("{:o}", self.bits()))
+            }
+            _ => serializer.serialize_str(&symbolic),
+        }
+    }
+}
+
+/// Accepts anything accepted by [`Mode::from_str`](struct.Mode.html#method.from_str)
+impl<'de> Deserialize<'de> for Mode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        s.parse().map_err(D::Error::custom)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "DeviceNumber")]
+struct DeviceNumberRepr {
+    major: u32,
+    minor: u32,
+}
+
+impl Serialize for inode::DeviceNumber {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        DeviceNumberRepr {
+            major: self.major(),
+            minor: self.minor(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for inode::DeviceNumber {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let DeviceNumberRepr { major, minor } = DeviceNumberRepr::deserialize(deserializer)?;
+        inode::DeviceNumber::new(major, minor).map_err(D::Error::custom)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Size")]
+struct SizeRepr {
+    size: u32,
+    uncompressed: bool,
+}
+
+impl Serialize for datablock::Size {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SizeRepr {
+            size: self.size(),
+            uncompressed: self.uncompressed(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for datablock::Size {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let SizeRepr { size, uncompressed } = SizeRepr::deserialize(deserializer)?;
+        if size > datablock::MAX_SIZE as u32 {
+            return Err(D::Error::custom(format!("block size {} too large", size)));
+        }
+        Ok(datablock::Size::new(size, uncompressed))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Ref")]
+struct RefRepr {
+    block_start: u32,
+    start_offset: u16,
+}
+
+impl Serialize for metablock::Ref {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        RefRepr {
+            block_start: self.block_start(),
+            start_offset: self.start_offset(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for metablock::Ref {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let RefRepr {
+            block_start,
+            start_offset,
+        } = RefRepr::deserialize(deserializer)?;
+        Ok(metablock::Ref::new(block_start, start_offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn human_readable() {
+        let mode = Mode::TYPE_DIR | Mode::O755;
+        let json = serde_json::to_string(&mode).unwrap();
+        assert_eq!(json, r#""drwxr-xr-x""#);
+        assert_eq!(serde_json::from_str::<Mode>(&json).unwrap(), mode);
+        assert_eq!(
+            serde_json::from_str::<Mode>(r#""0644""#).unwrap(),
+            Mode::O644
+        );
+        for mode in [Mode::O755, Mode::TYPE_MASK | Mode::O644] {
+            let json = serde_json::to_string(&mode).unwrap();
+            assert_eq!(serde_json::from_str::<Mode>(&json).unwrap(), mode);
+        }
+        assert_eq!(
+            serde_json::to_string(&Mode::O755).unwrap(),
+            r#""rwxr-xr-x""#
+        );
+
+        let header = inode::Header {
+            inode_type: inode::Kind::BASIC_FILE,
+            permissions: Mode::O644,
+            uid_idx: crate::uid_gid::Idx(0),
+            gid_idx: crate::uid_gid::Idx(1),
+            modified_time: crate::Time(1234),
+            inode_number: inode::Idx(2),
+        };
+        let json = serde_json::to_string(&header).unwrap();
+        assert_eq!(
+            serde_json::from_str::<inode::Header>(&json).unwrap(),
+            header
+        );
+
+        let device = inode::DeviceNumber::new(8, 1).unwrap();
+        let json = serde_json::to_string(&device).unwrap();
+        assert_eq!(json, r#"{"major":8,"minor":1}"#);
+        assert_eq!(
+            serde_json::from_str::<inode::DeviceNumber>(&json).unwrap(),
+            device
+        );
+
+        let inode_ref = metablock::Ref::new(1234, 56);
+        let json = serde_json::to_string(&inode_ref).unwrap();
+        assert_eq!(json, r#"{"block_start":1234,"start_offset":56}"#);
+        assert_eq!(
+            serde_json::from_str::<metablock::Ref>(&json).unwrap(),
+            inode_ref
+        );
+    }
+}
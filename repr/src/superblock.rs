@@ -7,6 +7,11 @@ use zerocopy::{AsBytes, FromBytes, Unaligned};
 
 use crate::{compression, inode};
 
+assert_size! {
+    Superblock => 96,
+    Flags => 2,
+}
+
 /// The magic constant which marks a squashfs archive ('hsqs' in ascii)
 pub const MAGIC: u32 = 0x7371_7368;
 
@@ -15,7 +20,23 @@ pub const VERSION_MAJOR: u16 = 4;
 /// The supported minor version of the squashfs archive metadata
 pub const VERSION_MINOR: u16 = 0;
 
+/// The size archives are conventionally padded to a multiple of
+///
+/// Block devices (such as loop devices) can only expose whole blocks, so `mksquashfs` pads its
+/// output to a multiple of 4KiB by default. Padding is not part of the archive: it follows the
+/// [`bytes_used`](Superblock::bytes_used) bytes of the archive proper.
+pub const PADDING: u64 = 4096;
+
+/// The size of an archive using `bytes_used` bytes, once padded to a multiple of [`PADDING`]
+pub const fn padded_size(bytes_used: u64) -> u64 {
+    match bytes_used % PADDING {
+        0 => bytes_used,
+        rem => bytes_used + (PADDING - rem),
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(C, packed)]
 pub struct Superblock {
     /// Must match the value of [`MAGIC`](constant.MAGIC.html) (`0x73717368`/'hsqs') to be considered a
@@ -67,6 +88,7 @@ pub struct Superblock {
 
 bitflags! {
     #[derive(Default, AsBytes, FromBytes)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     #[repr(transparent)]
     pub struct Flags: u16 {
         /// Inodes are stored uncompressed. For backward compatibility reasons, UID/GIDs are also stored uncompressed.
@@ -2,10 +2,17 @@
 
 use zerocopy::{AsBytes, FromBytes, Unaligned};
 
+assert_size! {
+    Id => 4,
+    Idx => 2,
+}
+
 /// UID/GIDs are both stored as u32s. Both UIDs and GIDs are treated as IDs
 #[derive(
     Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, AsBytes, FromBytes, Unaligned,
 )]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct Id(pub u32);
 
@@ -13,5 +20,7 @@ pub struct Id(pub u32);
 #[derive(
     Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, AsBytes, FromBytes, Unaligned,
 )]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct Idx(pub u16);
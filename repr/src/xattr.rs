@@ -30,10 +30,23 @@
 
 use zerocopy::{AsBytes, FromBytes, Unaligned};
 
+use crate::Reserved;
+
+assert_size! {
+    Key => 4,
+    Kind => 2,
+    Value => 4,
+    LookupTable => 16,
+    LookupEntry => 16,
+    Idx => 4,
+}
+
 /// An xattr key
 ///
 /// Followed by a name string of size `name_size`
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct Key {
     /// The ID of the key prefix
@@ -45,6 +58,8 @@ pub struct Key {
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct Kind(pub u16);
 
@@ -73,6 +88,8 @@ impl Kind {
 ///     metadata block containing the key value pairs.
 /// If the value is not stored out of line, the structure is followed by `value_size` bytes of data
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct Value {
     /// The size of the value string
@@ -91,6 +108,8 @@ pub struct Value {
 /// The table is followed by u64 locations of metadata blocks.
 /// There will be `ceil(xattr_entry_count * sizeof(LookupEntry) / metablock_size)` items
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct LookupTable {
     /// The absolute position of the first metadata block holding the key/value pairs.
@@ -98,7 +117,18 @@ pub struct LookupTable {
     /// The number of entries in the Xattr Lookup Table
     pub xattr_entry_count: u32,
     /// Unused
-    pub _unused: u32,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub _unused: Reserved<4>,
+}
+
+impl LookupTable {
+    pub fn new(xattr_table_start: u64, xattr_entry_count: u32) -> Self {
+        Self {
+            xattr_table_start,
+            xattr_entry_count,
+            _unused: Reserved::ZERO,
+        }
+    }
 }
 
 /// A Lookup Table Entry
@@ -111,6 +141,8 @@ pub struct LookupTable {
 /// attributes), the key/value block is only written once, there is only one lookup table entry and
 /// both inodes have the same index.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct LookupEntry {
     /// A reference to the start of the key value block
@@ -127,6 +159,8 @@ pub use crate::metablock::Ref;
 
 /// References the entry with the `i`th index in the Xattr Id Table
 #[derive(Debug, Copy, Clone, PartialEq, Eq, AsBytes, FromBytes, Unaligned)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C, packed)]
 pub struct Idx(pub u32);
 
@@ -1,15 +1,39 @@
-use super::pool;
-use crate::compression::{AnyCodec, Compressor, Decompressor};
+use super::pool::{self, BlockPool};
+use crate::compression::{compress_or_copy, AnyCodec, BlockCodec};
+use crate::config::{CpuAffinity, MemoryBudget};
+use crate::metrics::{Metrics, NoMetrics};
 use crate::thread;
 use futures::channel::oneshot;
 use futures::FutureExt;
+use std::collections::BTreeMap;
 use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::{fmt, io, mem};
 
 pub struct ParallelCompressor {
     // Destructors are run in top-down order, so this closes the sender before joining
     sender: flume::Sender<Request>,
     threads: crate::thread::Joiner<()>,
+    queue: Arc<Queue>,
+}
+
+/// The number of requests sent to the compression threads which haven't been completed
+struct Queue {
+    depth: AtomicUsize,
+    metrics: Arc<dyn Metrics>,
+}
+
+impl Queue {
+    fn push(&self) {
+        let depth = self.depth.fetch_add(1, Ordering::Relaxed) + 1;
+        self.metrics.queue_depth(depth);
+    }
+
+    fn pop(&self) {
+        let depth = self.depth.fetch_sub(1, Ordering::Relaxed) - 1;
+        self.metrics.queue_depth(depth);
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -25,7 +49,7 @@ struct Request {
 }
 
 pub struct Response {
-    pub data: pool::Block<'static>,
+    pub data: pool::Block,
     pub compressed: bool,
 }
 
@@ -35,14 +59,84 @@ impl ParallelCompressor {
     }
 
     pub fn with_threads(compressor: AnyCodec, threads: usize) -> Self {
+        Self::with_metrics(compressor, threads, Arc::new(NoMetrics))
+    }
+
+    /// Compress on `threads` threads, reporting compressed blocks and the queue depth to
+    /// `metrics`
+    pub fn with_metrics(compressor: AnyCodec, threads: usize, metrics: Arc<dyn Metrics>) -> Self {
+        let memory = MemoryBudget::default();
+        let pool = Arc::new(BlockPool::with_budget(&memory));
+        Self::with_budget(compressor, threads, metrics, pool, &memory)
+    }
+
+    /// Like [`with_metrics`](Self::with_metrics), taking buffers from `pool`, and allowing
+    /// `memory.buffers_per_thread` blocks per thread to wait for compression
+    pub fn with_budget(
+        compressor: AnyCodec,
+        threads: usize,
+        metrics: Arc<dyn Metrics>,
+        pool: Arc<BlockPool>,
+        memory: &MemoryBudget,
+    ) -> Self {
+        let new_codec = || Box::new(compressor.clone()) as Box<dyn BlockCodec>;
+        Self::with_codecs(new_codec, threads, metrics, pool, memory)
+    }
+
+    /// Like [`with_budget`](Self::with_budget), with a codec for each thread created by
+    /// `new_codec`
+    pub fn with_codecs<F>(
+        new_codec: F,
+        threads: usize,
+        metrics: Arc<dyn Metrics>,
+        pool: Arc<BlockPool>,
+        memory: &MemoryBudget,
+    ) -> Self
+    where
+        F: FnMut() -> Box<dyn BlockCodec>,
+    {
+        Self::with_affinity(new_codec, threads, metrics, pool, memory, &CpuAffinity::Any)
+    }
+
+    /// Like [`with_codecs`](Self::with_codecs), running the threads on the CPUs chosen by
+    /// `affinity`
+    ///
+    /// A thread which can't be pinned, e.g. because a CPU is offline, runs unpinned.
+    pub fn with_affinity<F>(
+        mut new_codec: F,
+        threads: usize,
+        metrics: Arc<dyn Metrics>,
+        pool: Arc<BlockPool>,
+        memory: &MemoryBudget,
+        affinity: &CpuAffinity,
+    ) -> Self
+    where
+        F: FnMut() -> Box<dyn BlockCodec>,
+    {
         assert!(threads > 0);
 
-        let (tx, rx) = flume::bounded(0);
-        let threads = thread::Joiner::new(threads, || thread_fn(rx.clone(), compressor.clone()));
+        let (tx, rx) = flume::bounded(threads * memory.buffers_per_thread);
+        let queue = Arc::new(Queue {
+            depth: AtomicUsize::new(0),
+            metrics,
+        });
+        let mut index = 0;
+        let threads = thread::Joiner::new(threads, || {
+            let cpus = affinity.cpus(index).to_vec();
+            index += 1;
+            thread_fn(
+                rx.clone(),
+                new_codec(),
+                Arc::clone(&queue),
+                Arc::clone(&pool),
+                cpus,
+            )
+        });
 
         Self {
             threads,
             sender: tx,
+            queue,
         }
     }
 
@@ -54,6 +148,7 @@ impl ParallelCompressor {
             reply: tx,
         };
 
+        self.queue.push();
         self.sender.send_async(request).await.unwrap();
 
         // Unwrap twice: Once to assert that the channel wasn't closed, and again because compression
@@ -73,39 +168,147 @@ impl ParallelCompressor {
             reply: tx,
         };
 
+        self.queue.push();
         self.sender.send_async(request).await.unwrap();
 
         rx.map(Result::unwrap)
     }
+
+    /// Submit `data` to be compressed, blocking until a thread is able to accept it
+    pub fn submit(&self, data: Vec<u8>) -> Pending {
+        let (tx, rx) = oneshot::channel();
+        let request = Request {
+            data,
+            request_type: RequestType::Compress,
+            reply: tx,
+        };
+        self.queue.push();
+        self.sender.send(request).unwrap();
+        Pending(rx)
+    }
+
+    /// Submit blocks to be compressed by sequence number, and collect them in that order
+    pub fn ordered(&self) -> Ordered<'_> {
+        Ordered {
+            compressor: self,
+            next: 0,
+            pending: BTreeMap::new(),
+        }
+    }
 }
 
-fn thread_fn(rx: flume::Receiver<Request>, mut compressor: AnyCodec) -> impl FnOnce() {
+/// A block submitted for compression, see [`ParallelCompressor::submit`]
+pub struct Pending(oneshot::Receiver<io::Result<Response>>);
+
+impl Pending {
+    /// Wait for the block to be compressed
+    pub fn wait(self) -> Response {
+        // Compression can't fail, see `compress`
+        futures::executor::block_on(self.0).unwrap().unwrap()
+    }
+}
+
+/// Blocks submitted for compression, which are returned in the order of their sequence
+/// numbers, no matter which finishes first
+///
+/// Sequence numbers start at 0. A block is only returned once every block before it has been
+/// submitted, or [skipped](Self::skip), and returned.
+pub struct Ordered<'a> {
+    compressor: &'a ParallelCompressor,
+    /// The sequence number of the next block to return
+    next: u64,
+    /// `None` for skipped blocks
+    pending: BTreeMap<u64, Option<Pending>>,
+}
+
+impl<'a> Ordered<'a> {
+    /// Submit `data` to be compressed as block number `seq`, blocking until a thread is able to
+    /// accept it
+    ///
+    /// # Panics
+    ///
+    /// Panics if block `seq` has already been submitted
+    pub fn submit(&mut self, seq: u64, data: Vec<u8>) {
+        self.check_new(seq);
+        let pending = self.compressor.submit(data);
+        self.pending.insert(seq, Some(pending));
+    }
+
+    /// Take the place of block number `seq` without compressing anything, for a block which
+    /// doesn't need it, such as a sparse block
+    ///
+    /// # Panics
+    ///
+    /// Panics if block `seq` has already been submitted
+    pub fn skip(&mut self, seq: u64) {
+        self.check_new(seq);
+        self.pending.insert(seq, None);
+    }
+
+    fn check_new(&self, seq: u64) {
+        assert!(
+            seq >= self.next && !self.pending.contains_key(&seq),
+            "block {} was already submitted",
+            seq
+        );
+    }
+
+    /// The number of submitted and skipped blocks which haven't been returned
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Return the compressed blocks which follow those already returned, waiting for each
+    ///
+    /// The iterator stops at the first block which hasn't been submitted yet.
+    pub fn drain(&mut self) -> Drain<'_, 'a> {
+        Drain { ordered: self }
+    }
+}
+
+/// An iterator over compressed blocks in order, see [`Ordered::drain`]
+pub struct Drain<'o, 'a> {
+    ordered: &'o mut Ordered<'a>,
+}
+
+impl Iterator for Drain<'_, '_> {
+    /// The sequence number of the block, and the block, or `None` if it was skipped
+    type Item = (u64, Option<Response>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let ordered = &mut *self.ordered;
+        let pending = ordered.pending.remove(&ordered.next)?;
+        let seq = ordered.next;
+        ordered.next += 1;
+        Some((seq, pending.map(Pending::wait)))
+    }
+}
+
+fn thread_fn(
+    rx: flume::Receiver<Request>,
+    mut compressor: Box<dyn BlockCodec>,
+    queue: Arc<Queue>,
+    pool: Arc<BlockPool>,
+    cpus: Vec<usize>,
+) -> impl FnOnce() {
     move || {
+        // Pinning only affects performance, so a failure isn't worth stopping for
+        let _ = thread::pin_current(&cpus);
         for mut request in rx {
-            let mut src = pool::attach_block(mem::take(&mut request.data));
+            let src = pool.attach(mem::take(&mut request.data));
+            let input_len = src.len();
             let mut response = Response {
-                data: pool::block(),
+                data: pool.get(),
                 compressed: false,
             };
             let response: io::Result<Response> = match request.request_type {
                 RequestType::Compress => {
-                    // TODO: Profile if this should use unsafe set_len
-                    // Set to 1 smaller, so compressing to an equal sized result will just be left uncompressed
-                    response.data.resize(src.len() - 1, 0);
-                    match compressor.compress(&src, &mut response.data) {
-                        Ok(n) => {
-                            response.data.truncate(n);
-                            response.compressed = true;
-                            Ok(response)
-                        }
-                        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
-                            // result should get request data, and we'll return the invalid response data to the pool
-                            mem::swap(&mut src, &mut response.data);
-                            response.compressed = false;
-                            Ok(response)
-                        }
-                        Err(e) => Err(e),
-                    }
+                    response.data.resize(src.len(), 0);
+                    let (len, compressed) =
+                        compress_or_copy(&mut compressor, &src, &mut response.data);
+                    response.data.truncate(len);
+                    response.compressed = compressed;
+                    Ok(response)
                 }
                 RequestType::Decompress { max_size } => {
                     response.data.resize(max_size, 0);
@@ -115,6 +318,17 @@ fn thread_fn(rx: flume::Receiver<Request>, mut compressor: AnyCodec) -> impl FnO
                     })
                 }
             };
+            if let Ok(response) = &response {
+                match request.request_type {
+                    RequestType::Compress => queue
+                        .metrics
+                        .block_compressed(input_len, response.data.len()),
+                    RequestType::Decompress { .. } => queue
+                        .metrics
+                        .block_decompressed(input_len, response.data.len()),
+                }
+            }
+            queue.pop();
             let _ = request.reply.send(response);
         }
     }
@@ -130,6 +344,7 @@ impl fmt::Debug for ParallelCompressor {
 mod tests {
     use super::*;
     use crate::compression::{self, AnyCodec};
+    use crate::metrics::Counters;
 
     #[test]
     fn multiple_requests() {
@@ -144,8 +359,12 @@ mod tests {
 
             let uncompressible = vec![1];
 
-            let compressor =
-                ParallelCompressor::with_threads(AnyCodec::new(compression::Kind::ZLib), 2);
+            let counters = Arc::new(Counters::default());
+            let compressor = ParallelCompressor::with_metrics(
+                AnyCodec::new(compression::Kind::ZLib),
+                2,
+                counters.clone(),
+            );
             let response1 = compressor.compress(duplicate_data.clone()).await;
             let response2 = compressor.compress(uncompressible.clone()).await;
 
@@ -155,6 +374,109 @@ mod tests {
             assert!(response1.data.len() < duplicate_data.len());
             assert!(!response2.compressed);
             assert_eq!(&*response2.data, &uncompressible);
+
+            let snapshot = counters.snapshot();
+            assert_eq!(snapshot.blocks_compressed, 2);
+            assert_eq!(
+                snapshot.compression_output_bytes,
+                (response1.data.len() + 1) as u64
+            );
+            assert_eq!(snapshot.queue_depth, 0);
+            assert!(snapshot.max_queue_depth >= 1);
+        });
+    }
+
+    /// Keeps only the data before any trailing zeros, which decompression restores
+    struct TrimZeros;
+
+    impl compression::Compressor for TrimZeros {
+        fn compress(&mut self, src: &[u8], dst: &mut [u8]) -> io::Result<usize> {
+            let len = src.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+            let dst = dst.get_mut(..len).ok_or(io::ErrorKind::UnexpectedEof)?;
+            dst.copy_from_slice(&src[..len]);
+            Ok(len)
+        }
+    }
+
+    impl compression::Decompressor for TrimZeros {
+        fn decompress(&mut self, src: &[u8], dst: &mut [u8]) -> io::Result<usize> {
+            dst[..src.len()].copy_from_slice(src);
+            dst[src.len()..].fill(0);
+            Ok(dst.len())
+        }
+    }
+
+    #[test]
+    fn custom_codec() {
+        futures::executor::block_on(async {
+            let memory = MemoryBudget::default();
+            let compressor = ParallelCompressor::with_codecs(
+                || Box::new(TrimZeros),
+                2,
+                Arc::new(NoMetrics),
+                Arc::new(BlockPool::with_budget(&memory)),
+                &memory,
+            );
+            let mut data = vec![0; 100];
+            data[..3].copy_from_slice(b"abc");
+            let response = compressor.compress(data.clone()).await.await;
+            assert!(response.compressed);
+            assert_eq!(&*response.data, b"abc");
+
+            let response = compressor
+                .decompress(response.data.to_vec(), 100)
+                .await
+                .await
+                .unwrap();
+            assert_eq!(&*response.data, &data[..]);
         });
     }
+
+    #[test]
+    fn pinned_threads() {
+        futures::executor::block_on(async {
+            let memory = MemoryBudget::default();
+            let compressor = ParallelCompressor::with_affinity(
+                || Box::new(TrimZeros),
+                2,
+                Arc::new(NoMetrics),
+                Arc::new(BlockPool::with_budget(&memory)),
+                &memory,
+                &CpuAffinity::Cores(vec![0]),
+            );
+            let response = compressor.compress(b"abc\0\0".to_vec()).await.await;
+            assert_eq!(&*response.data, b"abc");
+        });
+    }
+
+    #[test]
+    fn ordered_blocks() {
+        let compressor =
+            ParallelCompressor::with_threads(AnyCodec::new(compression::Kind::ZLib), 3);
+        let block = |seq: u8| vec![seq; 1000];
+        let mut ordered = compressor.ordered();
+        ordered.submit(2, block(2));
+        ordered.submit(0, block(0));
+        ordered.submit(3, block(3));
+        ordered.skip(4);
+
+        let seqs: Vec<u64> = ordered.drain().map(|(seq, _)| seq).collect();
+        assert_eq!(seqs, [0]);
+        assert_eq!(ordered.pending(), 3);
+
+        ordered.submit(1, block(1));
+        let mut codec = AnyCodec::new(compression::Kind::ZLib);
+        let drained: Vec<_> = ordered.drain().collect();
+        assert_eq!(drained.len(), 4);
+        assert!(drained[3].1.is_none(), "block 4 was skipped");
+        for (seq, response) in drained.into_iter().take(3) {
+            let response = response.unwrap();
+            assert!(response.compressed);
+            let mut data = vec![0; 1000];
+            let len = compression::Decompressor::decompress(&mut codec, &response.data, &mut data)
+                .unwrap();
+            assert_eq!(&data[..len], &block(seq as u8)[..]);
+        }
+        assert_eq!(ordered.pending(), 0);
+    }
 }
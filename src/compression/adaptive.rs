@@ -0,0 +1,128 @@
+//! Skipping compression of blocks which won't compress
+//!
+//! Already compressed data, like images, video and archives, makes up most of many trees, and
+//! running the codec over it only to store the block uncompressed anyway wastes most of the
+//! time spent compressing. [`SkipIncompressible`] guesses from the distribution of a sample of
+//! a block's bytes whether it's worth compressing, which takes a small fraction of the time.
+
+use std::io;
+
+use super::{Compressor, Decompressor};
+
+/// The number of bytes of a block sampled, in windows spread over the block
+const SAMPLE_LEN: usize = 4096;
+const WINDOW_LEN: usize = 1024;
+
+/// A codec which reports blocks that look incompressible as not compressing, without running
+/// `codec` on them
+///
+/// Meant to be used through [`compress_or_copy`](super::compress_or_copy), which then stores
+/// the block uncompressed. Decompression is passed straight to `codec`.
+#[derive(Debug, Clone)]
+pub struct SkipIncompressible<C> {
+    pub codec: C,
+    /// Blocks with at least this many bits of entropy per byte in their sample are skipped,
+    /// out of a maximum of 8
+    ///
+    /// A sample of random bytes measures just under 8 bits. Blocks too small for a full sample
+    /// measure lower, so they are always compressed.
+    pub max_entropy: f64,
+}
+
+impl<C> SkipIncompressible<C> {
+    pub fn new(codec: C) -> Self {
+        SkipIncompressible {
+            codec,
+            max_entropy: 7.9,
+        }
+    }
+}
+
+impl<C: Compressor> Compressor for SkipIncompressible<C> {
+    fn compress(&mut self, src: &[u8], dst: &mut [u8]) -> io::Result<usize> {
+        if sample_entropy(src) >= self.max_entropy {
+            return Err(io::Error::other("block looks incompressible"));
+        }
+        self.codec.compress(src, dst)
+    }
+}
+
+impl<C: Decompressor> Decompressor for SkipIncompressible<C> {
+    fn decompress(&mut self, src: &[u8], dst: &mut [u8]) -> io::Result<usize> {
+        self.codec.decompress(src, dst)
+    }
+}
+
+/// The Shannon entropy, in bits per byte, of a sample of up to 4 KiB of `data`
+///
+/// Large blocks are sampled in several windows, so a compressible header or trailer doesn't
+/// decide for the whole block.
+pub fn sample_entropy(data: &[u8]) -> f64 {
+    let mut histogram = [0u32; 256];
+    let mut count = |window: &[u8]| {
+        for &byte in window {
+            histogram[usize::from(byte)] += 1;
+        }
+    };
+    if data.len() <= SAMPLE_LEN {
+        count(data);
+    } else {
+        let windows = SAMPLE_LEN / WINDOW_LEN;
+        let stride = (data.len() - WINDOW_LEN) / (windows - 1);
+        for i in 0..windows {
+            let start = i * stride;
+            count(&data[start..start + WINDOW_LEN]);
+        }
+    }
+
+    let total: u32 = histogram.iter().sum();
+    if total == 0 {
+        return 0.0;
+    }
+    let total = f64::from(total);
+    histogram
+        .iter()
+        .filter(|&&n| n > 0)
+        .map(|&n| {
+            let p = f64::from(n) / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::compress_or_copy;
+    use crate::testing;
+
+    /// Fails the test if it is asked to compress anything
+    struct Unreachable;
+
+    impl Compressor for Unreachable {
+        fn compress(&mut self, _: &[u8], _: &mut [u8]) -> io::Result<usize> {
+            panic!("the codec shouldn't be run");
+        }
+    }
+
+    #[test]
+    fn entropy() {
+        assert_eq!(sample_entropy(b""), 0.0);
+        assert_eq!(sample_entropy(&[7; 10_000]), 0.0);
+        assert!((sample_entropy(b"abab") - 1.0).abs() < 1e-9);
+        assert!(sample_entropy(&testing::incompressible_contents()) > 7.9);
+        assert!(sample_entropy(&testing::compressible_contents()) < 5.0);
+    }
+
+    #[test]
+    fn skips_incompressible() {
+        let src = testing::incompressible_contents();
+        let mut dst = vec![0; src.len()];
+        let mut codec = SkipIncompressible::new(Unreachable);
+        assert_eq!(
+            compress_or_copy(&mut codec, &src, &mut dst),
+            (src.len(), false)
+        );
+        assert_eq!(dst, src);
+    }
+}
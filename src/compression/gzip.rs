@@ -16,6 +16,8 @@ pub struct GzipDecompressor(flate2::Decompress);
 impl super::Compressor for GzipCompressor {
     fn compress(&mut self, src: &[u8], dst: &mut [u8]) -> io::Result<usize> {
         let compressor = &mut self.0;
+        // Each block is a separate stream, and a previous one may have stopped part way through
+        compressor.reset();
         loop {
             let in_offset = min_mem(compressor.total_in(), src.len());
             let input = &src[in_offset..];
@@ -37,6 +39,7 @@ impl super::Compressor for GzipCompressor {
 impl super::Decompressor for GzipDecompressor {
     fn decompress(&mut self, src: &[u8], dst: &mut [u8]) -> io::Result<usize> {
         let decompressor = &mut self.0;
+        decompressor.reset(true);
         loop {
             let in_offset = min_mem(decompressor.total_in(), src.len());
             let input = &src[in_offset..];
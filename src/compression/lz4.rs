@@ -0,0 +1,107 @@
+use crate::compression::{CodecImpl, ConfigValue};
+use lz4::block::{self, CompressionMode};
+use repr::compression::options::Lz4Flags;
+use std::convert::TryFrom;
+use std::io;
+
+pub type Config = repr::compression::options::Lz4;
+
+/// The only version squashfs supports: each block is a bare lz4 block, without the headers of
+/// the lz4 frame format
+const LZ4_LEGACY: i32 = 1;
+
+/// The level `mksquashfs` compresses at in high compression mode, the highest there is
+const HC_LEVEL: i32 = 12;
+
+#[derive(Debug)]
+pub struct Lz4;
+
+#[derive(Debug)]
+pub struct Lz4Compressor {
+    mode: CompressionMode,
+}
+
+#[derive(Debug)]
+pub struct Lz4Decompressor;
+
+impl super::Compressor for Lz4Compressor {
+    fn compress(&mut self, src: &[u8], dst: &mut [u8]) -> io::Result<usize> {
+        block::compress_to_buffer(src, Some(self.mode), false, dst)
+    }
+}
+
+impl super::Decompressor for Lz4Decompressor {
+    fn decompress(&mut self, src: &[u8], dst: &mut [u8]) -> io::Result<usize> {
+        // Blocks don't record their decompressed size, so `dst` is only a limit
+        let limit = i32::try_from(dst.len()).unwrap_or(i32::MAX);
+        block::decompress_to_buffer(src, Some(limit), dst)
+    }
+}
+
+impl super::Config for Config {
+    fn set(&mut self, field: &str, value: &str) -> io::Result<()> {
+        match field {
+            "high_compression" => {
+                let value = value.parse().map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "Invalid high_compression")
+                })?;
+                let mut flags = self.flags;
+                flags.set(Lz4Flags::HIGH_COMPRESSION, value);
+                self.flags = flags;
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Unknown field {field}"),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn key_values(&self) -> Vec<(&'static str, ConfigValue<'_>)> {
+        let high_compression = { self.flags }.contains(Lz4Flags::HIGH_COMPRESSION);
+        vec![(
+            "high_compression",
+            ConfigValue::Str(if high_compression { "true" } else { "false" }),
+        )]
+    }
+}
+
+impl CodecImpl for Lz4 {
+    type Compressor = Lz4Compressor;
+    type Decompressor = Lz4Decompressor;
+    type Config = Config;
+
+    fn read_config(data: &[u8]) -> io::Result<Self::Config> {
+        let config: Config = repr::read(data)?;
+        let version = config.version;
+        if version != LZ4_LEGACY {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported lz4 version ({})", version),
+            ));
+        }
+        let flags = { config.flags }.bits();
+        if flags & !Lz4Flags::all().bits() != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown lz4 flags ({:#x})", flags),
+            ));
+        }
+        Ok(config)
+    }
+
+    fn compressor(config: Self::Config) -> Self::Compressor {
+        let mode = if { config.flags }.contains(Lz4Flags::HIGH_COMPRESSION) {
+            CompressionMode::HIGHCOMPRESSION(HC_LEVEL)
+        } else {
+            CompressionMode::DEFAULT
+        };
+        Lz4Compressor { mode }
+    }
+
+    fn decompressor(_config: Self::Config) -> Self::Decompressor {
+        Lz4Decompressor
+    }
+}
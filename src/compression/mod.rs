@@ -1,12 +1,24 @@
+use repr::compression::options;
 use repr::compression::Id as CompressionId;
-use std::{fmt, io};
+use std::{fmt, io, mem};
+use zerocopy::AsBytes;
+
+pub mod adaptive;
 
 #[cfg(feature = "gzip")]
 pub mod gzip;
 
+#[cfg(feature = "lz4")]
+pub mod lz4;
+
 #[cfg(feature = "zstd")]
 pub mod zstd;
 
+pub mod verify;
+
+#[cfg(feature = "xz")]
+pub mod xz;
+
 #[repr(u16)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Kind {
@@ -23,6 +35,10 @@ pub enum Kind {
 pub enum CodecBuilder {
     #[cfg(feature = "gzip")]
     Gzip(gzip::Config),
+    #[cfg(feature = "lz4")]
+    Lz4(lz4::Config),
+    #[cfg(feature = "xz")]
+    Xz(xz::Config),
     #[cfg(feature = "zstd")]
     Zstd(zstd::Config),
 }
@@ -32,6 +48,10 @@ impl Config for CodecBuilder {
         match self {
             #[cfg(feature = "gzip")]
             CodecBuilder::Gzip(config) => config.set(field, value),
+            #[cfg(feature = "lz4")]
+            CodecBuilder::Lz4(config) => config.set(field, value),
+            #[cfg(feature = "xz")]
+            CodecBuilder::Xz(config) => config.set(field, value),
             #[cfg(feature = "zstd")]
             CodecBuilder::Zstd(config) => config.set(field, value),
         }
@@ -41,6 +61,10 @@ impl Config for CodecBuilder {
         match self {
             #[cfg(feature = "gzip")]
             CodecBuilder::Gzip(config) => config.key_values(),
+            #[cfg(feature = "lz4")]
+            CodecBuilder::Lz4(config) => config.key_values(),
+            #[cfg(feature = "xz")]
+            CodecBuilder::Xz(config) => config.key_values(),
             #[cfg(feature = "zstd")]
             CodecBuilder::Zstd(config) => config.key_values(),
         }
@@ -52,6 +76,10 @@ impl CodecBuilder {
         match self {
             #[cfg(feature = "gzip")]
             CodecBuilder::Gzip(config) => AnyCodec::Gzip(Codec::with_config(config)),
+            #[cfg(feature = "lz4")]
+            CodecBuilder::Lz4(config) => AnyCodec::Lz4(Codec::with_config(config)),
+            #[cfg(feature = "xz")]
+            CodecBuilder::Xz(config) => AnyCodec::Xz(Codec::with_config(config)),
             #[cfg(feature = "zstd")]
             CodecBuilder::Zstd(config) => AnyCodec::Zstd(Codec::with_config(config)),
         }
@@ -118,6 +146,10 @@ where
 pub enum AnyCodec {
     #[cfg(feature = "gzip")]
     Gzip(Codec<gzip::Gzip>),
+    #[cfg(feature = "lz4")]
+    Lz4(Codec<lz4::Lz4>),
+    #[cfg(feature = "xz")]
+    Xz(Codec<xz::Xz>),
     #[cfg(feature = "zstd")]
     Zstd(Codec<zstd::Zstd>),
 }
@@ -127,6 +159,10 @@ impl AnyCodec {
         match kind {
             #[cfg(feature = "gzip")]
             Kind::ZLib => AnyCodec::Gzip(Codec::new()),
+            #[cfg(feature = "lz4")]
+            Kind::Lz4 => AnyCodec::Lz4(Codec::new()),
+            #[cfg(feature = "xz")]
+            Kind::Xz => AnyCodec::Xz(Codec::new()),
             #[cfg(feature = "zstd")]
             Kind::Zstd => AnyCodec::Zstd(Codec::new()),
             _ => panic!("Unsupported compressor kind {}", kind),
@@ -137,6 +173,10 @@ impl AnyCodec {
         let result = match kind {
             #[cfg(feature = "gzip")]
             Kind::ZLib => AnyCodec::Gzip(Codec::configured(data)?),
+            #[cfg(feature = "lz4")]
+            Kind::Lz4 => AnyCodec::Lz4(Codec::configured(data)?),
+            #[cfg(feature = "xz")]
+            Kind::Xz => AnyCodec::Xz(Codec::configured(data)?),
             #[cfg(feature = "zstd")]
             Kind::Zstd => AnyCodec::Zstd(Codec::configured(data)?),
             _ => panic!("Unsupported compressor kind {}", kind),
@@ -144,10 +184,19 @@ impl AnyCodec {
         Ok(result)
     }
 
+    /// Create a codec matching options read from an archive
+    pub fn from_options(options: &Options) -> io::Result<Self> {
+        Self::configured(options.kind(), options.as_bytes())
+    }
+
     pub fn config(&self) -> &dyn Config {
         match self {
             #[cfg(feature = "gzip")]
             AnyCodec::Gzip(codec) => &codec.config,
+            #[cfg(feature = "lz4")]
+            AnyCodec::Lz4(codec) => &codec.config,
+            #[cfg(feature = "xz")]
+            AnyCodec::Xz(codec) => &codec.config,
             #[cfg(feature = "zstd")]
             AnyCodec::Zstd(codec) => &codec.config,
         }
@@ -157,6 +206,10 @@ impl AnyCodec {
         match *self {
             #[cfg(feature = "gzip")]
             AnyCodec::Gzip(_) => Kind::ZLib,
+            #[cfg(feature = "lz4")]
+            AnyCodec::Lz4(_) => Kind::Lz4,
+            #[cfg(feature = "xz")]
+            AnyCodec::Xz(_) => Kind::Xz,
             #[cfg(feature = "zstd")]
             AnyCodec::Zstd(_) => Kind::Zstd,
         }
@@ -168,6 +221,10 @@ impl Compressor for AnyCodec {
         match self {
             #[cfg(feature = "gzip")]
             AnyCodec::Gzip(gzip) => gzip.comp.compress(src, dst),
+            #[cfg(feature = "lz4")]
+            AnyCodec::Lz4(lz4) => lz4.comp.compress(src, dst),
+            #[cfg(feature = "xz")]
+            AnyCodec::Xz(xz) => xz.comp.compress(src, dst),
             #[cfg(feature = "zstd")]
             AnyCodec::Zstd(zstd) => zstd.comp.compress(src, dst),
         }
@@ -179,6 +236,10 @@ impl Decompressor for AnyCodec {
         match self {
             #[cfg(feature = "gzip")]
             AnyCodec::Gzip(gzip) => gzip.decomp.decompress(src, dst),
+            #[cfg(feature = "lz4")]
+            AnyCodec::Lz4(lz4) => lz4.decomp.decompress(src, dst),
+            #[cfg(feature = "xz")]
+            AnyCodec::Xz(xz) => xz.decomp.decompress(src, dst),
             #[cfg(feature = "zstd")]
             AnyCodec::Zstd(zstd) => zstd.decomp.decompress(src, dst),
         }
@@ -260,6 +321,10 @@ impl Kind {
         match self {
             #[cfg(feature = "gzip")]
             Kind::ZLib => CodecBuilder::Gzip(Default::default()),
+            #[cfg(feature = "lz4")]
+            Kind::Lz4 => CodecBuilder::Lz4(Default::default()),
+            #[cfg(feature = "xz")]
+            Kind::Xz => CodecBuilder::Xz(Default::default()),
             #[cfg(feature = "zstd")]
             Kind::Zstd => CodecBuilder::Zstd(Default::default()),
             _ => {
@@ -269,6 +334,141 @@ impl Kind {
     }
 }
 
+/// The compression algorithm used by an archive, along with its options
+///
+/// Archives which do not store compression options imply the defaults of their algorithm,
+/// see [`Options::default_for`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Options {
+    Gzip(options::Gzip),
+    /// Lzma has no configurable options
+    Lzma,
+    Lzo(options::Lzo),
+    Xz(options::Xz),
+    Lz4(options::Lz4),
+    Zstd(options::Zstd),
+}
+
+impl Options {
+    /// The options implied for `kind` when an archive does not store any
+    ///
+    /// Returns `None` for [`Kind::Unknown`]
+    pub fn default_for(kind: Kind, block_size: u32) -> Option<Self> {
+        let result = match kind {
+            Kind::ZLib => Options::Gzip(Default::default()),
+            Kind::Lzma => Options::Lzma,
+            Kind::Lzo => Options::Lzo(Default::default()),
+            // The kernel uses the block size, but at least 8 KiB, the smallest dictionary xz
+            // accepts
+            Kind::Xz => Options::Xz(options::Xz {
+                dictionary_size: block_size.max(8192),
+                executable_filters: options::XzFilters::empty(),
+            }),
+            Kind::Lz4 => Options::Lz4(Default::default()),
+            Kind::Zstd => Options::Zstd(Default::default()),
+            Kind::Unknown => return None,
+        };
+        Some(result)
+    }
+
+    /// Parse the options stored in the compression options section of an archive
+    pub fn read(kind: Kind, data: &[u8]) -> io::Result<Self> {
+        fn exact<T: zerocopy::FromBytes>(data: &[u8]) -> io::Result<T> {
+            if data.len() != mem::size_of::<T>() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Compression options size mismatch: expected {}, got {}",
+                        mem::size_of::<T>(),
+                        data.len()
+                    ),
+                ));
+            }
+            repr::read(data)
+        }
+
+        let result = match kind {
+            Kind::ZLib => Options::Gzip(exact(data)?),
+            Kind::Lzma => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "lzma does not support compression options",
+                ))
+            }
+            Kind::Lzo => Options::Lzo(exact(data)?),
+            Kind::Xz => Options::Xz(exact(data)?),
+            Kind::Lz4 => Options::Lz4(exact(data)?),
+            Kind::Zstd => Options::Zstd(exact(data)?),
+            Kind::Unknown => return Err(io::ErrorKind::InvalidInput.into()),
+        };
+        Ok(result)
+    }
+
+    pub fn kind(&self) -> Kind {
+        match self {
+            Options::Gzip(_) => Kind::ZLib,
+            Options::Lzma => Kind::Lzma,
+            Options::Lzo(_) => Kind::Lzo,
+            Options::Xz(_) => Kind::Xz,
+            Options::Lz4(_) => Kind::Lz4,
+            Options::Zstd(_) => Kind::Zstd,
+        }
+    }
+
+    /// The raw bytes of the options, as stored in an archive
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Options::Gzip(options) => options.as_bytes(),
+            Options::Lzma => &[],
+            Options::Lzo(options) => options.as_bytes(),
+            Options::Xz(options) => options.as_bytes(),
+            Options::Lz4(options) => options.as_bytes(),
+            Options::Zstd(options) => options.as_bytes(),
+        }
+    }
+}
+
+/// Formats a short human readable description, e.g. `zstd level 19`
+impl fmt::Display for Options {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.kind().name())?;
+        match *self {
+            Options::Gzip(options::Gzip {
+                compression_level,
+                window_size,
+                strategies,
+            }) => {
+                write!(f, " level {} window {}", compression_level, window_size)?;
+                if strategies != options::GzipStrategies::DEFAULT {
+                    write!(f, " strategies {:?}", strategies)?;
+                }
+            }
+            Options::Lzma => {}
+            Options::Lzo(options::Lzo { algorithm, level }) => {
+                write!(f, " algorithm {} level {}", algorithm.0, level)?;
+            }
+            Options::Xz(options::Xz {
+                dictionary_size,
+                executable_filters,
+            }) => {
+                write!(f, " dictionary {}", dictionary_size)?;
+                if !executable_filters.is_empty() {
+                    write!(f, " filters {:?}", executable_filters)?;
+                }
+            }
+            Options::Lz4(options::Lz4 { flags, .. }) => {
+                if flags.contains(options::Lz4Flags::HIGH_COMPRESSION) {
+                    f.write_str(" high compression")?;
+                }
+            }
+            Options::Zstd(options::Zstd { compression_level }) => {
+                write!(f, " level {}", compression_level)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 pub trait Compressor {
     fn compress(&mut self, src: &[u8], dst: &mut [u8]) -> io::Result<usize>;
 }
@@ -277,20 +477,67 @@ pub trait Decompressor {
     fn decompress(&mut self, src: &[u8], dst: &mut [u8]) -> io::Result<usize>;
 }
 
+/// A codec which can be used as a trait object
+///
+/// Everything which is both a [`Compressor`] and a [`Decompressor`] is a `BlockCodec`, so
+/// custom codecs, or mocks in tests, can be used as a `Box<dyn BlockCodec>` wherever a codec
+/// is expected.
+pub trait BlockCodec: Compressor + Decompressor + Send {}
+
+impl<T: Compressor + Decompressor + Send> BlockCodec for T {}
+
+impl<T: Compressor + ?Sized> Compressor for Box<T> {
+    fn compress(&mut self, src: &[u8], dst: &mut [u8]) -> io::Result<usize> {
+        (**self).compress(src, dst)
+    }
+}
+
+impl<T: Decompressor + ?Sized> Decompressor for Box<T> {
+    fn decompress(&mut self, src: &[u8], dst: &mut [u8]) -> io::Result<usize> {
+        (**self).decompress(src, dst)
+    }
+}
+
+impl<T: Compressor + ?Sized> Compressor for &mut T {
+    fn compress(&mut self, src: &[u8], dst: &mut [u8]) -> io::Result<usize> {
+        (**self).compress(src, dst)
+    }
+}
+
+impl<T: Decompressor + ?Sized> Decompressor for &mut T {
+    fn decompress(&mut self, src: &[u8], dst: &mut [u8]) -> io::Result<usize> {
+        (**self).decompress(src, dst)
+    }
+}
+
 fn copy(src: &[u8], dst: &mut [u8]) -> io::Result<usize> {
     let dst = dst.get_mut(..src.len()).ok_or(io::ErrorKind::WriteZero)?;
     dst.copy_from_slice(src);
     Ok(dst.len())
 }
 
-/// Return size, and true if compressed, false if not
-pub(crate) fn compress_or_copy<Comp: Compressor>(
+/// Compress `src` into `dst`, or copy it as-is if compressing wouldn't make it smaller
+///
+/// Returns the number of bytes written to `dst`, and whether they are compressed. Squashfs
+/// stores blocks which don't shrink uncompressed, so the output is never larger than `src`:
+/// the compressor is only given `src.len() - 1` bytes of `dst`, and any error it returns (such
+/// as running out of space) results in a copy.
+///
+/// # Panics
+///
+/// Panics if `dst` is smaller than `src`
+pub fn compress_or_copy<Comp: Compressor + ?Sized>(
     comp: &mut Comp,
     src: &[u8],
     dst: &mut [u8],
 ) -> (usize, bool) {
-    match comp.compress(src, dst) {
-        Ok(n) => {
+    assert!(
+        dst.len() >= src.len(),
+        "destination must be at least as large as the source"
+    );
+    let limit = src.len().saturating_sub(1);
+    match comp.compress(src, &mut dst[..limit]) {
+        Ok(n) if n < src.len() => {
             tracing::trace!(
                 orig_size = src.len(),
                 compressed_size = n,
@@ -298,6 +545,7 @@ pub(crate) fn compress_or_copy<Comp: Compressor>(
             );
             (n, true)
         }
+        Ok(_) => (copy(src, dst).unwrap(), false),
         Err(err) => {
             tracing::trace!(%err, "Unable to compress block");
             (copy(src, dst).unwrap(), false)
@@ -356,6 +604,75 @@ mod tests {
             .expect_err("cannot compress to 1 bytes");
     }
 
+    /// "Compresses" by keeping every other byte, or fails if `dst` is too small
+    struct Halve;
+
+    impl Compressor for Halve {
+        fn compress(&mut self, src: &[u8], dst: &mut [u8]) -> io::Result<usize> {
+            let len = src.len() - src.len() / 2;
+            let dst = dst.get_mut(..len).ok_or(io::ErrorKind::UnexpectedEof)?;
+            for (dst, &src) in dst.iter_mut().zip(src.iter().step_by(2)) {
+                *dst = src;
+            }
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn compress_or_copy_never_grows() {
+        let mut dst = [0; 8];
+        assert_eq!(compress_or_copy(&mut Halve, b"abcdef", &mut dst), (3, true));
+        assert_eq!(&dst[..3], b"ace");
+
+        // Halving a single byte doesn't shrink it
+        assert_eq!(compress_or_copy(&mut Halve, b"z", &mut dst), (1, false));
+        assert_eq!(&dst[..1], b"z");
+        assert_eq!(compress_or_copy(&mut Halve, b"", &mut dst), (0, false));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn compress_or_copy_incompressible() {
+        let mut codec = AnyCodec::new(Kind::ZLib);
+        // A xorshift sequence doesn't compress
+        let mut state = 0x2545_f491u32;
+        let src: Vec<u8> = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        let mut dst = vec![0; src.len()];
+        assert_eq!(
+            compress_or_copy(&mut codec, &src, &mut dst),
+            (src.len(), false)
+        );
+        assert_eq!(dst, src);
+
+        let src = vec![7; 4096];
+        let (len, compressed) = compress_or_copy(&mut codec, &src, &mut dst);
+        assert!(compressed);
+        assert!(len < 100);
+    }
+
+    #[test]
+    fn options_display() {
+        let options = Options::Zstd(options::Zstd {
+            compression_level: 19,
+        });
+        assert_eq!(options.to_string(), "zstd level 19");
+        assert_eq!(
+            Options::read(Kind::Zstd, options.as_bytes()).unwrap(),
+            options
+        );
+
+        let options = Options::default_for(Kind::ZLib, repr::BLOCK_SIZE_DEFAULT).unwrap();
+        assert_eq!(options.to_string(), "gzip level 9 window 15");
+        Options::read(Kind::ZLib, &options.as_bytes()[1..]).expect_err("options too short");
+    }
+
     #[cfg(feature = "gzip")]
     #[test]
     fn gzip_compressor() {
@@ -369,4 +686,63 @@ mod tests {
         round_trip::<zstd::Zstd>();
         small_dst::<zstd::Zstd>();
     }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_compressor() {
+        round_trip::<lz4::Lz4>();
+        small_dst::<lz4::Lz4>();
+
+        let high = options::Lz4 {
+            version: 1,
+            flags: options::Lz4Flags::HIGH_COMPRESSION,
+        };
+        let mut codec = AnyCodec::from_options(&Options::Lz4(high)).unwrap();
+        let src = b"abcabcabcabcabcabcabcabcabcabcabcabc";
+        let mut compressed = [0; 64];
+        let len = codec.compress(src, &mut compressed).unwrap();
+        assert!(len < src.len());
+        // Partial blocks decompress into a buffer of the full block size
+        let mut decompressed = [0; 128];
+        let len = codec
+            .decompress(&compressed[..len], &mut decompressed)
+            .unwrap();
+        assert_eq!(&decompressed[..len], src);
+
+        let bad_version = options::Lz4 { version: 2, ..high };
+        AnyCodec::configured(Kind::Lz4, bad_version.as_bytes()).expect_err("only LZ4_LEGACY");
+    }
+
+    #[cfg(feature = "xz")]
+    #[test]
+    fn xz_compressor() {
+        small_dst::<xz::Xz>();
+
+        // Too small for the headers of an xz stream to pay off, so check a larger block
+        let src: Vec<u8> = (0..8192u32).flat_map(|i| (i % 251).to_le_bytes()).collect();
+        let filtered = options::Xz {
+            dictionary_size: 1 << 15,
+            executable_filters: options::XzFilters::X86 | options::XzFilters::ARM,
+        };
+        for options in [options::Xz::default(), filtered] {
+            let mut codec = AnyCodec::from_options(&Options::Xz(options)).unwrap();
+            let mut compressed = vec![0; src.len()];
+            let len = codec.compress(&src, &mut compressed).unwrap();
+            assert!(len < src.len() / 4);
+            let mut decompressed = vec![0; src.len() * 2];
+            let len = codec
+                .decompress(&compressed[..len], &mut decompressed)
+                .unwrap();
+            assert_eq!(&decompressed[..len], &src[..]);
+        }
+
+        for dictionary_size in [4096, 3 << 13, 5 << 13] {
+            let options = options::Xz {
+                dictionary_size,
+                ..filtered
+            };
+            let result = AnyCodec::configured(Kind::Xz, options.as_bytes());
+            assert_eq!(result.is_ok(), dictionary_size == 3 << 13);
+        }
+    }
 }
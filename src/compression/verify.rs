@@ -0,0 +1,135 @@
+//! Checking compressed blocks by decompressing them again, before they are written
+//!
+//! A bug in a codec, or a bit flipped in memory while a block is compressed, produces a block
+//! which decompresses to the wrong data, and nothing notices until the image is read.
+//! [`VerifyWrites`] hashes each block before compressing it, decompresses the result, and
+//! compares the hash of what comes out, at the cost of a decompression per block.
+
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use xxhash_rust::xxh3::xxh3_64;
+
+use super::{Compressor, Decompressor};
+
+/// A codec which checks that every block it compresses decompresses to the original data
+///
+/// A block which fails the check is reported as not compressing, so
+/// [`compress_or_copy`](super::compress_or_copy) stores it uncompressed, and is counted in
+/// [`failures`](Self::failures). Failures should be rare enough that any count above zero means
+/// the codec or the machine can't be trusted.
+#[derive(Debug, Clone)]
+pub struct VerifyWrites<C> {
+    pub codec: C,
+    /// Holds each decompressed block
+    scratch: Vec<u8>,
+    /// Shared between clones, so codecs on several threads share a count
+    failures: Arc<AtomicU64>,
+}
+
+impl<C> VerifyWrites<C> {
+    pub fn new(codec: C) -> Self {
+        VerifyWrites {
+            codec,
+            scratch: Vec::new(),
+            failures: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// A codec counting its failures in `failures`, shared with whatever else counts them
+    pub(crate) fn counting(codec: C, failures: Arc<AtomicU64>) -> Self {
+        VerifyWrites {
+            codec,
+            scratch: Vec::new(),
+            failures,
+        }
+    }
+
+    /// The number of blocks which didn't decompress to the original data, counted across all
+    /// clones of this codec
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+}
+
+impl<C: Compressor + Decompressor> Compressor for VerifyWrites<C> {
+    fn compress(&mut self, src: &[u8], dst: &mut [u8]) -> io::Result<usize> {
+        let expected = xxh3_64(src);
+        let len = self.codec.compress(src, dst)?;
+
+        self.scratch.resize(src.len(), 0);
+        let verified = match self.codec.decompress(&dst[..len], &mut self.scratch) {
+            Ok(n) => n == src.len() && xxh3_64(&self.scratch[..n]) == expected,
+            Err(_) => false,
+        };
+        if !verified {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "compressed block doesn't decompress to the original",
+            ));
+        }
+        Ok(len)
+    }
+}
+
+impl<C: Decompressor> Decompressor for VerifyWrites<C> {
+    fn decompress(&mut self, src: &[u8], dst: &mut [u8]) -> io::Result<usize> {
+        self.codec.decompress(src, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::compress_or_copy;
+    use crate::testing;
+
+    /// Keeps only the first half of every block, which decompression can't restore
+    #[derive(Clone)]
+    struct Lossy;
+
+    impl Compressor for Lossy {
+        fn compress(&mut self, src: &[u8], dst: &mut [u8]) -> io::Result<usize> {
+            let len = src.len() / 2;
+            dst[..len].copy_from_slice(&src[..len]);
+            Ok(len)
+        }
+    }
+
+    impl Decompressor for Lossy {
+        fn decompress(&mut self, src: &[u8], dst: &mut [u8]) -> io::Result<usize> {
+            dst[..src.len()].copy_from_slice(src);
+            dst[src.len()..].fill(0);
+            Ok(dst.len())
+        }
+    }
+
+    #[test]
+    fn lossy_codec() {
+        let src = testing::compressible_contents();
+        let mut dst = vec![0; src.len()];
+        let mut codec = VerifyWrites::new(Lossy);
+        let clone = codec.clone();
+        assert_eq!(
+            compress_or_copy(&mut codec, &src, &mut dst),
+            (src.len(), false)
+        );
+        assert_eq!(dst, src);
+        assert_eq!(clone.failures(), 1);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn verified() {
+        let src = testing::compressible_contents();
+        let mut dst = vec![0; src.len()];
+        let mut codec = VerifyWrites::new(crate::compression::AnyCodec::new(
+            crate::compression::Kind::ZLib,
+        ));
+        let (len, compressed) = compress_or_copy(&mut codec, &src, &mut dst);
+        assert!(compressed && len < src.len());
+        assert_eq!(codec.failures(), 0);
+    }
+}
@@ -0,0 +1,195 @@
+use crate::compression::{CodecImpl, ConfigValue};
+use liblzma::stream::{Action, Check, Filters, LzmaOptions, Status, Stream};
+use repr::compression::options::XzFilters;
+use std::{io, mem};
+
+pub type Config = repr::compression::options::Xz;
+
+/// The preset `mksquashfs` starts the lzma2 options from, before setting the dictionary size
+const PRESET: u32 = 6;
+
+/// The smallest dictionary the kernel accepts
+const DICTIONARY_SIZE_MIN: u32 = 8192;
+
+/// Adds a filter to a chain
+type AddFilter = fn(&mut Filters) -> &mut Filters;
+
+/// The branch/call/jump filters, by the names `mksquashfs -Xbcj` takes
+const FILTERS: [(&str, XzFilters, AddFilter); 6] = [
+    ("x86", XzFilters::X86, Filters::x86),
+    ("powerpc", XzFilters::POWERPC, Filters::powerpc),
+    ("ia64", XzFilters::IA64, Filters::ia64),
+    ("arm", XzFilters::ARM, Filters::arm),
+    ("armthumb", XzFilters::ARM_THUMB, Filters::arm_thumb),
+    ("sparc", XzFilters::SPARC, Filters::sparc),
+];
+
+#[derive(Debug)]
+pub struct Xz;
+
+#[derive(Debug)]
+pub struct XzCompressor {
+    dictionary_size: u32,
+    filters: XzFilters,
+    /// Holds the output of each filter tried after the first, until it's known to be smaller
+    scratch: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct XzDecompressor;
+
+impl XzCompressor {
+    /// Compress `src` into `dst` as an xz stream, with the branch/call/jump filter `filter`
+    /// before lzma2, if there is one
+    fn compress_with(
+        &self,
+        filter: Option<AddFilter>,
+        src: &[u8],
+        dst: &mut [u8],
+    ) -> io::Result<usize> {
+        let mut options = LzmaOptions::new_preset(PRESET)?;
+        options.dict_size(self.dictionary_size);
+        let mut filters = Filters::new();
+        if let Some(add) = filter {
+            add(&mut filters);
+        }
+        filters.lzma2(&options);
+        // The kernel only checks crc32, which is what `mksquashfs` uses
+        let stream = Stream::new_stream_encoder(&filters, Check::Crc32)?;
+        process(stream, src, dst)
+    }
+}
+
+impl super::Compressor for XzCompressor {
+    /// Compress `src` with lzma2 alone, and after each enabled filter, keeping the smallest
+    fn compress(&mut self, src: &[u8], dst: &mut [u8]) -> io::Result<usize> {
+        let mut best = self.compress_with(None, src, dst).ok();
+        for &(_, filter, add) in &FILTERS {
+            if !self.filters.contains(filter) {
+                continue;
+            }
+            let mut scratch = mem::take(&mut self.scratch);
+            scratch.resize(best.unwrap_or(dst.len()), 0);
+            if let Ok(n) = self.compress_with(Some(add), src, &mut scratch) {
+                if best.is_none_or(|best| n < best) {
+                    dst[..n].copy_from_slice(&scratch[..n]);
+                    best = Some(n);
+                }
+            }
+            self.scratch = scratch;
+        }
+        best.ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+    }
+}
+
+impl super::Decompressor for XzDecompressor {
+    fn decompress(&mut self, src: &[u8], dst: &mut [u8]) -> io::Result<usize> {
+        let stream = Stream::new_stream_decoder(u64::MAX, 0)?;
+        process(stream, src, dst)
+    }
+}
+
+/// Run `stream` over all of `src` to the end of the stream, returning the number of bytes
+/// written to `dst`
+fn process(mut stream: Stream, src: &[u8], dst: &mut [u8]) -> io::Result<usize> {
+    loop {
+        let input = &src[stream.total_in() as usize..];
+        let output = &mut dst[stream.total_out() as usize..];
+        match stream.process(input, output, Action::Finish)? {
+            Status::StreamEnd => return Ok(stream.total_out() as usize),
+            // Reported once no progress can be made, without more room in `dst`
+            Status::MemNeeded => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Status::Ok | Status::GetCheck => continue,
+        }
+    }
+}
+
+fn parse_filters(value: &str) -> Option<XzFilters> {
+    let mut filters = XzFilters::empty();
+    for name in value.split(',').filter(|name| !name.is_empty()) {
+        let &(_, filter, _) = FILTERS.iter().find(|&&(known, ..)| known == name)?;
+        filters |= filter;
+    }
+    Some(filters)
+}
+
+impl super::Config for Config {
+    fn set(&mut self, field: &str, value: &str) -> io::Result<()> {
+        match field {
+            "dictionary_size" => {
+                let value = value.parse().map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "Invalid dictionary_size")
+                })?;
+                self.dictionary_size = value;
+            }
+            "executable_filters" => {
+                let value = parse_filters(value).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "Invalid executable_filters")
+                })?;
+                self.executable_filters = value;
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Unknown field {field}"),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn key_values(&self) -> Vec<(&'static str, ConfigValue<'_>)> {
+        let filters = { self.executable_filters };
+        let names: Vec<&str> = FILTERS
+            .iter()
+            .filter(|&&(_, filter, _)| filters.contains(filter))
+            .map(|&(name, ..)| name)
+            .collect();
+        vec![
+            (
+                "dictionary_size",
+                ConfigValue::Int(self.dictionary_size.into()),
+            ),
+            ("executable_filters", ConfigValue::String(names.join(","))),
+        ]
+    }
+}
+
+impl CodecImpl for Xz {
+    type Compressor = XzCompressor;
+    type Decompressor = XzDecompressor;
+    type Config = Config;
+
+    fn read_config(data: &[u8]) -> io::Result<Self::Config> {
+        let config: Config = repr::read(data)?;
+        // Either 2^n or 2^n + 2^(n+1), as the kernel requires
+        let dictionary_size = config.dictionary_size;
+        let mantissa = dictionary_size >> dictionary_size.trailing_zeros().min(31);
+        if dictionary_size < DICTIONARY_SIZE_MIN || !(mantissa == 1 || mantissa == 3) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid dictionary size ({})", dictionary_size),
+            ));
+        }
+        let filters = { config.executable_filters }.bits();
+        if filters & !XzFilters::all().bits() != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown xz filters ({:#x})", filters),
+            ));
+        }
+        Ok(config)
+    }
+
+    fn compressor(config: Self::Config) -> Self::Compressor {
+        XzCompressor {
+            dictionary_size: config.dictionary_size,
+            filters: config.executable_filters,
+            scratch: Vec::new(),
+        }
+    }
+
+    fn decompressor(_config: Self::Config) -> Self::Decompressor {
+        XzDecompressor
+    }
+}
@@ -1,3 +1,10 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use bstr::{BString, ByteSlice};
+use slog::{Drain, Level, LevelFilter, Logger};
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum FragmentMode {
     /// Never create fragments
@@ -17,3 +24,490 @@ impl Default for FragmentMode {
         FragmentMode::Always
     }
 }
+
+/// What to do with sockets and fifos encountered while packing
+///
+/// The kernel will happily mount an archive containing these, but they are rarely useful inside
+/// a read-only image.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SpecialFilePolicy {
+    /// Store sockets and fifos in the archive
+    #[default]
+    Store,
+    /// Leave sockets and fifos out of the archive, logging a warning for each one
+    Skip,
+    /// Refuse to add sockets and fifos, returning an error
+    Error,
+}
+
+/// What to do with device numbers which can't be represented in squashfs
+///
+/// Squashfs stores only 12 bits of major and 20 bits of minor number, while modern linux
+/// `dev_t` values can be larger.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DeviceNumberPolicy {
+    /// Refuse to add the device, returning an error
+    #[default]
+    Error,
+    /// Saturate the major and minor numbers at the largest representable values, logging a
+    /// warning
+    Clamp,
+}
+
+/// When to flush the archive's contents to durable storage while writing
+///
+/// Syncing is only possible when the archive is written to a file, it is ignored for other
+/// writers.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SyncPolicy {
+    /// Never sync, leaving it to the operating system to write back the data eventually
+    #[default]
+    Never,
+    /// Sync once, after the archive has been completely written
+    OnFinish,
+    /// Sync every time this many bytes have been written, and after the archive has been
+    /// completely written
+    ///
+    /// This limits the amount of dirty data the operating system must hold on to when writing
+    /// very large archives.
+    Periodic(u64),
+}
+
+/// Limits on the entries added to an archive from a tree, e.g. by
+/// [`from_cpio`](crate::write::from_cpio)
+///
+/// The defaults are the limits of the format, or of what the kernel and common tools accept.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Limits {
+    /// The deepest an entry may be nested, the entries of the root directory are at depth 1
+    ///
+    /// The format has no limit, but paths longer than `PATH_MAX` (4096 bytes) can't be used
+    /// by most programs.
+    pub max_depth: usize,
+    /// The longest name of an entry, in bytes
+    pub max_name_len: usize,
+    /// The longest target of a symlink, in bytes
+    pub max_symlink_len: usize,
+    /// The most entries in one directory
+    pub max_dir_entries: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_depth: 2048,
+            // Linux's NAME_MAX, the kernel would read one more byte
+            max_name_len: repr::limits::MAX_NAME_LEN - 1,
+            max_symlink_len: repr::limits::MAX_SYMLINK_TARGET,
+            // The link count of a directory includes `.`, `..` and its entries
+            max_dir_entries: u32::MAX as usize - 2,
+        }
+    }
+}
+
+/// What to do with entries which exceed the archive's [`Limits`]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LimitPolicy {
+    /// Refuse to add the entry, returning an error
+    #[default]
+    Error,
+    /// Leave the entry out of the archive, along with anything beneath it, logging a warning
+    Skip,
+}
+
+/// What to do when both archives being merged have an entry at the same path, see
+/// [`write::merge`](crate::write::merge)
+///
+/// Directories in both archives are always merged, only their metadata is chosen by the policy.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ConflictPolicy {
+    /// Refuse to merge, returning an error
+    #[default]
+    Error,
+    /// Keep the entry from the first archive, and anything beneath it
+    KeepFirst,
+    /// Replace the entry with the one from the second archive
+    KeepSecond,
+}
+
+/// What to do with entries which can't be recreated while extracting
+///
+/// Device nodes, fifos and sockets are never extracted, and neither are symlinks on platforms
+/// without them, or on windows when the process isn't allowed to create them.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum UnsupportedEntryPolicy {
+    /// Leave the entry out, logging a warning
+    #[default]
+    Skip,
+    /// Stop extracting, returning an error
+    Error,
+}
+
+/// How the targets of absolute symlinks are rewritten when an archive is built from a tree, or
+/// extracted
+///
+/// An absolute target like `/usr/lib/libc.so` only points inside the image when it's mounted at
+/// `/`. Mounted anywhere else, or extracted to a directory, it points at the host's files.
+/// Relative targets, and every target with [`Keep`](SymlinkRewrite::Keep), are left alone.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub enum SymlinkRewrite {
+    /// Store the targets as they are
+    #[default]
+    Keep,
+    /// Replace absolute targets with the equivalent path relative to the link's directory, so
+    /// `/usr/bin/cc` linking to `/usr/lib/gcc` becomes `../lib/gcc`
+    Relative,
+    /// Put this path in front of absolute targets, like the location the image will be
+    /// mounted at
+    Prefix(BString),
+}
+
+impl SymlinkRewrite {
+    /// The target to store for the symlink at `path` (absolute within the archive) linking to
+    /// `target`
+    ///
+    /// `.` and `..` components of absolute targets are resolved as they would be in the image,
+    /// so a relative target never climbs out of the root.
+    pub fn rewrite(&self, path: &[u8], target: &[u8]) -> BString {
+        if !target.starts_with(b"/") {
+            return target.into();
+        }
+        match self {
+            SymlinkRewrite::Keep => target.into(),
+            SymlinkRewrite::Relative => {
+                let dir = normal_components(path);
+                let dir = &dir[..dir.len().saturating_sub(1)];
+                let target = normal_components(target);
+                let common = dir.iter().zip(&target).take_while(|(a, b)| a == b).count();
+                let mut parts = vec![&b".."[..]; dir.len() - common];
+                parts.extend(&target[common..]);
+                if parts.is_empty() {
+                    parts.push(b".");
+                }
+                bstr::join("/", parts).into()
+            }
+            SymlinkRewrite::Prefix(prefix) => {
+                let mut rewritten = BString::from(prefix.trim_end_with(|c| c == '/'));
+                rewritten.extend_from_slice(target);
+                rewritten
+            }
+        }
+    }
+}
+
+/// The components of the absolute `path`, with `.` and `..` resolved
+fn normal_components(path: &[u8]) -> Vec<&[u8]> {
+    let mut components = Vec::new();
+    for component in path.split_str("/") {
+        match component {
+            b"" | b"." => {}
+            b".." => {
+                components.pop();
+            }
+            _ => components.push(component),
+        }
+    }
+    components
+}
+
+/// Which CPUs the compression threads run on
+///
+/// By default threads run wherever the OS schedules them. On machines with several NUMA nodes,
+/// a thread moved to another node loses its caches and reads its buffers from remote memory,
+/// so keeping each thread on one node can speed up large builds. Threads are only pinned on
+/// linux, elsewhere this has no effect.
+///
+/// ```no_run
+/// use sqfs::config::CpuAffinity;
+/// use sqfs::write::ArchiveBuilder;
+///
+/// # fn main() -> std::io::Result<()> {
+/// let mut builder = ArchiveBuilder::new();
+/// builder.cpu_affinity = CpuAffinity::numa_nodes()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub enum CpuAffinity {
+    /// Let threads run on any CPU
+    #[default]
+    Any,
+    /// Pin each thread to a single one of these CPUs, taking them in turn
+    Cores(Vec<usize>),
+    /// Spread the threads evenly over these pools of CPUs, e.g. one per NUMA node, letting
+    /// each thread run on any CPU of its pool
+    Pools(Vec<Vec<usize>>),
+}
+
+impl CpuAffinity {
+    /// The most CPUs which can be named, the size of linux's `cpu_set_t`
+    pub const MAX_CPUS: usize = 1024;
+
+    /// A pool for each NUMA node of this machine
+    ///
+    /// Returns [`Any`](Self::Any) if there is only one node, or the nodes can't be listed
+    /// because this isn't linux.
+    pub fn numa_nodes() -> io::Result<Self> {
+        let nodes = match fs::read_dir("/sys/devices/system/node") {
+            Ok(nodes) => nodes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(CpuAffinity::Any),
+            Err(e) => return Err(e),
+        };
+        let mut pools = Vec::new();
+        for node in nodes {
+            let node = node?;
+            let name = node.file_name();
+            let is_node = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("node"))
+                .and_then(|n| n.parse::<u32>().ok())
+                .is_some();
+            if !is_node {
+                continue;
+            }
+            let cpus = parse_cpu_list(fs::read_to_string(node.path().join("cpulist"))?.trim())?;
+            if !cpus.is_empty() {
+                pools.push(cpus);
+            }
+        }
+        pools.sort();
+        Ok(if pools.len() > 1 {
+            CpuAffinity::Pools(pools)
+        } else {
+            CpuAffinity::Any
+        })
+    }
+
+    /// The CPUs the thread `index` may run on, empty if it isn't pinned
+    pub(crate) fn cpus(&self, index: usize) -> &[usize] {
+        match self {
+            CpuAffinity::Any => &[],
+            CpuAffinity::Cores(cores) if cores.is_empty() => &[],
+            CpuAffinity::Cores(cores) => std::slice::from_ref(&cores[index % cores.len()]),
+            CpuAffinity::Pools(pools) if pools.is_empty() => &[],
+            CpuAffinity::Pools(pools) => &pools[index % pools.len()],
+        }
+    }
+
+    /// Why the CPUs can't be used, if they can't
+    pub(crate) fn check(&self) -> std::result::Result<(), &'static str> {
+        let cpus: Vec<&usize> = match self {
+            CpuAffinity::Any => return Ok(()),
+            CpuAffinity::Cores(cores) if cores.is_empty() => return Err("no cores"),
+            CpuAffinity::Cores(cores) => cores.iter().collect(),
+            CpuAffinity::Pools(pools) if pools.iter().any(Vec::is_empty) || pools.is_empty() => {
+                return Err("an empty pool")
+            }
+            CpuAffinity::Pools(pools) => pools.iter().flatten().collect(),
+        };
+        if cpus.iter().any(|&&cpu| cpu >= Self::MAX_CPUS) {
+            return Err("a CPU number beyond the largest supported");
+        }
+        Ok(())
+    }
+}
+
+/// Parse a list of CPUs in the kernel's format, e.g. `0-3,8,10-11`
+fn parse_cpu_list(list: &str) -> io::Result<Vec<usize>> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid CPU list {list:?}"),
+        )
+    };
+    let mut cpus = Vec::new();
+    for range in list.split(',').filter(|range| !range.is_empty()) {
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (start, end),
+            None => (range, range),
+        };
+        let start: usize = start.parse().map_err(|_| invalid())?;
+        let end: usize = end.parse().map_err(|_| invalid())?;
+        if start > end {
+            return Err(invalid());
+        }
+        cpus.extend(start..=end);
+    }
+    Ok(cpus)
+}
+
+/// Limits on the memory used by an archive for buffers and caches
+///
+/// Every reading and writing archive has its own budget, so archives open at the same time in
+/// one process don't compete for the same buffers.
+///
+/// ```
+/// use sqfs::config::MemoryBudget;
+/// use sqfs::read::OpenOptions;
+///
+/// let mut options = OpenOptions::new();
+/// options.memory_budget(MemoryBudget {
+///     cache_bytes: 64 << 20,
+///     ..MemoryBudget::default()
+/// });
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct MemoryBudget {
+    /// The number of block buffers allocated up front for reuse
+    pub pooled_buffers: usize,
+    /// The most idle block buffers kept for reuse, rather than freed
+    pub pool_capacity: usize,
+    /// The most decompressed metadata kept in memory while reading, in bytes
+    ///
+    /// Directory listings and inodes are stored in metadata blocks of up to 8 KiB, and walking
+    /// a tree reads the same blocks repeatedly. `0` disables the cache.
+    pub cache_bytes: usize,
+    /// The number of blocks which may wait for each compression thread
+    ///
+    /// Each waiting block holds a buffer of up to the block size. With `0`, a block is only
+    /// handed over once a thread is ready for it.
+    pub buffers_per_thread: usize,
+}
+
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        let threads = num_cpus::get();
+        MemoryBudget {
+            pooled_buffers: threads,
+            pool_capacity: threads * 2,
+            cache_bytes: 4 << 20,
+            buffers_per_thread: 0,
+        }
+    }
+}
+
+/// The loggers used by each part of sqfs
+///
+/// By default, everything is logged through the [`log`](https://docs.rs/log) crate. Each
+/// subsystem can be given its own logger, or have its messages filtered by level, e.g. to
+/// silence debug output while reading, but keep warnings about the archives being written.
+#[derive(Debug, Clone)]
+pub struct LoggingConfig {
+    /// The logger used while reading archives
+    pub read: Logger,
+    /// The logger used while writing archives
+    pub write: Logger,
+    /// The logger used for compressing and decompressing data
+    pub compression: Logger,
+}
+
+impl LoggingConfig {
+    /// Log every subsystem to `logger`, tagging each message with the subsystem it came from
+    pub fn new(logger: &Logger) -> Self {
+        LoggingConfig {
+            read: logger.new(slog::o!("subsystem" => "read")),
+            write: logger.new(slog::o!("subsystem" => "write")),
+            compression: logger.new(slog::o!("subsystem" => "compression")),
+        }
+    }
+
+    /// Wrap `logger`, discarding any message less severe than `level`
+    ///
+    /// ```
+    /// use sqfs::config::LoggingConfig;
+    ///
+    /// let logger = slog::Logger::root(slog::Discard, slog::o!());
+    /// let mut logging = LoggingConfig::new(&logger);
+    /// logging.read = LoggingConfig::filter_level(&logging.read, slog::Level::Warning);
+    /// ```
+    pub fn filter_level(logger: &Logger, level: Level) -> Logger {
+        Logger::root(
+            LevelFilter::new(logger.clone(), level).ignore_res(),
+            slog::o!(),
+        )
+    }
+
+    /// Tag the messages of every subsystem with the file being read or written
+    pub(crate) fn for_file(&self, path: &Path) -> Self {
+        let file = path.display().to_string();
+        LoggingConfig {
+            read: self.read.new(slog::o!("file" => file.clone())),
+            write: self.write.new(slog::o!("file" => file.clone())),
+            compression: self.compression.new(slog::o!("file" => file)),
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig::new(&crate::default_logger())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn symlink_rewrite() {
+        let relative = SymlinkRewrite::Relative;
+        assert_eq!(
+            relative.rewrite(b"/usr/bin/cc", b"/usr/lib/gcc"),
+            "../lib/gcc"
+        );
+        assert_eq!(relative.rewrite(b"/bin", b"/usr/bin"), "usr/bin");
+        assert_eq!(relative.rewrite(b"/a/b/link", b"/"), "../..");
+        assert_eq!(relative.rewrite(b"/a/link", b"/a"), ".");
+        assert_eq!(relative.rewrite(b"/a/link", b"/../../a/./b/"), "b");
+        assert_eq!(relative.rewrite(b"/a/link", b"b/c"), "b/c");
+
+        let prefix = SymlinkRewrite::Prefix("/mnt/image/".into());
+        assert_eq!(prefix.rewrite(b"/bin", b"/usr/bin"), "/mnt/image/usr/bin");
+        assert_eq!(prefix.rewrite(b"/bin", b"usr/bin"), "usr/bin");
+        assert_eq!(
+            SymlinkRewrite::Keep.rewrite(b"/bin", b"/usr/bin"),
+            "/usr/bin"
+        );
+    }
+
+    struct Collect(Arc<Mutex<Vec<String>>>);
+
+    impl Drain for Collect {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(&self, record: &slog::Record, _: &slog::OwnedKVList) -> Result<(), slog::Never> {
+            self.0.lock().unwrap().push(record.msg().to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn cpu_lists() {
+        assert_eq!(
+            parse_cpu_list("0-3,8,10-11").unwrap(),
+            [0, 1, 2, 3, 8, 10, 11]
+        );
+        assert!(parse_cpu_list("").unwrap().is_empty());
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a").is_err());
+
+        let pools = CpuAffinity::Pools(vec![vec![0, 1], vec![2, 3]]);
+        assert_eq!(pools.cpus(0), [0, 1]);
+        assert_eq!(pools.cpus(3), [2, 3]);
+        assert_eq!(CpuAffinity::Cores(vec![4, 6]).cpus(3), [6]);
+        assert!(CpuAffinity::Any.cpus(3).is_empty());
+        assert!(pools.check().is_ok());
+        assert!(CpuAffinity::Pools(vec![vec![0], vec![]]).check().is_err());
+        assert!(CpuAffinity::Cores(vec![CpuAffinity::MAX_CPUS])
+            .check()
+            .is_err());
+    }
+
+    #[test]
+    fn filtered_levels() {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let logger = Logger::root(Collect(Arc::clone(&messages)), slog::o!());
+        let mut logging = LoggingConfig::new(&logger);
+        logging.read = LoggingConfig::filter_level(&logging.read, Level::Warning);
+
+        slog::debug!(logging.read, "read debug");
+        slog::warn!(logging.read, "read warning");
+        slog::debug!(logging.write, "write debug");
+        assert_eq!(
+            *messages.lock().unwrap(),
+            ["read warning".to_string(), "write debug".to_string()]
+        );
+    }
+}
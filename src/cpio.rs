@@ -0,0 +1,201 @@
+//! The cpio archive format, as used for Linux initramfs images
+
+use std::io;
+
+use crate::errors::{CpioError, Result};
+
+/// The variant of the cpio format to use
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Format {
+    /// The "new ASCII" (SVR4) format without checksums, magic `070701`
+    ///
+    /// This is the only format accepted by the Linux kernel for initramfs images
+    Newc,
+}
+
+impl Format {
+    fn magic(self) -> &'static [u8; 6] {
+        match self {
+            Format::Newc => b"070701",
+        }
+    }
+}
+
+/// The magic of the "crc" variant of newc, which is identical except for a checksum field
+const CRC_MAGIC: &[u8; 6] = b"070702";
+
+const HEADER_SIZE: usize = 110;
+
+const TRAILER: &[u8] = b"TRAILER!!!";
+
+/// The metadata of a single cpio entry
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Header {
+    pub ino: u32,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u32,
+    pub mtime: u32,
+    pub file_size: u32,
+    pub dev_major: u32,
+    pub dev_minor: u32,
+    pub rdev_major: u32,
+    pub rdev_minor: u32,
+    pub name: Vec<u8>,
+}
+
+/// Pad `len` bytes to a multiple of 4
+fn padding(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+impl Header {
+    /// Write the header and entry name, followed by padding
+    ///
+    /// The caller must then write exactly `file_size` bytes of data, followed by [`write_padding`]
+    pub fn write<W: io::Write>(&self, writer: &mut W, format: Format) -> io::Result<()> {
+        let mut header = Vec::with_capacity(HEADER_SIZE + self.name.len() + 4);
+        header.extend_from_slice(format.magic());
+        let fields = [
+            self.ino,
+            self.mode,
+            self.uid,
+            self.gid,
+            self.nlink,
+            self.mtime,
+            self.file_size,
+            self.dev_major,
+            self.dev_minor,
+            self.rdev_major,
+            self.rdev_minor,
+            self.name.len() as u32 + 1,
+            // check, only used by the crc format
+            0,
+        ];
+        for field in &fields {
+            header.extend_from_slice(format!("{:08X}", field).as_bytes());
+        }
+        header.extend_from_slice(&self.name);
+        header.push(0);
+        header.resize(header.len() + padding(header.len()), 0);
+        writer.write_all(&header)
+    }
+
+    /// Read an entry's header and data
+    ///
+    /// Both newc and crc archives are accepted, checksums are not verified.
+    /// Returns `None` at the end of the archive.
+    pub fn read_entry<R: io::Read>(reader: &mut R) -> Result<Option<(Self, Vec<u8>)>> {
+        let mut raw = [0; HEADER_SIZE];
+        reader.read_exact(&mut raw)?;
+        let (magic, fields) = raw.split_at(6);
+        if magic != Format::Newc.magic() && magic != CRC_MAGIC {
+            return Err(CpioError::BadMagic(magic.into()).into());
+        }
+
+        let mut values = [0; 13];
+        for (value, field) in values.iter_mut().zip(fields.chunks(8)) {
+            *value = std::str::from_utf8(field)
+                .ok()
+                .and_then(|field| u32::from_str_radix(field, 16).ok())
+                .ok_or(CpioError::InvalidHeader)?;
+        }
+        let [ino, mode, uid, gid, nlink, mtime, file_size, dev_major, dev_minor, rdev_major, rdev_minor, name_size, _check] =
+            values;
+
+        let name_size = name_size as usize;
+        let mut name = vec![0; name_size + padding(HEADER_SIZE + name_size)];
+        reader.read_exact(&mut name)?;
+        name.truncate(name_size);
+        if name.pop() != Some(0) {
+            return Err(CpioError::InvalidHeader.into());
+        }
+        if name == TRAILER {
+            return Ok(None);
+        }
+
+        let file_size = file_size as usize;
+        let mut data = vec![0; file_size + padding(file_size)];
+        reader.read_exact(&mut data)?;
+        data.truncate(file_size);
+
+        let header = Header {
+            ino,
+            mode,
+            uid,
+            gid,
+            nlink,
+            mtime,
+            file_size: file_size as u32,
+            dev_major,
+            dev_minor,
+            rdev_major,
+            rdev_minor,
+            name,
+        };
+        Ok(Some((header, data)))
+    }
+}
+
+/// Write the padding following `len` bytes of file data
+pub(crate) fn write_padding<W: io::Write>(writer: &mut W, len: usize) -> io::Result<()> {
+    writer.write_all(&[0; 4][..padding(len)])
+}
+
+/// Write the entry marking the end of the archive
+pub(crate) fn write_trailer<W: io::Write>(writer: &mut W, format: Format) -> io::Result<()> {
+    Header {
+        nlink: 1,
+        name: TRAILER.to_vec(),
+        ..Header::default()
+    }
+    .write(writer, format)
+}
+
+/// Convert a file size to the 32 bit size field of a cpio header
+pub(crate) fn file_size(size: u64) -> Result<u32> {
+    if size > u64::from(u32::MAX) {
+        return Err(CpioError::FileTooLarge(size).into());
+    }
+    Ok(size as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_roundtrip() {
+        let header = Header {
+            ino: 12,
+            mode: 0o100_644,
+            uid: 1000,
+            gid: 100,
+            nlink: 1,
+            mtime: 1_600_000_000,
+            file_size: 5,
+            rdev_major: 1,
+            rdev_minor: 3,
+            name: b"etc/hosts".to_vec(),
+            ..Header::default()
+        };
+        let mut data = Vec::new();
+        header.write(&mut data, Format::Newc).unwrap();
+        data.extend_from_slice(b"hello");
+        write_padding(&mut data, 5).unwrap();
+        write_trailer(&mut data, Format::Newc).unwrap();
+        assert_eq!(data.len() % 4, 0);
+
+        let mut reader = &data[..];
+        let (read, contents) = Header::read_entry(&mut reader).unwrap().unwrap();
+        assert_eq!(read, header);
+        assert_eq!(contents, b"hello");
+        assert!(Header::read_entry(&mut reader).unwrap().is_none());
+        assert!(reader.is_empty());
+
+        data[5] = b'9';
+        Header::read_entry(&mut &data[..]).unwrap_err();
+    }
+}
@@ -1,3 +1,4 @@
+use bstr::BString;
 use std::io;
 use thiserror::Error as ThisError;
 
@@ -15,6 +16,22 @@ pub(crate) enum ErrorInner {
     #[error("Metablock error: {0}")]
     Metablock(#[from] MetablockError),
 
+    #[error("Write error: {0}")]
+    Write(#[from] WriteError),
+
+    #[error("Read error: {0}")]
+    Read(#[from] ReadError),
+
+    #[error("Cpio error: {0}")]
+    Cpio(#[from] CpioError),
+
+    #[error("Block store error: {0}")]
+    Cas(#[from] CasError),
+
+    #[cfg(feature = "oci")]
+    #[error("OCI image error: {0}")]
+    Oci(#[from] OciError),
+
     #[error(transparent)]
     Io(#[from] io::Error),
 }
@@ -44,12 +61,15 @@ pub(crate) enum SuperblockError {
 
     #[error("Unsupported option: {0}")]
     UnsupportedOption(String),
+
+    #[error("Archive truncated: {0}")]
+    Truncated(crate::read::Truncation),
 }
 
 #[derive(Debug, ThisError)]
 pub(crate) enum MetablockError {
-    #[error("Metadata block size too large {0} (max {})", ::repr::metablock::SIZE)]
-    HugeMetablock(usize),
+    #[error("Metadata block size too large {size} (max {max})")]
+    HugeMetablock { size: usize, max: usize },
 
     #[error("Metadata block size mismatch: expected {expected}, got {actual}")]
     UnexpectedMetablockSize { actual: usize, expected: usize },
@@ -58,6 +78,196 @@ pub(crate) enum MetablockError {
     CompressedCompressorOptions,
 }
 
+#[derive(Debug, ThisError)]
+pub(crate) enum WriteError {
+    #[error("Refusing to store a {kind}: special files are disallowed")]
+    SpecialFile { kind: &'static str },
+
+    #[error(transparent)]
+    DeviceNumber(#[from] repr::inode::DeviceNumberError),
+
+    #[error("Invalid path {0:?}")]
+    InvalidPath(BString),
+
+    #[error("Unknown file type: mode {0:#o}")]
+    UnknownFileType(u16),
+
+    #[error("{path:?}: {what} of {actual} exceeds the limit of {max}")]
+    LimitExceeded {
+        path: BString,
+        what: &'static str,
+        actual: usize,
+        max: usize,
+    },
+
+    #[error("{0:?} exists in both archives being merged")]
+    MergeConflict(BString),
+
+    #[error(
+        "Block size {0} invalid: must be a power of two between {} and {}",
+        repr::BLOCK_SIZE_MIN,
+        repr::BLOCK_SIZE_MAX
+    )]
+    InvalidBlockSize(u32),
+
+    #[error(
+        "Padding {0} invalid: must be a multiple of {}",
+        crate::write::SECTOR_SIZE
+    )]
+    InvalidPadding(u64),
+
+    #[error("sqfs built without support for {0}")]
+    DisabledCompression(crate::compression::Kind),
+
+    #[error("Compression options for {options} given to the {compressor} compressor")]
+    MismatchedCompressionOptions {
+        options: crate::compression::Kind,
+        compressor: crate::compression::Kind,
+    },
+
+    #[error("Invalid CPU affinity: {0}")]
+    InvalidCpuAffinity(&'static str),
+
+    #[error("Invalid raw block: {0}")]
+    InvalidRawBlock(&'static str),
+
+    #[error(
+        "Can't store xattr {0:?}: only user., trusted. and security. attributes are supported"
+    )]
+    UnsupportedXattr(bstr::BString),
+
+    #[error("The archive has no root directory")]
+    NoRoot,
+
+    #[error("Invalid build plan at line {line}")]
+    InvalidBuildPlan { line: usize },
+
+    #[error("Invalid build state at line {line}")]
+    InvalidBuildState { line: usize },
+
+    #[error("{0:?} doesn't match the build plan")]
+    PlanMismatch(BString),
+
+    #[error("Invalid access trace at line {line}")]
+    InvalidAccessTrace { line: usize },
+
+    #[error("A planned build must start from an empty archive")]
+    PlanNotFirst,
+
+    #[error("Flushing the archive already failed")]
+    FlushFailed,
+}
+
+#[derive(Debug, ThisError)]
+pub(crate) enum ReadError {
+    #[error("Unknown inode type {0}")]
+    UnknownInodeKind(u16),
+
+    #[error("Expected a directory")]
+    NotADirectory,
+
+    #[error("Expected a regular file")]
+    NotAFile,
+
+    #[error("No such entry {0:?}")]
+    NotFound(BString),
+
+    #[error("Id index {0} out of range")]
+    IdIndexOutOfRange(u16),
+
+    #[error("Fragment index {0} out of range")]
+    FragmentIndexOutOfRange(u32),
+
+    #[error("Xattr index {0} out of range")]
+    XattrIndexOutOfRange(u32),
+
+    #[error("No inode numbered {0}")]
+    InodeNotFound(u32),
+
+    #[error("Corrupt export table entry: {0}")]
+    CorruptExportEntry(&'static str),
+
+    #[error("Corrupt xattrs: {0}")]
+    CorruptXattr(&'static str),
+
+    #[error("Corrupt directory listing: {0}")]
+    CorruptDirectory(&'static str),
+
+    #[error("Data block too large ({0} bytes)")]
+    HugeDatablock(u32),
+
+    #[error("{what} of {actual} exceeds the limit of {max}")]
+    LimitExceeded {
+        what: &'static str,
+        actual: u64,
+        max: u64,
+    },
+
+    #[error("File contents truncated: expected {expected} bytes, got {actual}")]
+    TruncatedFile { expected: u64, actual: u64 },
+
+    #[error("Archive has no embedded signature")]
+    MissingSignature,
+
+    #[error("Signature verification failed")]
+    InvalidSignature,
+
+    #[error("Embedded signature too large ({0} bytes)")]
+    SignatureTooLarge(u32),
+
+    #[error("{path:?}: extracting {kind}s is not supported")]
+    UnsupportedEntry { path: BString, kind: &'static str },
+
+    #[error("Invalid extraction journal at line {line}")]
+    InvalidJournal { line: usize },
+
+    #[error("Refusing to extract {0:?} outside of the destination")]
+    UnsafePath(BString),
+
+    #[error("Image is compressed with {0}, which is not enabled")]
+    UnsupportedWrapper(&'static str),
+}
+
+#[derive(Debug, ThisError)]
+pub(crate) enum CpioError {
+    #[error("File too large for cpio ({0} bytes)")]
+    FileTooLarge(u64),
+
+    #[error("Bad cpio magic {0:?}")]
+    BadMagic(BString),
+
+    #[error("Invalid cpio header")]
+    InvalidHeader,
+}
+
+#[derive(Debug, ThisError)]
+pub(crate) enum CasError {
+    #[error("Invalid recipe at line {line}")]
+    InvalidRecipe { line: usize },
+
+    #[error("Stored block {hash:032x} doesn't match its hash")]
+    CorruptSegment { hash: u128 },
+}
+
+#[cfg(feature = "oci")]
+#[derive(Debug, ThisError)]
+pub(crate) enum OciError {
+    #[error("Missing {0}")]
+    MissingFile(String),
+
+    #[error("Invalid JSON in {0}: {1}")]
+    InvalidJson(String, #[source] serde_json::Error),
+
+    #[error("Invalid manifest: {0}")]
+    InvalidManifest(&'static str),
+
+    #[error("Layer compressed with {0}, which is not enabled")]
+    UnsupportedCompression(&'static str),
+
+    #[error("Hard link target {0:?} is not a regular file")]
+    MissingLinkTarget(BString),
+}
+
 impl From<SuperblockError> for Error {
     fn from(e: SuperblockError) -> Self {
         Error(e.into())
@@ -70,6 +280,37 @@ impl From<MetablockError> for Error {
     }
 }
 
+impl From<WriteError> for Error {
+    fn from(e: WriteError) -> Self {
+        Error(e.into())
+    }
+}
+
+impl From<ReadError> for Error {
+    fn from(e: ReadError) -> Self {
+        Error(e.into())
+    }
+}
+
+impl From<CpioError> for Error {
+    fn from(e: CpioError) -> Self {
+        Error(e.into())
+    }
+}
+
+impl From<CasError> for Error {
+    fn from(e: CasError) -> Self {
+        Error(e.into())
+    }
+}
+
+#[cfg(feature = "oci")]
+impl From<OciError> for Error {
+    fn from(e: OciError) -> Self {
+        Error(e.into())
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Self {
         Error(e.into())
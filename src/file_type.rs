@@ -0,0 +1,138 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use repr::inode::Kind;
+
+use crate::Mode;
+
+/// The type of an entry in an archive
+///
+/// Unlike [`repr::inode::Kind`], this doesn't distinguish between the basic and extended
+/// on-disk representations of the same type.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FileType {
+    File,
+    Dir,
+    Symlink,
+    CharDev,
+    BlockDev,
+    Fifo,
+    Socket,
+}
+
+impl FileType {
+    /// The type described by the file type bits of `mode`, if they are valid
+    pub fn from_mode(mode: Mode) -> Option<Self> {
+        let file_type = match mode.ty() {
+            Mode::TYPE_FILE => FileType::File,
+            Mode::TYPE_DIR => FileType::Dir,
+            Mode::TYPE_LINK => FileType::Symlink,
+            Mode::TYPE_CHAR => FileType::CharDev,
+            Mode::TYPE_BLOCK => FileType::BlockDev,
+            Mode::TYPE_FIFO => FileType::Fifo,
+            Mode::TYPE_SOCKET => FileType::Socket,
+            _ => return None,
+        };
+        Some(file_type)
+    }
+
+    /// The file type bits of a mode for this type
+    pub fn mode_type(self) -> Mode {
+        match self {
+            FileType::File => Mode::TYPE_FILE,
+            FileType::Dir => Mode::TYPE_DIR,
+            FileType::Symlink => Mode::TYPE_LINK,
+            FileType::CharDev => Mode::TYPE_CHAR,
+            FileType::BlockDev => Mode::TYPE_BLOCK,
+            FileType::Fifo => Mode::TYPE_FIFO,
+            FileType::Socket => Mode::TYPE_SOCKET,
+        }
+    }
+
+    /// The basic on-disk inode type for this type
+    pub fn basic_kind(self) -> Kind {
+        match self {
+            FileType::File => Kind::BASIC_FILE,
+            FileType::Dir => Kind::BASIC_DIR,
+            FileType::Symlink => Kind::BASIC_SYMLINK,
+            FileType::CharDev => Kind::BASIC_CHAR_DEV,
+            FileType::BlockDev => Kind::BASIC_BLOCK_DEV,
+            FileType::Fifo => Kind::BASIC_FIFO,
+            FileType::Socket => Kind::BASIC_SOCKET,
+        }
+    }
+
+    /// A human readable name for the type
+    pub fn name(self) -> &'static str {
+        match self {
+            FileType::File => "file",
+            FileType::Dir => "directory",
+            FileType::Symlink => "symlink",
+            FileType::CharDev => "character device",
+            FileType::BlockDev => "block device",
+            FileType::Fifo => "fifo",
+            FileType::Socket => "socket",
+        }
+    }
+}
+
+impl TryFrom<Kind> for FileType {
+    type Error = Kind;
+
+    /// Convert a basic or extended on-disk inode type, returning it back if it is unknown
+    fn try_from(kind: Kind) -> Result<Self, Kind> {
+        let file_type = match kind.to_basic() {
+            Kind::BASIC_FILE => FileType::File,
+            Kind::BASIC_DIR => FileType::Dir,
+            Kind::BASIC_SYMLINK => FileType::Symlink,
+            Kind::BASIC_CHAR_DEV => FileType::CharDev,
+            Kind::BASIC_BLOCK_DEV => FileType::BlockDev,
+            Kind::BASIC_FIFO => FileType::Fifo,
+            Kind::BASIC_SOCKET => FileType::Socket,
+            _ => return Err(kind),
+        };
+        Ok(file_type)
+    }
+}
+
+impl TryFrom<Mode> for FileType {
+    type Error = Mode;
+
+    fn try_from(mode: Mode) -> Result<Self, Mode> {
+        FileType::from_mode(mode).ok_or(mode)
+    }
+}
+
+impl From<FileType> for Kind {
+    fn from(file_type: FileType) -> Self {
+        file_type.basic_kind()
+    }
+}
+
+impl fmt::Display for FileType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversions() {
+        assert_eq!(FileType::try_from(Kind::EXT_DIR), Ok(FileType::Dir));
+        assert_eq!(FileType::try_from(Kind::BASIC_SOCKET), Ok(FileType::Socket));
+        assert_eq!(FileType::try_from(Kind(0)), Err(Kind(0)));
+        assert_eq!(FileType::try_from(Kind(15)), Err(Kind(15)));
+        assert_eq!(
+            FileType::from_mode(Mode::TYPE_LINK | Mode::O777),
+            Some(FileType::Symlink)
+        );
+        assert_eq!(FileType::from_mode(Mode::O777), None);
+        for &file_type in &[FileType::File, FileType::CharDev, FileType::Fifo] {
+            assert_eq!(FileType::from_mode(file_type.mode_type()), Some(file_type));
+            assert_eq!(FileType::try_from(Kind::from(file_type)), Ok(file_type));
+        }
+    }
+}
@@ -0,0 +1,855 @@
+//! Serving archives read-only through FUSE, asynchronously
+//!
+//! [`serve`] answers the kernel's FUSE requests for an archive on a tokio runtime, speaking the
+//! protocol of `/dev/fuse` directly, as libfuse 3 does. Each request is answered in a task of
+//! its own, sharing one [`Archive`] handle, so a server busy with many requests at once, such as
+//! one serving container images, doesn't need a thread for each. [`FuseMount`] mounts a
+//! filesystem to serve, which needs root or `CAP_SYS_ADMIN`.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! use sqfs::fuse::FuseMount;
+//!
+//! let archive = sqfs::read::Archive::open("image.sqfs")?;
+//! let mount = FuseMount::mount("/mnt/image")?;
+//! // Returns once the filesystem is unmounted
+//! mount.serve(archive).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The kernel refers to files by node ids, which are looked up again by inode number for every
+//! request, with [`Archive::inode_by_number`]. FUSE requires the root to be node 1, so it is,
+//! and every other inode is its number plus one.
+
+use std::convert::{TryFrom, TryInto};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio::io::unix::AsyncFd;
+
+use crate::mount::{c_path, cvt};
+use crate::read::{Archive, Inode, InodeData, ReadAt};
+use crate::FileType;
+
+/// The version of the protocol spoken, 7.31
+const KERNEL_VERSION: u32 = 7;
+const KERNEL_MINOR_VERSION: u32 = 31;
+/// Replies to `INIT` from kernels older than 7.23 are this short
+const INIT_OUT_7_22_SIZE: usize = 24;
+
+const ROOT_ID: u64 = 1;
+/// How long the kernel may cache entries and attributes, in seconds: archives never change
+const TTL: u64 = 60 * 60;
+/// The most read ahead of a file
+const MAX_READAHEAD: u32 = 128 << 10;
+/// Writes are refused, but the kernel requires space for one in the buffer of every read
+const MAX_WRITE: u32 = 4096;
+/// Holds any request, the largest being `GETXATTR` with a long name
+const BUFFER_SIZE: usize = 64 << 10;
+
+const IN_HEADER_SIZE: usize = 40;
+
+// Opcodes
+const LOOKUP: u32 = 1;
+const FORGET: u32 = 2;
+const GETATTR: u32 = 3;
+const READLINK: u32 = 5;
+const OPEN: u32 = 14;
+const READ: u32 = 15;
+const STATFS: u32 = 17;
+const RELEASE: u32 = 18;
+const GETXATTR: u32 = 22;
+const LISTXATTR: u32 = 23;
+const FLUSH: u32 = 25;
+const INIT: u32 = 26;
+const OPENDIR: u32 = 27;
+const READDIR: u32 = 28;
+const RELEASEDIR: u32 = 29;
+const FSYNCDIR: u32 = 30;
+const ACCESS: u32 = 34;
+const INTERRUPT: u32 = 36;
+const DESTROY: u32 = 38;
+const BATCH_FORGET: u32 = 42;
+
+// Flags of INIT
+const ASYNC_READ: u32 = 1 << 0;
+const EXPORT_SUPPORT: u32 = 1 << 4;
+const PARALLEL_DIROPS: u32 = 1 << 18;
+const CACHE_SYMLINKS: u32 = 1 << 23;
+
+// Flags of OPEN and OPENDIR replies
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+const FOPEN_CACHE_DIR: u32 = 1 << 3;
+
+const SQUASHFS_MAGIC: u32 = 0x7371_7368;
+
+/// Answer the FUSE requests read from `device` with the contents of `archive`, until the
+/// filesystem is unmounted
+///
+/// `device` is the open `/dev/fuse` a filesystem was mounted with. This must be called within
+/// a tokio runtime, and the archive must have a readable root.
+pub async fn serve<R>(archive: Archive<R>, device: File) -> io::Result<()>
+where
+    R: ReadAt + Send + Sync + 'static,
+{
+    let session = Arc::new(Session::new(archive).map_err(io::Error::other)?);
+    set_nonblocking(&device)?;
+    let device = Arc::new(AsyncFd::new(device)?);
+    loop {
+        let mut request = vec![0; BUFFER_SIZE];
+        let len = match read_request(&device, &mut request).await {
+            // Unmounted, or the other end of a socket closed
+            Ok(0) => return Ok(()),
+            Ok(len) => len,
+            Err(e) if e.raw_os_error() == Some(libc::ENODEV) => return Ok(()),
+            // The request was interrupted before it was read
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => continue,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        request.truncate(len);
+        let (session, device) = (Arc::clone(&session), Arc::clone(&device));
+        tokio::spawn(async move {
+            if let Some(reply) = session.handle(&request) {
+                // Fails if the request was interrupted meanwhile, when no reply is wanted
+                let _ = write_reply(device.get_ref(), &reply);
+            }
+        });
+    }
+}
+
+async fn read_request(device: &AsyncFd<File>, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        // Once the filesystem's unmounted, /dev/fuse polls as only an error, which wakes writers
+        // rather than readers, and reading then fails
+        let mut guard = tokio::select! {
+            guard = device.readable() => guard?,
+            guard = device.writable() => guard?,
+        };
+        let result = guard.try_io(|device| {
+            let read =
+                unsafe { libc::read(device.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+            if read < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(read as usize)
+            }
+        });
+        if let Ok(result) = result {
+            return result;
+        }
+    }
+}
+
+/// Write a whole reply, which `/dev/fuse` takes in one write, without blocking
+fn write_reply(device: &File, reply: &[u8]) -> io::Result<()> {
+    let written = unsafe { libc::write(device.as_raw_fd(), reply.as_ptr().cast(), reply.len()) };
+    if written < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if written as usize != reply.len() {
+        return Err(io::ErrorKind::WriteZero.into());
+    }
+    Ok(())
+}
+
+fn set_nonblocking(device: &File) -> io::Result<()> {
+    let fd = device.as_raw_fd();
+    let flags = cvt(unsafe { libc::fcntl(fd, libc::F_GETFL) })?;
+    cvt(unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) })?;
+    Ok(())
+}
+
+/// An empty FUSE filesystem mounted read-only, for [`serve`] to answer, which is unmounted when
+/// dropped
+#[derive(Debug)]
+pub struct FuseMount {
+    mount_point: PathBuf,
+    device: File,
+    mounted: bool,
+}
+
+impl FuseMount {
+    /// Mount a filesystem at `mount_point`, which must be an existing directory
+    ///
+    /// The kernel checks permissions from the modes of the archive's inodes, and every user may
+    /// access the filesystem. Requests wait until the filesystem is [served](Self::serve).
+    pub fn mount<P: AsRef<Path>>(mount_point: P) -> io::Result<Self> {
+        Self::_mount(mount_point.as_ref())
+    }
+
+    fn _mount(mount_point: &Path) -> io::Result<Self> {
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/fuse")?;
+        let target = c_path(mount_point)?;
+        let options = format!(
+            "fd={},rootmode=40000,user_id={},group_id={},default_permissions,allow_other\0",
+            device.as_raw_fd(),
+            unsafe { libc::getuid() },
+            unsafe { libc::getgid() },
+        );
+        cvt(unsafe {
+            libc::mount(
+                b"sqfs\0".as_ptr().cast(),
+                target.as_ptr(),
+                b"fuse.sqfs\0".as_ptr().cast(),
+                libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV,
+                options.as_ptr().cast(),
+            )
+        })?;
+        Ok(FuseMount {
+            mount_point: mount_point.to_path_buf(),
+            device,
+            mounted: true,
+        })
+    }
+
+    pub fn mount_point(&self) -> &Path {
+        &self.mount_point
+    }
+
+    /// Answer the filesystem's requests with the contents of `archive` until it's unmounted,
+    /// see [`serve`]
+    pub async fn serve<R>(&self, archive: Archive<R>) -> io::Result<()>
+    where
+        R: ReadAt + Send + Sync + 'static,
+    {
+        serve(archive, self.device.try_clone()?).await
+    }
+
+    /// Unmount the filesystem, returning any error unmounting
+    pub fn unmount(mut self) -> io::Result<()> {
+        self.unmount_inner()
+    }
+
+    fn unmount_inner(&mut self) -> io::Result<()> {
+        if self.mounted {
+            let target = c_path(&self.mount_point)?;
+            cvt(unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) })?;
+            self.mounted = false;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FuseMount {
+    fn drop(&mut self) {
+        let _ = self.unmount_inner();
+    }
+}
+
+/// The fields of a request, taken in order
+struct Request<'a>(&'a [u8]);
+
+impl<'a> Request<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], i32> {
+        if self.0.len() < len {
+            return Err(libc::EPROTO);
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, i32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, i32> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// A nul terminated name
+    fn name(&mut self) -> Result<&'a [u8], i32> {
+        let len = self.0.iter().position(|&b| b == 0).ok_or(libc::EPROTO)?;
+        let name = self.bytes(len)?;
+        self.bytes(1)?;
+        Ok(name)
+    }
+}
+
+/// The fields of a reply, added in order
+#[derive(Default)]
+struct Reply(Vec<u8>);
+
+impl Reply {
+    fn u16(&mut self, value: u16) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, value: u64) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u8s(&mut self, value: &[u8]) -> &mut Self {
+        self.0.extend_from_slice(value);
+        self
+    }
+}
+
+/// The name and value of an extended attribute
+type Xattr = (Vec<u8>, Vec<u8>);
+
+/// The header every request starts with
+struct InHeader {
+    opcode: u32,
+    unique: u64,
+    node_id: u64,
+}
+
+/// Answers requests for an archive, shared by the tasks answering them
+struct Session<R> {
+    archive: Archive<R>,
+    /// The inode number of the root, which is node 1
+    root: u32,
+}
+
+impl<R: ReadAt> Session<R> {
+    fn new(archive: Archive<R>) -> crate::Result<Self> {
+        let root = archive.root()?.inode_number();
+        Ok(Session { archive, root })
+    }
+
+    /// Answer one request, returning the reply, or `None` for requests which have none
+    fn handle(&self, message: &[u8]) -> Option<Vec<u8>> {
+        let mut request = Request(message);
+        let header = request.bytes(IN_HEADER_SIZE).ok()?;
+        let mut fields = Request(header);
+        let (_len, opcode, unique, node_id) = (
+            fields.u32().ok()?,
+            fields.u32().ok()?,
+            fields.u64().ok()?,
+            fields.u64().ok()?,
+        );
+        let header = InHeader {
+            opcode,
+            unique,
+            node_id,
+        };
+        if matches!(header.opcode, FORGET | BATCH_FORGET | INTERRUPT) {
+            return None;
+        }
+
+        let (error, body) = match self.answer(&header, &mut request) {
+            Ok(body) => (0, body),
+            Err(errno) => (-errno, Reply::default()),
+        };
+        let mut reply = Reply::default();
+        reply
+            .u32((16 + body.0.len()) as u32)
+            .u32(error as u32)
+            .u64(header.unique)
+            .u8s(&body.0);
+        Some(reply.0)
+    }
+
+    fn answer(&self, header: &InHeader, request: &mut Request<'_>) -> Result<Reply, i32> {
+        let mut reply = Reply::default();
+        match header.opcode {
+            INIT => {
+                let (major, minor) = (request.u32()?, request.u32()?);
+                let max_readahead = request.u32()?;
+                let flags = request.u32()?;
+                if major < KERNEL_VERSION {
+                    return Err(libc::EPROTO);
+                }
+                let minor = minor.min(KERNEL_MINOR_VERSION);
+                let wanted = ASYNC_READ | EXPORT_SUPPORT | PARALLEL_DIROPS | CACHE_SYMLINKS;
+                reply
+                    .u32(KERNEL_VERSION)
+                    .u32(minor)
+                    .u32(max_readahead.min(MAX_READAHEAD))
+                    .u32(flags & wanted)
+                    // Background requests and the congestion threshold
+                    .u16(16)
+                    .u16(12)
+                    .u32(MAX_WRITE)
+                    // Times are whole seconds
+                    .u32(1_000_000_000)
+                    // Pages, alignment, more flags and unused fields
+                    .u16(0)
+                    .u16(0)
+                    .u32(0)
+                    .u8s(&[0; 28]);
+                if minor < 23 {
+                    reply.0.truncate(INIT_OUT_7_22_SIZE);
+                }
+            }
+            DESTROY | RELEASE | RELEASEDIR | FLUSH | FSYNCDIR => {}
+            LOOKUP => {
+                let name = request.name()?;
+                let dir = self.inode(header.node_id)?;
+                let inode_number = self.lookup(&dir, name)?;
+                let inode = self.inode(self.node_id(inode_number))?;
+                self.entry(&inode, &mut reply);
+            }
+            GETATTR => {
+                let inode = self.inode(header.node_id)?;
+                reply.u64(TTL).u32(0).u32(0);
+                self.attr(&inode, &mut reply);
+            }
+            READLINK => {
+                let inode = self.inode(header.node_id)?;
+                let target = inode.symlink_target().ok_or(libc::EINVAL)?;
+                reply.u8s(target);
+            }
+            OPEN | OPENDIR => {
+                let flags = request.u32()?;
+                if flags & libc::O_ACCMODE as u32 != libc::O_RDONLY as u32 {
+                    return Err(libc::EROFS);
+                }
+                let inode = self.inode(header.node_id)?;
+                let open_flags = match (header.opcode, inode.is_dir()) {
+                    (OPEN, true) => return Err(libc::EISDIR),
+                    (OPENDIR, false) => return Err(libc::ENOTDIR),
+                    (OPEN, false) => FOPEN_KEEP_CACHE,
+                    _ => FOPEN_KEEP_CACHE | FOPEN_CACHE_DIR,
+                };
+                reply.u64(0).u32(open_flags).u32(0);
+            }
+            READ => {
+                let (_fh, offset, size) = (request.u64()?, request.u64()?, request.u32()?);
+                let inode = self.inode(header.node_id)?;
+                let data = self.read(&inode, offset, size).map_err(|_| libc::EIO)?;
+                reply.u8s(&data);
+            }
+            READDIR => {
+                let (_fh, offset, size) = (request.u64()?, request.u64()?, request.u32()?);
+                let dir = self.inode(header.node_id)?;
+                for (i, (name, file_type, inode_number)) in self
+                    .read_dir(&dir)?
+                    .iter()
+                    .enumerate()
+                    .skip(offset as usize)
+                {
+                    // The inode number, the offset of the next entry, the name's length and
+                    // the type, then the name padded to 8 bytes
+                    let len = (24 + name.len()).next_multiple_of(8);
+                    if reply.0.len() + len > size as usize {
+                        break;
+                    }
+                    reply
+                        .u64((*inode_number).into())
+                        .u64(i as u64 + 1)
+                        .u32(name.len() as u32)
+                        .u32(dirent_type(*file_type))
+                        .u8s(name)
+                        .u8s(&[0; 7][..len - 24 - name.len()]);
+                }
+            }
+            STATFS => {
+                let block_size = self.archive.block_size();
+                let blocks = self.archive.bytes_used().div_ceil(block_size.into());
+                let inodes = self.archive.superblock().inode_count;
+                reply
+                    .u64(blocks)
+                    .u64(0)
+                    .u64(0)
+                    .u64(inodes.into())
+                    .u64(0)
+                    .u32(block_size)
+                    .u32(256)
+                    .u32(block_size)
+                    .u32(0)
+                    .u8s(&[0; 24]);
+            }
+            GETXATTR => {
+                let size = request.u32()?;
+                request.u32()?;
+                let name = request.name()?;
+                let inode = self.inode(header.node_id)?;
+                let value = self
+                    .xattrs(&inode)?
+                    .into_iter()
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| value)
+                    .ok_or(libc::ENODATA)?;
+                sized(&mut reply, size, &value)?;
+            }
+            LISTXATTR => {
+                let size = request.u32()?;
+                let inode = self.inode(header.node_id)?;
+                let mut names = Vec::new();
+                for (key, _) in self.xattrs(&inode)? {
+                    names.extend_from_slice(&key);
+                    names.push(0);
+                }
+                sized(&mut reply, size, &names)?;
+            }
+            ACCESS => {
+                // Permissions are checked by the kernel, writes are all that's refused
+                if request.u32()? & libc::W_OK as u32 != 0 {
+                    return Err(libc::EROFS);
+                }
+            }
+            _ => return Err(libc::ENOSYS),
+        }
+        Ok(reply)
+    }
+
+    fn node_id(&self, inode_number: u32) -> u64 {
+        if inode_number == self.root {
+            ROOT_ID
+        } else {
+            u64::from(inode_number) + 1
+        }
+    }
+
+    /// The inode with the node id `node_id`, looked up by number
+    fn inode(&self, node_id: u64) -> Result<Inode, i32> {
+        let inode_number = if node_id == ROOT_ID {
+            self.root
+        } else {
+            node_id
+                .checked_sub(1)
+                .and_then(|number| u32::try_from(number).ok())
+                .ok_or(libc::ENOENT)?
+        };
+        self.archive
+            .inode_by_number(inode_number)
+            .map_err(|_| libc::ENOENT)
+    }
+
+    /// The inode number of the parent of `dir`, or of `dir` itself if it's the root
+    fn parent(&self, dir: &Inode) -> Result<u32, i32> {
+        let parent = dir.parent_inode_number().ok_or(libc::ENOTDIR)?;
+        if dir.inode_number() == self.root {
+            return Ok(self.root);
+        }
+        Ok(parent)
+    }
+
+    /// The inode number of the entry `name` in `dir`
+    fn lookup(&self, dir: &Inode, name: &[u8]) -> Result<u32, i32> {
+        if !dir.is_dir() {
+            return Err(libc::ENOTDIR);
+        }
+        match name {
+            b"." => return Ok(dir.inode_number()),
+            b".." => return self.parent(dir),
+            _ => {}
+        }
+        let entries = self.archive.read_dir(dir).map_err(|_| libc::EIO)?;
+        entries
+            .iter()
+            .find(|entry| entry.name() == name)
+            .map(|entry| entry.inode_number())
+            .ok_or(libc::ENOENT)
+    }
+
+    /// The name, type and number of each entry of `dir`, starting with `.` and `..`
+    fn read_dir(&self, dir: &Inode) -> Result<Vec<(Vec<u8>, FileType, u32)>, i32> {
+        if !dir.is_dir() {
+            return Err(libc::ENOTDIR);
+        }
+        let mut entries = vec![
+            (b".".to_vec(), FileType::Dir, dir.inode_number()),
+            (b"..".to_vec(), FileType::Dir, self.parent(dir)?),
+        ];
+        let listing = self.archive.read_dir(dir).map_err(|_| libc::EIO)?;
+        entries.extend(listing.iter().map(|entry| {
+            let name = entry.name().to_vec();
+            (name, entry.file_type(), entry.inode_number())
+        }));
+        Ok(entries)
+    }
+
+    /// Up to `size` bytes of the contents of `file`, from `offset`
+    fn read(&self, file: &Inode, offset: u64, size: u32) -> crate::Result<Vec<u8>> {
+        let mut reader = self.archive.open_file(file)?;
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::new();
+        reader.take(size.into()).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// The names and values of the extended attributes of `inode`
+    fn xattrs(&self, inode: &Inode) -> Result<Vec<Xattr>, i32> {
+        use std::os::unix::ffi::OsStringExt;
+
+        let xattrs = self.archive.xattrs(inode).map_err(|_| libc::EIO)?;
+        xattrs
+            .map(|xattr| xattr.map(|(key, value)| (key.into_vec(), value)))
+            .collect::<crate::Result<_>>()
+            .map_err(|_| libc::EIO)
+    }
+
+    /// An entry reply for `inode`, which the kernel may cache
+    fn entry(&self, inode: &Inode, reply: &mut Reply) {
+        reply
+            .u64(self.node_id(inode.inode_number()))
+            // Generation, then how long the entry and attributes are valid
+            .u64(0)
+            .u64(TTL)
+            .u64(TTL)
+            .u32(0)
+            .u32(0);
+        self.attr(inode, reply);
+    }
+
+    fn attr(&self, inode: &Inode, reply: &mut Reply) {
+        let size = match inode.data() {
+            InodeData::File(_) => inode.file_size().unwrap(),
+            InodeData::Symlink(target) => target.len() as u64,
+            _ => 0,
+        };
+        let rdev = match inode.data() {
+            InodeData::BlockDevice(device) | InodeData::CharDevice(device) => {
+                let (major, minor) = (device.major(), device.minor());
+                // As linux's new_encode_dev
+                (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)
+            }
+            _ => 0,
+        };
+        let mtime = u64::from(inode.mtime());
+        reply
+            .u64(inode.inode_number().into())
+            .u64(size)
+            .u64(size.div_ceil(512))
+            // Access, modification and change times, then their nanoseconds
+            .u64(mtime)
+            .u64(mtime)
+            .u64(mtime)
+            .u32(0)
+            .u32(0)
+            .u32(0)
+            .u32(inode.mode().to_unix())
+            .u32(inode.hard_link_count())
+            .u32(inode.uid())
+            .u32(inode.gid())
+            .u32(rdev)
+            .u32(self.archive.block_size())
+            .u32(0);
+    }
+}
+
+/// Reply with the size of `value` if `size` is 0, otherwise `value` itself if it fits
+fn sized(reply: &mut Reply, size: u32, value: &[u8]) -> Result<(), i32> {
+    if size == 0 {
+        reply.u32(value.len() as u32).u32(0);
+    } else if value.len() > size as usize {
+        return Err(libc::ERANGE);
+    } else {
+        reply.u8s(value);
+    }
+    Ok(())
+}
+
+/// The `d_type` of a directory entry of type `file_type`
+fn dirent_type(file_type: FileType) -> u32 {
+    match file_type {
+        FileType::Fifo => 1,
+        FileType::CharDev => 2,
+        FileType::Dir => 4,
+        FileType::BlockDev => 6,
+        FileType::File => 8,
+        FileType::Symlink => 10,
+        FileType::Socket => 12,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ImageBuilder;
+    use std::io::Write;
+    use std::os::unix::io::FromRawFd;
+
+    fn session() -> Session<Vec<u8>> {
+        let image = ImageBuilder::new()
+            .file("sub/hello", "hi there\n")
+            .symlink("link", "sub/hello")
+            .xattr("sub/hello", "user.greeting", "hello")
+            .build();
+        Session::new(Archive::new(image).unwrap()).unwrap()
+    }
+
+    /// A request with `opcode` for `node_id`, and `fields`
+    fn message(opcode: u32, node_id: u64, fields: &[u8]) -> Vec<u8> {
+        let mut message = Reply::default();
+        message
+            .u32((IN_HEADER_SIZE + fields.len()) as u32)
+            .u32(opcode)
+            .u64(7)
+            .u64(node_id)
+            .u8s(&[0; 16])
+            .u8s(fields);
+        message.0
+    }
+
+    /// Send a request, returning the error of its reply and its fields
+    fn call(
+        session: &Session<Vec<u8>>,
+        opcode: u32,
+        node_id: u64,
+        fields: &[u8],
+    ) -> (i32, Vec<u8>) {
+        let reply = session.handle(&message(opcode, node_id, fields)).unwrap();
+        let mut header = Request(&reply);
+        assert_eq!(header.u32().unwrap() as usize, reply.len());
+        let error = header.u32().unwrap() as i32;
+        assert_eq!(header.u64().unwrap(), 7);
+        (error, reply[16..].to_vec())
+    }
+
+    /// Look up `name` in the directory `node_id`, returning the entry's node id and inode number
+    fn lookup(session: &Session<Vec<u8>>, node_id: u64, name: &str) -> (u64, u64) {
+        let (error, entry) = call(session, LOOKUP, node_id, format!("{}\0", name).as_bytes());
+        assert_eq!(error, 0, "{}", name);
+        let mut entry = Request(&entry);
+        let node_id = entry.u64().unwrap();
+        entry.bytes(32).unwrap();
+        (node_id, entry.u64().unwrap())
+    }
+
+    #[test]
+    fn requests() {
+        let session = session();
+        let archive = &session.archive;
+        let (sub, sub_number) = lookup(&session, ROOT_ID, "sub");
+        let (hello, hello_number) = lookup(&session, sub, "hello");
+        assert_eq!(
+            hello_number,
+            u64::from(archive.lookup("sub/hello").unwrap().inode_number())
+        );
+        assert_eq!(
+            sub_number,
+            u64::from(archive.lookup("sub").unwrap().inode_number())
+        );
+        assert_eq!(lookup(&session, sub, "..").0, ROOT_ID);
+        assert_eq!(lookup(&session, ROOT_ID, "..").0, ROOT_ID);
+        assert_eq!(call(&session, LOOKUP, sub, b"missing\0").0, -libc::ENOENT);
+
+        let mut read = Reply::default();
+        read.u64(0).u64(3).u32(100).u32(0);
+        assert_eq!(
+            call(&session, READ, hello, &read.0),
+            (0, b"there\n".to_vec())
+        );
+        let (link, _) = lookup(&session, ROOT_ID, "link");
+        assert_eq!(
+            call(&session, READLINK, link, &[]),
+            (0, b"sub/hello".to_vec())
+        );
+        assert_eq!(
+            call(&session, OPEN, hello, &[1, 0, 0, 0, 0, 0, 0, 0]).0,
+            -libc::EROFS
+        );
+        assert_eq!(call(&session, OPEN, hello, &[0; 8]).0, 0);
+
+        let mut readdir = Reply::default();
+        readdir.u64(0).u64(0).u32(4096).u32(0);
+        let (error, entries) = call(&session, READDIR, sub, &readdir.0);
+        assert_eq!(error, 0);
+        let mut entries = Request(&entries);
+        let mut names = Vec::new();
+        while !entries.0.is_empty() {
+            entries.bytes(16).unwrap();
+            let len = entries.u32().unwrap() as usize;
+            entries.u32().unwrap();
+            names.push(entries.bytes(len).unwrap().to_vec());
+            entries
+                .bytes((24 + len).next_multiple_of(8) - 24 - len)
+                .unwrap();
+        }
+        assert_eq!(names, [&b"."[..], b"..", b"hello"]);
+
+        let mut getxattr = Reply::default();
+        getxattr.u32(0).u32(0).u8s(b"user.greeting\0");
+        assert_eq!(
+            call(&session, GETXATTR, hello, &getxattr.0),
+            (0, vec![5, 0, 0, 0, 0, 0, 0, 0])
+        );
+        getxattr.0[..4].copy_from_slice(&64u32.to_le_bytes());
+        assert_eq!(
+            call(&session, GETXATTR, hello, &getxattr.0),
+            (0, b"hello".to_vec())
+        );
+        let mut listxattr = Reply::default();
+        listxattr.u32(64).u32(0);
+        assert_eq!(
+            call(&session, LISTXATTR, hello, &listxattr.0),
+            (0, b"user.greeting\0".to_vec())
+        );
+
+        assert_eq!(call(&session, 1000, ROOT_ID, &[]).0, -libc::ENOSYS);
+        assert_eq!(
+            session.handle(&message(FORGET, hello, &[1, 0, 0, 0, 0, 0, 0, 0])),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn serve_socket() {
+        let (mut client, server) = {
+            let mut fds = [0; 2];
+            let result = unsafe {
+                libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, fds.as_mut_ptr())
+            };
+            assert_eq!(result, 0);
+            unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+        };
+        let archive = session().archive;
+        let server = tokio::spawn(serve(archive, server));
+
+        let mut init = Reply::default();
+        init.u32(7).u32(36).u32(1 << 20).u32(ASYNC_READ | (1 << 31));
+        client.write_all(&message(INIT, 0, &init.0)).unwrap();
+        let reply = tokio::task::spawn_blocking(move || {
+            let mut reply = vec![0; 4096];
+            let len = client.read(&mut reply).unwrap();
+            reply.truncate(len);
+            drop(client);
+            reply
+        })
+        .await
+        .unwrap();
+        let mut reply = Request(&reply);
+        assert_eq!(reply.u32().unwrap(), 16 + 64);
+        assert_eq!((reply.u32().unwrap(), reply.u64().unwrap()), (0, 7));
+        assert_eq!(
+            (reply.u32().unwrap(), reply.u32().unwrap()),
+            (7, KERNEL_MINOR_VERSION)
+        );
+        assert_eq!(
+            (reply.u32().unwrap(), reply.u32().unwrap()),
+            (MAX_READAHEAD, ASYNC_READ)
+        );
+
+        // Closing the other end ends the session, as unmounting does
+        server.await.unwrap().unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs root and FUSE"]
+    fn mount_image() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let mount_point = tempfile::tempdir().unwrap();
+        let mount = FuseMount::mount(mount_point.path()).unwrap();
+        let device = mount.device.try_clone().unwrap();
+        let server = runtime.spawn(serve(session().archive, device));
+
+        let hello = mount.mount_point().join("sub/hello");
+        assert_eq!(std::fs::read(&hello).unwrap(), b"hi there\n");
+        assert_eq!(
+            std::fs::read_link(mount.mount_point().join("link")).unwrap(),
+            Path::new("sub/hello")
+        );
+        assert!(std::fs::write(&hello, "changed").is_err());
+        mount.unmount().unwrap();
+        runtime.block_on(server).unwrap().unwrap();
+    }
+}
@@ -3,14 +3,33 @@
 use slog::Drain;
 
 mod compress_threads;
-mod compression;
+pub mod compression;
 pub mod config;
+pub mod cpio;
+pub mod digest;
+mod file_type;
+#[cfg(all(feature = "fuse3", target_os = "linux"))]
+pub mod fuse;
+pub mod metrics;
+#[cfg(target_os = "linux")]
+pub mod mount;
+#[cfg(feature = "oci")]
+pub mod oci;
 mod pool;
+pub mod prelude;
+pub mod read;
+pub mod signature;
+mod split;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod trace;
 pub mod write;
 
 pub(crate) mod errors;
 mod thread;
 
+pub use errors::{Error, Result};
+pub use file_type::FileType;
 pub use repr::Mode;
 
 fn default_logger() -> slog::Logger {
@@ -0,0 +1,244 @@
+//! Hooks for monitoring compression and caching behaviour
+//!
+//! Long running services can implement [`Metrics`] to feed their own monitoring system, or use
+//! [`Counters`], which can be [snapshotted](Counters::snapshot) at any time.
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use std::sync::Arc;
+//! use sqfs::metrics::Counters;
+//! use sqfs::read::OpenOptions;
+//!
+//! let counters = Arc::new(Counters::default());
+//! let archive = OpenOptions::new()
+//!     .metrics(counters.clone())
+//!     .open("image.sqfs")?;
+//! let root = archive.root()?;
+//! archive.read_dir(&root)?;
+//! print!("{}", counters.snapshot().to_prometheus());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Receives events about compression and caching
+///
+/// Every method has a default no-op implementation. Methods may be called concurrently from
+/// many threads, so they should be cheap.
+pub trait Metrics: Send + Sync {
+    /// A block of `uncompressed` bytes was compressed to `compressed` bytes
+    ///
+    /// Blocks which could not be made smaller are stored uncompressed, and are reported with
+    /// `compressed == uncompressed`.
+    fn block_compressed(&self, uncompressed: usize, compressed: usize) {}
+
+    /// A block of `compressed` bytes was decompressed to `uncompressed` bytes
+    fn block_decompressed(&self, compressed: usize, uncompressed: usize) {}
+
+    /// A cached value was reused
+    fn cache_hit(&self) {}
+
+    /// A value was missing from a cache, and had to be created
+    fn cache_miss(&self) {}
+
+    /// The number of blocks waiting for, or being processed by, compression threads changed
+    fn queue_depth(&self, depth: usize) {}
+}
+
+/// [`Metrics`] which ignores every event
+#[derive(Debug, Copy, Clone, Default)]
+pub struct NoMetrics;
+
+impl Metrics for NoMetrics {}
+
+/// [`Metrics`] which accumulates every event into atomic counters
+#[derive(Debug, Default)]
+pub struct Counters {
+    blocks_compressed: AtomicU64,
+    compression_input_bytes: AtomicU64,
+    compression_output_bytes: AtomicU64,
+    blocks_decompressed: AtomicU64,
+    decompression_input_bytes: AtomicU64,
+    decompression_output_bytes: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    queue_depth: AtomicU64,
+    max_queue_depth: AtomicU64,
+}
+
+impl Counters {
+    /// The current value of every counter
+    pub fn snapshot(&self) -> Snapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        Snapshot {
+            blocks_compressed: load(&self.blocks_compressed),
+            compression_input_bytes: load(&self.compression_input_bytes),
+            compression_output_bytes: load(&self.compression_output_bytes),
+            blocks_decompressed: load(&self.blocks_decompressed),
+            decompression_input_bytes: load(&self.decompression_input_bytes),
+            decompression_output_bytes: load(&self.decompression_output_bytes),
+            cache_hits: load(&self.cache_hits),
+            cache_misses: load(&self.cache_misses),
+            queue_depth: load(&self.queue_depth),
+            max_queue_depth: load(&self.max_queue_depth),
+        }
+    }
+}
+
+impl Metrics for Counters {
+    fn block_compressed(&self, uncompressed: usize, compressed: usize) {
+        self.blocks_compressed.fetch_add(1, Ordering::Relaxed);
+        self.compression_input_bytes
+            .fetch_add(uncompressed as u64, Ordering::Relaxed);
+        self.compression_output_bytes
+            .fetch_add(compressed as u64, Ordering::Relaxed);
+    }
+
+    fn block_decompressed(&self, compressed: usize, uncompressed: usize) {
+        self.blocks_decompressed.fetch_add(1, Ordering::Relaxed);
+        self.decompression_input_bytes
+            .fetch_add(compressed as u64, Ordering::Relaxed);
+        self.decompression_output_bytes
+            .fetch_add(uncompressed as u64, Ordering::Relaxed);
+    }
+
+    fn cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn queue_depth(&self, depth: usize) {
+        self.queue_depth.store(depth as u64, Ordering::Relaxed);
+        self.max_queue_depth
+            .fetch_max(depth as u64, Ordering::Relaxed);
+    }
+}
+
+/// The values of [`Counters`] at one point in time
+///
+/// Apart from the queue depth, which is a gauge, every value only ever increases.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub blocks_compressed: u64,
+    /// Total size of the blocks passed to the compressor
+    pub compression_input_bytes: u64,
+    /// Total size of the blocks produced by the compressor
+    pub compression_output_bytes: u64,
+    pub blocks_decompressed: u64,
+    /// Total size of the blocks passed to the decompressor
+    pub decompression_input_bytes: u64,
+    /// Total size of the blocks produced by the decompressor
+    pub decompression_output_bytes: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    /// The most recently reported queue depth
+    pub queue_depth: u64,
+    /// The largest queue depth reported so far
+    pub max_queue_depth: u64,
+}
+
+impl Snapshot {
+    /// The compressed size of all compressed blocks, as a fraction of their uncompressed size
+    ///
+    /// This is `1.0` if no blocks have been compressed.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.compression_input_bytes == 0 {
+            return 1.0;
+        }
+        self.compression_output_bytes as f64 / self.compression_input_bytes as f64
+    }
+
+    /// The fraction of cache lookups which were hits, `0.0` if there were none
+    pub fn cache_hit_rate(&self) -> f64 {
+        let lookups = self.cache_hits + self.cache_misses;
+        if lookups == 0 {
+            return 0.0;
+        }
+        self.cache_hits as f64 / lookups as f64
+    }
+
+    /// Format the snapshot in the Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        self.write_prometheus(&mut out)
+            .expect("writing to a string can't fail");
+        out
+    }
+
+    fn write_prometheus(&self, out: &mut String) -> fmt::Result {
+        let metrics = [
+            ("blocks_compressed_total", "counter", self.blocks_compressed),
+            (
+                "compression_input_bytes_total",
+                "counter",
+                self.compression_input_bytes,
+            ),
+            (
+                "compression_output_bytes_total",
+                "counter",
+                self.compression_output_bytes,
+            ),
+            (
+                "blocks_decompressed_total",
+                "counter",
+                self.blocks_decompressed,
+            ),
+            (
+                "decompression_input_bytes_total",
+                "counter",
+                self.decompression_input_bytes,
+            ),
+            (
+                "decompression_output_bytes_total",
+                "counter",
+                self.decompression_output_bytes,
+            ),
+            ("cache_hits_total", "counter", self.cache_hits),
+            ("cache_misses_total", "counter", self.cache_misses),
+            ("queue_depth", "gauge", self.queue_depth),
+            ("max_queue_depth", "gauge", self.max_queue_depth),
+        ];
+        for (name, kind, value) in metrics.iter() {
+            writeln!(out, "# TYPE sqfs_{} {}", name, kind)?;
+            writeln!(out, "sqfs_{} {}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters() {
+        let counters = Counters::default();
+        counters.block_compressed(100, 40);
+        counters.block_compressed(100, 60);
+        counters.block_decompressed(40, 100);
+        counters.cache_miss();
+        counters.cache_hit();
+        counters.cache_hit();
+        counters.queue_depth(3);
+        counters.queue_depth(1);
+
+        let snapshot = counters.snapshot();
+        assert_eq!(snapshot.blocks_compressed, 2);
+        assert_eq!(snapshot.compression_ratio(), 0.5);
+        assert_eq!(snapshot.blocks_decompressed, 1);
+        assert_eq!(snapshot.decompression_output_bytes, 100);
+        assert_eq!(snapshot.cache_hit_rate(), 2.0 / 3.0);
+        assert_eq!(snapshot.queue_depth, 1);
+        assert_eq!(snapshot.max_queue_depth, 3);
+
+        let text = snapshot.to_prometheus();
+        assert!(text.contains("# TYPE sqfs_blocks_compressed_total counter\n"));
+        assert!(text.contains("sqfs_blocks_compressed_total 2\n"));
+        assert!(text.contains("sqfs_max_queue_depth 3\n"));
+    }
+}
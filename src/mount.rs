@@ -0,0 +1,161 @@
+//! Mounting archives with the kernel's squashfs driver, through a loop device
+//!
+//! This is mainly useful for checking that written archives are readable by the kernel. It
+//! needs root, or `CAP_SYS_ADMIN`, and loop device support.
+//!
+//! ```no_run
+//! # fn main() -> std::io::Result<()> {
+//! use sqfs::mount::LoopMount;
+//!
+//! let mount = LoopMount::mount("image.sqfs", "/mnt/image")?;
+//! let contents = std::fs::read(mount.mount_point().join("hello"))?;
+//! // Unmounted and detached from the loop device when dropped
+//! drop(mount);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+const LOOP_CONTROL: &str = "/dev/loop-control";
+const LOOP_CTL_GET_FREE: libc::c_ulong = 0x4C82;
+const LOOP_SET_FD: libc::c_ulong = 0x4C00;
+const LOOP_CLR_FD: libc::c_ulong = 0x4C01;
+
+/// An archive mounted read-only through a loop device, which is unmounted when dropped
+#[derive(Debug)]
+pub struct LoopMount {
+    mount_point: PathBuf,
+    mounted: bool,
+    // Dropped after unmounting
+    device: LoopDevice,
+}
+
+impl LoopMount {
+    /// Attach the archive in the file at `image` to a free loop device, and mount it at
+    /// `mount_point`, which must be an existing directory
+    pub fn mount<P: AsRef<Path>, Q: AsRef<Path>>(image: P, mount_point: Q) -> io::Result<Self> {
+        Self::_mount(image.as_ref(), mount_point.as_ref())
+    }
+
+    fn _mount(image: &Path, mount_point: &Path) -> io::Result<Self> {
+        // Opening the image read-only makes the loop device read-only
+        let device = LoopDevice::attach(&File::open(image)?)?;
+        let source = c_path(&device.path)?;
+        let target = c_path(mount_point)?;
+        cvt(unsafe {
+            libc::mount(
+                source.as_ptr(),
+                target.as_ptr(),
+                b"squashfs\0".as_ptr().cast(),
+                libc::MS_RDONLY,
+                std::ptr::null(),
+            )
+        })?;
+        Ok(LoopMount {
+            mount_point: mount_point.to_path_buf(),
+            mounted: true,
+            device,
+        })
+    }
+
+    pub fn mount_point(&self) -> &Path {
+        &self.mount_point
+    }
+
+    /// The loop device the archive is attached to, e.g. `/dev/loop0`
+    pub fn device(&self) -> &Path {
+        &self.device.path
+    }
+
+    /// Unmount the archive and detach the loop device, returning any error unmounting
+    pub fn unmount(mut self) -> io::Result<()> {
+        self.unmount_inner()
+    }
+
+    fn unmount_inner(&mut self) -> io::Result<()> {
+        if self.mounted {
+            let target = c_path(&self.mount_point)?;
+            cvt(unsafe { libc::umount2(target.as_ptr(), 0) })?;
+            self.mounted = false;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LoopMount {
+    fn drop(&mut self) {
+        let _ = self.unmount_inner();
+    }
+}
+
+/// A loop device with a file attached, which is detached when dropped
+#[derive(Debug)]
+struct LoopDevice {
+    path: PathBuf,
+    file: File,
+}
+
+impl LoopDevice {
+    fn attach(image: &File) -> io::Result<Self> {
+        let control = File::open(LOOP_CONTROL)?;
+        let number = cvt(unsafe { libc::ioctl(control.as_raw_fd(), LOOP_CTL_GET_FREE) })?;
+        let path = PathBuf::from(format!("/dev/loop{}", number));
+        let file = OpenOptions::new().read(true).open(&path)?;
+        cvt(unsafe { libc::ioctl(file.as_raw_fd(), LOOP_SET_FD, image.as_raw_fd()) })?;
+        Ok(LoopDevice { path, file })
+    }
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        unsafe {
+            libc::ioctl(self.file.as_raw_fd(), LOOP_CLR_FD);
+        }
+    }
+}
+
+pub(crate) fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a nul byte"))
+}
+
+pub(crate) fn cvt(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+#[cfg(all(test, feature = "gzip"))]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    #[ignore = "needs root and loop devices"]
+    fn mount_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.sqfs");
+        let mut data = crate::testing::one_file();
+        // Loop devices are a whole number of sectors, so unpadded images get cut short
+        data.resize(data.len() + (4096 - data.len() % 4096) % 4096, 0);
+        fs::write(&image, data).unwrap();
+
+        let mount_point = tempfile::tempdir().unwrap();
+        let mount = LoopMount::mount(&image, mount_point.path()).unwrap();
+        assert!(mount.device().to_str().unwrap().starts_with("/dev/loop"));
+        assert_eq!(
+            fs::read(mount.mount_point().join("hello")).unwrap(),
+            crate::testing::HELLO_CONTENTS
+        );
+        mount.unmount().unwrap();
+        assert!(!mount_point.path().join("hello").exists());
+    }
+}
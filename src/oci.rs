@@ -0,0 +1,455 @@
+//! Importing OCI and docker container images
+//!
+//! The layers of an image are applied in order, including whiteouts, to produce a single
+//! flattened root filesystem.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use bstr::{BString, ByteSlice};
+use slog::Logger;
+
+use crate::errors::{OciError, Result};
+use crate::write::tree::{Entry, Tree};
+use crate::write::Archive;
+use crate::Mode;
+
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+const OPAQUE_WHITEOUT: &[u8] = b".wh..wh..opq";
+
+/// Import the image at `path`, and set it as the root of `archive`
+///
+/// `path` may be an OCI image layout directory, or a docker archive (as produced by
+/// `docker save`), either as a tar file or extracted into a directory. If an OCI image index
+/// lists several images, the first is used.
+pub fn import<P: AsRef<Path>, W: io::Write>(archive: &mut Archive<W>, path: P) -> Result<()> {
+    let image = Image::open(path.as_ref())?;
+    let mut rootfs = Rootfs::with_logger(archive.logger().clone());
+    for layer in image.layer_names()? {
+        slog::debug!(rootfs.logger, "Applying layer"; "layer" => %layer);
+        rootfs.apply_layer(image.open_file(&layer)?)?;
+    }
+    rootfs.finish(archive)
+}
+
+/// A root filesystem built up from image layers
+#[derive(Debug)]
+pub struct Rootfs {
+    tree: Tree,
+    logger: Logger,
+}
+
+impl Default for Rootfs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Rootfs {
+    pub fn new() -> Self {
+        Self::with_logger(crate::default_logger())
+    }
+
+    pub fn with_logger(logger: Logger) -> Self {
+        Rootfs {
+            tree: Tree::new(),
+            logger,
+        }
+    }
+
+    /// Apply a layer tarball on top of the current contents
+    ///
+    /// The layer may be uncompressed, or compressed with gzip (or zstd, if the `zstd` feature
+    /// is enabled).
+    pub fn apply_layer<R: Read>(&mut self, layer: R) -> Result<()> {
+        let mut layer = BufReader::new(layer);
+        let magic = layer.fill_buf()?;
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            self.apply_tar(flate2::read::GzDecoder::new(layer))
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            #[cfg(feature = "zstd")]
+            {
+                self.apply_tar(zstd::stream::read::Decoder::with_buffer(layer)?)
+            }
+            #[cfg(not(feature = "zstd"))]
+            {
+                Err(OciError::UnsupportedCompression("zstd").into())
+            }
+        } else {
+            self.apply_tar(layer)
+        }
+    }
+
+    fn apply_tar<R: Read>(&mut self, layer: R) -> Result<()> {
+        // Whiteouts only hide entries from lower layers, so they must be applied before any
+        // entries from this layer, wherever they appear in the tarball
+        let mut entries = Vec::new();
+        let mut whiteouts = Vec::new();
+        let mut opaque_dirs = Vec::new();
+
+        let mut archive = tar::Archive::new(layer);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = BString::from(entry.path_bytes().into_owned());
+            let (dir, name) = match path.rfind_byte(b'/') {
+                Some(i) => (&path[..i], &path[i + 1..]),
+                None => (&b""[..], &path[..]),
+            };
+            if name == OPAQUE_WHITEOUT {
+                opaque_dirs.push(BString::from(dir));
+                continue;
+            }
+            if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+                let mut hidden_path = BString::from(dir);
+                hidden_path.push(b'/');
+                hidden_path.extend_from_slice(hidden);
+                whiteouts.push(hidden_path);
+                continue;
+            }
+
+            let header = entry.header();
+            let kind = header.entry_type();
+            let link_name = entry.link_name_bytes().map(|name| name.into_owned());
+            let mut tar_entry = TarEntry {
+                path,
+                kind,
+                uid: header.uid()? as u32,
+                gid: header.gid()? as u32,
+                mode: header.mode()?,
+                mtime: header.mtime()? as u32,
+                device: (0, 0),
+                link_name,
+                contents: Vec::new(),
+            };
+            if kind.is_file() {
+                entry.read_to_end(&mut tar_entry.contents)?;
+            } else if kind.is_character_special() || kind.is_block_special() {
+                let header = entry.header();
+                tar_entry.device = (
+                    header.device_major()?.unwrap_or(0),
+                    header.device_minor()?.unwrap_or(0),
+                );
+            }
+            entries.push(tar_entry);
+        }
+
+        for path in whiteouts {
+            self.tree.remove(&path)?;
+        }
+        for dir in opaque_dirs {
+            if let Some(node) = self.tree.get_mut(&dir)? {
+                node.children.clear();
+            }
+        }
+        for entry in entries {
+            self.add_entry(entry)?;
+        }
+        Ok(())
+    }
+
+    fn add_entry(&mut self, entry: TarEntry) -> Result<()> {
+        use tar::EntryType;
+
+        let ty = match entry.kind {
+            EntryType::Regular | EntryType::Continuous => Mode::TYPE_FILE,
+            EntryType::Directory => Mode::TYPE_DIR,
+            EntryType::Symlink => Mode::TYPE_LINK,
+            EntryType::Char => Mode::TYPE_CHAR,
+            EntryType::Block => Mode::TYPE_BLOCK,
+            EntryType::Fifo => Mode::TYPE_FIFO,
+            EntryType::Link => return self.add_hard_link(entry),
+            kind => {
+                slog::warn!(self.logger, "Skipping unsupported tar entry";
+                    "path" => %entry.path, "kind" => ?kind);
+                return Ok(());
+            }
+        };
+        let contents = if ty == Mode::TYPE_LINK {
+            entry.link_name.unwrap_or_default()
+        } else {
+            entry.contents
+        };
+        self.tree.insert(
+            &entry.path,
+            Entry {
+                uid: entry.uid,
+                gid: entry.gid,
+                mode: Mode::from_unix(entry.mode).perm() | ty,
+                mtime: entry.mtime,
+                contents,
+                device: entry.device,
+                link: None,
+                provider: None,
+                xattrs: Vec::new(),
+            },
+        )
+    }
+
+    fn add_hard_link(&mut self, entry: TarEntry) -> Result<()> {
+        let target = BString::from(entry.link_name.unwrap_or_default());
+        let target_link = self
+            .tree
+            .get_mut(&target)?
+            .and_then(|node| node.entry.as_ref())
+            .filter(|target| target.mode.ty() == Mode::TYPE_FILE)
+            .map(|target| target.link);
+        let link = match target_link {
+            Some(Some(link)) => link,
+            Some(None) => {
+                let link = self.tree.new_link();
+                let target_entry = self.target_entry(&target)?;
+                let contents = mem::take(&mut target_entry.contents);
+                target_entry.link = Some(link);
+                self.tree.set_link_contents(link, contents);
+                link
+            }
+            None => return Err(OciError::MissingLinkTarget(target).into()),
+        };
+
+        let link_entry = self.target_entry(&target)?.clone();
+        self.tree.insert(&entry.path, link_entry)
+    }
+
+    fn target_entry(&mut self, target: &[u8]) -> Result<&mut Entry> {
+        let entry = self
+            .tree
+            .get_mut(target)?
+            .and_then(|node| node.entry.as_mut());
+        Ok(entry.expect("link target was already found"))
+    }
+
+    /// Add the root filesystem to `archive`, and set it as the root
+    pub fn finish<W: io::Write>(self, archive: &mut Archive<W>) -> Result<()> {
+        let root = self.tree.build(archive)?;
+        archive.set_root(root);
+        Ok(())
+    }
+}
+
+struct TarEntry {
+    path: BString,
+    kind: tar::EntryType,
+    uid: u32,
+    gid: u32,
+    mode: u32,
+    mtime: u32,
+    device: (u32, u32),
+    link_name: Option<Vec<u8>>,
+    contents: Vec<u8>,
+}
+
+/// A container image on disk
+enum Image {
+    /// An OCI image layout directory, or an extracted docker archive
+    Dir(PathBuf),
+    /// A docker archive tarball, with the location and size of each file inside it
+    Tar {
+        path: PathBuf,
+        files: HashMap<String, (u64, u64)>,
+    },
+}
+
+impl Image {
+    fn open(path: &Path) -> Result<Self> {
+        if path.is_dir() {
+            return Ok(Image::Dir(path.to_owned()));
+        }
+        let mut files = HashMap::new();
+        let mut archive = tar::Archive::new(File::open(path)?);
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let name = entry.path()?.to_string_lossy().into_owned();
+            files.insert(name, (entry.raw_file_position(), entry.size()));
+        }
+        Ok(Image::Tar {
+            path: path.to_owned(),
+            files,
+        })
+    }
+
+    fn open_file(&self, name: &str) -> Result<Box<dyn Read>> {
+        match self {
+            Image::Dir(dir) => match File::open(dir.join(name)) {
+                Ok(file) => Ok(Box::new(file)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    Err(OciError::MissingFile(name.to_owned()).into())
+                }
+                Err(e) => Err(e.into()),
+            },
+            Image::Tar { path, files } => {
+                let &(offset, size) = files
+                    .get(name)
+                    .ok_or_else(|| OciError::MissingFile(name.to_owned()))?;
+                let mut file = File::open(path)?;
+                file.seek(SeekFrom::Start(offset))?;
+                Ok(Box::new(file.take(size)))
+            }
+        }
+    }
+
+    fn has_file(&self, name: &str) -> bool {
+        match self {
+            Image::Dir(dir) => dir.join(name).is_file(),
+            Image::Tar { files, .. } => files.contains_key(name),
+        }
+    }
+
+    fn read_json(&self, name: &str) -> Result<serde_json::Value> {
+        let value = serde_json::from_reader(self.open_file(name)?)
+            .map_err(|e| OciError::InvalidJson(name.to_owned(), e))?;
+        Ok(value)
+    }
+
+    /// The names of the files containing the image's layers, from the bottom up
+    fn layer_names(&self) -> Result<Vec<String>> {
+        if self.has_file("index.json") {
+            self.oci_layers()
+        } else if self.has_file("manifest.json") {
+            self.docker_layers()
+        } else {
+            Err(OciError::MissingFile("index.json".to_owned()).into())
+        }
+    }
+
+    fn oci_layers(&self) -> Result<Vec<String>> {
+        let mut manifest = self.read_json("index.json")?;
+        // Follow (possibly nested) indexes down to the first image manifest
+        while let Some(manifests) = manifest.get("manifests") {
+            let digest = manifests
+                .get(0)
+                .and_then(|descriptor| descriptor.get("digest"))
+                .and_then(|digest| digest.as_str())
+                .ok_or(OciError::InvalidManifest("index has no manifests"))?;
+            manifest = self.read_json(&blob_path(digest)?)?;
+        }
+        let layers = manifest
+            .get("layers")
+            .and_then(|layers| layers.as_array())
+            .ok_or(OciError::InvalidManifest("manifest has no layers"))?;
+        layers
+            .iter()
+            .map(|layer| {
+                let digest = layer
+                    .get("digest")
+                    .and_then(|digest| digest.as_str())
+                    .ok_or(OciError::InvalidManifest("layer has no digest"))?;
+                blob_path(digest)
+            })
+            .collect()
+    }
+
+    fn docker_layers(&self) -> Result<Vec<String>> {
+        let manifest = self.read_json("manifest.json")?;
+        let layers = manifest
+            .get(0)
+            .and_then(|image| image.get("Layers"))
+            .and_then(|layers| layers.as_array())
+            .ok_or(OciError::InvalidManifest("manifest has no layers"))?;
+        layers
+            .iter()
+            .map(|layer| {
+                layer
+                    .as_str()
+                    .map(str::to_owned)
+                    .ok_or_else(|| OciError::InvalidManifest("layer is not a path").into())
+            })
+            .collect()
+    }
+}
+
+/// The path of a blob in an OCI image layout, from its digest
+fn blob_path(digest: &str) -> Result<String> {
+    let (algorithm, hex) = digest
+        .split_once(':')
+        .filter(|(algorithm, hex)| {
+            !algorithm.is_empty()
+                && !hex.is_empty()
+                && (algorithm.chars().chain(hex.chars())).all(|c| c.is_ascii_alphanumeric())
+        })
+        .ok_or(OciError::InvalidManifest("invalid digest"))?;
+    Ok(format!("blobs/{}/{}", algorithm, hex))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(entries: &[(&str, tar::EntryType, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for &(path, kind, contents) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(kind);
+            header.set_mode(0o755);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            if kind == tar::EntryType::Link || kind == tar::EntryType::Symlink {
+                header
+                    .set_link_name(std::str::from_utf8(contents).unwrap())
+                    .unwrap();
+                header.set_size(0);
+                builder.append_data(&mut header, path, &[][..]).unwrap();
+            } else {
+                header.set_size(contents.len() as u64);
+                builder.append_data(&mut header, path, contents).unwrap();
+            }
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn names(rootfs: &mut Rootfs, path: &str) -> Vec<BString> {
+        let node = rootfs.tree.get_mut(path.as_bytes()).unwrap().unwrap();
+        node.children.keys().cloned().collect()
+    }
+
+    #[test]
+    fn whiteouts() {
+        use tar::EntryType::*;
+
+        let mut rootfs = Rootfs::new();
+        rootfs
+            .apply_layer(
+                &layer(&[
+                    ("etc", Directory, b""),
+                    ("etc/hosts", Regular, b"localhost"),
+                    ("etc/passwd", Regular, b"root"),
+                    ("var", Directory, b""),
+                    ("var/cache", Regular, b""),
+                    ("bin", Directory, b""),
+                    ("bin/sh", Regular, b"#!"),
+                ])[..],
+            )
+            .unwrap();
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        io::Write::write_all(
+            &mut gz,
+            &layer(&[
+                ("etc/.wh.hosts", Regular, b""),
+                ("var/new", Regular, b""),
+                ("var/.wh..wh..opq", Regular, b""),
+                ("bin/bash", Link, b"bin/sh"),
+            ]),
+        )
+        .unwrap();
+        rootfs.apply_layer(&gz.finish().unwrap()[..]).unwrap();
+
+        assert_eq!(names(&mut rootfs, "etc"), ["passwd"]);
+        assert_eq!(names(&mut rootfs, "var"), ["new"]);
+        let sh = rootfs.tree.get_mut(b"bin/sh").unwrap().unwrap();
+        let link = sh.entry.as_ref().unwrap().link;
+        assert!(link.is_some());
+        let bash = rootfs.tree.get_mut(b"bin/bash").unwrap().unwrap();
+        assert_eq!(bash.entry.as_ref().unwrap().link, link);
+    }
+
+    #[test]
+    fn blob_paths() {
+        assert_eq!(blob_path("sha256:abcd").unwrap(), "blobs/sha256/abcd");
+        blob_path("sha256:../../etc/passwd").unwrap_err();
+        blob_path("abcd").unwrap_err();
+    }
+}
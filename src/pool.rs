@@ -1,7 +1,8 @@
-use once_cell::sync::OnceCell;
+use crate::config::MemoryBudget;
 use parking_lot::Mutex;
 use std::mem::ManuallyDrop;
 use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
 use std::{fmt, mem};
 
 pub trait Recyclable {
@@ -19,10 +20,16 @@ impl Recyclable for Vec<u8> {
     }
 }
 
+/// Recycled values, to avoid reallocating buffers
+///
+/// Each archive owns its own pools, so archives with different block sizes don't swap buffers
+/// of the wrong capacity. Handles keep their pool alive, so they can outlive the archive.
 pub struct Pool<T> {
     items: Mutex<Vec<T>>,
 }
 
+pub type BlockPool = Pool<Vec<u8>>;
+
 impl<T: Recyclable> Pool<T> {
     pub fn new(size: usize, capacity: usize) -> Self {
         let mut items = Vec::with_capacity(capacity);
@@ -32,21 +39,26 @@ impl<T: Recyclable> Pool<T> {
         }
     }
 
+    /// A pool sized by the `pooled_buffers` and `pool_capacity` of `memory`
+    pub fn with_budget(memory: &MemoryBudget) -> Self {
+        Self::new(memory.pooled_buffers, memory.pool_capacity)
+    }
+
     pub fn detached(&self) -> T {
         self.items.lock().pop().unwrap_or_else(T::new)
     }
 
-    pub fn get(&self) -> Handle<'_, T> {
+    pub fn get(self: &Arc<Self>) -> Handle<T> {
         Handle {
             value: ManuallyDrop::new(self.detached()),
-            pool: self,
+            pool: Arc::clone(self),
         }
     }
 
-    pub fn attach(&self, item: T) -> Handle<'_, T> {
+    pub fn attach(self: &Arc<Self>, item: T) -> Handle<T> {
         Handle {
             value: ManuallyDrop::new(item),
-            pool: self,
+            pool: Arc::clone(self),
         }
     }
 
@@ -59,20 +71,22 @@ impl<T: Recyclable> Pool<T> {
     }
 }
 
-pub struct Handle<'a, T: Recyclable> {
+pub struct Handle<T: Recyclable> {
     value: ManuallyDrop<T>,
-    pool: &'a Pool<T>,
+    pool: Arc<Pool<T>>,
 }
 
-impl<T: Recyclable> Handle<'_, T> {
+impl<T: Recyclable> Handle<T> {
     pub fn detach(mut self) -> T {
         let value = unsafe { ManuallyDrop::take(&mut self.value) };
+        // Release the pool without running Drop, which would return the value
+        unsafe { std::ptr::drop_in_place(&mut self.pool) };
         mem::forget(self);
         value
     }
 }
 
-impl<T: Recyclable> Deref for Handle<'_, T> {
+impl<T: Recyclable> Deref for Handle<T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
@@ -80,38 +94,56 @@ impl<T: Recyclable> Deref for Handle<'_, T> {
     }
 }
 
-impl<T: Recyclable> DerefMut for Handle<'_, T> {
+impl<T: Recyclable> DerefMut for Handle<T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.value
     }
 }
 
-impl<T: fmt::Debug + Recyclable> fmt::Debug for Handle<'_, T> {
+impl<T> fmt::Debug for Pool<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pool")
+            .field("idle", &self.items.lock().len())
+            .finish()
+    }
+}
+
+impl<T: fmt::Debug + Recyclable> fmt::Debug for Handle<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         self.value.fmt(f)
     }
 }
 
-impl<T: Recyclable> Drop for Handle<'_, T> {
+impl<T: Recyclable> Drop for Handle<T> {
     fn drop(&mut self) {
         let item = unsafe { ManuallyDrop::take(&mut self.value) };
         self.pool.return_item(item);
     }
 }
 
-pub type Block<'a> = Handle<'a, Vec<u8>>;
-
-fn blocks() -> &'static Pool<Vec<u8>> {
-    static INSTANCE: OnceCell<Pool<Vec<u8>>> = OnceCell::new();
-
-    let threads = num_cpus::get();
-    INSTANCE.get_or_init(|| Pool::new(threads, threads * 2))
-}
-
-pub fn block() -> Handle<'static, Vec<u8>> {
-    blocks().get()
-}
-
-pub fn attach_block(block: Vec<u8>) -> Handle<'static, Vec<u8>> {
-    blocks().attach(block)
+pub type Block = Handle<Vec<u8>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn separate_pools() {
+        let small = Arc::new(BlockPool::new(0, 1));
+        let large = Arc::new(BlockPool::new(0, 1));
+
+        let mut block = small.get();
+        block.resize(10, 0);
+        drop(block);
+        let mut block = large.get();
+        block.resize(1000, 0);
+        drop(block);
+
+        assert!(small.get().capacity() < 1000);
+        assert!(large.get().capacity() >= 1000);
+        // Detached values are not returned
+        let detached = small.get().detach();
+        assert_eq!(small.get().capacity(), 0);
+        drop(detached);
+    }
 }
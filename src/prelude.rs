@@ -0,0 +1,35 @@
+//! The most commonly used types, for glob importing
+//!
+//! The reading and writing archive types are renamed to [`ReadArchive`] and [`WriteArchive`]
+//! so both can be imported at once, and the types from the `repr` crate needed to use sqfs are
+//! re-exported, so it doesn't need to be a direct dependency.
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use sqfs::prelude::*;
+//!
+//! let archive = ReadArchive::open("image.sqfs")?;
+//! for entry in archive.walk() {
+//!     let entry = entry?;
+//!     println!("{} {}", entry.inode().mode(), entry.path());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub use crate::compression::Kind as CompressionKind;
+pub use crate::config::{
+    ConflictPolicy, CpuAffinity, DeviceNumberPolicy, FragmentMode, LimitPolicy, Limits,
+    LoggingConfig, MemoryBudget, SpecialFilePolicy, SymlinkRewrite, SyncPolicy,
+    UnsupportedEntryPolicy,
+};
+pub use crate::read::{
+    Archive as ReadArchive, ArchiveInfo, DirEntry, Inode, InodeData, OpenOptions, ReadAt, WalkEntry,
+};
+pub use crate::write::{
+    Archive as WriteArchive, ArchiveBuilder, DeviceBuilder, DirBuilder, FileBuilder, IpcBuilder,
+    ItemRef, SymlinkBuilder,
+};
+pub use crate::{Error, FileType, Mode};
+pub use repr::inode::{Kind as InodeKind, Ref as InodeRef};
+pub use repr::superblock::{Flags as SuperblockFlags, Superblock};
@@ -0,0 +1,421 @@
+//! Mapping byte offsets of a file to the blocks storing them
+
+use std::convert::TryFrom;
+
+use repr::datablock::Size;
+
+use super::inode::FileInfo;
+use super::{Archive, Inode, ReadAt};
+use crate::errors::Result;
+
+/// Where each byte of a regular file is stored, see [`Archive::block_map`]
+///
+/// A file is stored as a list of data blocks, each covering `block_size` bytes of the file,
+/// except perhaps the last one. Sparse blocks aren't stored at all, and read as zeros. The end
+/// of a file may be stored in a fragment block instead, shared with other small files.
+#[derive(Debug, Clone)]
+pub struct BlockMap<'a> {
+    file: &'a FileInfo,
+    block_size: u32,
+    /// The offset of each data block in the source
+    offsets: Vec<u64>,
+}
+
+/// A range of a file stored in one place, see [`BlockMap`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Extent {
+    /// The offset in the file where the extent starts
+    pub file_offset: u64,
+    /// The number of bytes of the file in the extent
+    pub len: u64,
+    pub kind: ExtentKind,
+}
+
+/// Where the bytes of an [`Extent`] are stored
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExtentKind {
+    /// The data block `index` of the file, at `offset` in the source, which decompresses to the
+    /// bytes of the extent
+    Block {
+        index: usize,
+        offset: u64,
+        size: Size,
+    },
+    /// The sparse data block `index`, which reads as zeros
+    Hole { index: usize },
+    /// Starting at `offset` in the decompressed fragment block `fragment`
+    Fragment { fragment: u32, offset: u32 },
+}
+
+impl<'a> BlockMap<'a> {
+    pub(crate) fn new(file: &'a FileInfo, block_size: u32) -> Self {
+        let mut offset = file.blocks_start;
+        let offsets = file
+            .block_sizes
+            .iter()
+            .map(|size| {
+                let start = offset;
+                offset += u64::from(size.size());
+                start
+            })
+            .collect();
+        BlockMap {
+            file,
+            block_size,
+            offsets,
+        }
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.file.file_size
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    /// The number of data blocks, including sparse ones, but not the fragment
+    pub fn block_count(&self) -> usize {
+        self.file.block_sizes.len()
+    }
+
+    /// The extent holding the byte at `pos`, `None` past the end of the file
+    ///
+    /// The byte is `pos - extent.file_offset` bytes into the extent.
+    pub fn locate(&self, pos: u64) -> Option<Extent> {
+        if pos >= self.file_size() {
+            return None;
+        }
+        let index = pos / u64::from(self.block_size);
+        match usize::try_from(index) {
+            Ok(index) if index < self.block_count() => Some(self.block_extent(index)),
+            _ => self.fragment_extent(),
+        }
+    }
+
+    /// The data blocks of the file as stored, in order, including sparse blocks but not the
+    /// fragment
+    ///
+    /// The tail of a file in a fragment block shares the block with other files, so it can't
+    /// be copied on its own, and has to be read through [`extents`](Self::extents).
+    pub fn raw_blocks(&self) -> RawBlocks<'a> {
+        RawBlocks::new(self.file, self.block_size)
+    }
+
+    /// The extents of the file, in order
+    pub fn extents(&self) -> Extents<'_, 'a> {
+        Extents {
+            map: self,
+            next_block: 0,
+            fragment_done: false,
+        }
+    }
+
+    fn block_extent(&self, index: usize) -> Extent {
+        let block_size = u64::from(self.block_size);
+        let file_offset = index as u64 * block_size;
+        let len = block_size.min(self.file_size().saturating_sub(file_offset));
+        let size = self.file.block_sizes[index];
+        let kind = if size.size() == 0 {
+            ExtentKind::Hole { index }
+        } else {
+            ExtentKind::Block {
+                index,
+                offset: self.offsets[index],
+                size,
+            }
+        };
+        Extent {
+            file_offset,
+            len,
+            kind,
+        }
+    }
+
+    /// The extent of the fragment, which holds everything after the data blocks
+    fn fragment_extent(&self) -> Option<Extent> {
+        let fragment = self.file.fragment?;
+        let file_offset = self.block_count() as u64 * u64::from(self.block_size);
+        if file_offset >= self.file_size() {
+            return None;
+        }
+        Some(Extent {
+            file_offset,
+            len: self.file_size() - file_offset,
+            kind: ExtentKind::Fragment {
+                fragment: fragment.index,
+                offset: fragment.offset,
+            },
+        })
+    }
+}
+
+/// An iterator over the extents of a file, see [`BlockMap::extents`]
+#[derive(Debug, Clone)]
+pub struct Extents<'m, 'a> {
+    map: &'m BlockMap<'a>,
+    next_block: usize,
+    fragment_done: bool,
+}
+
+impl Iterator for Extents<'_, '_> {
+    type Item = Extent;
+
+    fn next(&mut self) -> Option<Extent> {
+        if self.next_block < self.map.block_count() {
+            let extent = self.map.block_extent(self.next_block);
+            self.next_block += 1;
+            return Some(extent);
+        }
+        if self.fragment_done {
+            return None;
+        }
+        self.fragment_done = true;
+        self.map.fragment_extent()
+    }
+}
+
+/// A data block of a file as stored, see [`BlockMap::raw_blocks`]
+///
+/// Blocks can be copied to another archive without decompressing them, as long as it uses the
+/// same codec and block size.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RawBlock {
+    /// The index of the block in the file
+    pub index: usize,
+    /// The offset of the block in the source
+    pub offset: u64,
+    /// The number of bytes the block takes in the source, 0 for a sparse block
+    pub stored_size: u32,
+    /// Whether the block is compressed, rather than stored as is
+    pub compressed: bool,
+    /// The number of bytes of the file in the block, once decompressed
+    pub uncompressed_size: u32,
+}
+
+impl RawBlock {
+    pub fn is_sparse(&self) -> bool {
+        self.stored_size == 0
+    }
+
+    /// The size of the block, as recorded in an inode's block list
+    pub fn size(&self) -> Size {
+        if self.is_sparse() {
+            return Size::ZERO;
+        }
+        Size::new(self.stored_size, !self.compressed)
+    }
+}
+
+/// An iterator over the data blocks of a file as stored, see [`BlockMap::raw_blocks`]
+#[derive(Debug, Clone)]
+pub struct RawBlocks<'a> {
+    file: &'a FileInfo,
+    block_size: u32,
+    index: usize,
+    offset: u64,
+}
+
+impl<'a> RawBlocks<'a> {
+    pub(crate) fn new(file: &'a FileInfo, block_size: u32) -> Self {
+        RawBlocks {
+            file,
+            block_size,
+            index: 0,
+            offset: file.blocks_start,
+        }
+    }
+}
+
+impl Iterator for RawBlocks<'_> {
+    type Item = RawBlock;
+
+    fn next(&mut self) -> Option<RawBlock> {
+        let size = *self.file.block_sizes.get(self.index)?;
+        let block_size = u64::from(self.block_size);
+        let file_offset = self.index as u64 * block_size;
+        let uncompressed_size = block_size.min(self.file.file_size.saturating_sub(file_offset));
+        let block = RawBlock {
+            index: self.index,
+            offset: self.offset,
+            stored_size: size.size(),
+            compressed: size.size() != 0 && !size.uncompressed(),
+            uncompressed_size: uncompressed_size as u32,
+        };
+        self.index += 1;
+        self.offset += u64::from(size.size());
+        Some(block)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.file.block_sizes.len() - self.index;
+        (remaining, Some(remaining))
+    }
+}
+
+impl ExactSizeIterator for RawBlocks<'_> {}
+
+impl FileInfo {
+    /// Map the bytes of the file to the blocks storing them, for an archive with blocks of
+    /// `block_size` bytes
+    pub fn block_map(&self, block_size: u32) -> BlockMap<'_> {
+        BlockMap::new(self, block_size)
+    }
+}
+
+impl<R> Archive<R> {
+    /// Map the bytes of the regular file `file` to the blocks storing them
+    ///
+    /// ```no_run
+    /// use sqfs::read::{Archive, ExtentKind};
+    ///
+    /// # fn main() -> sqfs::Result<()> {
+    /// let archive = Archive::open("image.sqfs")?;
+    /// let file = archive.root()?; // Any regular file
+    /// let map = archive.block_map(&file)?;
+    /// for extent in map.extents() {
+    ///     if let ExtentKind::Hole { .. } = extent.kind {
+    ///         println!("{} bytes of zeros at {}", extent.len, extent.file_offset);
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn block_map<'a>(&self, file: &'a Inode) -> Result<BlockMap<'a>> {
+        Ok(file.as_file()?.block_map(self.block_size()))
+    }
+
+    /// Read the stored bytes of a data block, without decompressing them
+    ///
+    /// A sparse block has no stored bytes.
+    pub fn read_raw_block(&self, block: &RawBlock) -> Result<Vec<u8>>
+    where
+        R: ReadAt,
+    {
+        let mut data = vec![0; block.stored_size as usize];
+        self.inner.source.read_exact_at(&mut data, block.offset)?;
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read::inode::Fragment;
+
+    fn file(block_sizes: Vec<Size>, file_size: u64, fragment: bool) -> FileInfo {
+        FileInfo {
+            blocks_start: 96,
+            file_size,
+            sparse: 0,
+            fragment: if fragment {
+                Some(Fragment {
+                    index: 2,
+                    offset: 10,
+                })
+            } else {
+                None
+            },
+            block_sizes,
+        }
+    }
+
+    #[test]
+    fn extents() {
+        let data = Size::new(10, false);
+        let file = file(vec![data, Size::ZERO, data], 3 * 4096 + 100, true);
+        let map = file.block_map(4096);
+        let extents: Vec<_> = map.extents().collect();
+        assert_eq!(
+            extents,
+            [
+                Extent {
+                    file_offset: 0,
+                    len: 4096,
+                    kind: ExtentKind::Block {
+                        index: 0,
+                        offset: 96,
+                        size: data
+                    }
+                },
+                Extent {
+                    file_offset: 4096,
+                    len: 4096,
+                    kind: ExtentKind::Hole { index: 1 }
+                },
+                Extent {
+                    file_offset: 2 * 4096,
+                    len: 4096,
+                    kind: ExtentKind::Block {
+                        index: 2,
+                        offset: 106,
+                        size: data
+                    }
+                },
+                Extent {
+                    file_offset: 3 * 4096,
+                    len: 100,
+                    kind: ExtentKind::Fragment {
+                        fragment: 2,
+                        offset: 10
+                    }
+                },
+            ]
+        );
+        assert_eq!(map.locate(0), Some(extents[0]));
+        assert_eq!(map.locate(4096 + 5), Some(extents[1]));
+        assert_eq!(map.locate(3 * 4096 + 99), Some(extents[3]));
+        assert_eq!(map.locate(3 * 4096 + 100), None);
+    }
+
+    #[test]
+    fn partial_last_block() {
+        let data = Size::new(10, false);
+        let file = file(vec![data, data], 4096 + 5, false);
+        let map = file.block_map(4096);
+        let lens: Vec<_> = map.extents().map(|extent| extent.len).collect();
+        assert_eq!(lens, [4096, 5]);
+        assert_eq!(map.locate(4100).unwrap().file_offset, 4096);
+        assert_eq!(map.locate(4096 + 5), None);
+    }
+
+    #[test]
+    fn raw_blocks() {
+        let compressed = Size::new(10, false);
+        let stored = Size::new(4096, true);
+        let file = file(vec![compressed, Size::ZERO, stored], 2 * 4096 + 50, false);
+        let blocks: Vec<_> = file.block_map(4096).raw_blocks().collect();
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(
+            blocks[0],
+            RawBlock {
+                index: 0,
+                offset: 96,
+                stored_size: 10,
+                compressed: true,
+                uncompressed_size: 4096,
+            }
+        );
+        assert!(blocks[1].is_sparse() && !blocks[1].compressed);
+        assert_eq!(blocks[1].size(), Size::ZERO);
+        assert_eq!((blocks[2].offset, blocks[2].uncompressed_size), (106, 50));
+        assert_eq!(blocks[2].size(), stored);
+    }
+
+    #[test]
+    fn read_raw() {
+        let archive = Archive::new(crate::testing::one_file()).unwrap();
+        let file = archive.walk().nth(1).unwrap().unwrap();
+        let map = archive.block_map(file.inode()).unwrap();
+        let contents = archive.read_file(file.inode()).unwrap();
+        let mut copied = Vec::new();
+        for block in map.raw_blocks() {
+            let data = archive.read_raw_block(&block).unwrap();
+            assert_eq!(data.len(), block.stored_size as usize);
+            if !block.compressed {
+                copied.extend_from_slice(&data);
+            }
+        }
+        assert_eq!(copied, contents);
+    }
+}
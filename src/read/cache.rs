@@ -0,0 +1,134 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+
+/// A decompressed metablock, and the size of the block on disk
+pub(crate) type Cached = (Arc<[u8]>, u64);
+
+/// Decompressed metablocks, by their absolute position
+///
+/// Once the cached data exceeds the size limit, the oldest blocks are evicted first. Pinned
+/// blocks don't count towards the limit, and are never evicted. Lookups only take a read lock
+/// and share the cached data, so threads reading at once don't wait on each other.
+pub(crate) struct MetablockCache {
+    limit: usize,
+    has_pinned: AtomicBool,
+    inner: RwLock<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    blocks: HashMap<u64, Cached>,
+    /// Cached block positions, oldest first
+    order: VecDeque<u64>,
+    bytes: usize,
+    pinned: HashMap<u64, Cached>,
+    pinned_bytes: usize,
+}
+
+impl MetablockCache {
+    pub fn new(limit: usize) -> Self {
+        MetablockCache {
+            limit,
+            has_pinned: AtomicBool::new(false),
+            inner: RwLock::new(Inner::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.limit > 0 || self.has_pinned.load(Ordering::Relaxed)
+    }
+
+    pub fn get(&self, offset: u64) -> Option<Cached> {
+        let inner = self.inner.read();
+        inner
+            .pinned
+            .get(&offset)
+            .or_else(|| inner.blocks.get(&offset))
+            .cloned()
+    }
+
+    pub fn get_pinned(&self, offset: u64) -> Option<Cached> {
+        self.inner.read().pinned.get(&offset).cloned()
+    }
+
+    /// Keep a block for as long as the cache exists
+    pub fn pin(&self, offset: u64, data: Arc<[u8]>, size_on_disk: u64) {
+        let mut inner = self.inner.write();
+        if inner.pinned.contains_key(&offset) {
+            return;
+        }
+        // The stale entry in `order` is skipped when it comes up for eviction
+        if let Some((cached, _)) = inner.blocks.remove(&offset) {
+            inner.bytes -= cached.len();
+        }
+        inner.pinned_bytes += data.len();
+        inner.pinned.insert(offset, (data, size_on_disk));
+        self.has_pinned.store(true, Ordering::Relaxed);
+    }
+
+    /// The total size of the pinned blocks
+    pub fn pinned_bytes(&self) -> usize {
+        self.inner.read().pinned_bytes
+    }
+
+    pub fn insert(&self, offset: u64, data: Arc<[u8]>, size_on_disk: u64) {
+        if data.len() > self.limit {
+            return;
+        }
+        let mut inner = self.inner.write();
+        if inner.blocks.contains_key(&offset) || inner.pinned.contains_key(&offset) {
+            return;
+        }
+        while inner.bytes + data.len() > self.limit {
+            let oldest = match inner.order.pop_front() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            if let Some((evicted, _)) = inner.blocks.remove(&oldest) {
+                inner.bytes -= evicted.len();
+            }
+        }
+        inner.bytes += data.len();
+        inner.order.push_back(offset);
+        inner.blocks.insert(offset, (data, size_on_disk));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_oldest() {
+        let cache = MetablockCache::new(10);
+        cache.insert(0, Arc::new([0; 4]), 6);
+        cache.insert(100, Arc::new([1; 4]), 6);
+        assert_eq!(cache.get(0), Some((Arc::from(&[0; 4][..]), 6)));
+
+        cache.insert(200, Arc::new([2; 4]), 6);
+        assert_eq!(cache.get(0), None);
+        assert_eq!(cache.get(100), Some((Arc::from(&[1; 4][..]), 6)));
+        assert_eq!(cache.get(200), Some((Arc::from(&[2; 4][..]), 6)));
+        // Hits share the cached data rather than copying it
+        let (first, _) = cache.get(200).unwrap();
+        assert!(Arc::ptr_eq(&first, &cache.get(200).unwrap().0));
+
+        cache.insert(300, Arc::new([3; 11]), 13);
+        assert_eq!(cache.get(300), None);
+    }
+
+    #[test]
+    fn pinned_blocks() {
+        let cache = MetablockCache::new(0);
+        assert!(!cache.is_enabled());
+        cache.pin(0, Arc::new([0; 4]), 6);
+        assert!(cache.is_enabled());
+        cache.insert(100, Arc::new([1; 4]), 6);
+        assert_eq!(cache.get(0), Some((Arc::from(&[0; 4][..]), 6)));
+        assert_eq!(cache.get(100), None);
+        assert_eq!(cache.pinned_bytes(), 4);
+    }
+}
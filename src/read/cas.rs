@@ -0,0 +1,244 @@
+//! Storing many similar images as their unique blocks
+//!
+//! Images built from similar trees, such as successive firmware releases, share most of their
+//! data blocks. [`Archive::export_cas`] splits an image into its data and fragment blocks and
+//! the metadata between them, stores each piece in a content addressable store, a directory
+//! of files named by the hash of their contents, and returns a [`Recipe`] listing the pieces in
+//! order. Pieces already in the store aren't written again, so the store grows by only what's
+//! new in each image. [`Recipe::assemble`] concatenates the pieces to restore the original
+//! image byte for byte.
+//!
+//! Pieces are named by their 128 bit xxh3 hash, which is checked when they are assembled, but
+//! isn't a cryptographic hash: a store shouldn't be shared with anyone who could benefit from
+//! forging a collision.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use xxhash_rust::xxh3::xxh3_128;
+
+use super::{Archive, InodeData, ReadAt};
+use crate::errors::{CasError, Result};
+
+/// The first line of a recipe file
+const RECIPE_HEADER: &str = "sqfs-recipe 1";
+
+/// The pieces of an image in a content addressable store, see [`Archive::export_cas`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recipe {
+    pub segments: Vec<Segment>,
+}
+
+/// A piece of an image, stored in a file named by its hash
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub hash: u128,
+    pub len: u64,
+}
+
+impl Segment {
+    /// The path of the piece in `store`, which is spread over 256 subdirectories
+    pub fn path(&self, store: &Path) -> PathBuf {
+        let name = format!("{:032x}", self.hash);
+        store.join(&name[..2]).join(name)
+    }
+}
+
+impl Recipe {
+    /// The size of the image assembled from the recipe
+    pub fn image_size(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.len).sum()
+    }
+
+    /// Write the recipe as text, a header line and then a line of hash and length per segment
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(out, "{}", RECIPE_HEADER)?;
+        for segment in &self.segments {
+            writeln!(out, "{:032x} {}", segment.hash, segment.len)?;
+        }
+        out.flush()
+    }
+
+    /// Read a recipe written by [`write`](Self::write)
+    pub fn read<R: BufRead>(input: R) -> Result<Self> {
+        let mut lines = input.lines();
+        match lines.next().transpose()? {
+            Some(header) if header == RECIPE_HEADER => {}
+            _ => return Err(CasError::InvalidRecipe { line: 1 }.into()),
+        }
+        let mut segments = Vec::new();
+        for (i, line) in lines.enumerate() {
+            let line = line?;
+            let segment = line.split_once(' ').and_then(|(hash, len)| {
+                Some(Segment {
+                    hash: u128::from_str_radix(hash, 16).ok()?,
+                    len: len.parse().ok()?,
+                })
+            });
+            match segment {
+                Some(segment) => segments.push(segment),
+                None => return Err(CasError::InvalidRecipe { line: i + 2 }.into()),
+            }
+        }
+        Ok(Recipe { segments })
+    }
+
+    /// Write the image described by the recipe to `out`, from the pieces in `store`
+    ///
+    /// Each piece is checked against its hash and length before it's written. Returns the size
+    /// of the image.
+    pub fn assemble<W: Write + ?Sized>(&self, store: &Path, out: &mut W) -> Result<u64> {
+        let mut written = 0;
+        for segment in &self.segments {
+            let data = fs::read(segment.path(store))?;
+            if data.len() as u64 != segment.len || xxh3_128(&data) != segment.hash {
+                return Err(CasError::CorruptSegment { hash: segment.hash }.into());
+            }
+            out.write_all(&data)?;
+            written += segment.len;
+        }
+        Ok(written)
+    }
+}
+
+impl<R: ReadAt> Archive<R> {
+    /// Store the pieces of the image in the content addressable store `store`, returning the
+    /// recipe to assemble it again
+    ///
+    /// Each data and fragment block is a piece, as is each run of bytes between them, so
+    /// blocks shared between images are stored once. The padding after the image is included,
+    /// so the assembled image is identical to the source.
+    ///
+    /// ```no_run
+    /// # fn main() -> sqfs::Result<()> {
+    /// use std::path::Path;
+    ///
+    /// let archive = sqfs::read::Archive::open("image.sqfs")?;
+    /// let recipe = archive.export_cas(Path::new("store"))?;
+    /// recipe.write(std::fs::File::create("image.recipe")?)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn export_cas(&self, store: &Path) -> Result<Recipe> {
+        let inner = &*self.inner;
+        // The size of every block, by offset. Files with the same contents share blocks
+        let mut blocks = BTreeMap::new();
+        for entry in self.walk() {
+            let entry = entry?;
+            if let InodeData::File(file) = entry.inode().data() {
+                let mut offset = file.blocks_start;
+                for size in &file.block_sizes {
+                    let len = u64::from(size.size());
+                    if len != 0 {
+                        blocks.insert(offset, len);
+                    }
+                    offset += len;
+                }
+            }
+        }
+        for fragment in &inner.fragments {
+            blocks.insert(fragment.start.0, u64::from(fragment.size.size()));
+        }
+
+        let end = self.file_size().unwrap_or_else(|| self.bytes_used());
+        let mut recipe = Recipe {
+            segments: Vec::new(),
+        };
+        let mut pos = 0;
+        for (offset, len) in blocks {
+            // Blocks overlapping the last are left in the bytes between blocks
+            if offset < pos || offset + len > end {
+                continue;
+            }
+            if offset > pos {
+                recipe
+                    .segments
+                    .push(self.store_segment(store, pos, offset - pos)?);
+            }
+            recipe
+                .segments
+                .push(self.store_segment(store, offset, len)?);
+            pos = offset + len;
+        }
+        if end > pos {
+            recipe
+                .segments
+                .push(self.store_segment(store, pos, end - pos)?);
+        }
+        Ok(recipe)
+    }
+
+    /// Store `len` bytes of the source at `offset` in `store`, unless they're already there
+    fn store_segment(&self, store: &Path, offset: u64, len: u64) -> Result<Segment> {
+        let mut data = vec![0; len as usize];
+        self.inner.source.read_exact_at(&mut data, offset)?;
+        let segment = Segment {
+            hash: xxh3_128(&data),
+            len,
+        };
+        let path = segment.path(store);
+        if !path.exists() {
+            let dir = path.parent().unwrap();
+            fs::create_dir_all(dir)?;
+            // Written to a temporary file first, so a piece is never seen half written
+            let mut file = tempfile::NamedTempFile::new_in(dir)?;
+            file.write_all(&data)?;
+            file.persist(&path).map_err(|e| e.error)?;
+        }
+        Ok(segment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ImageBuilder;
+
+    #[test]
+    fn round_trip() {
+        let store = tempfile::tempdir().unwrap();
+        let contents = crate::testing::compressible_contents();
+        let first = ImageBuilder::new()
+            .file("a", contents.clone())
+            .file("b", "small")
+            .build();
+        let second = ImageBuilder::new()
+            .file("a", contents)
+            .file("c", "other")
+            .build();
+
+        let mut recipes = Vec::new();
+        for image in [&first, &second] {
+            let archive = Archive::new(image.clone()).unwrap();
+            let recipe = archive.export_cas(store.path()).unwrap();
+            assert_eq!(recipe.image_size(), image.len() as u64);
+
+            let mut text = Vec::new();
+            recipe.write(&mut text).unwrap();
+            let recipe = Recipe::read(&text[..]).unwrap();
+            let mut assembled = Vec::new();
+            recipe.assemble(store.path(), &mut assembled).unwrap();
+            assert_eq!(&assembled, image);
+            recipes.push(recipe);
+        }
+        // The blocks of `a` are stored once
+        let shared = recipes[1]
+            .segments
+            .iter()
+            .filter(|segment| recipes[0].segments.contains(segment))
+            .count();
+        assert!(shared > 0);
+    }
+
+    #[test]
+    fn corrupt_segment() {
+        let store = tempfile::tempdir().unwrap();
+        let archive = Archive::new(crate::testing::one_file()).unwrap();
+        let recipe = archive.export_cas(store.path()).unwrap();
+        fs::write(recipe.segments[0].path(store.path()), b"garbage").unwrap();
+        assert!(recipe.assemble(store.path(), &mut Vec::new()).is_err());
+        assert!(Recipe::read(&b"sqfs-recipe 1\nnot a segment\n"[..]).is_err());
+    }
+}
@@ -0,0 +1,121 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use super::ReadAt;
+use crate::split::part_path;
+
+/// A source made of several consecutive parts, read as if they were one
+///
+/// This reads archives split with [`write::SplitFile`](crate::write::SplitFile), or by tools
+/// like `split`.
+#[derive(Debug)]
+pub struct Chain<R> {
+    parts: Vec<R>,
+    /// The offset of the start of each part, followed by the total length
+    starts: Vec<u64>,
+}
+
+impl<R> Chain<R> {
+    /// Chain together `parts`, each paired with its length
+    pub fn new<I: IntoIterator<Item = (R, u64)>>(parts: I) -> Self {
+        let mut starts = vec![0];
+        let parts = parts
+            .into_iter()
+            .map(|(part, len)| {
+                starts.push(starts.last().unwrap() + len);
+                part
+            })
+            .collect();
+        Self { parts, starts }
+    }
+
+    /// The total length of all parts
+    pub fn len(&self) -> u64 {
+        *self.starts.last().unwrap()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Chain<File> {
+    /// Open the parts named after `base`: `image.000`, `image.001`, ... until a part is missing
+    pub fn open_parts<P: AsRef<Path>>(base: P) -> io::Result<Self> {
+        let base = base.as_ref();
+        let mut parts = Vec::new();
+        loop {
+            let file = match File::open(part_path(base, parts.len())) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound && !parts.is_empty() => break,
+                Err(e) => return Err(e),
+            };
+            let len = file.metadata()?.len();
+            parts.push((file, len));
+        }
+        Ok(Self::new(parts))
+    }
+}
+
+impl<R: ReadAt> ReadAt for Chain<R> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if offset >= self.len() {
+            return Ok(0);
+        }
+        // The last part starting at or before offset
+        let index = match self.starts.binary_search(&offset) {
+            Ok(index) => index,
+            Err(index) => index - 1,
+        };
+        // Skip over empty parts
+        let index = (index..self.parts.len())
+            .find(|&i| self.starts[i + 1] > offset)
+            .unwrap();
+        let part_offset = offset - self.starts[index];
+        let part_remaining = self.starts[index + 1] - offset;
+        let len = buf.len().min(part_remaining as usize);
+        self.parts[index].read_at(&mut buf[..len], part_offset)
+    }
+
+    fn size(&self) -> io::Result<Option<u64>> {
+        Ok(Some(self.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write::SplitFile;
+    use std::io::{Seek, SeekFrom, Write};
+
+    #[test]
+    fn split_and_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("image");
+        let data: Vec<u8> = (0..25).collect();
+
+        let mut split = SplitFile::create(&base, 10).unwrap();
+        split.write_all(&data).unwrap();
+        split.seek(SeekFrom::Start(12)).unwrap();
+        split.write_all(&[0xFF]).unwrap();
+        split.flush().unwrap();
+        assert_eq!(split.part_paths().len(), 3);
+        assert_eq!(
+            std::fs::read(dir.path().join("image.002")).unwrap().len(),
+            5
+        );
+
+        let chain = Chain::open_parts(&base).unwrap();
+        assert_eq!(chain.len(), 25);
+        let mut buf = [0; 6];
+        chain.read_exact_at(&mut buf, 8).unwrap();
+        assert_eq!(buf, [8, 9, 10, 11, 0xFF, 13]);
+        chain.read_exact_at(&mut buf, 20).unwrap_err();
+
+        let chain = Chain::new(vec![(vec![1, 2], 2), (Vec::new(), 0), (vec![3], 1)]);
+        let mut buf = [0; 3];
+        chain.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+    }
+}
@@ -0,0 +1,269 @@
+//! Fast integrity checks, reading all the metadata but only a sample of the data
+//!
+//! Reading every data block of a large image takes as long as extracting it. Metadata is
+//! usually a small part of an image, and any corruption in it can make whole directories
+//! unreadable, so [`Archive::check`] reads all of it, but only decompresses a random sample of
+//! the data blocks. The [`CheckReport`] bounds how much corruption the sample could have missed.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use bstr::BString;
+
+use super::{child_path, Archive, InodeData, ReadAt};
+use crate::errors::Result;
+
+/// How thoroughly [`Archive::check`] checks data blocks
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CheckOptions {
+    /// The fraction of data blocks to read and decompress, from `0.0` for none to `1.0` for
+    /// all of them
+    pub sample_rate: f64,
+    /// Chooses which blocks are sampled: the same seed always samples the same blocks of an
+    /// image, so vary it to eventually cover all of them
+    pub seed: u64,
+}
+
+impl Default for CheckOptions {
+    fn default() -> Self {
+        CheckOptions {
+            sample_rate: 0.01,
+            seed: 0,
+        }
+    }
+}
+
+/// A problem found by [`Archive::check`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckFailure {
+    /// The inode or listing of the entry at `path` couldn't be read
+    Metadata { path: BString, error: String },
+    /// The data block at `offset` couldn't be read or decompressed
+    Data { offset: u64, error: String },
+}
+
+impl fmt::Display for CheckFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckFailure::Metadata { path, error } => write!(f, "{}: {}", path, error),
+            CheckFailure::Data { offset, error } => {
+                write!(f, "data block at {}: {}", offset, error)
+            }
+        }
+    }
+}
+
+/// The results of [`Archive::check`]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CheckReport {
+    /// The number of inodes read, each counted once however many links it has
+    pub inodes: u64,
+    /// The number of data and fragment blocks in the image, not counting sparse blocks
+    pub data_blocks: u64,
+    /// The number of data and fragment blocks which were read and decompressed
+    pub sampled_blocks: u64,
+    pub failures: Vec<CheckFailure>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// The largest fraction of data blocks which could be corrupt, at `confidence` (e.g.
+    /// `0.95`), given that none of the sampled blocks were
+    ///
+    /// Returns `None` if a sampled block was corrupt, and `Some(0.0)` if every block was
+    /// checked.
+    pub fn max_corrupt_fraction(&self, confidence: f64) -> Option<f64> {
+        let data_failed = self
+            .failures
+            .iter()
+            .any(|failure| matches!(failure, CheckFailure::Data { .. }));
+        if data_failed {
+            None
+        } else if self.sampled_blocks >= self.data_blocks {
+            Some(0.0)
+        } else {
+            // With a corrupt fraction of p, all n samples are good with probability (1 - p)^n
+            let n = self.sampled_blocks as f64;
+            Some(1.0 - (1.0 - confidence).powf(1.0 / n))
+        }
+    }
+}
+
+impl<R: ReadAt> Archive<R> {
+    /// Check the integrity of the archive, reading every inode and directory listing, and
+    /// decompressing a sample of the data blocks
+    ///
+    /// The superblock and the id and fragment tables are checked when the archive is opened.
+    /// Only a problem reading the root directory is returned as an error, anything else is
+    /// recorded in the report and checking continues.
+    ///
+    /// ```no_run
+    /// use sqfs::read::{Archive, CheckOptions};
+    ///
+    /// # fn main() -> sqfs::Result<()> {
+    /// let archive = Archive::open("image.sqfs")?;
+    /// let report = archive.check(&CheckOptions::default())?;
+    /// if let Some(fraction) = report.max_corrupt_fraction(0.99) {
+    ///     println!("at most {:.2}% of blocks are corrupt", fraction * 100.0);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn check(&self, options: &CheckOptions) -> Result<CheckReport> {
+        let mut report = CheckReport::default();
+        let sampler = Sampler::new(options);
+        let mut seen = HashSet::new();
+        let mut stack = vec![(BString::from("/"), self.root()?)];
+        while let Some((path, inode)) = stack.pop() {
+            if !seen.insert(inode.inode_number()) {
+                continue;
+            }
+            report.inodes += 1;
+            match inode.data() {
+                InodeData::Directory(_) => {
+                    let entries = match self.read_dir(&inode) {
+                        Ok(entries) => entries,
+                        Err(e) => {
+                            report.failures.push(CheckFailure::Metadata {
+                                path,
+                                error: e.to_string(),
+                            });
+                            continue;
+                        }
+                    };
+                    for entry in entries.iter().rev() {
+                        let child_path = child_path(&path, entry.name());
+                        match self.inode(entry.inode_ref()) {
+                            Ok(child) => stack.push((child_path, child)),
+                            Err(e) => report.failures.push(CheckFailure::Metadata {
+                                path: child_path,
+                                error: e.to_string(),
+                            }),
+                        }
+                    }
+                }
+                InodeData::File(file) => {
+                    let mut offset = file.blocks_start;
+                    for size in &file.block_sizes {
+                        if size.size() != 0 {
+                            self.check_block(offset, *size, &sampler, &mut report);
+                        }
+                        offset += u64::from(size.size());
+                    }
+                }
+                _ => {}
+            }
+        }
+        for entry in &self.inner.fragments {
+            self.check_block(entry.start.0, entry.size, &sampler, &mut report);
+        }
+        Ok(report)
+    }
+
+    fn check_block(
+        &self,
+        offset: u64,
+        size: repr::datablock::Size,
+        sampler: &Sampler,
+        report: &mut CheckReport,
+    ) {
+        report.data_blocks += 1;
+        if !sampler.sample(offset) {
+            return;
+        }
+        report.sampled_blocks += 1;
+        let block_size = self.block_size() as usize;
+        if let Err(e) = self.inner.read_datablock(offset, size, block_size) {
+            report.failures.push(CheckFailure::Data {
+                offset,
+                error: e.to_string(),
+            });
+        }
+    }
+}
+
+/// Chooses blocks independently, each with probability `sample_rate`, by hashing their offset
+struct Sampler {
+    seed: u64,
+    threshold: u64,
+    all: bool,
+}
+
+impl Sampler {
+    fn new(options: &CheckOptions) -> Self {
+        let rate = options.sample_rate.clamp(0.0, 1.0);
+        Sampler {
+            seed: options.seed,
+            threshold: (rate * u64::MAX as f64) as u64,
+            all: rate >= 1.0,
+        }
+    }
+
+    fn sample(&self, offset: u64) -> bool {
+        self.all || splitmix64(self.seed ^ offset) < self.threshold
+    }
+}
+
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, ImageBuilder};
+
+    #[test]
+    fn sampled_blocks() {
+        let contents = testing::compressible_contents();
+        let mut image = ImageBuilder::new().file("file", contents).build();
+        let archive = Archive::new(image.clone()).unwrap();
+        let all = CheckOptions {
+            sample_rate: 1.0,
+            seed: 0,
+        };
+        let report = archive.check(&all).unwrap();
+        assert!(report.is_ok());
+        assert_eq!((report.inodes, report.data_blocks), (2, 4));
+        assert_eq!(report.sampled_blocks, 4);
+        assert_eq!(report.max_corrupt_fraction(0.95), Some(0.0));
+
+        let none = CheckOptions {
+            sample_rate: 0.0,
+            seed: 0,
+        };
+        assert_eq!(archive.check(&none).unwrap().sampled_blocks, 0);
+
+        // Corrupt the first data block, which follows the superblock
+        for byte in &mut image[100..110] {
+            *byte = !*byte;
+        }
+        let archive = Archive::new(image).unwrap();
+        let report = archive.check(&all).unwrap();
+        assert_eq!(report.failures.len(), 1);
+        assert!(matches!(
+            report.failures[0],
+            CheckFailure::Data { offset: 96, .. }
+        ));
+        assert_eq!(report.max_corrupt_fraction(0.95), None);
+        assert!(archive.check(&none).unwrap().is_ok());
+    }
+
+    #[test]
+    fn confidence() {
+        let report = CheckReport {
+            inodes: 1,
+            data_blocks: 100_000,
+            sampled_blocks: 299,
+            failures: Vec::new(),
+        };
+        let fraction = report.max_corrupt_fraction(0.95).unwrap();
+        assert!((0.0099..0.0101).contains(&fraction), "{}", fraction);
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::HashSet;
+use std::io;
+
+use super::{Archive, Inode, InodeData, ReadAt};
+use crate::cpio::{self, Format};
+use crate::errors::Result;
+
+impl<R: ReadAt> Archive<R> {
+    /// Convert the archive to a cpio archive, written to `writer`
+    ///
+    /// Entries are written parent first, with paths relative to the root and no leading `./`,
+    /// as expected for initramfs images. The root directory itself is not written.
+    ///
+    /// Device nodes, fifos and sockets are preserved. Hard links share an inode number, and
+    /// only the first link carries the file's contents.
+    pub fn to_cpio<W: io::Write>(&self, mut writer: W, format: Format) -> Result<()> {
+        let mut written = HashSet::new();
+        let root = self.root()?;
+        self.dir_to_cpio(&mut writer, format, &root, b"", &mut written)?;
+        cpio::write_trailer(&mut writer, format)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn dir_to_cpio<W: io::Write>(
+        &self,
+        writer: &mut W,
+        format: Format,
+        dir: &Inode,
+        prefix: &[u8],
+        written: &mut HashSet<u32>,
+    ) -> Result<()> {
+        for entry in self.read_dir(dir)? {
+            let mut name = prefix.to_vec();
+            name.extend_from_slice(entry.name());
+            let inode = self.inode(entry.inode_ref())?;
+            let first_link = written.insert(inode.inode_number());
+            self.entry_to_cpio(writer, format, &inode, name.clone(), first_link)?;
+            if inode.is_dir() {
+                name.push(b'/');
+                self.dir_to_cpio(writer, format, &inode, &name, written)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn entry_to_cpio<W: io::Write>(
+        &self,
+        writer: &mut W,
+        format: Format,
+        inode: &Inode,
+        name: Vec<u8>,
+        first_link: bool,
+    ) -> Result<()> {
+        let mut header = cpio::Header {
+            ino: inode.inode_number(),
+            mode: inode.mode().to_unix(),
+            uid: inode.uid(),
+            gid: inode.gid(),
+            nlink: inode.hard_link_count(),
+            mtime: inode.mtime(),
+            name,
+            ..cpio::Header::default()
+        };
+        let data = match inode.data() {
+            InodeData::File(_) if first_link => self.read_file(inode)?,
+            InodeData::Symlink(target) => target.to_vec(),
+            InodeData::BlockDevice(device) | InodeData::CharDevice(device) => {
+                header.rdev_major = device.major();
+                header.rdev_minor = device.minor();
+                Vec::new()
+            }
+            _ => Vec::new(),
+        };
+        header.file_size = cpio::file_size(data.len() as u64)?;
+
+        header.write(writer, format)?;
+        writer.write_all(&data)?;
+        cpio::write_padding(writer, data.len())?;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_to_cpio() {
+        let archive = Archive::new(crate::testing::file_and_symlink()).unwrap();
+
+        let mut data = Vec::new();
+        archive.to_cpio(&mut data, Format::Newc).unwrap();
+
+        // Each entry is aligned to 4 bytes
+        assert_eq!(data.len() % 4, 0);
+        let file = &data[..110];
+        assert_eq!(&file[..6], b"070701");
+        // ino 1, 9 bytes of contents, 6 byte name
+        assert_eq!(&file[6..14], b"00000001");
+        assert_eq!(&file[54..62], b"00000009");
+        assert_eq!(&file[94..102], b"00000006");
+        assert_eq!(&data[110..116], b"hello\0");
+        assert_eq!(&data[116..125], b"hi there\n");
+
+        let link = &data[128..];
+        assert_eq!(&link[..6], b"070701");
+        assert_eq!(&link[110..115], b"link\0");
+        assert_eq!(&link[116..121], b"hello");
+        assert!(data.ends_with(b"TRAILER!!!\0\0\0\0"));
+    }
+}
@@ -0,0 +1,196 @@
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::mem;
+
+use bstr::BString;
+use repr::inode::Kind;
+use repr::offset::{ArchiveOffset, TableOffset};
+
+use super::inode::DirInfo;
+use super::metablock::Cursor;
+use super::{ArchiveInner, ReadAt};
+use crate::errors::{ReadError, Result};
+use crate::FileType;
+
+/// An entry in a directory listing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    name: BString,
+    inode_ref: repr::inode::Ref,
+    inode_number: u32,
+    kind: Kind,
+    file_type: FileType,
+}
+
+impl DirEntry {
+    pub fn name(&self) -> &BString {
+        &self.name
+    }
+
+    /// A reference to the inode of this entry, see [`Archive::inode`](super::Archive::inode)
+    pub fn inode_ref(&self) -> repr::inode::Ref {
+        self.inode_ref
+    }
+
+    pub fn inode_number(&self) -> u32 {
+        self.inode_number
+    }
+
+    /// The basic inode type of the entry
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+}
+
+/// The most entries the kernel accepts after one directory header
+pub(crate) use repr::limits::MAX_DIR_ENTRIES_PER_HEADER as MAX_ENTRIES_PER_HEADER;
+
+impl<R: ReadAt> ArchiveInner<R> {
+    pub(crate) fn read_dir(&self, dir: &DirInfo) -> Result<Vec<DirEntry>> {
+        self.read_listing(dir).map(|(entries, _)| entries)
+    }
+
+    /// Read a directory's entries, and the number of headers they are grouped under
+    pub(crate) fn read_listing(&self, dir: &DirInfo) -> Result<(Vec<DirEntry>, usize)> {
+        self.read_listing_with(dir, false)
+    }
+
+    /// Read a directory's entries, pinning the blocks they are read from in the cache if `pin`
+    pub(crate) fn read_listing_with(
+        &self,
+        dir: &DirInfo,
+        pin: bool,
+    ) -> Result<(Vec<DirEntry>, usize)> {
+        let listing = self.parse_listing(dir, pin, true)?;
+        Ok((listing.entries, listing.headers.len()))
+    }
+
+    /// Read a directory's entries, and the position of each header, allowing headers with more
+    /// entries than the kernel accepts
+    pub(crate) fn read_raw_listing(&self, dir: &DirInfo) -> Result<Listing> {
+        self.parse_listing(dir, false, false)
+    }
+
+    fn parse_listing(&self, dir: &DirInfo, pin: bool, limit_headers: bool) -> Result<Listing> {
+        let mut listing = Listing::default();
+        if dir.listing_size == 0 {
+            return Ok(listing);
+        }
+
+        let block = ArchiveOffset(self.superblock.directory_table_start)
+            + TableOffset(dir.block_start.into());
+        let mut cursor = Cursor::with_pinning(self, block, dir.block_offset, pin)?;
+        let mut remaining = dir.listing_size as usize;
+        let entries = &mut listing.entries;
+
+        while remaining > 0 {
+            let offset = dir.listing_size - remaining as u32;
+            let header: repr::directory::Header = take(&mut cursor, &mut remaining)?;
+            let count = header.count + 1;
+            listing.headers.push(HeaderInfo { offset, count });
+            if limit_headers && count > MAX_ENTRIES_PER_HEADER {
+                return Err(ReadError::CorruptDirectory("too many entries for one header").into());
+            }
+            for _ in 0..count {
+                let entry: repr::directory::Entry = take(&mut cursor, &mut remaining)?;
+                let name_size = usize::from(entry.name_size) + 1;
+                if limit_headers && name_size > repr::limits::MAX_NAME_LEN {
+                    return Err(ReadError::CorruptDirectory("entry name too long").into());
+                }
+                remaining = remaining
+                    .checked_sub(name_size)
+                    .ok_or(ReadError::CorruptDirectory("entry extends past listing"))?;
+                let name = cursor.read_vec(name_size)?;
+                if name.contains(&b'/') || name == b"." || name == b".." {
+                    return Err(ReadError::CorruptDirectory("invalid entry name").into());
+                }
+                if self.strict {
+                    if let Some(prev) = entries.last() {
+                        check_order(prev.name(), &name)?;
+                    }
+                }
+                let inode_number = i64::from(header.inode_number.0) + i64::from(entry.inode_offset);
+                let inode_number = u32::try_from(inode_number)
+                    .map_err(|_| ReadError::CorruptDirectory("inode number out of range"))?;
+                let file_type = FileType::try_from(entry.kind)
+                    .map_err(|_| ReadError::CorruptDirectory("unknown entry type"))?;
+                entries.push(DirEntry {
+                    name: name.into(),
+                    inode_ref: repr::inode::Ref::new(header.start, entry.offset),
+                    inode_number,
+                    kind: entry.kind,
+                    file_type,
+                });
+            }
+        }
+        Ok(listing)
+    }
+}
+
+/// A directory listing, see [`ArchiveInner::read_raw_listing`]
+#[derive(Debug, Default)]
+pub(crate) struct Listing {
+    pub entries: Vec<DirEntry>,
+    pub headers: Vec<HeaderInfo>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct HeaderInfo {
+    /// The position of the header from the start of the listing
+    pub offset: u32,
+    /// The number of entries following the header
+    pub count: u32,
+}
+
+/// Check that `name` sorts after the previous entry's name, as the kernel requires
+fn check_order(prev: &[u8], name: &[u8]) -> Result<()> {
+    match prev.cmp(name) {
+        Ordering::Less => Ok(()),
+        Ordering::Equal => Err(ReadError::CorruptDirectory("duplicate entry name").into()),
+        Ordering::Greater => Err(ReadError::CorruptDirectory("entries not sorted").into()),
+    }
+}
+
+fn take<T: zerocopy::FromBytes, R: ReadAt>(
+    cursor: &mut Cursor<'_, R>,
+    remaining: &mut usize,
+) -> Result<T> {
+    *remaining = remaining
+        .checked_sub(mem::size_of::<T>())
+        .ok_or(ReadError::CorruptDirectory("entry extends past listing"))?;
+    cursor.read()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::super::{Archive, OpenOptions};
+
+    #[test]
+    fn strict_order() {
+        let mut data = crate::testing::ImageBuilder::new()
+            .uncompressed()
+            .file("hello", crate::testing::HELLO_CONTENTS)
+            .symlink("link", "hello")
+            .build();
+        // The last "hello" is the entry in the directory listing, rename it to sort after "link"
+        let pos = data.windows(5).rposition(|name| name == b"hello").unwrap();
+        data[pos] = b'z';
+
+        let lenient = Archive::new(data.clone()).unwrap();
+        let names: Vec<_> = lenient
+            .read_dir(&lenient.root().unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name().clone())
+            .collect();
+        assert_eq!(names, ["zello", "link"]);
+
+        let strict = OpenOptions::new().strict(true).open_source(data).unwrap();
+        let err = strict.read_dir(&strict.root().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("not sorted"));
+    }
+}
@@ -0,0 +1,165 @@
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+
+use super::ReadAt;
+use crate::config::MemoryBudget;
+use crate::pool::BlockPool;
+
+/// The alignment used by [`DirectFile::open`], which suits devices with 512 byte or 4k sectors
+pub const DEFAULT_ALIGNMENT: usize = 4096;
+
+/// The most read at once, so small reads near each other don't each allocate huge buffers
+const MAX_READ: usize = 1 << 20;
+
+/// A file or block device read with direct I/O, bypassing the page cache
+///
+/// Reading an image straight from a raw block device or partition with buffered reads keeps a
+/// second copy of everything in the page cache. With direct I/O the kernel requires every read
+/// to be aligned: its offset, length and buffer address must all be multiples of the device's
+/// sector size. This widens each read to aligned boundaries, reads into a pooled buffer, and
+/// copies out the requested range.
+///
+/// Direct I/O is only requested on Linux. On other platforms the file is opened normally, but
+/// reads are still aligned.
+///
+/// ```no_run
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use sqfs::read::{Archive, DirectFile};
+///
+/// let archive = Archive::new(DirectFile::open("/dev/sdb1")?)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct DirectFile {
+    file: File,
+    alignment: usize,
+    size: u64,
+    pool: Arc<BlockPool>,
+}
+
+impl DirectFile {
+    /// Open the file or device at `path` for direct I/O, aligning reads to
+    /// [`DEFAULT_ALIGNMENT`]
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::_open(path.as_ref())
+    }
+
+    fn _open(path: &Path) -> io::Result<Self> {
+        let mut options = OpenOptions::new();
+        options.read(true);
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.custom_flags(libc::O_DIRECT);
+        }
+        Self::from_file(options.open(path)?, DEFAULT_ALIGNMENT)
+    }
+
+    /// Read `file` with every read aligned to `alignment` bytes
+    ///
+    /// The file must already have been opened for direct I/O if that is wanted.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `alignment` is not a power of two
+    pub fn from_file(mut file: File, alignment: usize) -> io::Result<Self> {
+        assert!(
+            alignment.is_power_of_two(),
+            "alignment must be a power of two"
+        );
+        // The metadata of a block device reports a length of zero, but seeking finds its end
+        let size = file.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            file,
+            alignment,
+            size,
+            pool: Arc::new(BlockPool::with_budget(&MemoryBudget::default())),
+        })
+    }
+
+    pub fn alignment(&self) -> usize {
+        self.alignment
+    }
+
+    pub fn get_ref(&self) -> &File {
+        &self.file
+    }
+
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+}
+
+impl ReadAt for DirectFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if offset >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let remaining = usize::try_from(self.size - offset).unwrap_or(usize::MAX);
+        let len = buf.len().min(remaining).min(MAX_READ);
+
+        let alignment = self.alignment as u64;
+        let start = offset - offset % alignment;
+        let skip = (offset - start) as usize;
+        let aligned_len = round_up(skip + len, self.alignment);
+
+        // Over-allocate, so an aligned region can be found within the buffer
+        let mut block = self.pool.get();
+        block.resize(aligned_len + self.alignment, 0);
+        let align_offset = block.as_ptr().align_offset(self.alignment);
+        let aligned = &mut block[align_offset..][..aligned_len];
+
+        let mut filled = 0;
+        while filled < skip + len {
+            match self
+                .file
+                .read_at(&mut aligned[filled..], start + filled as u64)
+            {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let available = filled.saturating_sub(skip).min(len);
+        buf[..available].copy_from_slice(&aligned[skip..][..available]);
+        Ok(available)
+    }
+
+    fn size(&self) -> io::Result<Option<u64>> {
+        Ok(Some(self.size))
+    }
+}
+
+fn round_up(value: usize, alignment: usize) -> usize {
+    (value + alignment - 1) & !(alignment - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unaligned_reads() {
+        let data: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+        let mut file = tempfile::tempfile().unwrap();
+        io::Write::write_all(&mut file, &data).unwrap();
+        let direct = DirectFile::from_file(file, 512).unwrap();
+        assert_eq!(direct.size().unwrap(), Some(3000));
+
+        for &(offset, len) in &[(0, 10), (100, 1000), (511, 2), (2990, 10), (1024, 512)] {
+            let mut buf = vec![0; len];
+            direct.read_exact_at(&mut buf, offset as u64).unwrap();
+            assert_eq!(buf, &data[offset..offset + len]);
+        }
+
+        let mut buf = [0; 20];
+        assert_eq!(direct.read_at(&mut buf, 2990).unwrap(), 10);
+        assert_eq!(direct.read_at(&mut buf, 3000).unwrap(), 0);
+    }
+}
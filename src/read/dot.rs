@@ -0,0 +1,135 @@
+//! Drawing the inodes of an archive as a graph, for debugging and teaching
+//!
+//! The graph is written in the DOT language of Graphviz, and can be rendered with
+//! `dot -Tsvg image.dot > image.svg`. Each inode is a node, labelled with its first path and
+//! type, and each directory entry is an edge from the directory to the entry's inode, so hard
+//! links show up as inodes with several edges leading to them. Fragment blocks are nodes too,
+//! with a dashed edge from each file whose tail they store.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+
+use bstr::{BStr, ByteSlice};
+
+use super::{Archive, InodeData, ReadAt};
+use crate::errors::Result;
+
+impl<R: ReadAt> Archive<R> {
+    /// Write a DOT graph of the archive's directories, files, hard links and fragments to `out`
+    ///
+    /// ```no_run
+    /// # fn main() -> sqfs::Result<()> {
+    /// let archive = sqfs::read::Archive::open("image.sqfs")?;
+    /// archive.to_dot(std::fs::File::create("image.dot")?)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn to_dot<W: io::Write>(&self, mut out: W) -> Result<()> {
+        out.write_all(self.graphviz()?.as_bytes())?;
+        Ok(())
+    }
+
+    /// The DOT graph written by [`to_dot`](Self::to_dot), as a string
+    pub fn graphviz(&self) -> Result<String> {
+        let mut dot = String::from("digraph squashfs {\n    node [shape=box];\n");
+        let mut edges = String::new();
+        // The inode numbers of the files with a tail in each fragment block
+        let mut fragments: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+
+        for entry in self.walk() {
+            let entry = entry?;
+            if entry.hard_link_target().is_some() {
+                continue;
+            }
+            let inode = entry.inode();
+            let number = inode.inode_number();
+            writeln!(
+                dot,
+                "    i{} [label=\"{}\\n{:?} #{}\"];",
+                number,
+                escape(entry.path()),
+                inode.file_type(),
+                number
+            )
+            .unwrap();
+            match inode.data() {
+                InodeData::Directory(_) => {
+                    for child in self.read_dir(inode)? {
+                        writeln!(
+                            edges,
+                            "    i{} -> i{} [label=\"{}\"];",
+                            number,
+                            child.inode_number(),
+                            escape(child.name().as_ref())
+                        )
+                        .unwrap();
+                    }
+                }
+                InodeData::File(file) => {
+                    if let Some(fragment) = file.fragment {
+                        fragments.entry(fragment.index).or_default().push(number);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for (index, files) in &fragments {
+            writeln!(
+                dot,
+                "    f{} [label=\"fragment {}\", shape=ellipse];",
+                index, index
+            )
+            .unwrap();
+            for file in files {
+                writeln!(edges, "    i{} -> f{} [style=dashed];", file, index).unwrap();
+            }
+        }
+        dot.push_str(&edges);
+        dot.push_str("}\n");
+        Ok(dot)
+    }
+}
+
+/// Quote `name` for a DOT string, replacing invalid UTF-8
+fn escape(name: &BStr) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hard_links() {
+        let archive = Archive::new(crate::testing::hard_links()).unwrap();
+        let dot = archive.graphviz().unwrap();
+        assert!(dot.starts_with("digraph squashfs {\n"));
+        assert!(dot.ends_with("}\n"));
+
+        let a = archive.walk().nth(1).unwrap().unwrap();
+        let target = format!("-> i{} ", a.inode().inode_number());
+        // From the root as `a` and `b`, and from `dir` as `c`
+        assert_eq!(dot.matches(&target).count(), 3);
+        assert_eq!(dot.matches("[label=\"/a\\nFile").count(), 1);
+        assert!(!dot.contains("/b\\n"));
+    }
+
+    #[test]
+    fn escaped_names() {
+        assert_eq!(escape(b"a\"b\\c\nd".as_bstr()), "a\\\"b\\\\c\\nd");
+        assert_eq!(escape(b"\xff".as_bstr()), "\u{fffd}");
+    }
+}
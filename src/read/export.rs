@@ -0,0 +1,200 @@
+//! Finding inodes by number, through the export table
+//!
+//! An exportable archive has a table mapping every inode number to a reference to its inode,
+//! which NFS servers use to turn file handles back into inodes. The table is only read the
+//! first time it's needed. Some writers emit tables which are stale or plain wrong, so each
+//! entry is checked as it's used: the reference must lie within the inode table, and the
+//! inode there must parse and have the number looked up. When an entry fails, or the archive
+//! has no table at all, the inode is found by walking the tree instead. The walk happens once,
+//! keeping every inode by number, so later lookups which miss the table are just as quick.
+
+use std::collections::HashMap;
+
+use super::{Archive, ArchiveInner, Inode, ReadAt};
+use crate::errors::{ReadError, Result};
+
+impl<R: ReadAt> Archive<R> {
+    /// The inode numbered `inode_number`
+    ///
+    /// Numbers start at 1. Looking up an inode is quick when the export table has a valid
+    /// entry for it. Otherwise the whole tree is walked, the first time, and every inode is
+    /// kept in memory, so it's best used on archives which are
+    /// [exportable](repr::superblock::Flags::EXPORTABLE).
+    pub fn inode_by_number(&self, inode_number: u32) -> Result<Inode> {
+        let inner = &*self.inner;
+        if inode_number == 0 || inode_number > inner.superblock.inode_count {
+            return Err(ReadError::InodeNotFound(inode_number).into());
+        }
+        if let Some(inode) = inner.exported_inode(inode_number) {
+            return Ok(inode);
+        }
+        let inodes = inner
+            .walked_inodes
+            .get_or_try_init(|| self.inodes_by_number())?;
+        match inodes.get(&inode_number) {
+            Some(inode) => Ok(inode.clone()),
+            None => Err(ReadError::InodeNotFound(inode_number).into()),
+        }
+    }
+
+    /// Every inode reachable from the root, by number
+    fn inodes_by_number(&self) -> Result<HashMap<u32, Inode>> {
+        let mut inodes = HashMap::new();
+        for entry in self.walk() {
+            let inode = entry?.inode().clone();
+            inodes.entry(inode.inode_number()).or_insert(inode);
+        }
+        Ok(inodes)
+    }
+}
+
+impl<R: ReadAt> ArchiveInner<R> {
+    /// The inode numbered `inode_number`, if the export table has a valid entry for it
+    fn exported_inode(&self, inode_number: u32) -> Option<Inode> {
+        let inode_ref = *self.export_table()?.get(inode_number as usize - 1)?;
+        match self.check_exported(inode_number, inode_ref) {
+            Ok(inode) => Some(inode),
+            Err(e) => {
+                slog::warn!(self.logger, "Invalid export table entry, walking the tree instead";
+                    "inode_number" => inode_number, "error" => %e);
+                None
+            }
+        }
+    }
+
+    /// Read the inode `inode_ref` refers to, checking it's the inode numbered `inode_number`
+    fn check_exported(&self, inode_number: u32, inode_ref: repr::inode::Ref) -> Result<Inode> {
+        let superblock = &self.superblock;
+        let inode_table_len = superblock
+            .directory_table_start
+            .checked_sub(superblock.inode_table_start)
+            .ok_or(ReadError::CorruptExportEntry(
+                "the inode table is out of place",
+            ))?;
+        if u64::from(inode_ref.block_start()) >= inode_table_len
+            || usize::from(inode_ref.start_offset()) >= self.max_metablock
+        {
+            return Err(ReadError::CorruptExportEntry("outside the inode table").into());
+        }
+        let inode = self.read_inode(inode_ref)?;
+        if inode.inode_number() != inode_number {
+            return Err(ReadError::CorruptExportEntry("refers to another inode").into());
+        }
+        Ok(inode)
+    }
+
+    /// The entries of the export table, read the first time they're needed, or `None` if the
+    /// archive has no table or it can't be read
+    fn export_table(&self) -> Option<&[repr::inode::Ref]> {
+        let table = self.export_table.get_or_init(|| {
+            let start = self.superblock.export_table_start;
+            if start == u64::MAX {
+                return None;
+            }
+            match self.read_lookup_table(start, self.superblock.inode_count) {
+                Ok(table) => Some(table),
+                Err(e) => {
+                    slog::warn!(self.logger, "Unable to read the export table"; "error" => %e);
+                    None
+                }
+            }
+        });
+        table.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read::Bytes;
+    use crate::write::ArchiveBuilder;
+    use std::convert::TryInto;
+
+    /// An uncompressed archive of `/sub/file`, and a link to it, returning its bytes
+    fn image(exportable: bool) -> Vec<u8> {
+        let mut builder = ArchiveBuilder::new();
+        builder.exportable = exportable;
+        builder.compressed_inodes = false;
+        let (mut archive, image) = builder.build_in_memory();
+        let file = archive.create_file().finish(&mut archive).unwrap();
+        let mut sub = archive.create_dir();
+        sub.add_item("file", file);
+        let sub = sub.finish(&mut archive);
+        let mut root = archive.create_dir();
+        root.add_item("sub", sub).add_item("other", file);
+        let root = root.finish(&mut archive);
+        archive.set_root(root);
+        archive.flush().unwrap();
+        image.bytes()
+    }
+
+    fn numbers(archive: &Archive<Bytes<Vec<u8>>>) -> Vec<(u32, bool)> {
+        (1..=archive.superblock().inode_count)
+            .map(|number| {
+                let inode = archive.inode_by_number(number).unwrap();
+                assert_eq!(inode.inode_number(), number);
+                (number, inode.is_dir())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lookup() {
+        let expected = [(1, false), (2, true), (3, true)];
+        for exportable in [true, false] {
+            let archive = Archive::from_bytes(image(exportable)).unwrap();
+            assert_eq!(numbers(&archive), expected);
+            // The tree is only walked without a table
+            let walked = archive.inner.walked_inodes.get().map(HashMap::len);
+            assert_eq!(walked, if exportable { None } else { Some(3) });
+            assert!(archive.inode_by_number(0).is_err());
+            assert!(archive.inode_by_number(4).is_err());
+        }
+    }
+
+    #[test]
+    fn invalid_entries() {
+        let mut bytes = image(true);
+        let archive = Archive::from_bytes(bytes.clone()).unwrap();
+        let expected = numbers(&archive);
+        let superblock = archive.superblock();
+        let start = superblock.export_table_start as usize;
+        let metablock = u64::from_le_bytes(bytes[start..start + 8].try_into().unwrap()) as usize;
+        // Entries of an uncompressed metablock follow its two byte header
+        let entries = metablock + 2;
+        let (first, second) = (entries, entries + 8);
+        // Past the end of the inode table
+        bytes[first..first + 8].copy_from_slice(&(u64::MAX >> 1).to_le_bytes());
+        // The entry of inode 3, in place of inode 2's
+        bytes.copy_within(entries + 16..entries + 24, second);
+
+        let archive = Archive::from_bytes(bytes).unwrap();
+        assert_eq!(numbers(&archive), expected);
+    }
+
+    #[test]
+    fn swapped_tables() {
+        use repr::superblock::Superblock;
+        use zerocopy::{AsBytes, FromBytes};
+
+        let mut bytes = image(true);
+        let mut superblock = Superblock::read_from_prefix(&bytes[..]).unwrap();
+        // The fields are packed, so can't be borrowed to swap them
+        (
+            superblock.inode_table_start,
+            superblock.directory_table_start,
+        ) = (
+            superblock.directory_table_start,
+            superblock.inode_table_start,
+        );
+        bytes[..superblock.as_bytes().len()].copy_from_slice(superblock.as_bytes());
+
+        let archive = Archive::from_bytes(bytes).unwrap();
+        let inode_ref = archive.inner.export_table().unwrap()[0];
+        let err = archive.inner.check_exported(1, inode_ref).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Read error: Corrupt export table entry: the inode table is out of place"
+        );
+    }
+}
@@ -0,0 +1,611 @@
+//! Extracting the contents of an archive to disk
+
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::{fmt, fs, io};
+
+use bstr::{BStr, ByteSlice};
+use chrono::{DateTime, Utc};
+
+use super::journal::{self, ExtractJournal, Journal};
+use super::{Advice, Archive, Inode, InodeData, ReadAt, Walk, WalkEntry};
+use crate::config::{SymlinkRewrite, UnsupportedEntryPolicy};
+use crate::errors::{ReadError, Result};
+use crate::Mode;
+
+/// How far ahead of the file being extracted the source is advised of upcoming reads
+const READAHEAD: u64 = 8 << 20;
+
+/// How [`Archive::extract_with_options`] recreates entries
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ExtractOptions {
+    /// What to do with entries which can't be recreated
+    pub unsupported: UnsupportedEntryPolicy,
+    /// How the targets of absolute symlinks are rewritten, so they can point inside the
+    /// destination rather than at the host's files, see [`SymlinkRewrite`]
+    pub symlinks: SymlinkRewrite,
+    /// Called as each entry is started and finished, see [`ExtractProgress`]
+    pub progress: Option<ExtractProgress>,
+    /// Record completed entries in a journal, so an interrupted extraction can be resumed,
+    /// see [`ExtractJournal`]
+    pub journal: Option<ExtractJournal>,
+}
+
+/// A step of an extraction, reported to an [`ExtractProgress`] callback
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExtractEvent<'a> {
+    /// The entry at `path` is about to be extracted, writing `size` bytes of file contents
+    Started { path: &'a BStr, size: u64 },
+    /// The entry at `path` has been extracted, or skipped as the
+    /// [`unsupported`](ExtractOptions::unsupported) policy allows
+    ///
+    /// `extracted` is the number of bytes of file contents written so far, this entry's
+    /// included. Once an entry is finished it's complete on disk, apart from the permissions
+    /// of directories, which are applied last.
+    Finished {
+        path: &'a BStr,
+        size: u64,
+        extracted: u64,
+    },
+}
+
+/// A callback reporting the progress of an extraction, entry by entry
+///
+/// Entries are reported in the order they're extracted, which isn't the order of
+/// [`walk`](Archive::walk): regular files follow the rest, in the order of their data, and
+/// hard links come last. A caller recording finished entries can skip them when an interrupted
+/// extraction is run again.
+///
+/// ```no_run
+/// use sqfs::read::{Archive, ExtractEvent, ExtractOptions, ExtractProgress};
+///
+/// # fn main() -> sqfs::Result<()> {
+/// let archive = Archive::open("image.sqfs")?;
+/// let options = ExtractOptions {
+///     progress: Some(ExtractProgress::new(|event| {
+///         if let ExtractEvent::Finished { path, extracted, .. } = event {
+///             println!("{} ({} bytes so far)", path, extracted);
+///         }
+///     })),
+///     ..ExtractOptions::default()
+/// };
+/// archive.extract_with_options("rootfs", &options)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ExtractProgress(Arc<dyn Fn(ExtractEvent<'_>) + Send + Sync>);
+
+impl ExtractProgress {
+    pub fn new<F>(callback: F) -> Self
+    where
+        F: Fn(ExtractEvent<'_>) + Send + Sync + 'static,
+    {
+        ExtractProgress(Arc::new(callback))
+    }
+
+    fn report(&self, event: ExtractEvent<'_>) {
+        (self.0)(event)
+    }
+}
+
+impl fmt::Debug for ExtractProgress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ExtractProgress").finish_non_exhaustive()
+    }
+}
+
+/// Callbacks are only equal to their clones
+impl PartialEq for ExtractProgress {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for ExtractProgress {}
+
+impl<R: ReadAt> Archive<R> {
+    /// Extract the contents of the archive into the directory `dest`, creating it if needed
+    ///
+    /// Directories, regular files, symlinks and hard links are recreated with their
+    /// permissions. Device nodes, fifos and sockets are skipped with a warning, as are symlinks
+    /// where they can't be created. On platforms without unix permissions, entries without any
+    /// write permission are made read-only.
+    ///
+    /// Regular files are extracted in the order their data is stored, so the source is read
+    /// sequentially, and the source is [advised](ReadAt::advise) of the data which will be read
+    /// next.
+    pub fn extract<P: AsRef<Path>>(&self, dest: P) -> Result<()> {
+        self.extract_with(dest, UnsupportedEntryPolicy::default())
+    }
+
+    /// Extract the contents of the archive like [`extract`](Archive::extract), choosing what to
+    /// do with entries which can't be recreated
+    pub fn extract_with<P: AsRef<Path>>(
+        &self,
+        dest: P,
+        unsupported: UnsupportedEntryPolicy,
+    ) -> Result<()> {
+        let options = ExtractOptions {
+            unsupported,
+            ..ExtractOptions::default()
+        };
+        self.extract_with_options(dest, &options)
+    }
+
+    /// Extract the contents of the archive like [`extract`](Archive::extract), with `options`
+    ///
+    /// ```no_run
+    /// use sqfs::config::SymlinkRewrite;
+    /// use sqfs::read::{Archive, ExtractOptions};
+    ///
+    /// # fn main() -> sqfs::Result<()> {
+    /// let archive = Archive::open("image.sqfs")?;
+    /// let options = ExtractOptions {
+    ///     symlinks: SymlinkRewrite::Relative,
+    ///     ..ExtractOptions::default()
+    /// };
+    /// archive.extract_with_options("rootfs", &options)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn extract_with_options<P: AsRef<Path>>(
+        &self,
+        dest: P,
+        options: &ExtractOptions,
+    ) -> Result<()> {
+        let dest = dest.as_ref();
+        fs::create_dir_all(dest)?;
+        let mut dirs = Vec::new();
+        let mut tracking = Tracking {
+            extracted: 0,
+            journal: match &options.journal {
+                Some(journal) => Some(Journal::open(journal, self.inner.logger.clone())?),
+                None => None,
+            },
+        };
+        let mut files = Vec::new();
+        let mut links = Vec::new();
+        for entry in self.walk() {
+            let entry = entry?;
+            if entry.hard_link_target().is_some() {
+                links.push(entry);
+            } else if let InodeData::File(file) = entry.inode().data() {
+                let (offset, len) = self.inner.data_range(file)?;
+                files.push((offset, len, entry));
+            } else {
+                self.extract_reported(&entry, dest, &mut dirs, options, &mut tracking)?;
+            }
+        }
+
+        files.sort_by_key(|&(offset, _, _)| offset);
+        self.inner
+            .source
+            .advise(0, self.bytes_used(), Advice::Sequential);
+        let mut advised = 0;
+        for &(offset, _, ref entry) in &files {
+            // Keep the source advised of the files up to READAHEAD bytes past this one
+            while advised < files.len() && files[advised].0 < offset + READAHEAD {
+                let (offset, len, _) = files[advised];
+                self.inner.source.advise(offset, len, Advice::WillNeed);
+                advised += 1;
+            }
+            self.extract_reported(entry, dest, &mut dirs, options, &mut tracking)?;
+        }
+        // Hard links can only be created once their targets exist
+        for entry in &links {
+            self.extract_reported(entry, dest, &mut dirs, options, &mut tracking)?;
+        }
+        set_dir_permissions(dirs)
+    }
+
+    /// Extract the entries modified after `cutoff` into `dest`, see
+    /// [`walk_since`](Archive::walk_since)
+    ///
+    /// Directories holding changed entries are created as needed, with default permissions
+    /// unless they changed too. Extracting the changes of each image into its own directory
+    /// keeps incremental backups of a series of images, without storing their unchanged files
+    /// again.
+    pub fn extract_since<P: AsRef<Path>>(&self, dest: P, cutoff: DateTime<Utc>) -> Result<()> {
+        let dest = dest.as_ref();
+        let options = ExtractOptions::default();
+        let mut dirs = Vec::new();
+        for entry in self.walk_since(cutoff) {
+            let entry = entry?;
+            if let Some(parent) = dest_path(dest, entry.path())?.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            self.extract_entry(&entry, dest, &mut dirs, &options)?;
+        }
+        set_dir_permissions(dirs)
+    }
+
+    /// Extract every entry produced by `walk`, skipping entries which can't be recreated
+    ///
+    /// Directory permissions are only applied by [`set_dir_permissions`], after their contents
+    /// have been written, in case they are not writable.
+    pub(crate) fn extract_walk(
+        &self,
+        walk: Walk<'_, R>,
+        dest: &Path,
+        dirs: &mut Vec<(PathBuf, Inode)>,
+    ) -> Result<()> {
+        let options = ExtractOptions::default();
+        for entry in walk {
+            let entry = entry?;
+            self.extract_entry(&entry, dest, dirs, &options)?;
+        }
+        Ok(())
+    }
+
+    /// Extract an entry, reporting it to the options' progress callback, and recording it in
+    /// the journal, unless the journal shows an earlier extraction completed it
+    fn extract_reported(
+        &self,
+        entry: &WalkEntry,
+        dest: &Path,
+        dirs: &mut Vec<(PathBuf, Inode)>,
+        options: &ExtractOptions,
+        tracking: &mut Tracking,
+    ) -> Result<()> {
+        let path = entry.path();
+        let inode = entry.inode();
+        // Hard links share their target's contents, which was already written
+        let size = match entry.hard_link_target() {
+            Some(_) => 0,
+            None => inode.file_size().unwrap_or(0),
+        };
+        let report = |event| {
+            if let Some(progress) = &options.progress {
+                progress.report(event);
+            }
+        };
+        report(ExtractEvent::Started { path, size });
+
+        match (&mut tracking.journal, inode.data()) {
+            // Directories are cheap to create again, and need their permissions applied
+            (None, _) | (Some(_), InodeData::Directory(_)) => {
+                self.extract_entry(entry, dest, dirs, options)?
+            }
+            (Some(journal), data) => {
+                let disk = dest_path(dest, path)?;
+                if !journal.completed(path, &disk)? {
+                    let hash = match (data, entry.hard_link_target()) {
+                        (InodeData::File(_), None) => {
+                            let hash = journal.write_file(self, inode, path, &disk)?;
+                            set_permissions(&disk, inode.permissions())?;
+                            Some(hash)
+                        }
+                        _ => {
+                            journal::remove_stale(&disk)?;
+                            self.extract_entry(entry, dest, dirs, options)?;
+                            None
+                        }
+                    };
+                    journal.done(path, size, hash)?;
+                }
+            }
+        }
+
+        tracking.extracted += size;
+        report(ExtractEvent::Finished {
+            path,
+            size,
+            extracted: tracking.extracted,
+        });
+        Ok(())
+    }
+
+    fn extract_entry(
+        &self,
+        entry: &WalkEntry,
+        dest: &Path,
+        dirs: &mut Vec<(PathBuf, Inode)>,
+        options: &ExtractOptions,
+    ) -> Result<()> {
+        let unsupported = options.unsupported;
+        let path = dest_path(dest, entry.path())?;
+        let inode = entry.inode();
+
+        if let Some(target) = entry.hard_link_target() {
+            fs::hard_link(dest_path(dest, target)?, &path)?;
+            return Ok(());
+        }
+        match inode.data() {
+            InodeData::Directory(_) => {
+                fs::create_dir_all(&path)?;
+                dirs.push((path, inode.clone()));
+                return Ok(());
+            }
+            InodeData::File(_) => fs::write(&path, self.read_file(inode)?)?,
+            InodeData::Symlink(target) => {
+                let target = options.symlinks.rewrite(entry.path(), target);
+                return match symlink(&target, &path) {
+                    Err(e) if symlinks_unsupported(&e) => self.unsupported(entry, unsupported),
+                    // Symlink permissions are meaningless
+                    result => Ok(result?),
+                };
+            }
+            InodeData::BlockDevice(_)
+            | InodeData::CharDevice(_)
+            | InodeData::Fifo
+            | InodeData::Socket => return self.unsupported(entry, unsupported),
+        }
+        set_permissions(&path, inode.permissions())?;
+        Ok(())
+    }
+
+    /// Apply `policy` to an entry which can't be recreated
+    fn unsupported(&self, entry: &WalkEntry, policy: UnsupportedEntryPolicy) -> Result<()> {
+        let kind = entry.file_type().name();
+        match policy {
+            UnsupportedEntryPolicy::Skip => {
+                slog::warn!(self.inner.logger, "Skipping {}", kind; "path" => %entry.path());
+                Ok(())
+            }
+            UnsupportedEntryPolicy::Error => Err(ReadError::UnsupportedEntry {
+                path: entry.path().to_owned(),
+                kind,
+            }
+            .into()),
+        }
+    }
+}
+
+/// The state of an extraction carried from entry to entry
+struct Tracking {
+    /// The number of bytes of file contents extracted so far
+    extracted: u64,
+    journal: Option<Journal>,
+}
+
+/// The location to extract the entry at `path` (absolute within the archive) into
+///
+/// Names which would escape `dest`, like `..`, or on windows names containing `\` or a drive
+/// prefix, are refused.
+fn dest_path(dest: &Path, path: &[u8]) -> Result<PathBuf> {
+    let relative = path.trim_start_with(|c| c == '/').to_path_lossy();
+    let normal = relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if !normal {
+        return Err(ReadError::UnsafePath(path.into()).into());
+    }
+    Ok(dest.join(relative))
+}
+
+#[cfg(unix)]
+fn symlink(target: &[u8], path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target.to_path_lossy(), path)
+}
+
+/// Create a file symlink, since the type of the target isn't known until it is extracted
+#[cfg(windows)]
+fn symlink(target: &[u8], path: &Path) -> io::Result<()> {
+    let target = target.replace("/", "\\");
+    std::os::windows::fs::symlink_file(target.to_path_lossy(), path)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn symlink(target: &[u8], path: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Whether `e`, from creating a symlink, means symlinks can't be created here at all
+fn symlinks_unsupported(e: &io::Error) -> bool {
+    /// `ERROR_PRIVILEGE_NOT_HELD`, without developer mode or administrator rights
+    const PRIVILEGE_NOT_HELD: i32 = 1314;
+    e.kind() == io::ErrorKind::Unsupported
+        || (cfg!(windows) && e.raw_os_error() == Some(PRIVILEGE_NOT_HELD))
+}
+
+#[cfg(unix)]
+fn set_permissions(path: &Path, mode: Mode) -> io::Result<()> {
+    fs::set_permissions(path, mode.into())
+}
+
+#[cfg(not(unix))]
+fn set_permissions(path: &Path, mode: Mode) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions
+        .set_readonly(!mode.intersects(Mode::USER_WRITE | Mode::GROUP_WRITE | Mode::OTHER_WRITE));
+    fs::set_permissions(path, permissions)
+}
+
+/// Apply the permissions of extracted directories, deepest first
+pub(crate) fn set_dir_permissions(mut dirs: Vec<(PathBuf, Inode)>) -> Result<()> {
+    while let Some((path, inode)) = dirs.pop() {
+        set_permissions(&path, inode.permissions())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, ImageBuilder};
+    use chrono::TimeZone;
+    use std::sync::Mutex;
+
+    /// A source which records the advice it is given
+    struct Advised {
+        data: Vec<u8>,
+        advice: Mutex<Vec<(u64, u64, Advice)>>,
+    }
+
+    impl ReadAt for Advised {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            self.data.read_at(buf, offset)
+        }
+
+        fn advise(&self, offset: u64, len: u64, advice: Advice) {
+            self.advice.lock().unwrap().push((offset, len, advice));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn extract_advice() {
+        let data = crate::testing::file_and_symlink();
+        let archive = Archive::new(Advised {
+            data,
+            advice: Mutex::new(Vec::new()),
+        })
+        .unwrap();
+
+        let dest = tempfile::tempdir().unwrap();
+        archive.extract(dest.path()).unwrap();
+        let advice = archive.inner.source.advice.lock().unwrap();
+        assert_eq!(advice[0], (0, archive.bytes_used(), Advice::Sequential));
+        assert_eq!(advice[1..], [(96, 9, Advice::WillNeed)]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn extract_tree() {
+        let archive = Archive::new(crate::testing::file_and_symlink()).unwrap();
+
+        let dest = tempfile::tempdir().unwrap();
+        archive.extract(dest.path()).unwrap();
+        assert_eq!(fs::read(dest.path().join("hello")).unwrap(), b"hi there\n");
+        assert_eq!(
+            fs::read_link(dest.path().join("link")).unwrap(),
+            Path::new("hello")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rewritten_symlinks() {
+        let image = ImageBuilder::new()
+            .file("usr/lib/libc.so", "libc")
+            .symlink("usr/bin/libc.so", "/usr/lib/libc.so")
+            .build();
+        let archive = Archive::new(image).unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        let options = ExtractOptions {
+            symlinks: SymlinkRewrite::Relative,
+            ..ExtractOptions::default()
+        };
+        archive.extract_with_options(dest.path(), &options).unwrap();
+        let link = dest.path().join("usr/bin/libc.so");
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("../lib/libc.so"));
+        assert_eq!(fs::read(&link).unwrap(), b"libc");
+    }
+
+    #[test]
+    fn progress() {
+        let image = ImageBuilder::new()
+            .file("b", "contents")
+            .file("a/c", "more")
+            .build();
+        let archive = Archive::new(image).unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&events);
+        let options = ExtractOptions {
+            progress: Some(ExtractProgress::new(move |event| {
+                let event = match event {
+                    ExtractEvent::Started { path, size } => (path.to_string(), size, None),
+                    ExtractEvent::Finished {
+                        path,
+                        size,
+                        extracted,
+                    } => (path.to_string(), size, Some(extracted)),
+                };
+                recorded.lock().unwrap().push(event);
+            })),
+            ..ExtractOptions::default()
+        };
+        assert_eq!(options.clone(), options);
+        archive.extract_with_options(dest.path(), &options).unwrap();
+
+        let events = events.lock().unwrap();
+        let (dirs, files) = events.split_at(4);
+        let dir = |path: &str| (path.to_string(), 0, None);
+        assert_eq!(dirs[..2], [dir("/"), (String::from("/"), 0, Some(0))]);
+        assert_eq!(dirs[2..], [dir("/a"), (String::from("/a"), 0, Some(0))]);
+        // Files come after the other entries, each finished before the next is started
+        assert_eq!(files.len(), 4);
+        assert_eq!(files[1].2, Some(files[1].1));
+        assert_eq!(files[3].2, Some(12));
+        assert_eq!(files[0].0, files[1].0);
+    }
+
+    #[test]
+    fn extract_changes() {
+        let image = ImageBuilder::new()
+            .file("a/old", "old")
+            .file("a/b/new", "new")
+            .mtime("a/b/new", 10)
+            .build();
+        let archive = Archive::new(image).unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        archive
+            .extract_since(dest.path(), Utc.timestamp_opt(0, 0).unwrap())
+            .unwrap();
+        assert_eq!(fs::read(dest.path().join("a/b/new")).unwrap(), b"new");
+        assert!(!dest.path().join("a/old").exists());
+    }
+
+    #[test]
+    fn unsupported_entries() {
+        let archive = Archive::new(testing::all_inode_kinds()).unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        archive.extract(dest.path()).unwrap();
+        assert_eq!(
+            fs::read(dest.path().join("file")).unwrap(),
+            testing::HELLO_CONTENTS
+        );
+        assert!(dest.path().join("dir").is_dir());
+        for name in &["block", "char", "fifo", "socket"] {
+            assert!(fs::symlink_metadata(dest.path().join(name)).is_err());
+        }
+        #[cfg(unix)]
+        assert!(fs::symlink_metadata(dest.path().join("symlink"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+
+        let dest = tempfile::tempdir().unwrap();
+        let err = archive
+            .extract_with(dest.path(), UnsupportedEntryPolicy::Error)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            r#"Read error: "/block": extracting block devices is not supported"#
+        );
+    }
+
+    #[test]
+    fn read_only() {
+        let image = ImageBuilder::new()
+            .file("ro", "ro")
+            .mode("ro", Mode::from_unix(0o444))
+            .file("rw", "rw")
+            .build();
+        let archive = Archive::new(image).unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        archive.extract(dest.path()).unwrap();
+        let read_only = |name| {
+            let metadata = fs::metadata(dest.path().join(name)).unwrap();
+            metadata.permissions().readonly()
+        };
+        assert!(read_only("ro"));
+        assert!(!read_only("rw"));
+        set_permissions(&dest.path().join("ro"), Mode::O644).unwrap();
+    }
+
+    #[test]
+    fn unsafe_paths() {
+        let dest = Path::new("dest");
+        assert_eq!(dest_path(dest, b"/").unwrap(), dest);
+        assert_eq!(dest_path(dest, b"/a/b").unwrap(), dest.join("a").join("b"));
+        for path in &[&b"/a/../../b"[..], b"/.."] {
+            assert!(dest_path(dest, path).is_err(), "{:?}", path.as_bstr());
+        }
+        #[cfg(windows)]
+        assert!(dest_path(dest, b"/a\\..\\..\\b").is_err());
+    }
+}
@@ -0,0 +1,139 @@
+//! Which optional parts of the format an archive uses
+//!
+//! Every archive this crate reads is squashfs 4.0, which linux has read since 2.6.29, but
+//! extended attributes and most compressors were added in later releases. [`Features`] lists
+//! what an archive relies on, so tooling can check it against the kernels it's deployed to
+//! before it fails to mount.
+
+use repr::superblock::{Flags, VERSION_MAJOR, VERSION_MINOR};
+
+use super::Archive;
+use crate::compression;
+
+/// The optional structures an archive uses, see [`Archive::features`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Features {
+    /// The `(major, minor)` version of the format
+    pub version: (u16, u16),
+    pub compression: compression::Kind,
+    /// Whether the compression options are stored, rather than implied by the compressor
+    pub compression_options: bool,
+    /// Whether any inode has extended attributes
+    pub xattrs: bool,
+    /// Whether an export table maps inode numbers to inodes, for exporting over NFS
+    pub export_table: bool,
+    /// Whether any file ends in a fragment
+    pub fragments: bool,
+    /// Whether files larger than a block may end in a fragment too
+    pub always_fragments: bool,
+    /// Whether identical files are only stored once
+    pub duplicates: bool,
+    /// The sections flagged as stored uncompressed, out of `inodes`, `data`, `fragments`,
+    /// `xattrs` and `ids`
+    pub uncompressed: Vec<&'static str>,
+}
+
+impl Features {
+    /// The first mainline linux release which can mount the archive, as `(major, minor,
+    /// patch)`, or `None` if none can
+    ///
+    /// Based on when each structure and compressor was added: xattrs in 2.6.35, lzo in 2.6.36,
+    /// xz and compression options in 2.6.38, lz4 in 3.19 and zstd in 4.14. Lzma was never
+    /// merged. Kernels may also be built without some compressors, or without xz's
+    /// branch/call/jump filters, so this is a lower bound.
+    pub fn minimum_kernel(&self) -> Option<(u32, u32, u32)> {
+        if self.version != (VERSION_MAJOR, VERSION_MINOR) {
+            return None;
+        }
+        let compressor = match self.compression {
+            compression::Kind::ZLib => (2, 6, 29),
+            compression::Kind::Lzo => (2, 6, 36),
+            compression::Kind::Xz => (2, 6, 38),
+            compression::Kind::Lz4 => (3, 19, 0),
+            compression::Kind::Zstd => (4, 14, 0),
+            compression::Kind::Lzma | compression::Kind::Unknown => return None,
+        };
+        let required = [
+            (true, compressor),
+            (self.xattrs, (2, 6, 35)),
+            (self.compression_options, (2, 6, 38)),
+        ];
+        required
+            .iter()
+            .filter(|&&(used, _)| used)
+            .map(|&(_, version)| version)
+            .max()
+    }
+}
+
+impl<R> Archive<R> {
+    /// The optional structures the archive uses, see [`Features`]
+    ///
+    /// Only the superblock is consulted, so this is cheap, and works on archives opened
+    /// truncated.
+    pub fn features(&self) -> Features {
+        let superblock = self.superblock();
+        let flags = { superblock.flags };
+        let uncompressed = [
+            ("inodes", Flags::UNCOMPRESSED_INODES),
+            ("data", Flags::UNCOMPRESSED_DATA),
+            ("fragments", Flags::UNCOMPRESSED_FRAGMENTS),
+            ("xattrs", Flags::UNCOMPRESSED_XATTRS),
+            ("ids", Flags::UNCOMPRESSED_IDS),
+        ];
+        Features {
+            version: (superblock.version_major, superblock.version_minor),
+            compression: self.compression().kind(),
+            compression_options: flags.contains(Flags::COMPRESSOR_OPTIONS),
+            xattrs: superblock.xattr_id_table_start != u64::MAX,
+            export_table: superblock.export_table_start != u64::MAX,
+            fragments: superblock.fragment_entry_count > 0,
+            always_fragments: flags.contains(Flags::ALWAYS_FRAGMENTS),
+            duplicates: flags.contains(Flags::DUPLICATES),
+            uncompressed: uncompressed
+                .iter()
+                .filter(|&&(_, flag)| flags.contains(flag))
+                .map(|&(name, _)| name)
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::write::ArchiveBuilder;
+    use std::io;
+
+    #[test]
+    fn features() {
+        let build = |xattr: bool| {
+            let mut builder = ArchiveBuilder::new();
+            builder.compressed_data = false;
+            let (mut archive, image) = builder.build_in_memory();
+            let mut file = archive.create_file();
+            file.set_contents(Box::new(io::Cursor::new("contents")));
+            if xattr {
+                file.set_xattr("user.comment", "hello").unwrap();
+            }
+            let file = file.finish(&mut archive).unwrap();
+            let mut root = archive.create_dir();
+            root.add_item("file", file);
+            let root = root.finish(&mut archive);
+            archive.set_root(root);
+            archive.flush().unwrap();
+            image.open().unwrap().features()
+        };
+
+        let plain = build(false);
+        assert_eq!(plain.version, (4, 0));
+        assert!(!plain.xattrs);
+        assert!(plain.export_table);
+        assert!(plain.fragments);
+        assert_eq!(plain.uncompressed, ["data"]);
+        assert_eq!(plain.minimum_kernel(), Some((2, 6, 29)));
+
+        let with_xattrs = build(true);
+        assert!(with_xattrs.xattrs);
+        assert_eq!(with_xattrs.minimum_kernel(), Some((2, 6, 35)));
+    }
+}
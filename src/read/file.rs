@@ -0,0 +1,160 @@
+use std::convert::TryFrom;
+
+use super::inode::FileInfo;
+use super::{ArchiveInner, ReadAt};
+use crate::errors::{ReadError, Result};
+
+impl<R: ReadAt> ArchiveInner<R> {
+    pub(crate) fn read_file(&self, file: &FileInfo) -> Result<Vec<u8>> {
+        let file_size = file.file_size as usize;
+        let mut result = Vec::with_capacity(file_size);
+
+        for extent in file.block_map(self.superblock.block_size).extents() {
+            result.extend_from_slice(&self.read_extent(&extent)?);
+        }
+
+        if result.len() != file_size {
+            return Err(ReadError::TruncatedFile {
+                expected: file.file_size,
+                actual: result.len() as u64,
+            }
+            .into());
+        }
+        Ok(result)
+    }
+
+    /// The range of the source holding a file's data blocks, or its fragment block if it has no
+    /// data blocks, as an offset and length
+    pub(crate) fn data_range(&self, file: &FileInfo) -> Result<(u64, u64)> {
+        if !file.block_sizes.is_empty() {
+            let len = file
+                .block_sizes
+                .iter()
+                .map(|size| u64::from(size.size()))
+                .sum();
+            return Ok((file.blocks_start, len));
+        }
+        match file.fragment {
+            Some(fragment) => {
+                let entry = self.fragment(fragment.index)?;
+                Ok((entry.start.0, { entry.size }.size().into()))
+            }
+            None => Ok((file.blocks_start, 0)),
+        }
+    }
+
+    /// Read the (possibly compressed) data block at `offset`, which is expected to hold `len`
+    /// bytes: the block size, or less for the last block of a file
+    ///
+    /// Returns an empty vec for a sparse block
+    pub(crate) fn read_datablock(
+        &self,
+        offset: u64,
+        size: repr::datablock::Size,
+        len: usize,
+    ) -> Result<Vec<u8>> {
+        let mut data = vec![0; size.size() as usize];
+        self.source.read_exact_at(&mut data, offset)?;
+        if size.uncompressed() || data.is_empty() {
+            return Ok(data);
+        }
+        let block_size = self.superblock.block_size as usize;
+        Ok(self.decompress_sized(&data, len, block_size)?)
+    }
+}
+
+impl FileInfo {
+    /// The first offset at or after `offset` which is in a hole (if `hole`) or in data
+    pub(crate) fn seek(&self, block_size: u32, offset: u64, hole: bool) -> Option<u64> {
+        if offset >= self.file_size {
+            return None;
+        }
+        let block_size = u64::from(block_size);
+        let first = usize::try_from(offset / block_size).unwrap_or(usize::MAX);
+        for (i, size) in self.block_sizes.iter().enumerate().skip(first) {
+            if (size.size() == 0) == hole {
+                return Some(offset.max(i as u64 * block_size));
+            }
+        }
+        // Everything past the data blocks is in the fragment, followed by the implicit hole at
+        // the end of the file
+        let blocks_end = self.block_sizes.len() as u64 * block_size;
+        if hole {
+            Some(self.file_size)
+        } else if blocks_end < self.file_size {
+            Some(offset.max(blocks_end))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read::inode::Fragment;
+    use crate::read::{Extent, ExtentKind};
+    use repr::datablock::Size;
+
+    #[test]
+    fn seek_extents() {
+        // data, hole, hole, data, then a fragment
+        let data = Size::new(10, false);
+        let file = FileInfo {
+            blocks_start: 0,
+            file_size: 4 * 4096 + 100,
+            sparse: 2 * 4096,
+            fragment: Some(Fragment {
+                index: 0,
+                offset: 0,
+            }),
+            block_sizes: vec![data, Size::ZERO, Size::ZERO, data],
+        };
+        let seek_data = |offset| file.seek(4096, offset, false);
+        let seek_hole = |offset| file.seek(4096, offset, true);
+
+        assert_eq!(seek_data(0), Some(0));
+        assert_eq!(seek_data(100), Some(100));
+        assert_eq!(seek_data(4096), Some(3 * 4096));
+        assert_eq!(seek_data(4 * 4096 + 50), Some(4 * 4096 + 50));
+        assert_eq!(seek_data(4 * 4096 + 100), None);
+
+        assert_eq!(seek_hole(0), Some(4096));
+        assert_eq!(seek_hole(5000), Some(5000));
+        assert_eq!(seek_hole(3 * 4096), Some(4 * 4096 + 100));
+        assert_eq!(seek_hole(4 * 4096 + 100), None);
+
+        let empty = FileInfo {
+            file_size: 0,
+            fragment: None,
+            block_sizes: Vec::new(),
+            ..file
+        };
+        assert_eq!(empty.seek(4096, 0, false), None);
+    }
+
+    #[test]
+    fn sized_datablocks() {
+        let contents = crate::testing::compressible_contents();
+        let image = crate::testing::ImageBuilder::new()
+            .file("file", contents.clone())
+            .build();
+        let archive = crate::read::Archive::new(image).unwrap();
+        let file = archive.walk().nth(1).unwrap().unwrap();
+        let (offset, size) = match archive.block_map(file.inode()).unwrap().locate(0) {
+            Some(Extent {
+                kind: ExtentKind::Block { offset, size, .. },
+                ..
+            }) => (offset, size),
+            extent => panic!("{:?}", extent),
+        };
+        assert!(!size.uncompressed());
+
+        let block = archive.inner.read_datablock(offset, size, 4096).unwrap();
+        assert_eq!((block.len(), block.capacity()), (4096, 4096));
+        // Too small a hint still reads the whole block
+        let block = archive.inner.read_datablock(offset, size, 100).unwrap();
+        assert_eq!(block, contents[..4096]);
+        assert_eq!(archive.read_file(file.inode()).unwrap(), contents);
+    }
+}
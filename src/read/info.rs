@@ -0,0 +1,205 @@
+//! Statistics about the layout of an archive
+
+use std::mem;
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use super::{Archive, ArchiveInner, InodeData, ReadAt};
+use crate::errors::Result;
+
+/// Statistics about an archive, see [`Archive::info`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ArchiveInfo {
+    inode_count: u32,
+    modification_time: u32,
+    directory_count: u64,
+    metadata_block_counts: MetadataBlockCounts,
+}
+
+impl ArchiveInfo {
+    pub fn inode_count(&self) -> u32 {
+        self.inode_count
+    }
+
+    /// The raw modification time of the archive, in seconds since the unix epoch
+    pub fn modification_time(&self) -> u32 {
+        self.modification_time
+    }
+
+    /// The time the archive was created
+    ///
+    /// The timestamp is unsigned, so archives can't be dated before 1970, but can be dated
+    /// up to 2106.
+    pub fn created(&self) -> DateTime<Utc> {
+        Utc.timestamp_opt(self.modification_time.into(), 0).unwrap()
+    }
+
+    pub fn directory_count(&self) -> u64 {
+        self.directory_count
+    }
+
+    /// Statistics about the inode and directory tables, which determine how quickly paths can
+    /// be looked up
+    pub fn metadata_block_counts(&self) -> &MetadataBlockCounts {
+        &self.metadata_block_counts
+    }
+}
+
+/// Statistics about the metadata blocks of the inode and directory tables
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetadataBlockCounts {
+    /// Number of metadata blocks in the inode table
+    pub inode_blocks: u64,
+    /// Number of metadata blocks in the directory table
+    pub directory_blocks: u64,
+    /// Number of directory headers, across all directories
+    pub directory_headers: u64,
+    /// Number of directory entries, across all directories
+    pub directory_entries: u64,
+    /// Number of directories whose listing spans more than one metadata block
+    pub large_directories: u64,
+    /// Number of large directories which have an index for faster lookups
+    pub indexed_directories: u64,
+}
+
+impl MetadataBlockCounts {
+    /// The average number of entries sharing a directory header
+    ///
+    /// The kernel can skip over a header without reading its entries, so fewer, larger groups
+    /// are faster to search.
+    pub fn average_entries_per_header(&self) -> f64 {
+        if self.directory_headers == 0 {
+            return 0.0;
+        }
+        self.directory_entries as f64 / self.directory_headers as f64
+    }
+
+    /// The fraction of large directories which have an index, `1.0` if there are none
+    pub fn index_coverage(&self) -> f64 {
+        if self.large_directories == 0 {
+            return 1.0;
+        }
+        self.indexed_directories as f64 / self.large_directories as f64
+    }
+}
+
+impl<R: ReadAt> Archive<R> {
+    /// Gather statistics about the archive
+    ///
+    /// This reads every directory in the archive.
+    pub fn info(&self) -> Result<ArchiveInfo> {
+        let inner = &*self.inner;
+        let superblock = &inner.superblock;
+        let mut counts = MetadataBlockCounts {
+            inode_blocks: inner.count_metablocks(
+                superblock.inode_table_start,
+                superblock.directory_table_start,
+            )?,
+            directory_blocks: inner.count_metablocks(
+                superblock.directory_table_start,
+                inner.directory_table_end()?,
+            )?,
+            ..MetadataBlockCounts::default()
+        };
+
+        let mut directory_count = 0;
+        for entry in self.walk() {
+            let entry = entry?;
+            let dir = match entry.inode().data() {
+                InodeData::Directory(dir) => dir,
+                _ => continue,
+            };
+            directory_count += 1;
+            let (entries, headers) = inner.read_listing(dir)?;
+            counts.directory_headers += headers as u64;
+            counts.directory_entries += entries.len() as u64;
+            if dir.listing_size as usize > repr::metablock::SIZE {
+                counts.large_directories += 1;
+                if !dir.index.is_empty() {
+                    counts.indexed_directories += 1;
+                }
+            }
+        }
+
+        Ok(ArchiveInfo {
+            inode_count: superblock.inode_count,
+            modification_time: superblock.modification_time.0,
+            directory_count,
+            metadata_block_counts: counts,
+        })
+    }
+}
+
+impl<R: ReadAt> ArchiveInner<R> {
+    /// Count the metadata blocks stored between `start` and `end`
+    fn count_metablocks(&self, start: u64, end: u64) -> Result<u64> {
+        let mut count = 0;
+        let mut offset = start;
+        while offset < end {
+            let mut header = [0; mem::size_of::<repr::metablock::Header>()];
+            self.source.read_exact_at(&mut header, offset)?;
+            offset += header.len() as u64;
+            let header: repr::metablock::Header = repr::read(&header[..])?;
+            offset += u64::from(header.size());
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// The end of the directory table, which is the start of the first table following it
+    fn directory_table_end(&self) -> Result<u64> {
+        let superblock = &self.superblock;
+        let mut end = superblock.bytes_used;
+        let mut first_block = |lookup_start: u64, count: u64| -> Result<()> {
+            if lookup_start == u64::MAX || count == 0 {
+                return Ok(());
+            }
+            let mut block = [0; mem::size_of::<u64>()];
+            self.source.read_exact_at(&mut block, lookup_start)?;
+            end = end.min(u64::from_le_bytes(block));
+            Ok(())
+        };
+        first_block(
+            superblock.fragment_table_start,
+            superblock.fragment_entry_count.into(),
+        )?;
+        first_block(superblock.export_table_start, superblock.inode_count.into())?;
+        first_block(superblock.id_table_start, superblock.id_count.into())?;
+        if superblock.xattr_id_table_start != u64::MAX {
+            // The xattr key/value table precedes the xattr id table, and its start is the first
+            // field of the id table's header
+            first_block(superblock.xattr_id_table_start, 1)?;
+        }
+        Ok(end.max(superblock.directory_table_start))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_counts() {
+        let archive = Archive::new(crate::testing::file_and_symlink()).unwrap();
+
+        let info = archive.info().unwrap();
+        assert_eq!(info.inode_count(), 3);
+        assert_eq!(info.directory_count(), 1);
+        assert_eq!(info.modification_time(), 0);
+        assert_eq!(info.created(), Utc.timestamp_opt(0, 0).unwrap());
+        let counts = info.metadata_block_counts();
+        assert_eq!(
+            *counts,
+            MetadataBlockCounts {
+                inode_blocks: 1,
+                directory_blocks: 1,
+                directory_headers: 1,
+                directory_entries: 2,
+                large_directories: 0,
+                indexed_directories: 0,
+            }
+        );
+        assert_eq!(counts.average_entries_per_header(), 2.0);
+        assert_eq!(counts.index_coverage(), 1.0);
+    }
+}
@@ -0,0 +1,397 @@
+use bstr::BString;
+use chrono::{DateTime, TimeZone, Utc};
+use repr::inode::Kind;
+use repr::offset::ArchiveOffset;
+
+use super::metablock::Cursor;
+use super::{ArchiveInner, ReadAt};
+use crate::errors::{ReadError, Result};
+use crate::{FileType, Mode};
+
+/// The most block sizes of a file allocated for before they're read
+const PREALLOCATED_BLOCKS: u64 = 1 << 16;
+
+/// An inode read from an archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Inode {
+    kind: Kind,
+    permissions: Mode,
+    uid: u32,
+    gid: u32,
+    mtime: u32,
+    inode_number: u32,
+    hard_link_count: u32,
+    xattr_idx: repr::xattr::Idx,
+    data: InodeData,
+}
+
+/// The type specific contents of an [`Inode`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InodeData {
+    Directory(DirInfo),
+    File(FileInfo),
+    Symlink(BString),
+    BlockDevice(repr::inode::DeviceNumber),
+    CharDevice(repr::inode::DeviceNumber),
+    Fifo,
+    Socket,
+}
+
+/// The location of a directory's listing in the directory table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirInfo {
+    pub(crate) block_start: u32,
+    pub(crate) block_offset: u16,
+    /// Size of the listing, not including the 3 bytes for the implicit `.` and `..`
+    pub(crate) listing_size: u32,
+    /// The size as stored in the inode, which should include the implicit entries
+    pub(crate) stored_size: u32,
+    pub(crate) parent_inode_number: u32,
+    pub(crate) index: Vec<DirIndex>,
+}
+
+/// An entry of an extended directory's index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirIndex {
+    pub(crate) index: u32,
+    pub(crate) start: u32,
+    pub(crate) name: BString,
+}
+
+/// The location of a file's data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    pub(crate) blocks_start: u64,
+    pub(crate) file_size: u64,
+    pub(crate) sparse: u64,
+    pub(crate) fragment: Option<Fragment>,
+    pub(crate) block_sizes: Vec<repr::datablock::Size>,
+}
+
+/// The location of the tail end of a file stored in a fragment block
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Fragment {
+    pub(crate) index: u32,
+    pub(crate) offset: u32,
+}
+
+impl Inode {
+    /// The on-disk inode type. Basic and extended types are distinct
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    /// The type of the inode, without the basic/extended distinction of [`kind`](Self::kind)
+    pub fn file_type(&self) -> FileType {
+        self.data.file_type()
+    }
+
+    /// The full mode of the inode, including the file type bits
+    pub fn mode(&self) -> Mode {
+        self.permissions.perm() | self.file_type().mode_type()
+    }
+
+    pub fn permissions(&self) -> Mode {
+        self.permissions.perm()
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    /// The modification time, as seconds since the unix epoch
+    pub fn mtime(&self) -> u32 {
+        self.mtime
+    }
+
+    pub fn modified_time(&self) -> DateTime<Utc> {
+        Utc.timestamp_opt(self.mtime.into(), 0).unwrap()
+    }
+
+    /// The unique number of this inode, in the range `1..=inode_count`
+    pub fn inode_number(&self) -> u32 {
+        self.inode_number
+    }
+
+    pub fn hard_link_count(&self) -> u32 {
+        self.hard_link_count
+    }
+
+    pub fn xattr_idx(&self) -> repr::xattr::Idx {
+        self.xattr_idx
+    }
+
+    pub fn data(&self) -> &InodeData {
+        &self.data
+    }
+
+    pub fn is_dir(&self) -> bool {
+        matches!(self.data, InodeData::Directory(_))
+    }
+
+    pub fn is_file(&self) -> bool {
+        matches!(self.data, InodeData::File(_))
+    }
+
+    /// The size of a regular file's contents, or `None` for other kinds of inode
+    pub fn file_size(&self) -> Option<u64> {
+        match &self.data {
+            InodeData::File(file) => Some(file.file_size),
+            _ => None,
+        }
+    }
+
+    /// The inode number of a directory's parent, or `None` for other kinds of inode
+    ///
+    /// The root directory's parent is conventionally one past the last inode, which
+    /// [`Archive::inode_by_number`](super::Archive::inode_by_number) won't find.
+    pub fn parent_inode_number(&self) -> Option<u32> {
+        match &self.data {
+            InodeData::Directory(dir) => Some(dir.parent_inode_number),
+            _ => None,
+        }
+    }
+
+    /// The target of a symlink, or `None` for other kinds of inode
+    pub fn symlink_target(&self) -> Option<&BString> {
+        match &self.data {
+            InodeData::Symlink(target) => Some(target),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_dir(&self) -> Result<&DirInfo> {
+        match &self.data {
+            InodeData::Directory(dir) => Ok(dir),
+            _ => Err(ReadError::NotADirectory.into()),
+        }
+    }
+
+    pub(crate) fn as_file(&self) -> Result<&FileInfo> {
+        match &self.data {
+            InodeData::File(file) => Ok(file),
+            _ => Err(ReadError::NotAFile.into()),
+        }
+    }
+}
+
+impl InodeData {
+    pub fn file_type(&self) -> FileType {
+        match self {
+            InodeData::Directory(_) => FileType::Dir,
+            InodeData::File(_) => FileType::File,
+            InodeData::Symlink(_) => FileType::Symlink,
+            InodeData::BlockDevice(_) => FileType::BlockDev,
+            InodeData::CharDevice(_) => FileType::CharDev,
+            InodeData::Fifo => FileType::Fifo,
+            InodeData::Socket => FileType::Socket,
+        }
+    }
+}
+
+impl FileInfo {
+    /// The number of data blocks (not counting the fragment) used by the file
+    pub(crate) fn block_count(&self) -> usize {
+        self.block_sizes.len()
+    }
+}
+
+fn fragment(idx: repr::fragment::Idx, offset: u32) -> Option<Fragment> {
+    if idx.0 == u32::MAX {
+        None
+    } else {
+        Some(Fragment {
+            index: idx.0,
+            offset,
+        })
+    }
+}
+
+impl<R: ReadAt> ArchiveInner<R> {
+    pub(crate) fn read_inode(&self, inode_ref: repr::inode::Ref) -> Result<Inode> {
+        self.read_inode_with(inode_ref, false)
+    }
+
+    /// Read an inode, pinning the blocks it is read from in the cache if `pin`
+    pub(crate) fn read_inode_with(&self, inode_ref: repr::inode::Ref, pin: bool) -> Result<Inode> {
+        let block = ArchiveOffset(self.superblock.inode_table_start) + inode_ref.table_offset();
+        let mut cursor = Cursor::with_pinning(self, block, inode_ref.start_offset(), pin)?;
+        self.parse_inode(&mut cursor)
+    }
+
+    /// Parse the inode at the cursor, leaving the cursor just past its end
+    pub(crate) fn parse_inode(&self, cursor: &mut Cursor<'_, R>) -> Result<Inode> {
+        let header: repr::inode::Header = cursor.read()?;
+        let kind = header.inode_type;
+        let mut xattr_idx = repr::xattr::Idx::NONE;
+
+        let (hard_link_count, data) = match kind {
+            Kind::BASIC_DIR => {
+                let dir: repr::inode::BasicDir = cursor.read()?;
+                let info = DirInfo {
+                    block_start: dir.dir_block_start,
+                    block_offset: dir.block_offset,
+                    listing_size: u32::from(dir.file_size).saturating_sub(3),
+                    stored_size: dir.file_size.into(),
+                    parent_inode_number: dir.parent_inode_number.0,
+                    index: Vec::new(),
+                };
+                (dir.hard_link_count, InodeData::Directory(info))
+            }
+            Kind::EXT_DIR => {
+                let dir: repr::inode::ExtendedDir = cursor.read()?;
+                xattr_idx = dir.xattr_idx;
+                let mut index = Vec::with_capacity(dir.index_count.into());
+                for _ in 0..dir.index_count {
+                    let raw: repr::directory::Index = cursor.read()?;
+                    let name_size = raw.name_size as usize + 1;
+                    index.push(DirIndex {
+                        index: raw.index,
+                        start: raw.start,
+                        name: cursor.read_vec(name_size)?.into(),
+                    });
+                }
+                let info = DirInfo {
+                    block_start: dir.dir_block_start,
+                    block_offset: dir.block_offset,
+                    listing_size: dir.file_size.saturating_sub(3),
+                    stored_size: dir.file_size,
+                    parent_inode_number: dir.parent_inode_number.0,
+                    index,
+                };
+                (dir.hard_link_count, InodeData::Directory(info))
+            }
+            Kind::BASIC_FILE => {
+                let file: repr::inode::BasicFile = cursor.read()?;
+                let fragment = fragment(file.fragment_block_index, file.block_offset);
+                let file_size = u64::from(file.file_size);
+                let block_sizes = self.read_block_sizes(cursor, file_size, fragment)?;
+                let info = FileInfo {
+                    blocks_start: file.blocks_start.into(),
+                    file_size,
+                    sparse: 0,
+                    fragment,
+                    block_sizes,
+                };
+                (1, InodeData::File(info))
+            }
+            Kind::EXT_FILE => {
+                let file: repr::inode::ExtendedFile = cursor.read()?;
+                xattr_idx = file.xattr_idx;
+                let fragment = fragment(file.fragment_block_index, file.block_offset);
+                let block_sizes = self.read_block_sizes(cursor, file.file_size, fragment)?;
+                let info = FileInfo {
+                    blocks_start: file.blocks_start.0,
+                    file_size: file.file_size,
+                    sparse: file.sparse,
+                    fragment,
+                    block_sizes,
+                };
+                (file.hard_link_count, InodeData::File(info))
+            }
+            Kind::BASIC_SYMLINK | Kind::EXT_SYMLINK => {
+                let symlink: repr::inode::Symlink = cursor.read()?;
+                let target_size = symlink.target_size as usize;
+                if target_size > repr::limits::MAX_SYMLINK_TARGET {
+                    return Err(ReadError::LimitExceeded {
+                        what: "symlink target length",
+                        actual: target_size as u64,
+                        max: repr::limits::MAX_SYMLINK_TARGET as u64,
+                    }
+                    .into());
+                }
+                let target = cursor.read_vec(target_size)?;
+                if kind == Kind::EXT_SYMLINK {
+                    xattr_idx = cursor.read()?;
+                }
+                (symlink.hard_link_count, InodeData::Symlink(target.into()))
+            }
+            Kind::BASIC_BLOCK_DEV | Kind::BASIC_CHAR_DEV => {
+                let dev: repr::inode::BasicDevice = cursor.read()?;
+                (dev.hard_link_count, device_data(kind, dev.device))
+            }
+            Kind::EXT_BLOCK_DEV | Kind::EXT_CHAR_DEV => {
+                let dev: repr::inode::ExtendedDevice = cursor.read()?;
+                xattr_idx = dev.xattr_idx;
+                (dev.hard_link_count, device_data(kind, dev.device))
+            }
+            Kind::BASIC_FIFO | Kind::BASIC_SOCKET => {
+                let ipc: repr::inode::BasicIpc = cursor.read()?;
+                (ipc.hard_link_count, ipc_data(kind))
+            }
+            Kind::EXT_FIFO | Kind::EXT_SOCKET => {
+                let ipc: repr::inode::ExtendedIpc = cursor.read()?;
+                xattr_idx = ipc.xattr_idx;
+                (ipc.hard_link_count, ipc_data(kind))
+            }
+            Kind(other) => return Err(ReadError::UnknownInodeKind(other).into()),
+        };
+
+        Ok(Inode {
+            kind,
+            permissions: header.permissions,
+            uid: self.id(header.uid_idx)?,
+            gid: self.id(header.gid_idx)?,
+            mtime: header.modified_time.0,
+            inode_number: header.inode_number.0,
+            hard_link_count,
+            xattr_idx,
+            data,
+        })
+    }
+
+    fn read_block_sizes(
+        &self,
+        cursor: &mut Cursor<'_, R>,
+        file_size: u64,
+        fragment: Option<Fragment>,
+    ) -> Result<Vec<repr::datablock::Size>> {
+        if file_size > repr::limits::MAX_FILE_SIZE {
+            return Err(ReadError::LimitExceeded {
+                what: "file size",
+                actual: file_size,
+                max: repr::limits::MAX_FILE_SIZE,
+            }
+            .into());
+        }
+        let block_size = self.superblock.block_size;
+        let count = repr::limits::block_count(file_size, block_size, fragment.is_some());
+        // The list is only as long as the inode table allows, which a corrupt size can't tell
+        let mut sizes = Vec::with_capacity(count.min(PREALLOCATED_BLOCKS) as usize);
+        for _ in 0..count {
+            let size: repr::datablock::Size = cursor.read()?;
+            if size.size() > self.superblock.block_size {
+                return Err(ReadError::HugeDatablock(size.size()).into());
+            }
+            sizes.push(size);
+        }
+        Ok(sizes)
+    }
+
+    fn id(&self, idx: repr::uid_gid::Idx) -> Result<u32> {
+        self.ids
+            .get(usize::from(idx.0))
+            .copied()
+            .ok_or_else(|| ReadError::IdIndexOutOfRange(idx.0).into())
+    }
+}
+
+fn device_data(kind: Kind, device: repr::inode::DeviceNumber) -> InodeData {
+    if kind.to_basic() == Kind::BASIC_BLOCK_DEV {
+        InodeData::BlockDevice(device)
+    } else {
+        InodeData::CharDevice(device)
+    }
+}
+
+fn ipc_data(kind: Kind) -> InodeData {
+    if kind.to_basic() == Kind::BASIC_FIFO {
+        InodeData::Fifo
+    } else {
+        InodeData::Socket
+    }
+}
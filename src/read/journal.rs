@@ -0,0 +1,421 @@
+//! Resuming an interrupted extraction
+//!
+//! Extracting a large image can take long enough that it's worth not starting over when it's
+//! interrupted. With a [`ExtractJournal`], each entry is recorded in a journal file once it's
+//! complete on disk, and large files are also recorded every [`CHECKPOINT`] bytes. Extracting
+//! into the same destination with the same journal skips the entries it lists, and continues
+//! partly written files from their last checkpoint.
+//!
+//! Skipped files are checked against the size the journal records, and a sample of them is
+//! also hashed, so files damaged or changed since aren't trusted blindly, without reading back
+//! everything that was already extracted. A file which fails its check is extracted again.
+//!
+//! The journal is text: a header line, then a line per record, which later records of the same
+//! path replace:
+//!
+//! ```text
+//! sqfs-extract-journal 1
+//! partial 67108864 /usr/lib/big.so
+//! done 90000000 0123456789abcdef0123456789abcdef /usr/lib/big.so
+//! done 0 - /usr/lib/link.so
+//! ```
+//!
+//! Files are recorded with their size and 128 bit xxh3 hash, other entries with `-`. Backslashes
+//! and newlines in paths are escaped with a backslash.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use bstr::{BStr, BString, ByteSlice};
+use slog::Logger;
+use xxhash_rust::xxh3::Xxh3;
+
+use super::{Archive, Inode, ReadAt};
+use crate::errors::{ReadError, Result};
+
+/// The first line of a journal file
+const JOURNAL_HEADER: &str = "sqfs-extract-journal 1";
+
+/// How many bytes of a file are written between records of its progress
+const CHECKPOINT: u64 = 64 << 20;
+
+/// Where to keep the journal of an extraction, see [`ExtractOptions::journal`]
+///
+/// [`ExtractOptions::journal`]: super::ExtractOptions::journal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtractJournal {
+    /// The journal file, which is created if it doesn't exist
+    pub path: PathBuf,
+    /// Hash one in this many of the files an earlier extraction completed, to check they're
+    /// intact: 1 hashes every file, and 0 only checks their sizes
+    pub spot_check_interval: u32,
+}
+
+impl ExtractJournal {
+    /// Keep the journal in `path`, hashing one in 16 completed files when resuming
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        ExtractJournal {
+            path: path.into(),
+            spot_check_interval: 16,
+        }
+    }
+}
+
+/// The last record of a path
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Record {
+    /// The entry is complete, holding `size` bytes of file contents with `hash`, if it's a file
+    Done { size: u64, hash: Option<u128> },
+    /// The first `offset` bytes of the file are written
+    Partial { offset: u64 },
+}
+
+/// An open journal, see [`ExtractJournal`]
+pub(crate) struct Journal {
+    file: File,
+    records: HashMap<BString, Record>,
+    spot_check_interval: u32,
+    /// The number of completed files checked since the last was hashed
+    checked: u32,
+    logger: Logger,
+}
+
+impl Journal {
+    /// Open the journal, reading the records of an earlier extraction if there are any
+    pub(crate) fn open(options: &ExtractJournal, logger: Logger) -> Result<Self> {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&options.path)?;
+        let records = if file.metadata()?.len() == 0 {
+            writeln!(file, "{}", JOURNAL_HEADER)?;
+            HashMap::new()
+        } else {
+            read_records(BufReader::new(&file))?
+        };
+        if !records.is_empty() {
+            slog::info!(logger, "Resuming extraction"; "recorded" => records.len());
+        }
+        Ok(Journal {
+            file,
+            records,
+            spot_check_interval: options.spot_check_interval,
+            checked: 0,
+            logger,
+        })
+    }
+
+    /// Whether an earlier extraction completed the entry at `path`, extracted to `disk`,
+    /// checking a file's size, and sometimes its hash
+    pub(crate) fn completed(&mut self, path: &BStr, disk: &Path) -> io::Result<bool> {
+        let (size, hash) = match self.records.get(path) {
+            Some(&Record::Done { size, hash }) => (size, hash),
+            _ => return Ok(false),
+        };
+        let metadata = match fs::symlink_metadata(disk) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let hash = match hash {
+            Some(hash) => hash,
+            None => return Ok(true),
+        };
+        let intact = if metadata.len() != size {
+            false
+        } else {
+            self.checked += 1;
+            if self.checked == self.spot_check_interval {
+                self.checked = 0;
+                hash_prefix(&mut File::open(disk)?, size)? == hash
+            } else {
+                true
+            }
+        };
+        if !intact {
+            slog::warn!(self.logger, "Extracting changed file again"; "path" => %path);
+        }
+        Ok(intact)
+    }
+
+    /// Write the contents of `file`, the entry at `path`, to `disk`, continuing from the last
+    /// checkpoint of an earlier extraction if there was one, and returning its hash
+    ///
+    /// Permissions are left for the caller to set, as a read-only file couldn't be continued.
+    pub(crate) fn write_file<R: ReadAt>(
+        &mut self,
+        archive: &Archive<R>,
+        file: &Inode,
+        path: &BStr,
+        disk: &Path,
+    ) -> Result<u128> {
+        let mut reader = archive.open_file(file)?;
+        let size = reader.file_size();
+        let offset = match self.records.get(path) {
+            Some(&Record::Partial { offset }) if offset <= size => offset,
+            _ => 0,
+        };
+        // A complete file from a run which stopped before recording it may be read-only
+        if offset == 0 {
+            remove_stale(disk)?;
+        }
+        let mut out = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(disk)?;
+        let (mut offset, mut hasher) = if out.metadata()?.len() < offset {
+            (0, Xxh3::new())
+        } else {
+            let mut hasher = Xxh3::new();
+            io::copy(
+                &mut (&mut out).take(offset),
+                &mut Hashing(&mut hasher, io::sink()),
+            )?;
+            (offset, hasher)
+        };
+        out.set_len(offset)?;
+        out.seek(SeekFrom::Start(offset))?;
+        reader.seek(SeekFrom::Start(offset))?;
+
+        while offset < size {
+            let written = io::copy(
+                &mut (&mut reader).take(CHECKPOINT),
+                &mut Hashing(&mut hasher, &mut out),
+            )?;
+            if written == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            offset += written;
+            if offset < size {
+                // The contents must be on disk before the journal says they are
+                out.sync_data()?;
+                self.record(path, Record::Partial { offset })?;
+            }
+        }
+        Ok(hasher.digest128())
+    }
+
+    /// Record the entry at `path` as complete, with its size and hash if it's a file
+    pub(crate) fn done(&mut self, path: &BStr, size: u64, hash: Option<u128>) -> Result<()> {
+        self.record(path, Record::Done { size, hash })
+    }
+
+    fn record(&mut self, path: &BStr, record: Record) -> Result<()> {
+        let mut line = match record {
+            Record::Done {
+                size,
+                hash: Some(hash),
+            } => format!("done {} {:032x} ", size, hash).into_bytes(),
+            Record::Done { size, hash: None } => format!("done {} - ", size).into_bytes(),
+            Record::Partial { offset } => format!("partial {} ", offset).into_bytes(),
+        };
+        escape_into(path, &mut line);
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.records.insert(path.to_owned(), record);
+        Ok(())
+    }
+}
+
+/// Remove whatever an earlier extraction left at `disk`, if anything
+pub(crate) fn remove_stale(disk: &Path) -> io::Result<()> {
+    match fs::remove_file(disk) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn read_records<R: BufRead>(input: R) -> Result<HashMap<BString, Record>> {
+    let mut lines = input.split(b'\n');
+    match lines.next().transpose()? {
+        Some(header) if header == JOURNAL_HEADER.as_bytes() => {}
+        _ => return Err(ReadError::InvalidJournal { line: 1 }.into()),
+    }
+    let mut records = HashMap::new();
+    for (i, line) in lines.enumerate() {
+        let line = line?;
+        match parse_record(&line) {
+            Some((path, record)) => {
+                records.insert(path, record);
+            }
+            None => return Err(ReadError::InvalidJournal { line: i + 2 }.into()),
+        }
+    }
+    Ok(records)
+}
+
+fn parse_record(line: &[u8]) -> Option<(BString, Record)> {
+    let number = |field: &[u8]| field.to_str().ok()?.parse().ok();
+    let mut fields = line.splitn_str(2, " ");
+    let (record, path) = match fields.next()? {
+        b"done" => {
+            let mut fields = fields.next()?.splitn_str(3, " ");
+            let size = number(fields.next()?)?;
+            let hash = match fields.next()? {
+                b"-" => None,
+                hash => Some(u128::from_str_radix(hash.to_str().ok()?, 16).ok()?),
+            };
+            (Record::Done { size, hash }, fields.next()?)
+        }
+        b"partial" => {
+            let mut fields = fields.next()?.splitn_str(2, " ");
+            let offset = number(fields.next()?)?;
+            (Record::Partial { offset }, fields.next()?)
+        }
+        _ => return None,
+    };
+    Some((unescape(path)?, record))
+}
+
+pub(crate) fn escape_into(path: &[u8], out: &mut Vec<u8>) {
+    for &byte in path {
+        match byte {
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            byte => out.push(byte),
+        }
+    }
+}
+
+pub(crate) fn unescape(escaped: &[u8]) -> Option<BString> {
+    let mut path = Vec::with_capacity(escaped.len());
+    let mut bytes = escaped.iter();
+    while let Some(&byte) = bytes.next() {
+        path.push(match byte {
+            b'\\' => match bytes.next()? {
+                b'\\' => b'\\',
+                b'n' => b'\n',
+                _ => return None,
+            },
+            byte => byte,
+        });
+    }
+    Some(path.into())
+}
+
+/// The hash of the first `len` bytes of `file`
+fn hash_prefix(file: &mut File, len: u64) -> io::Result<u128> {
+    let mut hasher = Xxh3::new();
+    io::copy(&mut file.take(len), &mut Hashing(&mut hasher, io::sink()))?;
+    Ok(hasher.digest128())
+}
+
+/// Hashes everything written through it to the inner writer
+struct Hashing<'a, W>(&'a mut Xxh3, W);
+
+impl<W: Write> Write for Hashing<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.1.write(buf)?;
+        self.0.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.1.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = ExtractJournal::new(dir.path().join("journal"));
+        let path = b"/odd\\name\nhere".as_bstr();
+        let mut journal = Journal::open(&options, Logger::root(slog::Discard, slog::o!())).unwrap();
+        journal
+            .record(path, Record::Partial { offset: 10 })
+            .unwrap();
+        journal.done(path, 20, Some(0xabc)).unwrap();
+        journal.done(b"/dir/link".as_bstr(), 0, None).unwrap();
+        drop(journal);
+
+        let journal = Journal::open(&options, Logger::root(slog::Discard, slog::o!())).unwrap();
+        assert_eq!(
+            journal.records[path],
+            Record::Done {
+                size: 20,
+                hash: Some(0xabc)
+            }
+        );
+        assert_eq!(
+            journal.records[b"/dir/link".as_bstr()],
+            Record::Done {
+                size: 0,
+                hash: None
+            }
+        );
+
+        fs::write(&options.path, "something else\n").unwrap();
+        let err = Journal::open(&options, Logger::root(slog::Discard, slog::o!()))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("line 1"), "{}", err);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resume() {
+        use crate::read::ExtractOptions;
+        use crate::testing::ImageBuilder;
+
+        let contents = crate::testing::compressible_contents();
+        let image = ImageBuilder::new()
+            .file("a", "aaaa")
+            .file("dir/big", contents.clone())
+            .symlink("link", "a")
+            .hard_link("b", "a")
+            .build();
+        let archive = Archive::new(image).unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        let journal_dir = tempfile::tempdir().unwrap();
+        let mut journal = ExtractJournal::new(journal_dir.path().join("journal"));
+        journal.spot_check_interval = 1;
+        let options = ExtractOptions {
+            journal: Some(journal.clone()),
+            ..ExtractOptions::default()
+        };
+        archive.extract_with_options(dest.path(), &options).unwrap();
+
+        // A changed file is caught by its hash, a missing one by its absence
+        fs::remove_file(dest.path().join("a")).unwrap();
+        fs::write(dest.path().join("a"), "bbbb").unwrap();
+        fs::remove_file(dest.path().join("dir/big")).unwrap();
+        // Resuming finds the symlink and hard link already made
+        archive.extract_with_options(dest.path(), &options).unwrap();
+        assert_eq!(fs::read(dest.path().join("a")).unwrap(), b"aaaa");
+        assert_eq!(fs::read(dest.path().join("dir/big")).unwrap(), contents);
+
+        // A partly written file is continued from its checkpoint
+        let half = contents.len() / 2;
+        fs::write(dest.path().join("dir/big"), &contents[..half]).unwrap();
+        let mut open = Journal::open(&journal, Logger::root(slog::Discard, slog::o!())).unwrap();
+        let path = b"/dir/big".as_bstr();
+        open.record(
+            path,
+            Record::Partial {
+                offset: half as u64,
+            },
+        )
+        .unwrap();
+        drop(open);
+        archive.extract_with_options(dest.path(), &options).unwrap();
+        assert_eq!(fs::read(dest.path().join("dir/big")).unwrap(), contents);
+        let open = Journal::open(&journal, Logger::root(slog::Discard, slog::o!())).unwrap();
+        let hash = xxhash_rust::xxh3::xxh3_128(&contents);
+        assert_eq!(
+            open.records[path],
+            Record::Done {
+                size: contents.len() as u64,
+                hash: Some(hash)
+            }
+        );
+    }
+}
@@ -0,0 +1,259 @@
+//! Checking an archive for things the kernel tolerates poorly, or not at all
+//!
+//! Unlike [`Archive::verify_tree`], this needs nothing but the archive itself. It's meant for
+//! checking the output of writers under development, and images from third parties.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use bstr::BString;
+
+use super::dir::{HeaderInfo, MAX_ENTRIES_PER_HEADER};
+use super::inode::DirInfo;
+use super::{child_path, Archive, InodeData, ReadAt};
+use crate::errors::Result;
+use crate::FileType;
+
+/// A problem found by [`Archive::lint`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lint {
+    /// A directory header followed by more entries than the kernel accepts
+    OversizedHeader { path: BString, entries: u32 },
+    /// A directory whose size doesn't include the 3 bytes the kernel expects for the implicit
+    /// `.` and `..` entries
+    MissingDotEntries { path: BString, size: u32 },
+    /// A directory whose `..` doesn't refer to the directory containing it
+    WrongParent {
+        path: BString,
+        expected: u32,
+        actual: u32,
+    },
+    /// A fragment block which extends past the end of the image
+    FragmentPastEnd { index: u32, end: u64 },
+    /// An index entry of a directory which doesn't point at a directory header
+    MisplacedIndex { path: BString, offset: u32 },
+    /// A range of an indexed directory's listing which is larger than a metadata block, but
+    /// which has no index entries, so lookups in it must be read through from the start
+    IndexGap { path: BString, start: u32, end: u32 },
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Lint::OversizedHeader { path, entries } => write!(
+                f,
+                "{}: directory header has {} entries, the kernel accepts {}",
+                path, entries, MAX_ENTRIES_PER_HEADER
+            ),
+            Lint::MissingDotEntries { path, size } => write!(
+                f,
+                "{}: directory size {} doesn't include `.` and `..`",
+                path, size
+            ),
+            Lint::WrongParent {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{}: parent inode is {}, should be {}",
+                path, actual, expected
+            ),
+            Lint::FragmentPastEnd { index, end } => {
+                write!(f, "fragment {} ends past the image, at {}", index, end)
+            }
+            Lint::MisplacedIndex { path, offset } => write!(
+                f,
+                "{}: index entry at {} isn't at a directory header",
+                path, offset
+            ),
+            Lint::IndexGap { path, start, end } => write!(
+                f,
+                "{}: listing from {} to {} has no index entries",
+                path, start, end
+            ),
+        }
+    }
+}
+
+impl<R: ReadAt> Archive<R> {
+    /// Check the archive for things the kernel tolerates poorly
+    ///
+    /// Every directory is read, without the limits the reader otherwise enforces on directory
+    /// headers. Problems which stop the archive being read at all are returned as errors.
+    ///
+    /// Returns every problem found, an empty vec means none were.
+    pub fn lint(&self) -> Result<Vec<Lint>> {
+        let inner = &*self.inner;
+        let mut lints = Vec::new();
+
+        let bytes_used = inner.superblock.bytes_used;
+        for (index, fragment) in inner.fragments.iter().enumerate() {
+            let end = fragment.start.0 + u64::from(fragment.size.size());
+            if end > bytes_used {
+                lints.push(Lint::FragmentPastEnd {
+                    index: index as u32,
+                    end,
+                });
+            }
+        }
+
+        let mut seen = HashSet::new();
+        let mut stack = vec![(BString::from("/"), self.root()?, None)];
+        while let Some((path, inode, parent)) = stack.pop() {
+            let dir = match inode.data() {
+                InodeData::Directory(dir) => dir,
+                _ => continue,
+            };
+            if !seen.insert(inode.inode_number()) {
+                continue;
+            }
+            // The root's parent is conventionally one past the last inode, but nothing reads it
+            if let Some(expected) = parent {
+                if dir.parent_inode_number != expected {
+                    lints.push(Lint::WrongParent {
+                        path: path.clone(),
+                        expected,
+                        actual: dir.parent_inode_number,
+                    });
+                }
+            }
+            if dir.stored_size < 3 {
+                lints.push(Lint::MissingDotEntries {
+                    path: path.clone(),
+                    size: dir.stored_size,
+                });
+            }
+
+            let listing = inner.read_raw_listing(dir)?;
+            for header in &listing.headers {
+                if header.count > MAX_ENTRIES_PER_HEADER {
+                    lints.push(Lint::OversizedHeader {
+                        path: path.clone(),
+                        entries: header.count,
+                    });
+                }
+            }
+            lint_index(&path, dir, &listing.headers, &mut lints);
+
+            for entry in listing.entries.iter().rev() {
+                if entry.file_type() == FileType::Dir {
+                    let child = self.inode(entry.inode_ref())?;
+                    let child_path = child_path(&path, entry.name());
+                    stack.push((child_path, child, Some(inode.inode_number())));
+                }
+            }
+        }
+        Ok(lints)
+    }
+}
+
+fn lint_index(path: &BString, dir: &DirInfo, headers: &[HeaderInfo], lints: &mut Vec<Lint>) {
+    if dir.index.is_empty() {
+        return;
+    }
+    let mut start = 0;
+    for index in &dir.index {
+        if !headers.iter().any(|header| header.offset == index.index) {
+            lints.push(Lint::MisplacedIndex {
+                path: path.clone(),
+                offset: index.index,
+            });
+        }
+        check_gap(path, start, index.index, lints);
+        start = index.index;
+    }
+    check_gap(path, start, dir.listing_size, lints);
+}
+
+fn check_gap(path: &BString, start: u32, end: u32, lints: &mut Vec<Lint>) {
+    if end.saturating_sub(start) as usize > repr::metablock::SIZE {
+        lints.push(Lint::IndexGap {
+            path: path.clone(),
+            start,
+            end,
+        });
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::mem;
+
+    #[test]
+    fn kernel_compat() {
+        let mut data = crate::testing::ImageBuilder::new()
+            .uncompressed()
+            .file("hello", crate::testing::HELLO_CONTENTS)
+            .symlink("link", "hello")
+            .build();
+        let archive = Archive::new(data.clone()).unwrap();
+        assert_eq!(archive.lint().unwrap(), []);
+
+        // Shrink the root directory to nothing, not even `.` and `..`
+        let superblock = archive.superblock();
+        let file_size = superblock.inode_table_start as usize
+            + mem::size_of::<repr::metablock::Header>()
+            + usize::from(superblock.root_inode_ref.start_offset())
+            + mem::size_of::<repr::inode::Header>()
+            + 8;
+        data[file_size..file_size + 2].copy_from_slice(&0u16.to_le_bytes());
+
+        let archive = Archive::new(data).unwrap();
+        let lints = archive.lint().unwrap();
+        assert_eq!(
+            lints,
+            [Lint::MissingDotEntries {
+                path: "/".into(),
+                size: 0
+            }]
+        );
+        assert_eq!(
+            lints[0].to_string(),
+            "/: directory size 0 doesn't include `.` and `..`"
+        );
+    }
+
+    #[test]
+    fn index_gaps() {
+        let dir = DirInfo {
+            block_start: 0,
+            block_offset: 0,
+            listing_size: 20_000,
+            stored_size: 20_003,
+            parent_inode_number: 1,
+            index: vec![super::super::inode::DirIndex {
+                index: 100,
+                start: 0,
+                name: "a".into(),
+            }],
+        };
+        let headers = [
+            HeaderInfo {
+                offset: 0,
+                count: 1,
+            },
+            HeaderInfo {
+                offset: 90,
+                count: 1,
+            },
+        ];
+        let mut lints = Vec::new();
+        lint_index(&"/dir".into(), &dir, &headers, &mut lints);
+        assert_eq!(
+            lints,
+            [
+                Lint::MisplacedIndex {
+                    path: "/dir".into(),
+                    offset: 100
+                },
+                Lint::IndexGap {
+                    path: "/dir".into(),
+                    start: 100,
+                    end: 20_000
+                },
+            ]
+        );
+    }
+}
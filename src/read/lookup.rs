@@ -0,0 +1,177 @@
+//! Finding entries by path, and listing directories with the metadata of their entries
+
+use bstr::{BStr, BString, ByteSlice};
+
+use super::{child_path, Archive, DirEntry, Inode, ReadAt};
+use crate::errors::{ReadError, Result};
+use crate::{FileType, Mode};
+
+impl<R: ReadAt> Archive<R> {
+    /// Find the inode at `path`, relative to the root
+    ///
+    /// Empty components are ignored, so `usr/bin`, `/usr/bin` and `//usr/bin/` are all the
+    /// same path. Symlinks aren't followed, and `.` and `..` aren't special.
+    pub fn lookup<P: AsRef<[u8]>>(&self, path: P) -> Result<Inode> {
+        self.lookup_path(path.as_ref()).map(|(_, _, inode)| inode)
+    }
+
+    /// Open the directory at `path`, see [`lookup`](Self::lookup)
+    ///
+    /// ```no_run
+    /// # fn main() -> sqfs::Result<()> {
+    /// let archive = sqfs::read::Archive::open("image.sqfs")?;
+    /// for entry in archive.open_dir("/usr/bin")?.entries() {
+    ///     let entry = entry?;
+    ///     println!("{} {:?} {:?}", entry.name(), entry.mode(), entry.size());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open_dir<P: AsRef<[u8]>>(&self, path: P) -> Result<Dir<'_, R>> {
+        let (path, _, inode) = self.lookup_path(path.as_ref())?;
+        let entries = self.read_dir(&inode)?;
+        Ok(Dir {
+            archive: self,
+            path,
+            inode,
+            entries,
+        })
+    }
+
+    /// Find the inode at `path`, returning it with its normalized path and depth
+    pub(crate) fn lookup_path(&self, path: &[u8]) -> Result<(BString, usize, Inode)> {
+        let mut inode = self.root()?;
+        let mut normalized = BString::from("/");
+        let mut depth = 0;
+        for name in path.split_str("/").filter(|name| !name.is_empty()) {
+            let entry = self
+                .read_dir(&inode)?
+                .into_iter()
+                .find(|entry| entry.name() == name)
+                .ok_or_else(|| ReadError::NotFound(path.into()))?;
+            inode = self.inode(entry.inode_ref())?;
+            normalized = child_path(&normalized, name);
+            depth += 1;
+        }
+        Ok((normalized, depth, inode))
+    }
+}
+
+/// A directory opened by [`Archive::open_dir`]
+#[derive(Debug)]
+pub struct Dir<'a, R> {
+    archive: &'a Archive<R>,
+    path: BString,
+    inode: Inode,
+    entries: Vec<DirEntry>,
+}
+
+impl<'a, R: ReadAt> Dir<'a, R> {
+    /// The normalized path of the directory, starting with `/`
+    pub fn path(&self) -> &BStr {
+        self.path.as_ref()
+    }
+
+    pub fn inode(&self) -> &Inode {
+        &self.inode
+    }
+
+    /// The number of entries, not including `.` and `..`
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The entries of the directory as stored, without reading their inodes
+    pub fn dir_entries(&self) -> &[DirEntry] {
+        &self.entries
+    }
+
+    /// The entries of the directory, sorted by name, with their inodes
+    ///
+    /// Each entry's inode is read as the iterator reaches it.
+    pub fn entries(&self) -> impl Iterator<Item = Result<DirChild>> + '_ {
+        self.entries.iter().map(move |entry| {
+            Ok(DirChild {
+                name: entry.name().clone(),
+                inode: self.archive.inode(entry.inode_ref())?,
+            })
+        })
+    }
+}
+
+/// An entry of a directory, with its inode, see [`Dir::entries`]
+#[derive(Debug, Clone)]
+pub struct DirChild {
+    name: BString,
+    inode: Inode,
+}
+
+impl DirChild {
+    pub fn name(&self) -> &BStr {
+        self.name.as_ref()
+    }
+
+    pub fn inode(&self) -> &Inode {
+        &self.inode
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.inode.file_type()
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.inode.mode()
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.inode.uid()
+    }
+
+    pub fn gid(&self) -> u32 {
+        self.inode.gid()
+    }
+
+    /// The size of a regular file, `None` for other types
+    pub fn size(&self) -> Option<u64> {
+        self.inode.file_size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ImageBuilder;
+
+    #[test]
+    fn open_dir() {
+        let image = ImageBuilder::new()
+            .file("usr/bin/a", "abc")
+            .owner("usr/bin/a", 10, 20)
+            .symlink("usr/bin/b", "a")
+            .dir("usr/lib")
+            .build();
+        let archive = Archive::new(image).unwrap();
+
+        let dir = archive.open_dir("//usr/bin/").unwrap();
+        assert_eq!(dir.path(), "/usr/bin");
+        assert_eq!(dir.len(), 2);
+        let entries: Vec<_> = dir.entries().collect::<Result<_>>().unwrap();
+        assert_eq!(entries[0].name(), "a");
+        assert_eq!(entries[0].file_type(), FileType::File);
+        assert_eq!(entries[0].size(), Some(3));
+        assert_eq!((entries[0].uid(), entries[0].gid()), (10, 20));
+        assert_eq!(entries[1].name(), "b");
+        assert_eq!(entries[1].file_type(), FileType::Symlink);
+        assert_eq!(entries[1].size(), None);
+
+        assert!(archive.open_dir("usr/lib").unwrap().is_empty());
+        assert!(archive.lookup("/").unwrap().is_dir());
+        assert!(archive.lookup("usr/bin/a").unwrap().is_file());
+        assert!(archive.open_dir("usr/bin/a").is_err());
+        assert!(archive.lookup("usr/missing").is_err());
+    }
+}
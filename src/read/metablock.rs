@@ -0,0 +1,137 @@
+use std::mem;
+use std::sync::Arc;
+
+use repr::offset::ArchiveOffset;
+use zerocopy::FromBytes;
+
+use super::{ArchiveInner, ReadAt};
+use crate::errors::{MetablockError, Result};
+
+/// Reads a stream of bytes spanning consecutive metadata blocks
+pub(crate) struct Cursor<'a, R> {
+    archive: &'a ArchiveInner<R>,
+    /// The position of the current metablock
+    block: ArchiveOffset,
+    /// The position of the next metablock to read
+    next_block: ArchiveOffset,
+    data: Arc<[u8]>,
+    pos: usize,
+    /// Whether to pin every block read in the archive's cache
+    pin: bool,
+    /// The number of bytes left in the stream from the start of the next metablock, if known
+    remaining: Option<usize>,
+}
+
+impl<'a, R: ReadAt> Cursor<'a, R> {
+    /// Start reading at `offset` bytes into the (uncompressed) metablock at `block`
+    pub(crate) fn new(
+        archive: &'a ArchiveInner<R>,
+        block: ArchiveOffset,
+        offset: u16,
+    ) -> Result<Self> {
+        Self::with_pinning(archive, block, offset, false)
+    }
+
+    /// Like [`new`](Self::new), pinning every block read in the archive's cache if `pin`
+    pub(crate) fn with_pinning(
+        archive: &'a ArchiveInner<R>,
+        block: ArchiveOffset,
+        offset: u16,
+        pin: bool,
+    ) -> Result<Self> {
+        let mut cursor = Self {
+            archive,
+            block,
+            next_block: block,
+            data: Arc::new([]),
+            pos: 0,
+            pin,
+            remaining: None,
+        };
+        cursor.next()?;
+        let offset = usize::from(offset);
+        if offset > cursor.data.len() {
+            return Err(MetablockError::UnexpectedMetablockSize {
+                actual: cursor.data.len(),
+                expected: offset,
+            }
+            .into());
+        }
+        cursor.pos = offset;
+        Ok(cursor)
+    }
+
+    /// Read a stream of exactly `len` bytes starting at the start of the metablock at `block`,
+    /// so the last block is allocated no larger than needed
+    pub(crate) fn sized(
+        archive: &'a ArchiveInner<R>,
+        block: ArchiveOffset,
+        len: usize,
+    ) -> Result<Self> {
+        let mut cursor = Self {
+            archive,
+            block,
+            next_block: block,
+            data: Arc::new([]),
+            pos: 0,
+            pin: false,
+            remaining: Some(len),
+        };
+        cursor.next()?;
+        Ok(cursor)
+    }
+
+    fn next(&mut self) -> Result<()> {
+        let len = self.remaining.map_or(repr::metablock::SIZE, |remaining| {
+            remaining.min(repr::metablock::SIZE)
+        });
+        let (data, size_on_disk) = if self.pin {
+            self.archive.pin_metablock(self.next_block, len)?
+        } else {
+            self.archive.read_metablock(self.next_block, len)?
+        };
+        if let Some(remaining) = &mut self.remaining {
+            *remaining = remaining.saturating_sub(data.len());
+        }
+        self.data = data;
+        self.pos = 0;
+        self.block = self.next_block;
+        self.next_block += size_on_disk;
+        Ok(())
+    }
+
+    /// The position of the metablock holding the next byte, and the offset of the byte within
+    /// it
+    pub(crate) fn position(&self) -> (ArchiveOffset, usize) {
+        if self.pos == self.data.len() {
+            (self.next_block, 0)
+        } else {
+            (self.block, self.pos)
+        }
+    }
+
+    pub(crate) fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<()> {
+        while !buf.is_empty() {
+            if self.pos == self.data.len() {
+                self.next()?;
+            }
+            let available = &self.data[self.pos..];
+            let len = available.len().min(buf.len());
+            buf[..len].copy_from_slice(&available[..len]);
+            self.pos += len;
+            buf = &mut buf[len..];
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read_vec(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut result = vec![0; len];
+        self.read_exact(&mut result)?;
+        Ok(result)
+    }
+
+    pub(crate) fn read<T: FromBytes>(&mut self) -> Result<T> {
+        let data = self.read_vec(mem::size_of::<T>())?;
+        Ok(repr::read(&data[..])?)
+    }
+}
@@ -0,0 +1,708 @@
+//! Reading squashfs archives
+
+mod block_map;
+mod cache;
+mod cas;
+mod chain;
+mod check;
+mod cpio;
+mod dir;
+mod direct;
+#[cfg(feature = "devtools")]
+mod dot;
+mod export;
+mod extract;
+mod features;
+mod file;
+mod info;
+mod inode;
+mod journal;
+mod lint;
+mod lookup;
+mod metablock;
+mod options;
+#[cfg(feature = "rayon")]
+mod par;
+mod preload;
+mod ranges;
+mod reader;
+mod salvage;
+mod signature;
+mod source;
+mod subtree;
+mod truncation;
+pub(crate) mod verify;
+mod walk;
+mod wrapped;
+mod xattr;
+
+pub use block_map::{BlockMap, Extent, ExtentKind, Extents, RawBlock, RawBlocks};
+pub use cas::{Recipe, Segment};
+pub use chain::Chain;
+pub use check::{CheckFailure, CheckOptions, CheckReport};
+pub use dir::DirEntry;
+pub use direct::{DirectFile, DEFAULT_ALIGNMENT};
+pub use extract::{ExtractEvent, ExtractOptions, ExtractProgress};
+pub use features::Features;
+pub use info::{ArchiveInfo, MetadataBlockCounts};
+pub use inode::{Inode, InodeData};
+pub use journal::ExtractJournal;
+pub use lint::Lint;
+pub use lookup::{Dir, DirChild};
+pub use options::OpenOptions;
+pub use reader::FileReader;
+pub use salvage::LOST_FOUND;
+pub use source::{Advice, Bytes, ReadAt};
+pub use truncation::{Section, Truncation};
+pub use verify::Mismatch;
+pub use walk::{HardLinkGroup, Walk, WalkEntry};
+pub use xattr::Xattrs;
+
+pub(crate) use journal::{escape_into, unescape};
+pub(crate) use walk::child_path;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+use std::{fmt, io, mem};
+
+use once_cell::sync::OnceCell;
+use slog::Logger;
+use thread_local::ThreadLocal;
+
+use zerocopy::FromBytes;
+
+use self::cache::{Cached, MetablockCache};
+use crate::compression::{self, AnyCodec, Decompressor};
+use crate::config::LoggingConfig;
+use crate::errors::{MetablockError, ReadError, Result, SuperblockError};
+use crate::metrics::Metrics;
+use crate::trace::AccessRecorder;
+use repr::offset::ArchiveOffset;
+use repr::superblock::{Flags, Superblock};
+
+/// A squashfs archive opened for reading
+///
+/// Archives are cheap to clone: clones share the underlying source and tables. An archive is
+/// `Send` and `Sync` when its source is, and any number of threads may read from it at once.
+/// Each thread decompresses with its own codec, so readers never wait on each other.
+pub struct Archive<R> {
+    inner: Arc<ArchiveInner<R>>,
+}
+
+struct ArchiveInner<R> {
+    source: R,
+    superblock: Superblock,
+    /// The size of the source, including padding after the archive
+    file_size: Option<u64>,
+    compression: compression::Options,
+    /// A codec for each thread reading from the archive
+    codecs: ThreadLocal<RefCell<AnyCodec>>,
+    metablocks: MetablockCache,
+    ids: Vec<u32>,
+    fragments: Vec<repr::fragment::Entry>,
+    /// The start of the xattr key value pairs, and the entries of the xattr id table
+    xattr_table_start: u64,
+    xattr_ids: Vec<repr::xattr::LookupEntry>,
+    logger: Logger,
+    metrics: Arc<dyn Metrics>,
+    /// The entries of the export table, once first needed, see [`Archive::inode_by_number`]
+    export_table: OnceCell<Option<Vec<repr::inode::Ref>>>,
+    /// Every inode by number, from a walk of the tree the first time the export table can't
+    /// be used, see [`Archive::inode_by_number`]
+    walked_inodes: OnceCell<HashMap<u32, Inode>>,
+    /// Where reads of files are recorded, see [`OpenOptions::record_accesses`]
+    accesses: Option<Arc<AccessRecorder>>,
+    /// Whether to check rules which aren't needed for reading, see [`OpenOptions::strict`]
+    strict: bool,
+    /// What's missing from the source, if it was opened truncated
+    truncation: Option<Truncation>,
+    /// The largest uncompressed metablock accepted, larger than [`repr::metablock::SIZE`] only
+    /// when nonstandard metablocks are tolerated
+    max_metablock: usize,
+}
+
+impl Archive<File> {
+    /// Open the archive stored in the file at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::_open(path.as_ref())
+    }
+
+    fn _open(path: &Path) -> Result<Self> {
+        OpenOptions::new().open(path)
+    }
+}
+
+impl<T: AsRef<[u8]>> Archive<Bytes<T>> {
+    /// Open an archive which is already in memory
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # static IMAGE: &[u8] = &[];
+    /// // static IMAGE: &[u8] = include_bytes!("image.sqfs");
+    /// let archive = sqfs::read::Archive::from_bytes(IMAGE)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_bytes(bytes: T) -> Result<Self> {
+        Self::new(Bytes::new(bytes))
+    }
+}
+
+impl<R: ReadAt> Archive<R> {
+    pub fn new(source: R) -> Result<Self> {
+        OpenOptions::new().open_source(source)
+    }
+
+    /// Open an archive, logging every subsystem to `logger`
+    pub fn with_logger(source: R, logger: Logger) -> Result<Self> {
+        Self::with_logging(source, LoggingConfig::new(&logger))
+    }
+
+    /// Open an archive, using separate loggers for each subsystem
+    pub fn with_logging(source: R, logging: LoggingConfig) -> Result<Self> {
+        OpenOptions::new().logging(logging).open_source(source)
+    }
+
+    fn from_options(source: R, logging: LoggingConfig, options: &OpenOptions) -> Result<Self> {
+        let mut superblock_data = [0; mem::size_of::<Superblock>()];
+        source.read_exact_at(&mut superblock_data, 0)?;
+        let superblock: Superblock = repr::read(&superblock_data[..])?;
+        validate_superblock(&superblock)?;
+        let file_size = source.size()?;
+        let truncation = match file_size {
+            Some(file_size) if file_size < superblock.bytes_used => {
+                let truncation = Truncation::new(&source, &superblock, file_size);
+                if !options.allow_truncated {
+                    return Err(SuperblockError::Truncated(truncation).into());
+                }
+                slog::warn!(logging.read, "Archive truncated"; "details" => %truncation);
+                Some(truncation)
+            }
+            _ => None,
+        };
+
+        let kind = compression::Kind::from_id(superblock.compression_id);
+        let compression = if { superblock.flags }.contains(Flags::COMPRESSOR_OPTIONS) {
+            let data = read_compression_options(&source)?;
+            compression::Options::read(kind, &data)?
+        } else {
+            compression::Options::default_for(kind, superblock.block_size)
+                .expect("compression kind was validated")
+        };
+        slog::debug!(logging.compression, "Opened archive"; "compression" => %compression);
+        // Fail early if the codec can't be configured, and keep it for this thread
+        let codecs = ThreadLocal::new();
+        codecs.get_or_try(|| AnyCodec::from_options(&compression).map(RefCell::new))?;
+
+        let mut inner = ArchiveInner {
+            source,
+            superblock,
+            file_size,
+            compression,
+            codecs,
+            metablocks: MetablockCache::new(options.memory.cache_bytes),
+            ids: Vec::new(),
+            fragments: Vec::new(),
+            xattr_table_start: u64::MAX,
+            xattr_ids: Vec::new(),
+            logger: logging.read,
+            metrics: Arc::clone(&options.metrics),
+            export_table: OnceCell::new(),
+            walked_inodes: OnceCell::new(),
+            accesses: options.accesses.clone(),
+            strict: options.strict,
+            truncation,
+            max_metablock: if options.nonstandard_metablocks {
+                repr::metablock::MAX_SIZE
+            } else {
+                repr::metablock::SIZE
+            },
+        };
+        // The tables of a truncated archive may be gone, leaving only what the inodes need
+        let ids: Result<Vec<repr::uid_gid::Id>> =
+            inner.read_lookup_table(superblock.id_table_start, superblock.id_count.into());
+        match ids {
+            Ok(ids) => inner.ids = ids.into_iter().map(|id| id.0).collect(),
+            Err(e) if inner.truncation.is_some() => {
+                slog::warn!(inner.logger, "Unable to read the id table"; "error" => %e)
+            }
+            Err(e) => return Err(e),
+        }
+        if superblock.fragment_table_start != u64::MAX {
+            let fragments = inner.read_lookup_table(
+                superblock.fragment_table_start,
+                superblock.fragment_entry_count,
+            );
+            match fragments {
+                Ok(fragments) => inner.fragments = fragments,
+                Err(e) if inner.truncation.is_some() => {
+                    slog::warn!(inner.logger, "Unable to read the fragment table"; "error" => %e)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        if superblock.xattr_id_table_start != u64::MAX {
+            match inner.read_xattr_ids(superblock.xattr_id_table_start) {
+                Ok(()) => {}
+                Err(e) if inner.truncation.is_some() => {
+                    slog::warn!(inner.logger, "Unable to read the xattr id table"; "error" => %e)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(Self {
+            inner: Arc::new(inner),
+        })
+    }
+
+    /// Read the root directory inode
+    pub fn root(&self) -> Result<Inode> {
+        self.inner.read_inode(self.inner.superblock.root_inode_ref)
+    }
+
+    /// Read the inode referenced by `inode_ref`
+    pub fn inode(&self, inode_ref: repr::inode::Ref) -> Result<Inode> {
+        self.inner.read_inode(inode_ref)
+    }
+
+    /// List the entries of a directory, not including `.` and `..`
+    pub fn read_dir(&self, dir: &Inode) -> Result<Vec<DirEntry>> {
+        self.inner.read_dir(dir.as_dir()?)
+    }
+
+    /// Read the entire contents of a regular file
+    pub fn read_file(&self, file: &Inode) -> Result<Vec<u8>> {
+        let info = file.as_file()?;
+        self.record_access(file);
+        self.inner.read_file(info)
+    }
+
+    /// Note that the contents of `file` are being read, if accesses are recorded
+    fn record_access(&self, file: &Inode) {
+        if let Some(recorder) = &self.inner.accesses {
+            recorder.record(file.inode_number());
+        }
+    }
+
+    /// The offset of the first data in a regular file at or after `offset`, like `lseek` with
+    /// `SEEK_DATA`
+    ///
+    /// Returns `None` if there is no data at or after `offset`, where `lseek` would fail with
+    /// `ENXIO`. Only sparse blocks are holes, the fragment at the end of a file is always data.
+    pub fn seek_data(&self, file: &Inode, offset: u64) -> Result<Option<u64>> {
+        Ok(file.as_file()?.seek(self.block_size(), offset, false))
+    }
+
+    /// The offset of the first hole in a regular file at or after `offset`, like `lseek` with
+    /// `SEEK_HOLE`
+    ///
+    /// The end of the file counts as a hole, so this only returns `None` if `offset` is at or
+    /// past the end of the file.
+    pub fn seek_hole(&self, file: &Inode, offset: u64) -> Result<Option<u64>> {
+        Ok(file.as_file()?.seek(self.block_size(), offset, true))
+    }
+}
+
+impl<R: ReadAt> ArchiveInner<R> {
+    /// Read and decompress the metablock at the absolute offset `offset`, which is expected to
+    /// hold `len` bytes (at most [`repr::metablock::SIZE`])
+    ///
+    /// Returns the uncompressed data, and the number of bytes the block used on disk
+    fn read_metablock(&self, offset: ArchiveOffset, len: usize) -> Result<Cached> {
+        if !self.metablocks.is_enabled() {
+            let (data, size_on_disk) = self.read_metablock_uncached(offset, len)?;
+            return Ok((data.into(), size_on_disk));
+        }
+        if let Some(cached) = self.metablocks.get(offset.0) {
+            self.metrics.cache_hit();
+            return Ok(cached);
+        }
+        self.metrics.cache_miss();
+        let (data, size_on_disk) = self.read_metablock_uncached(offset, len)?;
+        let data: Arc<[u8]> = data.into();
+        self.metablocks.insert(offset.0, data.clone(), size_on_disk);
+        Ok((data, size_on_disk))
+    }
+
+    /// Like [`read_metablock`](Self::read_metablock), keeping the block in the cache for as long
+    /// as the archive is open
+    fn pin_metablock(&self, offset: ArchiveOffset, len: usize) -> Result<Cached> {
+        if let Some(pinned) = self.metablocks.get_pinned(offset.0) {
+            return Ok(pinned);
+        }
+        let (data, size_on_disk) = self.read_metablock_uncached(offset, len)?;
+        let data: Arc<[u8]> = data.into();
+        self.metablocks.pin(offset.0, data.clone(), size_on_disk);
+        Ok((data, size_on_disk))
+    }
+
+    fn read_metablock_uncached(&self, offset: ArchiveOffset, len: usize) -> Result<(Vec<u8>, u64)> {
+        let mut header = [0; mem::size_of::<repr::metablock::Header>()];
+        self.source.read_exact_at(&mut header, offset.0)?;
+        let data_offset = offset + header.len() as u64;
+        let header: repr::metablock::Header = repr::read(&header[..])?;
+
+        let size: usize = header.size().into();
+        if size > self.max_metablock {
+            let max = self.max_metablock;
+            return Err(MetablockError::HugeMetablock { size, max }.into());
+        }
+        let mut data = vec![0; size];
+        self.source.read_exact_at(&mut data, data_offset.0)?;
+        if header.compressed() {
+            data = self.decompress_sized(&data, len, self.max_metablock)?;
+        }
+        Ok((data, data_offset - offset + size as u64))
+    }
+
+    /// Read `count` entries of a table stored in metablocks, located through a lookup table
+    /// of metablock locations at `start`
+    fn read_lookup_table<T: FromBytes>(&self, start: u64, count: u32) -> Result<Vec<T>> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let mut first_block = [0; mem::size_of::<u64>()];
+        self.source.read_exact_at(&mut first_block, start)?;
+        let first_block = ArchiveOffset(u64::from_le_bytes(first_block));
+
+        let len = count as usize * mem::size_of::<T>();
+        let mut cursor = metablock::Cursor::sized(self, first_block, len)?;
+        (0..count).map(|_| cursor.read()).collect()
+    }
+
+    fn decompress(&self, src: &[u8], dst: &mut [u8]) -> io::Result<usize> {
+        let codec = self
+            .codecs
+            .get_or_try(|| AnyCodec::from_options(&self.compression).map(RefCell::new))?;
+        let n = codec.borrow_mut().decompress(src, dst)?;
+        self.metrics.block_decompressed(src.len(), n);
+        Ok(n)
+    }
+
+    /// Decompress `src`, which is expected to decompress to `len` bytes, and can't decompress
+    /// to more than `max`
+    ///
+    /// Only `len` bytes are allocated, so the last block of a file or table doesn't take a
+    /// whole block. If `src` turns out to be larger, it is decompressed again into `max` bytes.
+    fn decompress_sized(&self, src: &[u8], len: usize, max: usize) -> io::Result<Vec<u8>> {
+        let len = len.min(max);
+        let mut uncompressed = vec![0; len];
+        match self.decompress(src, &mut uncompressed) {
+            Ok(n) => {
+                uncompressed.truncate(n);
+                return Ok(uncompressed);
+            }
+            Err(_) if len < max => {}
+            Err(e) => return Err(e),
+        }
+        let mut uncompressed = vec![0; max];
+        let n = self.decompress(src, &mut uncompressed)?;
+        uncompressed.truncate(n);
+        Ok(uncompressed)
+    }
+
+    fn fragment(&self, index: u32) -> Result<repr::fragment::Entry> {
+        self.fragments
+            .get(index as usize)
+            .copied()
+            .ok_or_else(|| ReadError::FragmentIndexOutOfRange(index).into())
+    }
+}
+
+impl<R> Archive<R> {
+    pub fn superblock(&self) -> &Superblock {
+        &self.inner.superblock
+    }
+
+    /// The compression algorithm used by the archive, and its options
+    pub fn compression(&self) -> compression::Options {
+        self.inner.compression
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.inner.superblock.block_size
+    }
+
+    pub fn flags(&self) -> Flags {
+        self.inner.superblock.flags
+    }
+
+    /// The number of bytes used by the archive, not including any padding
+    pub fn bytes_used(&self) -> u64 {
+        self.inner.superblock.bytes_used
+    }
+
+    /// The size of the source the archive was read from, if known
+    ///
+    /// This is at least [`bytes_used`](Self::bytes_used), and is usually padded to a multiple
+    /// of [`PADDING`](repr::superblock::PADDING).
+    pub fn file_size(&self) -> Option<u64> {
+        self.inner.file_size
+    }
+
+    /// The number of bytes following the archive in its source, if known
+    ///
+    /// A truncated archive has no padding.
+    pub fn padding(&self) -> Option<u64> {
+        self.file_size()
+            .map(|file_size| file_size.saturating_sub(self.bytes_used()))
+    }
+}
+
+impl<R> Clone for Archive<R> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<R> fmt::Debug for Archive<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Archive")
+            .field("superblock", &self.inner.superblock)
+            .field("compression", &self.inner.compression)
+            .finish_non_exhaustive()
+    }
+}
+
+fn validate_superblock(superblock: &Superblock) -> Result<(), SuperblockError> {
+    let Superblock {
+        magic,
+        version_major,
+        version_minor,
+        compression_id,
+        block_size,
+        block_log,
+        bytes_used,
+        ..
+    } = *superblock;
+    if magic != repr::superblock::MAGIC {
+        return Err(SuperblockError::BadMagic { magic });
+    }
+    if (version_major, version_minor)
+        != (
+            repr::superblock::VERSION_MAJOR,
+            repr::superblock::VERSION_MINOR,
+        )
+    {
+        return Err(SuperblockError::BadVersion {
+            major: version_major,
+            minor: version_minor,
+        });
+    }
+
+    let kind = compression::Kind::from_id(compression_id);
+    if kind == compression::Kind::Unknown {
+        return Err(SuperblockError::UnknownCompression { id: compression_id });
+    }
+    if !kind.supported() {
+        return Err(SuperblockError::DisabledCompression { kind });
+    }
+
+    if !(repr::BLOCK_SIZE_MIN..=repr::BLOCK_SIZE_MAX).contains(&block_size)
+        || !block_size.is_power_of_two()
+    {
+        return Err(SuperblockError::OutOfRangeBlockSize { actual: block_size });
+    }
+    if u32::from(block_log) != block_size.trailing_zeros() {
+        return Err(SuperblockError::CorruptBlockSizes {
+            block_log,
+            block_size,
+        });
+    }
+
+    let superblock_size = mem::size_of::<Superblock>() as u64;
+    let sections = [
+        ("inode table", superblock.inode_table_start, false),
+        ("directory table", superblock.directory_table_start, false),
+        ("id table", superblock.id_table_start, false),
+        ("fragment table", superblock.fragment_table_start, true),
+        ("export table", superblock.export_table_start, true),
+        ("xattr id table", superblock.xattr_id_table_start, true),
+    ];
+    for &(section, offset, optional) in &sections {
+        if optional && offset == u64::MAX {
+            continue;
+        }
+        if offset < superblock_size || offset > bytes_used {
+            return Err(SuperblockError::InvalidSectionStart { section, offset });
+        }
+    }
+
+    Ok(())
+}
+
+fn read_compression_options<R: ReadAt>(source: &R) -> Result<Vec<u8>> {
+    let offset = mem::size_of::<Superblock>() as u64;
+    let mut header = [0; mem::size_of::<repr::metablock::Header>()];
+    source.read_exact_at(&mut header, offset)?;
+    let data_offset = offset + header.len() as u64;
+    let header: repr::metablock::Header = repr::read(&header[..])?;
+    if header.compressed() {
+        return Err(MetablockError::CompressedCompressorOptions.into());
+    }
+
+    let size: usize = header.size().into();
+    if size > repr::metablock::SIZE {
+        let max = repr::metablock::SIZE;
+        return Err(MetablockError::HugeMetablock { size, max }.into());
+    }
+    let mut data = vec![0; size];
+    source.read_exact_at(&mut data, data_offset)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zerocopy::AsBytes;
+
+    pub(super) fn superblock(compression_id: repr::compression::Id, flags: Flags) -> Superblock {
+        Superblock {
+            magic: repr::superblock::MAGIC,
+            inode_count: 0,
+            modification_time: repr::Time(0),
+            block_size: repr::BLOCK_SIZE_DEFAULT,
+            fragment_entry_count: 0,
+            compression_id,
+            block_log: repr::BLOCK_LOG_DEFAULT,
+            flags,
+            id_count: 0,
+            version_major: repr::superblock::VERSION_MAJOR,
+            version_minor: repr::superblock::VERSION_MINOR,
+            root_inode_ref: repr::inode::Ref::new(0, 0),
+            bytes_used: 96,
+            id_table_start: 96,
+            xattr_id_table_start: u64::MAX,
+            inode_table_start: 96,
+            directory_table_start: 96,
+            fragment_table_start: u64::MAX,
+            export_table_start: u64::MAX,
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn compression_options() {
+        let mut data = superblock(repr::compression::Id::ZSTD, Flags::COMPRESSOR_OPTIONS)
+            .as_bytes()
+            .to_vec();
+        let options = repr::compression::options::Zstd {
+            compression_level: 19,
+        };
+        data.extend_from_slice(repr::metablock::Header::new(4, false).as_bytes());
+        data.extend_from_slice(options.as_bytes());
+
+        let archive = Archive::new(data).unwrap();
+        assert_eq!(archive.compression(), compression::Options::Zstd(options));
+        assert_eq!(archive.compression().to_string(), "zstd level 19");
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn default_options() {
+        let data = superblock(repr::compression::Id::GZIP, Flags::empty())
+            .as_bytes()
+            .to_vec();
+        let archive = Archive::new(data).unwrap();
+        assert_eq!(
+            archive.compression(),
+            compression::Options::Gzip(Default::default())
+        );
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn file_size() {
+        let mut data = superblock(repr::compression::Id::GZIP, Flags::empty())
+            .as_bytes()
+            .to_vec();
+        data.resize(repr::superblock::PADDING as usize, 0);
+        let archive = Archive::new(data.clone()).unwrap();
+        assert_eq!(archive.bytes_used(), 96);
+        assert_eq!(archive.file_size(), Some(repr::superblock::PADDING));
+        assert_eq!(archive.padding(), Some(repr::superblock::PADDING - 96));
+
+        let mut sb = superblock(repr::compression::Id::GZIP, Flags::empty());
+        sb.bytes_used = 200;
+        let err = Archive::new(sb.as_bytes().to_vec()).unwrap_err();
+        assert!(err.to_string().contains("truncated"));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn from_bytes() {
+        let sb = superblock(repr::compression::Id::GZIP, Flags::empty());
+        let archive = Archive::from_bytes(sb.as_bytes()).unwrap();
+        assert_eq!(archive.file_size(), Some(96));
+
+        let boxed: Box<[u8]> = sb.as_bytes().into();
+        let archive = Archive::from_bytes(boxed).unwrap();
+        assert_eq!(archive.file_size(), Some(96));
+    }
+
+    static_assertions::assert_impl_all!(Archive<File>: Send, Sync, Clone);
+    static_assertions::assert_impl_all!(Archive<Vec<u8>>: Send, Sync, Clone);
+
+    #[test]
+    fn concurrent_reads() {
+        let archive = Archive::new(crate::testing::file_and_symlink()).unwrap();
+
+        let threads: Vec<_> = (0..16)
+            .map(|_| {
+                let archive = archive.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        let root = archive.root().unwrap();
+                        let entries = archive.read_dir(&root).unwrap();
+                        let file = archive.inode(entries[0].inode_ref()).unwrap();
+                        assert_eq!(archive.read_file(&file).unwrap(), b"hi there\n");
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn cold_read_metrics() {
+        let counters = Arc::new(crate::metrics::Counters::default());
+        let archive = OpenOptions::new()
+            .metrics(counters.clone())
+            .open_source(crate::testing::one_file())
+            .unwrap();
+        let file = archive.lookup("hello").unwrap();
+        assert_eq!(
+            archive.read_file(&file).unwrap(),
+            crate::testing::HELLO_CONTENTS
+        );
+        // Misses for the inode, directory and fragment tables, then a hit for the file's inode,
+        // in the same metablock as the root's
+        let snapshot = counters.snapshot();
+        assert_eq!((snapshot.cache_hits, snapshot.cache_misses), (1, 3));
+    }
+
+    #[test]
+    fn bad_superblock() {
+        let mut sb = superblock(repr::compression::Id::GZIP, Flags::empty());
+        sb.magic = 0;
+        Archive::new(sb.as_bytes().to_vec()).unwrap_err();
+
+        let mut sb = superblock(repr::compression::Id(100), Flags::empty());
+        Archive::new(sb.as_bytes().to_vec()).unwrap_err();
+
+        sb = superblock(repr::compression::Id::GZIP, Flags::empty());
+        sb.block_log += 1;
+        Archive::new(sb.as_bytes().to_vec()).unwrap_err();
+
+        Archive::new(vec![0; 10]).unwrap_err();
+    }
+}
@@ -0,0 +1,144 @@
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::{wrapped, Archive, ReadAt};
+use crate::config::{LoggingConfig, MemoryBudget};
+use crate::errors::Result;
+use crate::metrics::{Metrics, NoMetrics};
+use crate::trace::AccessRecorder;
+
+/// Options for opening an archive
+///
+/// ```no_run
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use sqfs::config::LoggingConfig;
+/// use sqfs::read::OpenOptions;
+///
+/// let archive = OpenOptions::new()
+///     .logging(LoggingConfig::default())
+///     .open("image.sqfs")?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct OpenOptions {
+    logging: Option<LoggingConfig>,
+    pub(super) metrics: Arc<dyn Metrics>,
+    pub(super) accesses: Option<Arc<AccessRecorder>>,
+    pub(super) strict: bool,
+    pub(super) nonstandard_metablocks: bool,
+    pub(super) allow_truncated: bool,
+    pub(super) memory: MemoryBudget,
+}
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use separate loggers for each subsystem
+    pub fn logging(&mut self, logging: LoggingConfig) -> &mut Self {
+        self.logging = Some(logging);
+        self
+    }
+
+    /// Report decompression and cache events to `metrics`
+    pub fn metrics(&mut self, metrics: Arc<dyn Metrics>) -> &mut Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Record the order files are first read in to `recorder`, see [`AccessRecorder`]
+    ///
+    /// A file counts as read when it's [read](Archive::read_file) or
+    /// [opened](Archive::open_file), or its [blocks](Archive::file_blocks) are.
+    pub fn record_accesses(&mut self, recorder: Arc<AccessRecorder>) -> &mut Self {
+        self.accesses = Some(recorder);
+        self
+    }
+
+    /// Check structural rules which the kernel relies on, but which aren't needed to read the
+    /// archive, returning an error when they are broken
+    ///
+    /// Currently, this checks that the entries of each directory are sorted by name, without
+    /// duplicates, as the kernel's binary search depends on it.
+    pub fn strict(&mut self, strict: bool) -> &mut Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Accept metadata blocks of any size up to [`repr::metablock::MAX_SIZE`], rather than only
+    /// those of the size this crate was built with
+    ///
+    /// Only useful for reading images built for experiments with the
+    /// `nonstandard-metablock-size` feature, by builds with a different size.
+    #[cfg(feature = "nonstandard-metablock-size")]
+    pub fn nonstandard_metablocks(&mut self, tolerate: bool) -> &mut Self {
+        self.nonstandard_metablocks = tolerate;
+        self
+    }
+
+    /// Open an archive whose source is shorter than the archive, rather than failing
+    ///
+    /// Whatever lies in the intact prefix can be read, anything past the end fails with an
+    /// error. [`Archive::truncation`] describes what's missing, and
+    /// [`Archive::truncated_files`] lists the files which can't be read in full.
+    pub fn allow_truncated(&mut self, allow: bool) -> &mut Self {
+        self.allow_truncated = allow;
+        self
+    }
+
+    /// Limit the memory used for caching metadata
+    pub fn memory_budget(&mut self, memory: MemoryBudget) -> &mut Self {
+        self.memory = memory;
+        self
+    }
+
+    /// Open the archive stored in the file at `path`
+    ///
+    /// An image compressed as a whole with gzip or zstd, like `image.squashfs.gz`, is
+    /// decompressed into an anonymous temporary file first, so it can be read like any other.
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<Archive<File>> {
+        self._open(path.as_ref())
+    }
+
+    fn _open(&self, path: &Path) -> Result<Archive<File>> {
+        let logging = self.logging.clone().unwrap_or_default().for_file(path);
+        let file = wrapped::unwrap(File::open(path)?)?;
+        Archive::from_options(file, logging, self)
+    }
+
+    /// Open the archive stored in `source`
+    pub fn open_source<R: ReadAt>(&self, source: R) -> Result<Archive<R>> {
+        let logging = self.logging.clone().unwrap_or_default();
+        Archive::from_options(source, logging, self)
+    }
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        OpenOptions {
+            logging: None,
+            metrics: Arc::new(NoMetrics),
+            accesses: None,
+            strict: false,
+            nonstandard_metablocks: false,
+            allow_truncated: false,
+            memory: MemoryBudget::default(),
+        }
+    }
+}
+
+impl fmt::Debug for OpenOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpenOptions")
+            .field("logging", &self.logging)
+            .field("strict", &self.strict)
+            .field("nonstandard_metablocks", &self.nonstandard_metablocks)
+            .field("allow_truncated", &self.allow_truncated)
+            .field("memory", &self.memory)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,93 @@
+//! Parallel traversal and extraction with rayon
+
+use std::fs;
+use std::path::Path;
+
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use super::extract::set_dir_permissions;
+use super::walk::child_path;
+use super::{Archive, ReadAt, WalkEntry};
+use crate::errors::Result;
+
+impl<R: ReadAt + Send + Sync> Archive<R> {
+    /// Walk every entry in the archive in parallel
+    ///
+    /// Every entry produced by [`walk`](Archive::walk) is produced, in no particular order.
+    /// Hard links are not detected: [`WalkEntry::hard_link_target`] is always `None`, group
+    /// entries by [`Inode::inode_number`](super::Inode::inode_number) to find them.
+    ///
+    /// If a directory can't be read, an error is produced in place of its contents.
+    pub fn par_walk(&self) -> impl ParallelIterator<Item = Result<WalkEntry>> + '_ {
+        let root = self
+            .root()
+            .map(|root| WalkEntry::new("/".into(), 0, root, None));
+        rayon::iter::walk_tree_prefix(root, move |entry| self.children(entry))
+    }
+
+    fn children(&self, entry: &Result<WalkEntry>) -> Vec<Result<WalkEntry>> {
+        let entry = match entry {
+            Ok(entry) if entry.inode().is_dir() => entry,
+            _ => return Vec::new(),
+        };
+        let dir_entries = match self.read_dir(entry.inode()) {
+            Ok(dir_entries) => dir_entries,
+            Err(e) => return vec![Err(e)],
+        };
+        dir_entries
+            .into_iter()
+            .map(|dir_entry| {
+                let inode = self.inode(dir_entry.inode_ref())?;
+                let path = child_path(entry.path(), dir_entry.name());
+                Ok(WalkEntry::new(path, entry.depth() + 1, inode, None))
+            })
+            .collect()
+    }
+
+    /// Extract the contents of the archive into `dest` like [`extract`](Archive::extract),
+    /// extracting each top level entry in parallel
+    ///
+    /// Hard links between files under different top level entries are extracted as
+    /// independent copies.
+    pub fn par_extract<P: AsRef<Path>>(&self, dest: P) -> Result<()> {
+        let dest = dest.as_ref();
+        fs::create_dir_all(dest)?;
+        let root = self.root()?;
+        self.read_dir(&root)?
+            .into_par_iter()
+            .try_for_each(|dir_entry| {
+                let inode = self.inode(dir_entry.inode_ref())?;
+                let path = child_path(b"/", dir_entry.name());
+                let mut dirs = Vec::new();
+                self.extract_walk(self.walk_from(path, 1, inode), dest, &mut dirs)?;
+                set_dir_permissions(dirs)
+            })?;
+        set_dir_permissions(vec![(dest.to_owned(), root)])
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn par_walk_and_extract() {
+        let archive = Archive::new(crate::testing::file_and_symlink()).unwrap();
+
+        let mut paths: Vec<_> = archive
+            .par_walk()
+            .map(|entry| entry.unwrap().path().to_owned())
+            .collect();
+        paths.sort();
+        assert_eq!(paths, ["/", "/hello", "/link"]);
+
+        let dest = tempfile::tempdir().unwrap();
+        archive.par_extract(dest.path()).unwrap();
+        // Modification times are not restored, nor owners, which are root in the image
+        let mismatches = archive.verify_tree(dest.path()).unwrap();
+        assert!(mismatches.iter().all(|mismatch| matches!(
+            mismatch,
+            super::super::Mismatch::ModifiedTime { .. } | super::super::Mismatch::Owner { .. }
+        )));
+    }
+}
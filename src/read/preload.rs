@@ -0,0 +1,80 @@
+use std::collections::{HashSet, VecDeque};
+
+use super::{Archive, ReadAt};
+use crate::errors::Result;
+use crate::FileType;
+
+impl<R: ReadAt> Archive<R> {
+    /// Decompress the metadata of the directories nearest the root, and keep it in memory for
+    /// as long as the archive is open
+    ///
+    /// Directories are visited breadth first, pinning the metadata blocks holding their inodes
+    /// and listings, until at least `max_bytes` of decompressed metadata is pinned. This avoids
+    /// the latency of reading and decompressing on the first lookups, e.g. for a server which
+    /// starts serving many lookups as soon as it opens an archive. Pinned blocks don't count
+    /// towards [`MemoryBudget::cache_bytes`](crate::config::MemoryBudget::cache_bytes), and are
+    /// never evicted.
+    ///
+    /// Returns the total size of the pinned metadata.
+    pub fn preload(&self, max_bytes: usize) -> Result<usize> {
+        let inner = &self.inner;
+        let mut queue = VecDeque::from(vec![inner.superblock.root_inode_ref]);
+        let mut seen = HashSet::new();
+        while let Some(inode_ref) = queue.pop_front() {
+            if inner.metablocks.pinned_bytes() >= max_bytes {
+                break;
+            }
+            let inode = inner.read_inode_with(inode_ref, true)?;
+            if !seen.insert(inode.inode_number()) {
+                continue;
+            }
+            let (entries, _) = inner.read_listing_with(inode.as_dir()?, true)?;
+            queue.extend(
+                entries
+                    .iter()
+                    .filter(|entry| entry.file_type() == FileType::Dir)
+                    .map(|entry| entry.inode_ref()),
+            );
+        }
+        Ok(inner.metablocks.pinned_bytes())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::config::MemoryBudget;
+    use crate::metrics::Counters;
+    use crate::read::OpenOptions;
+    use std::sync::Arc;
+
+    #[test]
+    fn pinned_root() {
+        let data = crate::testing::ImageBuilder::new()
+            .uncompressed()
+            .file("hello", crate::testing::HELLO_CONTENTS)
+            .symlink("link", "hello")
+            .build();
+
+        let counters = Arc::new(Counters::default());
+        let archive = OpenOptions::new()
+            .metrics(counters.clone())
+            .memory_budget(MemoryBudget {
+                cache_bytes: 0,
+                ..MemoryBudget::default()
+            })
+            .open_source(data)
+            .unwrap();
+        let pinned = archive.preload(usize::MAX).unwrap();
+        // The inode table and the directory table are a single block each, followed by the
+        // block holding the only id
+        let superblock = archive.superblock();
+        let header = std::mem::size_of::<repr::metablock::Header>() as u64;
+        let expected = (superblock.id_table_start - superblock.inode_table_start) - 3 * header - 4;
+        assert_eq!(pinned as u64, expected);
+
+        let hits = counters.snapshot().cache_hits;
+        let root = archive.root().unwrap();
+        assert_eq!(archive.read_dir(&root).unwrap().len(), 2);
+        assert_eq!(counters.snapshot().cache_hits, hits + 2);
+    }
+}
@@ -0,0 +1,132 @@
+//! Planning the reads needed to extract part of an archive
+//!
+//! Sources on the other end of a network, like an object store serving HTTP range requests,
+//! pay a round trip per read, so reading a file a block at a time is slow. The data of the files
+//! in an archive is mostly laid out in the order of their paths, so the blocks of a directory's
+//! files are close together, and [`Archive::fetch_ranges`] merges them into a few large
+//! ranges, which can be fetched up front.
+
+use std::ops::Range;
+
+use super::{Archive, InodeData, ReadAt};
+use crate::errors::Result;
+
+impl<R: ReadAt> Archive<R> {
+    /// The ranges of the source holding the data of `paths`, sorted and merged
+    ///
+    /// Directories include everything beneath them. Ranges separated by at most `max_gap`
+    /// bytes are merged, so a few unneeded bytes are fetched rather than making another
+    /// request: a gap of about what a request costs in transfer time is a good choice.
+    ///
+    /// Only the data and fragment blocks are included. Finding the files reads the inodes and
+    /// directories of `paths` from the source, which are then in the metadata cache.
+    ///
+    /// ```no_run
+    /// # fn main() -> sqfs::Result<()> {
+    /// let archive = sqfs::read::Archive::open("image.sqfs")?;
+    /// for range in archive.fetch_ranges(["/usr/lib", "/etc/hosts"], 1 << 20)? {
+    ///     println!("GET bytes={}-{}", range.start, range.end - 1);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn fetch_ranges<I, P>(&self, paths: I, max_gap: u64) -> Result<Vec<Range<u64>>>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<[u8]>,
+    {
+        let mut ranges = Vec::new();
+        for path in paths {
+            let (path, depth, inode) = self.lookup_path(path.as_ref())?;
+            for entry in self.walk_from(path, depth, inode) {
+                let entry = entry?;
+                let file = match entry.inode().data() {
+                    InodeData::File(file) => file,
+                    _ => continue,
+                };
+                let len: u64 = file
+                    .block_sizes
+                    .iter()
+                    .map(|size| u64::from(size.size()))
+                    .sum();
+                ranges.push(file.blocks_start..file.blocks_start + len);
+                if let Some(fragment) = file.fragment {
+                    let entry = self.inner.fragment(fragment.index)?;
+                    let start = entry.start.0;
+                    ranges.push(start..start + u64::from({ entry.size }.size()));
+                }
+            }
+        }
+        Ok(coalesce(ranges, max_gap))
+    }
+}
+
+/// Sort `ranges`, and merge those which overlap or are separated by at most `max_gap` bytes
+///
+/// Empty ranges are dropped.
+pub(crate) fn coalesce(mut ranges: Vec<Range<u64>>, max_gap: u64) -> Vec<Range<u64>> {
+    ranges.retain(|range| !range.is_empty());
+    ranges.sort_unstable_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(max_gap) => {
+                last.end = last.end.max(range.end);
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ImageBuilder;
+
+    #[test]
+    fn coalesced() {
+        let ranges = vec![20..30, 0..10, 12..15, 5..8, 50..50, 100..110];
+        assert_eq!(
+            coalesce(ranges.clone(), 0),
+            [0..10, 12..15, 20..30, 100..110]
+        );
+        assert_eq!(coalesce(ranges.clone(), 2), [0..15, 20..30, 100..110]);
+        let all = coalesce(ranges, 70);
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0], 0..110);
+    }
+
+    #[test]
+    fn directory_ranges() {
+        let image = ImageBuilder::new()
+            .file("a/1", "one")
+            .file("a/2", "two")
+            .file("b", "three")
+            .file("c/3", "four")
+            .build();
+        let archive = Archive::new(image).unwrap();
+
+        // The blocks of `a/1` and `a/2` are next to each other
+        let block = |path| {
+            let file = archive.lookup(path).unwrap();
+            let extent = archive.block_map(&file).unwrap().locate(0).unwrap();
+            match extent.kind {
+                crate::read::ExtentKind::Block { offset, size, .. } => {
+                    offset..offset + u64::from(size.size())
+                }
+                kind => panic!("{:?} isn't a block", kind),
+            }
+        };
+        let a = archive.fetch_ranges(["a"], 0).unwrap();
+        assert_eq!(a.len(), 1);
+        assert_eq!(a[0], block("a/1").start..block("a/2").end);
+
+        let apart = archive.fetch_ranges(["/a", "c/3"], 0).unwrap();
+        assert_eq!(apart.len(), 2);
+        let merged = archive.fetch_ranges(["/a", "c/3"], 5).unwrap();
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0], a[0].start..apart[1].end);
+        assert!(archive.fetch_ranges(["missing"], 0).is_err());
+    }
+}
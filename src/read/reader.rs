@@ -0,0 +1,219 @@
+//! Reading the contents of a file as a stream, a block at a time
+//!
+//! [`Archive::read_file`] decompresses a whole file into memory, which doesn't suit large
+//! files. A [`FileReader`] decompresses one block at a time as it's read, and can seek, so
+//! only the blocks covering the bytes read are ever decompressed.
+
+use std::borrow::Cow;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use super::block_map::{Extent, ExtentKind, RawBlocks};
+use super::inode::FileInfo;
+use super::{Archive, ArchiveInner, Inode, ReadAt};
+use crate::errors::{ReadError, Result};
+
+impl<R: ReadAt> Archive<R> {
+    /// Open the regular file `file` for reading as a stream
+    ///
+    /// ```no_run
+    /// # fn main() -> sqfs::Result<()> {
+    /// let archive = sqfs::read::Archive::open("image.sqfs")?;
+    /// let file = archive.lookup("/etc/os-release")?;
+    /// let mut reader = archive.open_file(&file)?;
+    /// std::io::copy(&mut reader, &mut std::io::stdout())?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open_file(&self, file: &Inode) -> Result<FileReader<'_, R>> {
+        let info = file.as_file()?.clone();
+        self.record_access(file);
+        Ok(FileReader {
+            archive: Cow::Borrowed(self),
+            file: info,
+            pos: 0,
+            extent: None,
+        })
+    }
+
+    /// Like [`open_file`](Self::open_file), but the reader keeps this handle to the archive,
+    /// so it can be returned or moved to another thread on its own
+    pub fn into_file_reader(self, file: &Inode) -> Result<FileReader<'static, R>>
+    where
+        R: 'static,
+    {
+        let info = file.as_file()?.clone();
+        self.record_access(file);
+        Ok(FileReader {
+            archive: Cow::Owned(self),
+            file: info,
+            pos: 0,
+            extent: None,
+        })
+    }
+
+    /// The decompressed blocks of the regular file `file`, in order
+    ///
+    /// Each block is the bytes of one [`Extent`] of the file: a data block, zeros for a sparse
+    /// block, or the file's part of a fragment block.
+    pub fn file_blocks<'a>(
+        &'a self,
+        file: &'a Inode,
+    ) -> Result<impl Iterator<Item = Result<Vec<u8>>> + 'a> {
+        let map = self.block_map(file)?;
+        self.record_access(file);
+        let extents: Vec<_> = map.extents().collect();
+        Ok(extents
+            .into_iter()
+            .map(move |extent| self.inner.read_extent(&extent)))
+    }
+}
+
+impl<R: ReadAt> ArchiveInner<R> {
+    /// Read the bytes of `extent`, which are exactly `extent.len` bytes unless the archive is
+    /// corrupt
+    pub(crate) fn read_extent(&self, extent: &Extent) -> Result<Vec<u8>> {
+        let len = extent.len as usize;
+        let mut data = match extent.kind {
+            ExtentKind::Block { offset, size, .. } => self.read_datablock(offset, size, len)?,
+            ExtentKind::Hole { .. } => vec![0; len],
+            ExtentKind::Fragment { fragment, offset } => {
+                let entry = self.fragment(fragment)?;
+                let block_size = self.superblock.block_size as usize;
+                let block = self.read_datablock(entry.start.0, entry.size, block_size)?;
+                block.get(offset as usize..).unwrap_or_default().to_vec()
+            }
+        };
+        data.truncate(len);
+        Ok(data)
+    }
+}
+
+/// A stream of the contents of a regular file, see [`Archive::open_file`]
+///
+/// Reading a block the archive can't provide, because it is corrupt or truncated, fails with
+/// [`io::ErrorKind::InvalidData`].
+#[derive(Debug)]
+pub struct FileReader<'a, R> {
+    archive: Cow<'a, Archive<R>>,
+    file: FileInfo,
+    pos: u64,
+    /// The offset in the file and contents of the extent last read
+    extent: Option<(u64, Vec<u8>)>,
+}
+
+impl<R> FileReader<'_, R> {
+    pub fn file_size(&self) -> u64 {
+        self.file.file_size
+    }
+
+    /// The number of bytes left to read
+    pub fn remaining(&self) -> u64 {
+        self.file.file_size.saturating_sub(self.pos)
+    }
+
+    /// The data blocks of the file as stored, see [`BlockMap::raw_blocks`]
+    ///
+    /// [`BlockMap::raw_blocks`]: super::BlockMap::raw_blocks
+    pub fn raw_blocks(&self) -> RawBlocks<'_> {
+        RawBlocks::new(&self.file, self.archive.block_size())
+    }
+}
+
+impl<R: ReadAt> FileReader<'_, R> {
+    /// The contents of the extent holding the byte at `self.pos`, from that byte
+    fn current(&mut self) -> Result<&[u8]> {
+        let cached = match &self.extent {
+            Some((start, data)) => (*start..*start + data.len() as u64).contains(&self.pos),
+            None => false,
+        };
+        if !cached {
+            let map = self.file.block_map(self.archive.block_size());
+            let extent = match map.locate(self.pos) {
+                Some(extent) => extent,
+                None => return Ok(&[]),
+            };
+            let data = self.archive.inner.read_extent(&extent)?;
+            if data.len() as u64 != extent.len {
+                return Err(ReadError::TruncatedFile {
+                    expected: self.file.file_size,
+                    actual: extent.file_offset + data.len() as u64,
+                }
+                .into());
+            }
+            self.extent = Some((extent.file_offset, data));
+        }
+        let (start, data) = self.extent.as_ref().unwrap();
+        Ok(&data[(self.pos - start) as usize..])
+    }
+}
+
+impl<R: ReadAt> Read for FileReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.file.file_size {
+            return Ok(0);
+        }
+        let available = self
+            .current()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: ReadAt> Seek for FileReader<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.file.file_size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        match new_pos {
+            Some(new_pos) => {
+                self.pos = new_pos;
+                Ok(new_pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_sparse() {
+        let archive = Archive::new(crate::testing::sparse()).unwrap();
+        let file = archive.lookup("sparse").unwrap();
+        let expected = crate::testing::sparse_contents();
+
+        let mut reader = archive.open_file(&file).unwrap();
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, expected);
+        assert_eq!(reader.remaining(), 0);
+
+        let blocks: Vec<Vec<u8>> = archive
+            .file_blocks(&file)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(blocks.concat(), expected);
+        assert!(blocks.len() > 1);
+
+        // Seek into the middle of the sparse blocks, and read across into the tail
+        let start = expected.len() as u64 - 4096 - 10;
+        reader.seek(SeekFrom::Start(start)).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, expected[start as usize..]);
+        assert!(reader
+            .seek(SeekFrom::Current(-(expected.len() as i64) - 1))
+            .is_err());
+    }
+}
@@ -0,0 +1,188 @@
+//! Recovering inodes which can't be reached from the root directory
+//!
+//! A corrupt or truncated directory table can leave inodes which are intact, but no longer
+//! listed in any directory. These are found by scanning the inode table directly, and given
+//! synthetic names under `/lost+found`, as `fsck` does.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use bstr::BString;
+use repr::offset::ArchiveOffset;
+
+use super::metablock::Cursor;
+use super::{Archive, ArchiveInner, Inode, ReadAt, WalkEntry};
+use crate::errors::Result;
+use crate::FileType;
+
+/// The directory orphaned inodes are placed in
+pub const LOST_FOUND: &str = "lost+found";
+
+impl<R: ReadAt> Archive<R> {
+    /// Find the inodes which aren't reachable from the root directory
+    ///
+    /// Each orphan is named `/lost+found/#<inode number>`. Orphaned directories are returned
+    /// without their contents, which are reached through them. Directories which can't be read
+    /// while looking for reachable inodes are skipped with a warning, so this works even when
+    /// [`walk`](Archive::walk) fails.
+    pub fn orphans(&self) -> Result<Vec<WalkEntry>> {
+        let mut reachable = HashSet::new();
+        match self.root() {
+            Ok(root) => self.mark_reachable(root, &mut reachable),
+            Err(e) => {
+                slog::warn!(self.inner.logger, "Unable to read the root inode"; "error" => %e)
+            }
+        }
+        let orphans: Vec<Inode> = self
+            .inner
+            .scan_inodes()?
+            .into_iter()
+            .filter(|inode| !reachable.contains(&inode.inode_number()))
+            .collect();
+
+        // Orphaned directories take their contents with them
+        let mut contained = HashSet::new();
+        for dir in orphans.iter().filter(|inode| inode.is_dir()) {
+            let mut subtree = HashSet::new();
+            self.mark_reachable(dir.clone(), &mut subtree);
+            subtree.remove(&dir.inode_number());
+            contained.extend(subtree);
+        }
+
+        Ok(orphans
+            .into_iter()
+            .filter(|inode| !contained.contains(&inode.inode_number()))
+            .map(|inode| {
+                let path = BString::from(format!("/{}/#{}", LOST_FOUND, inode.inode_number()));
+                WalkEntry::new(path, 1, inode, None)
+            })
+            .collect())
+    }
+
+    /// Extract every [orphaned](Archive::orphans) inode into `lost+found` within `dest`,
+    /// returning the number of orphans found
+    ///
+    /// This is meant to follow [`extract`](Archive::extract) of a damaged archive. An orphan
+    /// which can't be extracted is skipped with a warning.
+    pub fn extract_orphans<P: AsRef<Path>>(&self, dest: P) -> Result<usize> {
+        let orphans = self.orphans()?;
+        if orphans.is_empty() {
+            return Ok(0);
+        }
+        let dest = dest.as_ref();
+        fs::create_dir_all(dest.join(LOST_FOUND))?;
+        let mut dirs = Vec::new();
+        for orphan in &orphans {
+            let path = orphan.path().to_owned();
+            let walk = self.walk_from(path, 1, orphan.inode().clone());
+            if let Err(e) = self.extract_walk(walk, dest, &mut dirs) {
+                slog::warn!(self.inner.logger, "Unable to extract orphan"; "path" => %orphan.path(), "error" => %e);
+            }
+        }
+        super::extract::set_dir_permissions(dirs)?;
+        Ok(orphans.len())
+    }
+
+    /// Add the inode number of `start` and everything beneath it to `seen`, skipping anything
+    /// which can't be read
+    fn mark_reachable(&self, start: Inode, seen: &mut HashSet<u32>) {
+        let mut stack = vec![start];
+        while let Some(inode) = stack.pop() {
+            if !seen.insert(inode.inode_number()) || !inode.is_dir() {
+                continue;
+            }
+            let entries = match self.read_dir(&inode) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    slog::warn!(self.inner.logger, "Skipping unreadable directory";
+                        "inode" => inode.inode_number(), "error" => %e);
+                    continue;
+                }
+            };
+            for entry in entries {
+                if entry.file_type() != FileType::Dir {
+                    seen.insert(entry.inode_number());
+                    continue;
+                }
+                match self.inode(entry.inode_ref()) {
+                    Ok(inode) => stack.push(inode),
+                    Err(e) => slog::warn!(self.inner.logger, "Skipping unreadable inode";
+                        "name" => %entry.name(), "error" => %e),
+                }
+            }
+        }
+    }
+}
+
+impl<R: ReadAt> ArchiveInner<R> {
+    /// Parse every inode in the inode table, in the order they are stored
+    ///
+    /// The scan stops at the end of the table, after `inode_count` inodes, or at the first
+    /// inode which can't be parsed.
+    fn scan_inodes(&self) -> Result<Vec<Inode>> {
+        let table_start = ArchiveOffset(self.superblock.inode_table_start);
+        let table_end = ArchiveOffset(self.superblock.directory_table_start);
+        let count = self.superblock.inode_count as usize;
+        let mut inodes = Vec::new();
+        if table_start >= table_end {
+            return Ok(inodes);
+        }
+        let mut cursor = Cursor::new(self, table_start, 0)?;
+        while inodes.len() < count && cursor.position().0 < table_end {
+            match self.parse_inode(&mut cursor) {
+                Ok(inode) => inodes.push(inode),
+                Err(e) => {
+                    slog::warn!(self.logger, "Stopping inode table scan"; "inodes" => inodes.len(), "error" => %e);
+                    break;
+                }
+            }
+        }
+        Ok(inodes)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::mem;
+
+    #[test]
+    fn lost_and_found() {
+        let mut data = crate::testing::ImageBuilder::new()
+            .uncompressed()
+            .file("hello", crate::testing::HELLO_CONTENTS)
+            .symlink("link", "hello")
+            .build();
+
+        let archive = Archive::new(data.clone()).unwrap();
+        assert!(archive.orphans().unwrap().is_empty());
+
+        // Empty the root directory's listing, orphaning both of its entries
+        let superblock = archive.superblock();
+        let file_size = superblock.inode_table_start as usize
+            + mem::size_of::<repr::metablock::Header>()
+            + usize::from(superblock.root_inode_ref.start_offset())
+            + mem::size_of::<repr::inode::Header>()
+            + 8;
+        data[file_size..file_size + 2].copy_from_slice(&3u16.to_le_bytes());
+
+        let archive = Archive::new(data).unwrap();
+        assert!(archive
+            .read_dir(&archive.root().unwrap())
+            .unwrap()
+            .is_empty());
+        let orphans = archive.orphans().unwrap();
+        let paths: Vec<_> = orphans.iter().map(|orphan| orphan.path()).collect();
+        assert_eq!(paths, ["/lost+found/#1", "/lost+found/#2"]);
+
+        let dest = tempfile::tempdir().unwrap();
+        assert_eq!(archive.extract_orphans(dest.path()).unwrap(), 2);
+        let lost_found = dest.path().join(LOST_FOUND);
+        assert_eq!(fs::read(lost_found.join("#1")).unwrap(), b"hi there\n");
+        assert_eq!(
+            fs::read_link(lost_found.join("#2")).unwrap(),
+            Path::new("hello")
+        );
+    }
+}
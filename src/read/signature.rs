@@ -0,0 +1,119 @@
+use super::{Archive, ReadAt};
+use crate::digest::{Digest, Sha256};
+use crate::errors::{ReadError, Result};
+use crate::signature::{self, SignatureVerifier, TRAILER_HEADER_SIZE};
+
+/// The largest signature which will be read from a trailer
+const MAX_SIGNATURE_SIZE: u32 = 1 << 20;
+
+impl<R: ReadAt> Archive<R> {
+    /// The SHA-256 digest of the image, which is what a signature signs
+    ///
+    /// This covers the first [`bytes_used`](Self::bytes_used) bytes of the source, and reads
+    /// all of them.
+    pub fn image_digest(&self) -> Result<Digest> {
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; 64 * 1024];
+        let mut offset = 0;
+        let end = self.bytes_used();
+        while offset < end {
+            let len = buf.len().min((end - offset) as usize);
+            self.inner.source.read_exact_at(&mut buf[..len], offset)?;
+            hasher.update(&buf[..len]);
+            offset += len as u64;
+        }
+        Ok(hasher.finish())
+    }
+
+    /// The signature stored in a trailer after the image, if there is one
+    ///
+    /// See the [`signature`](crate::signature) module for the trailer's format.
+    pub fn embedded_signature(&self) -> Result<Option<Vec<u8>>> {
+        let offset = self.bytes_used();
+        let mut header = [0; TRAILER_HEADER_SIZE];
+        if self.inner.source.read_at(&mut header, offset)? < header.len() {
+            return Ok(None);
+        }
+        let len = match signature::trailer_len(&header) {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        if len > MAX_SIGNATURE_SIZE {
+            return Err(ReadError::SignatureTooLarge(len).into());
+        }
+        let mut signature = vec![0; len as usize];
+        self.inner
+            .source
+            .read_exact_at(&mut signature, offset + TRAILER_HEADER_SIZE as u64)?;
+        Ok(Some(signature))
+    }
+
+    /// Check the signature of the image with `verifier`
+    ///
+    /// The detached `signature` is checked if it is given, otherwise the
+    /// [embedded signature](Self::embedded_signature) is.
+    pub fn verify_signature(
+        &self,
+        verifier: &dyn SignatureVerifier,
+        signature: Option<&[u8]>,
+    ) -> Result<()> {
+        let embedded;
+        let signature = match signature {
+            Some(signature) => signature,
+            None => {
+                embedded = self
+                    .embedded_signature()?
+                    .ok_or(ReadError::MissingSignature)?;
+                &embedded[..]
+            }
+        };
+        if !verifier.verify(&self.image_digest()?, signature) {
+            return Err(ReadError::InvalidSignature.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    /// A toy scheme, where the signature is the digest followed by a key byte
+    struct Key(u8);
+
+    impl signature::Signer for Key {
+        fn sign(&self, digest: &Digest) -> std::io::Result<Vec<u8>> {
+            let mut signature = digest.as_bytes().to_vec();
+            signature.push(self.0);
+            Ok(signature)
+        }
+    }
+
+    impl SignatureVerifier for Key {
+        fn verify(&self, digest: &Digest, signature: &[u8]) -> bool {
+            signature.split_last() == Some((&self.0, &digest.as_bytes()[..]))
+        }
+    }
+
+    #[test]
+    fn embedded_signature() {
+        use crate::signature::Signer;
+
+        let mut data = crate::testing::file_and_symlink();
+        let unsigned = Archive::new(data.clone()).unwrap();
+        assert_eq!(unsigned.embedded_signature().unwrap(), None);
+        unsigned.verify_signature(&Key(1), None).unwrap_err();
+
+        let signature = Key(1).sign(&unsigned.image_digest().unwrap()).unwrap();
+        unsigned
+            .verify_signature(&Key(1), Some(&signature))
+            .unwrap();
+        data.extend_from_slice(&signature::trailer(&signature).unwrap());
+        data.resize(4096, 0);
+
+        let signed = Archive::new(data).unwrap();
+        assert_eq!(signed.embedded_signature().unwrap(), Some(signature));
+        signed.verify_signature(&Key(1), None).unwrap();
+        signed.verify_signature(&Key(2), None).unwrap_err();
+    }
+}
@@ -0,0 +1,189 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::sync::Arc;
+
+/// A source of archive data supporting positional reads
+///
+/// Reads take `&self`, so a single source can be shared between threads without a cursor.
+pub trait ReadAt {
+    /// Read bytes starting at `offset`, returning the number of bytes read
+    ///
+    /// Like [`io::Read::read`], this may read fewer bytes than requested. Reading at or past the
+    /// end of the source returns `Ok(0)`
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// The total size of the source, if it is known
+    fn size(&self) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Hint how `len` bytes starting at `offset` will be accessed, `len == 0` meaning until the
+    /// end of the source
+    ///
+    /// This is only a hint, which sources may ignore. The default implementation does nothing.
+    fn advise(&self, offset: u64, len: u64, advice: Advice) {}
+
+    /// Read exactly `buf.len()` bytes starting at `offset`
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How a range of a source is about to be accessed, see [`ReadAt::advise`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Advice {
+    /// The range will be read mostly sequentially
+    Sequential,
+    /// The range will be read soon, so it could be read ahead
+    WillNeed,
+}
+
+impl ReadAt for [u8] {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let start = match usize::try_from(offset) {
+            Ok(start) if start < self.len() => start,
+            _ => return Ok(0),
+        };
+        let src = &self[start..];
+        let len = src.len().min(buf.len());
+        buf[..len].copy_from_slice(&src[..len]);
+        Ok(len)
+    }
+
+    fn size(&self) -> io::Result<Option<u64>> {
+        Ok(Some(self.len() as u64))
+    }
+}
+
+impl ReadAt for Vec<u8> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.as_slice().read_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<Option<u64>> {
+        self.as_slice().size()
+    }
+}
+
+/// A source backed by any in-memory buffer, such as a `&'static [u8]` from `include_bytes!`,
+/// a `Box<[u8]>` or a memory map
+///
+/// See [`Archive::from_bytes`](super::Archive::from_bytes).
+#[derive(Debug, Clone)]
+pub struct Bytes<T>(T);
+
+impl<T: AsRef<[u8]>> Bytes<T> {
+    pub fn new(bytes: T) -> Self {
+        Bytes(bytes)
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: AsRef<[u8]>> ReadAt for Bytes<T> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.0.as_ref().read_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<Option<u64>> {
+        self.0.as_ref().size()
+    }
+}
+
+#[cfg(unix)]
+impl ReadAt for File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(self, buf, offset)
+    }
+
+    fn size(&self) -> io::Result<Option<u64>> {
+        Ok(Some(self.metadata()?.len()))
+    }
+
+    #[cfg(all(feature = "readahead", target_os = "linux"))]
+    fn advise(&self, offset: u64, len: u64, advice: Advice) {
+        use std::os::unix::io::AsRawFd;
+
+        let advice = match advice {
+            Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+            Advice::WillNeed => libc::POSIX_FADV_WILLNEED,
+        };
+        let offset = libc::off_t::try_from(offset).unwrap_or(libc::off_t::MAX);
+        let len = libc::off_t::try_from(len).unwrap_or(0);
+        // The advice is only a hint, so failures don't matter
+        unsafe {
+            libc::posix_fadvise(self.as_raw_fd(), offset, len, advice);
+        }
+    }
+}
+
+#[cfg(windows)]
+impl ReadAt for File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(self, buf, offset)
+    }
+
+    fn size(&self) -> io::Result<Option<u64>> {
+        Ok(Some(self.metadata()?.len()))
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for &T {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<Option<u64>> {
+        (**self).size()
+    }
+
+    fn advise(&self, offset: u64, len: u64, advice: Advice) {
+        (**self).advise(offset, len, advice)
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for Box<T> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<Option<u64>> {
+        (**self).size()
+    }
+
+    fn advise(&self, offset: u64, len: u64, advice: Advice) {
+        (**self).advise(offset, len, advice)
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for Arc<T> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<Option<u64>> {
+        (**self).size()
+    }
+
+    fn advise(&self, offset: u64, len: u64, advice: Advice) {
+        (**self).advise(offset, len, advice)
+    }
+}
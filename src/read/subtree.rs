@@ -0,0 +1,125 @@
+//! Carving a directory out of an archive into an image of its own
+
+use std::io;
+
+use super::{Archive, ReadAt};
+use crate::errors::{ReadError, Result};
+use crate::write::tree::Tree;
+use crate::write::{ArchiveBuilder, Report};
+
+impl<R: ReadAt + 'static> Archive<R> {
+    /// Write a new image to `writer`, containing only the directory at `path` and everything
+    /// beneath it, with the directory as its root
+    ///
+    /// File contents are streamed from this archive a block at a time as they're written, so
+    /// nothing is extracted or held in memory. Extended attributes, and hard links within the
+    /// directory, are kept.
+    ///
+    /// ```no_run
+    /// use sqfs::read::Archive;
+    /// use sqfs::write::ArchiveBuilder;
+    ///
+    /// # fn main() -> sqfs::Result<()> {
+    /// let archive = Archive::open("rootfs.sqfs")?;
+    /// let output = std::fs::File::create("usr.sqfs")?;
+    /// archive.export_subtree("/usr", ArchiveBuilder::new(), output)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn export_subtree<P: AsRef<[u8]>, W: io::Write>(
+        &self,
+        path: P,
+        builder: ArchiveBuilder,
+        writer: W,
+    ) -> Result<Report> {
+        let tree = self.subtree(path.as_ref())?;
+        let mut archive = builder.build(writer);
+        let root = tree.build(&mut archive)?;
+        archive.set_root(root);
+        archive.flush()
+    }
+
+    fn subtree(&self, path: &[u8]) -> Result<Tree> {
+        let (path, depth, dir) = self.lookup_path(path)?;
+        if !dir.is_dir() {
+            return Err(ReadError::NotADirectory.into());
+        }
+        let mut tree = Tree::new();
+        let walk = self.walk_from(path.clone(), depth, dir);
+        tree.add_walk(self, walk, |tree, entry_path, entry| {
+            tree.insert(&entry_path[path.len()..], entry)
+        })?;
+        Ok(tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ImageBuilder;
+
+    #[test]
+    fn carve_subtree() {
+        let image = ImageBuilder::new()
+            .file("top", "top")
+            .file("usr/bin/a", "a")
+            .hard_link("usr/lib/b", "usr/bin/a")
+            .symlink("usr/sbin", "bin")
+            .build();
+        let archive = Archive::new(image).unwrap();
+
+        let mut tree = archive.subtree(b"//usr/").unwrap();
+        let root = tree.get_mut(b"").unwrap().unwrap();
+        let names: Vec<_> = root.children.keys().cloned().collect();
+        assert_eq!(names, ["bin", "lib", "sbin"]);
+        assert_eq!(
+            root.entry.as_ref().unwrap().mode.ty(),
+            crate::Mode::TYPE_DIR
+        );
+        let a = tree
+            .get_mut(b"bin/a")
+            .unwrap()
+            .unwrap()
+            .entry
+            .clone()
+            .unwrap();
+        let b = tree
+            .get_mut(b"lib/b")
+            .unwrap()
+            .unwrap()
+            .entry
+            .clone()
+            .unwrap();
+        assert!(a.link.is_some());
+        assert_eq!(a.link, b.link);
+
+        let err = archive.subtree(b"usr/missing").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            r#"Read error: No such entry "usr/missing""#
+        );
+        let err = archive.subtree(b"top").err().unwrap();
+        assert_eq!(err.to_string(), "Read error: Expected a directory");
+    }
+
+    #[test]
+    fn export() {
+        let big: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let image = ImageBuilder::new()
+            .file("top", "top")
+            .file("usr/big", big.clone())
+            .hard_link("usr/again", "usr/big")
+            .build();
+        let archive = Archive::new(image).unwrap();
+        let mut output = Vec::new();
+        archive
+            .export_subtree("usr", ArchiveBuilder::new(), &mut output)
+            .unwrap();
+
+        let exported = Archive::new(output).unwrap();
+        let file = exported.lookup("big").unwrap();
+        assert_eq!(exported.read_file(&file).unwrap(), big);
+        assert_eq!(file.hard_link_count(), 2);
+        assert!(exported.lookup("top").is_err());
+    }
+}
@@ -0,0 +1,272 @@
+//! Describing what's missing from a truncated image
+//!
+//! An interrupted download or copy leaves an image shorter than the `bytes_used` its superblock
+//! records. Opening one fails by default, with an error naming the tables past the end of the
+//! file, which is usually enough to tell whether the image is worth fetching again or can be
+//! read as is. [`OpenOptions::allow_truncated`](super::OpenOptions::allow_truncated) opens it
+//! anyway, so whatever lies in the intact prefix can still be read, and
+//! [`Archive::truncated_files`] lists the files whose contents were lost.
+
+use std::fmt;
+use std::mem;
+use std::ops::Range;
+
+use bstr::BString;
+use repr::superblock::Superblock;
+
+use super::{Archive, InodeData, ReadAt};
+use crate::errors::Result;
+
+/// The parts of an image which lie past the end of its source, see
+/// [`OpenOptions::allow_truncated`](super::OpenOptions::allow_truncated)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Truncation {
+    pub bytes_used: u64,
+    pub file_size: u64,
+    /// The sections of the image which are wholly or partly missing, in the order they're
+    /// stored
+    pub sections: Vec<Section>,
+}
+
+/// A section of an image, such as the inode table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub name: &'static str,
+    /// Where the section is stored in the image
+    pub range: Range<u64>,
+}
+
+impl Truncation {
+    /// Describe an image which is only `file_size` bytes of `source`
+    ///
+    /// The start of the fragment, export and id tables' metablocks is read from their lists of
+    /// locations, when those survived, otherwise their metablocks are counted with the section
+    /// before them.
+    pub(crate) fn new<R: ReadAt>(source: &R, superblock: &Superblock, file_size: u64) -> Self {
+        let bytes_used = superblock.bytes_used;
+        // The first metablock of a lookup table, if its list of locations can be read
+        let metablocks_start = |start: u64| {
+            let mut first = [0; mem::size_of::<u64>()];
+            if start == u64::MAX || start + first.len() as u64 > file_size {
+                return None;
+            }
+            source.read_exact_at(&mut first, start).ok()?;
+            Some(u64::from_le_bytes(first)).filter(|&first| first <= start)
+        };
+
+        let mut starts = vec![
+            ("data blocks", mem::size_of::<Superblock>() as u64),
+            ("inode table", superblock.inode_table_start),
+            ("directory table", superblock.directory_table_start),
+        ];
+        let lookup_tables = [
+            ("fragment table", superblock.fragment_table_start),
+            ("export table", superblock.export_table_start),
+            ("id table", superblock.id_table_start),
+        ];
+        for &(name, start) in &lookup_tables {
+            if start != u64::MAX {
+                starts.push((name, metablocks_start(start).unwrap_or(start)));
+            }
+        }
+        if superblock.xattr_id_table_start != u64::MAX {
+            let xattr_start = metablocks_start(superblock.xattr_id_table_start);
+            starts.push((
+                "xattr table",
+                xattr_start.unwrap_or(superblock.xattr_id_table_start),
+            ));
+        }
+        starts.sort_by_key(|&(_, start)| start);
+
+        let mut sections = Vec::new();
+        for (i, &(name, start)) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).map_or(bytes_used, |&(_, next)| next);
+            if end > file_size && end > start {
+                sections.push(Section {
+                    name,
+                    range: start..end,
+                });
+            }
+        }
+        Truncation {
+            bytes_used,
+            file_size,
+            sections,
+        }
+    }
+
+    /// The number of bytes missing from the end of the image
+    pub fn missing_bytes(&self) -> u64 {
+        self.bytes_used.saturating_sub(self.file_size)
+    }
+
+    /// Whether the bytes of the image in `range` are all present
+    pub fn is_intact(&self, range: Range<u64>) -> bool {
+        range.end <= self.file_size
+    }
+}
+
+impl Section {
+    /// Whether part of the section survived
+    pub fn is_partial(&self, truncation: &Truncation) -> bool {
+        self.range.start < truncation.file_size
+    }
+}
+
+impl fmt::Display for Truncation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "uses {} bytes, but the file is only {} bytes, missing ",
+            self.bytes_used, self.file_size
+        )?;
+        for (i, section) in self.sections.iter().enumerate() {
+            if i != 0 {
+                f.write_str(", ")?;
+            }
+            f.write_str(section.name)?;
+            if section.is_partial(self) {
+                f.write_str(" (partly)")?;
+            }
+        }
+        write!(
+            f,
+            ". Fetch the last {} bytes again, or open it with `allow_truncated` to read what remains",
+            self.missing_bytes()
+        )
+    }
+}
+
+impl<R: ReadAt> Archive<R> {
+    /// What's missing from the image, if it was opened truncated, see
+    /// [`OpenOptions::allow_truncated`](super::OpenOptions::allow_truncated)
+    pub fn truncation(&self) -> Option<&Truncation> {
+        self.inner.truncation.as_ref()
+    }
+
+    /// The paths of the regular files with data or fragment blocks past the end of a truncated
+    /// image, which can't be read in full
+    ///
+    /// Finding them needs the inode and directory tables, so this fails if those were lost
+    /// too. An image which isn't truncated has none.
+    pub fn truncated_files(&self) -> Result<Vec<BString>> {
+        let truncation = match self.truncation() {
+            Some(truncation) => truncation,
+            None => return Ok(Vec::new()),
+        };
+        let mut paths = Vec::new();
+        for entry in self.walk() {
+            let entry = entry?;
+            let file = match entry.inode().data() {
+                InodeData::File(file) => file,
+                _ => continue,
+            };
+            let stored: u64 = file
+                .block_sizes
+                .iter()
+                .map(|size| u64::from(size.size()))
+                .sum();
+            let mut intact = truncation.is_intact(file.blocks_start..file.blocks_start + stored);
+            if let Some(fragment) = file.fragment {
+                intact &= match self.inner.fragment(fragment.index) {
+                    Ok(entry) => {
+                        let start = entry.start.0;
+                        truncation.is_intact(start..start + u64::from({ entry.size }.size()))
+                    }
+                    Err(_) => false,
+                };
+            }
+            if !intact {
+                paths.push(entry.path().to_owned());
+            }
+        }
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read::OpenOptions;
+    use crate::testing::ImageBuilder;
+
+    #[test]
+    fn truncated_image() {
+        let contents = crate::testing::compressible_contents();
+        let image = ImageBuilder::new()
+            .file("a", "small")
+            .file("b", contents)
+            .build();
+        let archive = Archive::new(image.clone()).unwrap();
+        let b = archive.lookup("b").unwrap();
+        let first = archive.block_map(&b).unwrap().raw_blocks().next().unwrap();
+
+        // Cut into the data of `b`, after the block holding all of `a`
+        let cut = first.offset as usize + 10;
+        let short = image[..cut].to_vec();
+        let err = Archive::new(short.clone()).unwrap_err().to_string();
+        assert!(err.contains("truncated"), "{}", err);
+        assert!(err.contains("data blocks (partly), inode table, directory table, id table"));
+
+        let archive = OpenOptions::new()
+            .allow_truncated(true)
+            .open_source(short)
+            .unwrap();
+        let truncation = archive.truncation().unwrap();
+        assert_eq!(truncation.missing_bytes(), (image.len() - cut) as u64);
+        assert_eq!(truncation.sections.len(), 4);
+        assert!(truncation.sections[0].is_partial(truncation));
+        assert!(!truncation.sections[1].is_partial(truncation));
+        assert_eq!(truncation.sections[3].range.end, {
+            archive.superblock().bytes_used
+        });
+        // The data of `a` is before the cut, but the inodes are gone
+        assert!(archive.root().is_err());
+        assert!(archive.truncated_files().is_err());
+    }
+
+    /// An image whose size is reported as `size`, though every byte can still be read, like
+    /// a download which fetched the tables at the end but not all of the data
+    struct Fetched {
+        image: Vec<u8>,
+        size: u64,
+    }
+
+    impl ReadAt for Fetched {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+            self.image.read_at(buf, offset)
+        }
+
+        fn size(&self) -> std::io::Result<Option<u64>> {
+            Ok(Some(self.size))
+        }
+    }
+
+    #[test]
+    fn truncated_files() {
+        let contents = crate::testing::compressible_contents();
+        let image = ImageBuilder::new()
+            .file("a", "small")
+            .file("b", contents)
+            .build();
+        let intact = Archive::new(image.clone()).unwrap();
+        assert!(intact.truncation().is_none());
+        assert!(intact.truncated_files().unwrap().is_empty());
+        let b = intact.lookup("b").unwrap();
+        let first = intact.block_map(&b).unwrap().raw_blocks().next().unwrap();
+
+        let source = Fetched {
+            image,
+            size: first.offset + 10,
+        };
+        let archive = OpenOptions::new()
+            .allow_truncated(true)
+            .open_source(source)
+            .unwrap();
+        assert_eq!(archive.truncated_files().unwrap(), ["/b"]);
+        assert_eq!(
+            archive.read_file(&archive.lookup("a").unwrap()).unwrap(),
+            b"small"
+        );
+    }
+}
@@ -0,0 +1,348 @@
+//! Comparing the contents of an archive against a directory tree on disk
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use bstr::ByteSlice;
+
+use super::{Archive, Inode, InodeData, ReadAt};
+use crate::errors::Result;
+use crate::Mode;
+
+/// A difference between an archive and a directory tree on disk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    /// The path exists in the archive, but not on disk
+    OnlyInImage { path: PathBuf },
+    /// The path exists on disk, but not in the archive
+    OnlyOnDisk { path: PathBuf },
+    /// The file types differ
+    Kind {
+        path: PathBuf,
+        image: Mode,
+        disk: Mode,
+    },
+    Permissions {
+        path: PathBuf,
+        image: Mode,
+        disk: Mode,
+    },
+    /// The owning uid and gid differ
+    Owner {
+        path: PathBuf,
+        image: (u32, u32),
+        disk: (u32, u32),
+    },
+    /// The modification times differ, in seconds since the unix epoch
+    ModifiedTime {
+        path: PathBuf,
+        image: i64,
+        disk: i64,
+    },
+    Size {
+        path: PathBuf,
+        image: u64,
+        disk: u64,
+    },
+    /// Regular files of the same size with different contents
+    Content { path: PathBuf },
+    SymlinkTarget {
+        path: PathBuf,
+        image: PathBuf,
+        disk: PathBuf,
+    },
+    /// Device numbers, as `(major, minor)`
+    Device {
+        path: PathBuf,
+        image: (u32, u32),
+        disk: (u32, u32),
+    },
+}
+
+impl Mismatch {
+    pub fn path(&self) -> &Path {
+        match self {
+            Mismatch::OnlyInImage { path }
+            | Mismatch::OnlyOnDisk { path }
+            | Mismatch::Kind { path, .. }
+            | Mismatch::Permissions { path, .. }
+            | Mismatch::Owner { path, .. }
+            | Mismatch::ModifiedTime { path, .. }
+            | Mismatch::Size { path, .. }
+            | Mismatch::Content { path }
+            | Mismatch::SymlinkTarget { path, .. }
+            | Mismatch::Device { path, .. } => path,
+        }
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = self.path().display();
+        match self {
+            Mismatch::OnlyInImage { .. } => write!(f, "{}: only in image", path),
+            Mismatch::OnlyOnDisk { .. } => write!(f, "{}: only on disk", path),
+            Mismatch::Kind { image, disk, .. } => {
+                write!(f, "{}: type differs: image {} disk {}", path, image, disk)
+            }
+            Mismatch::Permissions { image, disk, .. } => {
+                write!(f, "{}: mode differs: image {} disk {}", path, image, disk)
+            }
+            Mismatch::Owner { image, disk, .. } => write!(
+                f,
+                "{}: owner differs: image {}:{} disk {}:{}",
+                path, image.0, image.1, disk.0, disk.1
+            ),
+            Mismatch::ModifiedTime { image, disk, .. } => {
+                write!(f, "{}: mtime differs: image {} disk {}", path, image, disk)
+            }
+            Mismatch::Size { image, disk, .. } => {
+                write!(f, "{}: size differs: image {} disk {}", path, image, disk)
+            }
+            Mismatch::Content { .. } => write!(f, "{}: contents differ", path),
+            Mismatch::SymlinkTarget { image, disk, .. } => write!(
+                f,
+                "{}: link target differs: image {} disk {}",
+                path,
+                image.display(),
+                disk.display()
+            ),
+            Mismatch::Device { image, disk, .. } => write!(
+                f,
+                "{}: device differs: image {},{} disk {},{}",
+                path, image.0, image.1, disk.0, disk.1
+            ),
+        }
+    }
+}
+
+impl<R: ReadAt> Archive<R> {
+    /// Compare the contents of the archive against the directory tree rooted at `dir`
+    ///
+    /// Types, permissions, ownership, modification times, symlink targets, device numbers and
+    /// file contents are compared. Ownership, times and device numbers are only compared on unix.
+    ///
+    /// Returns every difference found, an empty vec means the trees match.
+    pub fn verify_tree<P: AsRef<Path>>(&self, dir: P) -> Result<Vec<Mismatch>> {
+        let mut verifier = Verifier {
+            archive: self,
+            mismatches: Vec::new(),
+        };
+        let root = self.root()?;
+        let disk = fs::symlink_metadata(dir.as_ref())?;
+        verifier.verify(&root, dir.as_ref(), Path::new(""), &disk)?;
+        Ok(verifier.mismatches)
+    }
+}
+
+struct Verifier<'a, R> {
+    archive: &'a Archive<R>,
+    mismatches: Vec<Mismatch>,
+}
+
+impl<R: ReadAt> Verifier<'_, R> {
+    fn verify(
+        &mut self,
+        inode: &Inode,
+        disk_path: &Path,
+        path: &Path,
+        disk: &fs::Metadata,
+    ) -> Result<()> {
+        let disk_mode = disk_mode(disk);
+        if inode.mode().ty() != disk_mode.ty() {
+            self.mismatches.push(Mismatch::Kind {
+                path: path.to_owned(),
+                image: inode.mode().ty(),
+                disk: disk_mode.ty(),
+            });
+            return Ok(());
+        }
+        if inode.permissions() != disk_mode.perm() {
+            self.mismatches.push(Mismatch::Permissions {
+                path: path.to_owned(),
+                image: inode.permissions(),
+                disk: disk_mode.perm(),
+            });
+        }
+        self.verify_unix(inode, path, disk);
+
+        match inode.data() {
+            InodeData::Directory(_) => self.verify_dir(inode, disk_path, path)?,
+            InodeData::File(_) => {
+                let image = inode.file_size().unwrap_or_default();
+                if image != disk.len() {
+                    self.mismatches.push(Mismatch::Size {
+                        path: path.to_owned(),
+                        image,
+                        disk: disk.len(),
+                    });
+                } else if self.archive.read_file(inode)? != fs::read(disk_path)? {
+                    self.mismatches.push(Mismatch::Content {
+                        path: path.to_owned(),
+                    });
+                }
+            }
+            InodeData::Symlink(target) => {
+                let image = target.to_path_lossy().into_owned();
+                let disk = fs::read_link(disk_path)?;
+                if image != disk {
+                    self.mismatches.push(Mismatch::SymlinkTarget {
+                        path: path.to_owned(),
+                        image,
+                        disk,
+                    });
+                }
+            }
+            InodeData::BlockDevice(_)
+            | InodeData::CharDevice(_)
+            | InodeData::Fifo
+            | InodeData::Socket => {}
+        }
+        Ok(())
+    }
+
+    fn verify_dir(&mut self, inode: &Inode, disk_path: &Path, path: &Path) -> Result<()> {
+        let mut seen = HashSet::new();
+        for entry in self.archive.read_dir(inode)? {
+            let name = entry.name().to_os_str_lossy().into_owned();
+            let child_path = path.join(&name);
+            let child_disk_path = disk_path.join(&name);
+            seen.insert(name);
+
+            let disk = match fs::symlink_metadata(&child_disk_path) {
+                Ok(disk) => disk,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    self.mismatches
+                        .push(Mismatch::OnlyInImage { path: child_path });
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let child = self.archive.inode(entry.inode_ref())?;
+            self.verify(&child, &child_disk_path, &child_path, &disk)?;
+        }
+
+        let mut only_on_disk: Vec<OsString> = fs::read_dir(disk_path)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<io::Result<_>>()?;
+        only_on_disk.retain(|name| !seen.contains(name));
+        only_on_disk.sort();
+        self.mismatches
+            .extend(only_on_disk.into_iter().map(|name| Mismatch::OnlyOnDisk {
+                path: path.join(name),
+            }));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn verify_unix(&mut self, inode: &Inode, path: &Path, disk: &fs::Metadata) {
+        use std::os::unix::fs::MetadataExt;
+
+        if (inode.uid(), inode.gid()) != (disk.uid(), disk.gid()) {
+            self.mismatches.push(Mismatch::Owner {
+                path: path.to_owned(),
+                image: (inode.uid(), inode.gid()),
+                disk: (disk.uid(), disk.gid()),
+            });
+        }
+        // Directory mtimes are easily disturbed, and rarely meaningful
+        if !inode.is_dir() && i64::from(inode.mtime()) != disk.mtime() {
+            self.mismatches.push(Mismatch::ModifiedTime {
+                path: path.to_owned(),
+                image: inode.mtime().into(),
+                disk: disk.mtime(),
+            });
+        }
+        if let InodeData::BlockDevice(device) | InodeData::CharDevice(device) = inode.data() {
+            let image = (device.major(), device.minor());
+            let disk = split_device(disk.rdev());
+            if image != disk {
+                self.mismatches.push(Mismatch::Device {
+                    path: path.to_owned(),
+                    image,
+                    disk,
+                });
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn verify_unix(&mut self, inode: &Inode, path: &Path, disk: &fs::Metadata) {}
+}
+
+#[cfg(unix)]
+pub(crate) fn disk_mode(metadata: &fs::Metadata) -> Mode {
+    use std::os::unix::fs::MetadataExt;
+
+    Mode::from_unix(metadata.mode())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn disk_mode(metadata: &fs::Metadata) -> Mode {
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_dir() {
+        Mode::TYPE_DIR
+    } else if file_type.is_symlink() {
+        Mode::TYPE_LINK
+    } else {
+        Mode::TYPE_FILE
+    };
+    Mode::from(metadata.permissions()) | kind
+}
+
+/// Split a linux `dev_t` into its major and minor numbers
+#[cfg(unix)]
+pub(crate) fn split_device(dev: u64) -> (u32, u32) {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    (major as u32, minor as u32)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::testing::{ImageBuilder, HELLO_CONTENTS};
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn verify_matching_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("hello"), HELLO_CONTENTS).unwrap();
+        std::os::unix::fs::symlink("hello", dir.path().join("link")).unwrap();
+
+        // An image of the directory as it is on disk
+        let mut image = ImageBuilder::new();
+        image.file("hello", HELLO_CONTENTS).symlink("link", "hello");
+        for path in ["", "hello", "link"] {
+            let metadata = fs::symlink_metadata(dir.path().join(path)).unwrap();
+            image
+                .mode(path, disk_mode(&metadata))
+                .owner(path, metadata.uid(), metadata.gid())
+                .mtime(path, metadata.mtime() as u32);
+        }
+        let archive = Archive::new(image.build()).unwrap();
+        assert_eq!(archive.verify_tree(dir.path()).unwrap(), []);
+
+        fs::write(dir.path().join("hello"), b"hi where\n").unwrap();
+        fs::write(dir.path().join("extra"), b"").unwrap();
+        fs::remove_file(dir.path().join("link")).unwrap();
+        std::os::unix::fs::symlink("elsewhere", dir.path().join("link")).unwrap();
+
+        let mismatches = archive.verify_tree(dir.path()).unwrap();
+        assert!(mismatches.contains(&Mismatch::Content {
+            path: "hello".into()
+        }));
+        assert!(mismatches.contains(&Mismatch::SymlinkTarget {
+            path: "link".into(),
+            image: "hello".into(),
+            disk: "elsewhere".into(),
+        }));
+        assert!(mismatches.contains(&Mismatch::OnlyOnDisk {
+            path: "extra".into()
+        }));
+    }
+}
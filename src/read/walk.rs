@@ -0,0 +1,327 @@
+//! Depth-first traversal of every entry in an archive
+//!
+//! This is the building block for converting an archive to other formats, such as EROFS or
+//! tar: entries are produced parents first, with their full metadata, and hard links are
+//! identified so they can be recreated rather than duplicated.
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use sqfs::read::{Archive, InodeData};
+//!
+//! let archive = Archive::open("image.sqfs")?;
+//! for entry in archive.walk() {
+//!     let entry = entry?;
+//!     match (entry.hard_link_target(), entry.inode().data()) {
+//!         (Some(target), _) => println!("{} => {}", entry.path(), target),
+//!         (None, InodeData::File(_)) => {
+//!             let contents = archive.read_file(entry.inode())?;
+//!             println!("{}: {} bytes", entry.path(), contents.len());
+//!         }
+//!         (None, _) => println!("{}", entry.path()),
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::mem;
+
+use bstr::{BStr, BString};
+use chrono::{DateTime, Utc};
+use indexmap::IndexMap;
+
+use super::{Archive, DirEntry, Inode, ReadAt};
+use crate::errors::Result;
+use crate::FileType;
+
+/// An entry produced by [`Archive::walk`]
+#[derive(Debug, Clone)]
+pub struct WalkEntry {
+    path: BString,
+    depth: usize,
+    inode: Inode,
+    hard_link_target: Option<BString>,
+}
+
+impl WalkEntry {
+    pub(crate) fn new(
+        path: BString,
+        depth: usize,
+        inode: Inode,
+        hard_link_target: Option<BString>,
+    ) -> Self {
+        WalkEntry {
+            path,
+            depth,
+            inode,
+            hard_link_target,
+        }
+    }
+
+    /// The absolute path of the entry, `/` for the root
+    pub fn path(&self) -> &BStr {
+        self.path.as_ref()
+    }
+
+    /// The number of directories between the root and this entry, the root has depth 0
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn inode(&self) -> &Inode {
+        &self.inode
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.inode.file_type()
+    }
+
+    /// The path of an earlier entry with the same inode, if this entry is a hard link to it
+    pub fn hard_link_target(&self) -> Option<&BStr> {
+        self.hard_link_target.as_ref().map(|target| target.as_ref())
+    }
+}
+
+/// An iterator over every entry in an archive, see [`Archive::walk`]
+pub struct Walk<'a, R> {
+    archive: &'a Archive<R>,
+    start: Start,
+    /// For each directory being walked: its path, depth and remaining entries, in reverse order
+    stack: Vec<(BString, usize, Vec<DirEntry>)>,
+    seen: HashMap<u32, BString>,
+}
+
+/// Where a walk starts, until the first entry is produced
+enum Start {
+    Root,
+    Entry(BString, usize, Inode),
+    Started,
+}
+
+impl<R: ReadAt> Archive<R> {
+    /// Walk every entry in the archive, depth first
+    ///
+    /// The root comes first, and every directory is produced before its contents. Entries in a
+    /// directory are produced in the order they are stored in, which is sorted by name.
+    pub fn walk(&self) -> Walk<'_, R> {
+        Walk {
+            archive: self,
+            start: Start::Root,
+            stack: Vec::new(),
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Walk the entries modified after `cutoff`, in the same order as [`walk`](Archive::walk)
+    ///
+    /// Every directory is still read, since a file can change without its directory changing,
+    /// but only entries whose modification time is later than `cutoff` are produced. Comparing
+    /// successive images this way finds the files changed between them, as long as the tool
+    /// building them kept the original times.
+    pub fn walk_since(
+        &self,
+        cutoff: DateTime<Utc>,
+    ) -> impl Iterator<Item = Result<WalkEntry>> + '_ {
+        self.walk().filter(move |entry| match entry {
+            Ok(entry) => entry.inode().modified_time() > cutoff,
+            Err(_) => true,
+        })
+    }
+
+    /// Walk the subtree rooted at `inode`, which is at `path` and `depth`
+    pub(crate) fn walk_from(&self, path: BString, depth: usize, inode: Inode) -> Walk<'_, R> {
+        Walk {
+            archive: self,
+            start: Start::Entry(path, depth, inode),
+            stack: Vec::new(),
+            seen: HashMap::new(),
+        }
+    }
+}
+
+/// A set of paths which are hard links to the same inode, see [`Archive::hardlinks`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HardLinkGroup {
+    inode_number: u32,
+    paths: Vec<BString>,
+}
+
+impl HardLinkGroup {
+    pub fn inode_number(&self) -> u32 {
+        self.inode_number
+    }
+
+    /// Every path to the inode, in the order [`Archive::walk`] produces them
+    ///
+    /// The first path is the one which the walk produces without a
+    /// [`hard_link_target`](WalkEntry::hard_link_target), and which the others link to.
+    pub fn paths(&self) -> &[BString] {
+        &self.paths
+    }
+}
+
+impl<R: ReadAt> Archive<R> {
+    /// Find every inode which is reachable by more than one path
+    ///
+    /// Groups are in the order the walk reaches their first path. Directories can't be hard
+    /// linked, so are never included.
+    pub fn hardlinks(&self) -> Result<Vec<HardLinkGroup>> {
+        let mut groups: IndexMap<u32, Vec<BString>> = IndexMap::new();
+        for entry in self.walk() {
+            let entry = entry?;
+            if !entry.inode.is_dir() {
+                let paths = groups.entry(entry.inode.inode_number()).or_default();
+                paths.push(entry.path);
+            }
+        }
+        Ok(groups
+            .into_iter()
+            .filter(|(_, paths)| paths.len() > 1)
+            .map(|(inode_number, paths)| HardLinkGroup {
+                inode_number,
+                paths,
+            })
+            .collect())
+    }
+}
+
+/// Join an entry's name onto the path of its directory
+pub(crate) fn child_path(dir_path: &[u8], name: &[u8]) -> BString {
+    let mut path = BString::from(dir_path);
+    if path.last() != Some(&b'/') {
+        path.push(b'/');
+    }
+    path.extend_from_slice(name);
+    path
+}
+
+impl<R: ReadAt> Walk<'_, R> {
+    fn visit(&mut self, path: BString, depth: usize, inode: Inode) -> Result<WalkEntry> {
+        let hard_link_target = if inode.is_dir() {
+            None
+        } else {
+            match self.seen.get(&inode.inode_number()) {
+                Some(target) => Some(target.clone()),
+                None => {
+                    self.seen.insert(inode.inode_number(), path.clone());
+                    None
+                }
+            }
+        };
+        if inode.is_dir() {
+            let mut entries = self.archive.read_dir(&inode)?;
+            entries.reverse();
+            self.stack.push((path.clone(), depth + 1, entries));
+        }
+        Ok(WalkEntry {
+            path,
+            depth,
+            inode,
+            hard_link_target,
+        })
+    }
+
+    fn next_entry(&mut self) -> Result<Option<WalkEntry>> {
+        match mem::replace(&mut self.start, Start::Started) {
+            Start::Root => {
+                let root = self.archive.root()?;
+                return self.visit("/".into(), 0, root).map(Some);
+            }
+            Start::Entry(path, depth, inode) => return self.visit(path, depth, inode).map(Some),
+            Start::Started => {}
+        }
+        loop {
+            let (dir_path, depth, entries) = match self.stack.last_mut() {
+                Some(top) => top,
+                None => return Ok(None),
+            };
+            let entry = match entries.pop() {
+                Some(entry) => entry,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+            let path = child_path(dir_path, entry.name());
+            let depth = *depth;
+            let inode = self.archive.inode(entry.inode_ref())?;
+            return self.visit(path, depth, inode).map(Some);
+        }
+    }
+}
+
+impl<R: ReadAt> Iterator for Walk<'_, R> {
+    type Item = Result<WalkEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_entry() {
+            Ok(entry) => entry.map(Ok),
+            Err(e) => {
+                // Don't continue after an error
+                self.start = Start::Started;
+                self.stack.clear();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn walk_tree() {
+        let archive = Archive::new(crate::testing::file_and_symlink()).unwrap();
+
+        let entries: Vec<WalkEntry> = archive.walk().collect::<Result<_>>().unwrap();
+        let paths: Vec<_> = entries.iter().map(|entry| entry.path()).collect();
+        assert_eq!(paths, ["/", "/hello", "/link"]);
+        assert_eq!(entries[0].depth(), 0);
+        assert_eq!(entries[1].depth(), 1);
+        assert!(entries[2].inode().symlink_target().is_some());
+        let types: Vec<_> = entries.iter().map(|entry| entry.file_type()).collect();
+        assert_eq!(types, [FileType::Dir, FileType::File, FileType::Symlink]);
+        assert!(entries
+            .iter()
+            .all(|entry| entry.hard_link_target().is_none()));
+        assert_eq!(archive.hardlinks().unwrap(), []);
+    }
+
+    #[test]
+    fn walk_since() {
+        let image = crate::testing::ImageBuilder::new()
+            .file("old", "")
+            .mtime("old", 100)
+            .dir("dir")
+            .mtime("dir", 100)
+            .file("dir/new", "")
+            .mtime("dir/new", 300)
+            .symlink("link", "old")
+            .mtime("link", 200)
+            .build();
+        let archive = Archive::new(image).unwrap();
+        let paths = |cutoff| -> Vec<BString> {
+            let cutoff = Utc.timestamp_opt(cutoff, 0).unwrap();
+            archive
+                .walk_since(cutoff)
+                .map(|entry| entry.unwrap().path().into())
+                .collect()
+        };
+        assert_eq!(paths(200), ["/dir/new"]);
+        assert_eq!(paths(100), ["/dir/new", "/link"]);
+    }
+
+    #[test]
+    fn hardlink_groups() {
+        let archive = Archive::new(crate::testing::hard_links()).unwrap();
+        let groups = archive.hardlinks().unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].paths(), ["/a", "/b", "/dir/c"]);
+        let b = archive.walk().nth(2).unwrap().unwrap();
+        assert_eq!(b.path(), "/b");
+        assert_eq!(groups[0].inode_number(), b.inode().inode_number());
+    }
+}
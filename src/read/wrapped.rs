@@ -0,0 +1,149 @@
+//! Opening images which are compressed as a whole, like `image.squashfs.gz`
+//!
+//! A compressed stream can't be read at arbitrary offsets, so a wrapped image is decompressed
+//! into an anonymous temporary file, which is removed as soon as the archive is dropped.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::errors::{ReadError, Result};
+
+/// A compression format wrapping a whole image
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Wrapper {
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Wrapper {
+    /// Detect the wrapper from the first bytes of a file, `None` for anything else, including
+    /// a plain image
+    pub fn detect(magic: &[u8]) -> Option<Self> {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Some(Wrapper::Gzip)
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Wrapper::Xz)
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Wrapper::Zstd)
+        } else {
+            None
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Wrapper::Gzip => "gzip",
+            Wrapper::Xz => "xz",
+            Wrapper::Zstd => "zstd",
+        }
+    }
+
+    /// A reader decompressing `file`
+    fn decoder(self, file: File) -> Result<Box<dyn Read>> {
+        let file = io::BufReader::new(file);
+        match self {
+            #[cfg(feature = "gzip")]
+            Wrapper::Gzip => Ok(Box::new(flate2::bufread::MultiGzDecoder::new(file))),
+            #[cfg(feature = "xz")]
+            Wrapper::Xz => Ok(Box::new(liblzma::bufread::XzDecoder::new_multi_decoder(
+                file,
+            ))),
+            #[cfg(feature = "zstd")]
+            Wrapper::Zstd => Ok(Box::new(zstd::stream::read::Decoder::with_buffer(file)?)),
+            // Only reachable while some of the features are disabled
+            #[allow(unreachable_patterns)]
+            _ => Err(ReadError::UnsupportedWrapper(self.name()).into()),
+        }
+    }
+}
+
+/// Return `file` unchanged if it isn't wrapped, or a temporary file holding its decompressed
+/// contents
+pub(crate) fn unwrap(mut file: File) -> Result<File> {
+    let mut magic = [0; 6];
+    let mut len = 0;
+    while len < magic.len() {
+        match file.read(&mut magic[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    file.seek(SeekFrom::Start(0))?;
+    let wrapper = match Wrapper::detect(&magic[..len]) {
+        Some(wrapper) => wrapper,
+        None => return Ok(file),
+    };
+    let mut decoder = wrapper.decoder(file)?;
+    let mut unwrapped = tempfile::tempfile()?;
+    io::copy(&mut decoder, &mut unwrapped)?;
+    Ok(unwrapped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read::Archive;
+    use crate::testing;
+    use std::io::Write;
+
+    fn open_wrapped(wrapped: &[u8]) -> Result<Archive<File>> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(wrapped).unwrap();
+        Archive::open(file.path())
+    }
+
+    fn hello(archive: &Archive<File>) -> Vec<u8> {
+        let file = archive
+            .walk()
+            .map(|entry| entry.unwrap())
+            .find(|entry| entry.path() == "/hello")
+            .unwrap();
+        archive.read_file(file.inode()).unwrap()
+    }
+
+    #[test]
+    fn detect() {
+        assert_eq!(Wrapper::detect(b"hsqs"), None);
+        assert_eq!(Wrapper::detect(b"\x1f\x8b\x08"), Some(Wrapper::Gzip));
+        assert_eq!(Wrapper::detect(b"\xfd7zXZ\x00"), Some(Wrapper::Xz));
+        assert_eq!(Wrapper::detect(b"\xfd7z"), None);
+        assert_eq!(Wrapper::detect(b"\x28\xb5\x2f\xfd"), Some(Wrapper::Zstd));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip_wrapped() {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(&testing::one_file()).unwrap();
+        let archive = open_wrapped(&encoder.finish().unwrap()).unwrap();
+        assert_eq!(hello(&archive), testing::HELLO_CONTENTS);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_wrapped() {
+        let wrapped = zstd::encode_all(&testing::one_file()[..], 0).unwrap();
+        let archive = open_wrapped(&wrapped).unwrap();
+        assert_eq!(hello(&archive), testing::HELLO_CONTENTS);
+    }
+
+    #[cfg(feature = "xz")]
+    #[test]
+    fn xz_wrapped() {
+        let mut encoder = liblzma::write::XzEncoder::new(Vec::new(), 1);
+        encoder.write_all(&testing::one_file()).unwrap();
+        let archive = open_wrapped(&encoder.finish().unwrap()).unwrap();
+        assert_eq!(hello(&archive), testing::HELLO_CONTENTS);
+    }
+
+    #[cfg(not(feature = "xz"))]
+    #[test]
+    fn xz_unsupported() {
+        let err = open_wrapped(b"\xfd7zXZ\x00\x00\x04").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Read error: Image is compressed with xz, which is not enabled"
+        );
+    }
+}
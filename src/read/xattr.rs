@@ -0,0 +1,210 @@
+//! Reading extended attributes
+//!
+//! An inode refers to its attributes by an index into the xattr id table, which is read when
+//! the archive is opened. Each entry locates a run of key value pairs in the xattr table, which
+//! are read as they're iterated. Values stored out of line are followed to where they were
+//! first written.
+
+use std::ffi::OsString;
+use std::mem;
+
+use bstr::ByteVec;
+use repr::offset::ArchiveOffset;
+use repr::xattr::{Key, Kind, Value};
+
+use super::{metablock, Archive, ArchiveInner, Inode, ReadAt};
+use crate::errors::{ReadError, Result};
+
+/// The extended attributes of an inode, see [`Archive::xattrs`]
+pub struct Xattrs<'a, R> {
+    archive: &'a ArchiveInner<R>,
+    /// `None` for an inode without attributes
+    cursor: Option<metablock::Cursor<'a, R>>,
+    remaining: u32,
+}
+
+impl<R: ReadAt> Archive<R> {
+    /// The extended attributes of `inode`, as their full names and values
+    ///
+    /// Names have their namespace prefix, such as `security.`, put back. An image without an
+    /// xattr table has no attributes.
+    ///
+    /// ```no_run
+    /// # fn main() -> sqfs::Result<()> {
+    /// let archive = sqfs::read::Archive::open("image.sqfs")?;
+    /// let file = archive.lookup("usr/bin/ping")?;
+    /// for xattr in archive.xattrs(&file)? {
+    ///     let (name, value) = xattr?;
+    ///     println!("{:?}: {} bytes", name, value.len());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn xattrs(&self, inode: &Inode) -> Result<Xattrs<'_, R>> {
+        let inner = &*self.inner;
+        let idx = inode.xattr_idx();
+        if !idx.is_some() {
+            return Ok(Xattrs {
+                archive: inner,
+                cursor: None,
+                remaining: 0,
+            });
+        }
+        let entry = inner
+            .xattr_ids
+            .get(idx.0 as usize)
+            .copied()
+            .ok_or(ReadError::XattrIndexOutOfRange(idx.0))?;
+        let cursor = inner.xattr_cursor(entry.xattr_ref)?;
+        Ok(Xattrs {
+            archive: inner,
+            cursor: Some(cursor),
+            remaining: entry.count,
+        })
+    }
+}
+
+impl<R: ReadAt> ArchiveInner<R> {
+    /// Read the header of the xattr id table at `start`, and its entries
+    pub(super) fn read_xattr_ids(&mut self, start: u64) -> Result<()> {
+        let mut header = [0; mem::size_of::<repr::xattr::LookupTable>()];
+        self.source.read_exact_at(&mut header, start)?;
+        let lookup_start = start + header.len() as u64;
+        let header: repr::xattr::LookupTable = repr::read(&header[..])?;
+        self.xattr_ids = self.read_lookup_table(lookup_start, header.xattr_entry_count)?;
+        self.xattr_table_start = header.xattr_table_start;
+        Ok(())
+    }
+
+    /// Start reading the xattr table at `xattr_ref`, which is relative to its start
+    fn xattr_cursor(&self, xattr_ref: repr::xattr::Ref) -> Result<metablock::Cursor<'_, R>> {
+        let block = ArchiveOffset(self.xattr_table_start + u64::from(xattr_ref.block_start()));
+        metablock::Cursor::new(self, block, xattr_ref.start_offset())
+    }
+}
+
+impl<R: ReadAt> Xattrs<'_, R> {
+    fn read_pair(&mut self) -> Result<(OsString, Vec<u8>)> {
+        let cursor = self.cursor.as_mut().expect("only read while pairs remain");
+        let key: Key = cursor.read()?;
+        let prefix: &[u8] = match key.kind.prefix() {
+            Kind::USER => b"user.",
+            Kind::TRUSTED => b"trusted.",
+            Kind::SECURITY => b"security.",
+            _ => return Err(ReadError::CorruptXattr("unknown name prefix").into()),
+        };
+        let mut name = prefix.to_vec();
+        name.extend(cursor.read_vec(key.name_size.into())?);
+
+        let value: Value = cursor.read()?;
+        let value = if key.kind.out_of_line() {
+            if value.value_size as usize != mem::size_of::<repr::xattr::Ref>() {
+                return Err(ReadError::CorruptXattr("bad out of line value").into());
+            }
+            let value_ref: repr::xattr::Ref = cursor.read()?;
+            let mut cursor = self.archive.xattr_cursor(value_ref)?;
+            let value: Value = cursor.read()?;
+            cursor.read_vec(value.value_size as usize)?
+        } else {
+            cursor.read_vec(value.value_size as usize)?
+        };
+        Ok((name.into_os_string_lossy(), value))
+    }
+}
+
+impl<R: ReadAt> Iterator for Xattrs<'_, R> {
+    type Item = Result<(OsString, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let pair = self.read_pair();
+        if pair.is_err() {
+            self.remaining = 0;
+        }
+        Some(pair)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.remaining as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ImageBuilder;
+
+    #[test]
+    fn read_xattrs() {
+        let image = ImageBuilder::new()
+            .file("a", "a")
+            .xattr("a", "security.capability", b"\x01\x00\x00\x02")
+            .xattr("a", "user.comment", b"hello")
+            .file("b", "b")
+            .build();
+        let archive = Archive::new(image).unwrap();
+        let xattrs: Vec<_> = archive
+            .xattrs(&archive.lookup("a").unwrap())
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            xattrs,
+            [
+                (
+                    OsString::from("security.capability"),
+                    b"\x01\x00\x00\x02".to_vec()
+                ),
+                (OsString::from("user.comment"), b"hello".to_vec()),
+            ]
+        );
+        let b = archive.lookup("b").unwrap();
+        assert_eq!(archive.xattrs(&b).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn out_of_line_values() {
+        use crate::write::{Archive as Writer, ArchiveBuilder};
+        use std::io;
+
+        let label = "system_u:object_r:usr_t:s0";
+        let mut builder = ArchiveBuilder::new();
+        builder.block_size = 4096;
+        let (mut writer, image) = builder.build_in_memory();
+        let add = |writer: &mut Writer<_>, extra: &str| {
+            let mut file = writer.create_file();
+            file.set_contents(Box::new(io::Cursor::new(extra.to_string())))
+                .set_xattr("security.selinux", label)
+                .unwrap()
+                .set_xattr("user.extra", extra)
+                .unwrap();
+            file.finish(writer).unwrap()
+        };
+        let one = add(&mut writer, "one");
+        let two = add(&mut writer, "two");
+        let mut root = writer.create_dir();
+        root.add_item("one", one).add_item("two", two);
+        let root = root.finish(&mut writer);
+        writer.set_root(root);
+        writer.flush().unwrap();
+
+        let archive = image.open().unwrap();
+        let two = archive.lookup("two").unwrap();
+        assert_eq!(two.xattr_idx(), repr::xattr::Idx(1));
+        let xattrs: Vec<_> = archive
+            .xattrs(&two)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            xattrs,
+            [
+                (OsString::from("security.selinux"), label.into()),
+                (OsString::from("user.extra"), b"two".to_vec()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,66 @@
+//! Signing finished archives, and verifying their signatures
+//!
+//! The signed data is the image itself: every byte from the start of the superblock up to
+//! [`bytes_used`](crate::read::Archive::bytes_used), not including any padding. Signers and
+//! verifiers are given the SHA-256 [`Digest`] of that range.
+//!
+//! A signature can be kept in a detached file, or embedded in a trailer directly following the
+//! image. The kernel never reads past `bytes_used`, so an embedded signature doesn't affect
+//! mounting the image. The trailer is the [`TRAILER_MAGIC`], the length of the signature as a
+//! little endian `u32`, then the signature itself.
+
+use std::convert::TryFrom;
+use std::io;
+
+use crate::digest::Digest;
+
+/// The magic bytes at the start of a signature trailer
+pub const TRAILER_MAGIC: [u8; 8] = *b"sqfs-sig";
+
+/// The size of a trailer's magic and length, before the signature itself
+pub const TRAILER_HEADER_SIZE: usize = TRAILER_MAGIC.len() + 4;
+
+/// Produces a signature for the digest of an archive
+///
+/// Implement this for an HSM, a signing service, or a local key, e.g. for secure boot
+/// firmware images.
+pub trait Signer: Send + Sync {
+    fn sign(&self, digest: &Digest) -> io::Result<Vec<u8>>;
+}
+
+/// Checks a signature against the digest of an archive
+pub trait SignatureVerifier {
+    /// Returns true if `signature` is a valid signature of `digest`
+    fn verify(&self, digest: &Digest, signature: &[u8]) -> bool;
+}
+
+/// Where the signature of an archive is stored
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SignaturePlacement {
+    /// The signature is only returned from
+    /// [`write::Archive::signature`](crate::write::Archive::signature), to be stored elsewhere
+    Detached,
+    /// The signature is also written in a trailer directly after the image
+    Trailer,
+}
+
+/// Encode a signature trailer
+pub(crate) fn trailer(signature: &[u8]) -> io::Result<Vec<u8>> {
+    let len = u32::try_from(signature.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "signature too large"))?;
+    let mut trailer = Vec::with_capacity(TRAILER_HEADER_SIZE + signature.len());
+    trailer.extend_from_slice(&TRAILER_MAGIC);
+    trailer.extend_from_slice(&len.to_le_bytes());
+    trailer.extend_from_slice(signature);
+    Ok(trailer)
+}
+
+/// Decode the header of a signature trailer, returning the length of the signature, or `None`
+/// if there is no trailer
+pub(crate) fn trailer_len(header: &[u8; TRAILER_HEADER_SIZE]) -> Option<u32> {
+    let (magic, len) = header.split_at(TRAILER_MAGIC.len());
+    if magic != TRAILER_MAGIC {
+        return None;
+    }
+    Some(u32::from_le_bytes([len[0], len[1], len[2], len[3]]))
+}
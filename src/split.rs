@@ -0,0 +1,11 @@
+//! Naming of the parts of an archive split into fixed size chunks
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// The path of part `index` of the archive at `base`: `image.000`, `image.001`, ...
+pub(crate) fn part_path(base: &Path, index: usize) -> PathBuf {
+    let mut path = OsString::from(base.as_os_str());
+    path.push(format!(".{:03}", index));
+    PathBuf::from(path)
+}
@@ -0,0 +1,1232 @@
+//! Small reference images, for testing code which reads squashfs
+//!
+//! Every image is deterministic: a fixture is the same bytes on every run, with everything
+//! owned by root and every time at the epoch. Images are assembled directly from the on-disk
+//! structures, in the layout `mksquashfs` uses, without fragments or an export table.
+//!
+//! ```
+//! use sqfs::read::Archive;
+//!
+//! let archive = Archive::from_bytes(sqfs::testing::one_file()).unwrap();
+//! let root = archive.root().unwrap();
+//! assert_eq!(archive.read_dir(&root).unwrap()[0].name(), "hello");
+//! ```
+//!
+//! Other images can be described with an [`ImageBuilder`].
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::mem;
+
+use bstr::{BString, ByteSlice};
+use repr::inode::Kind as InodeKind;
+use zerocopy::AsBytes;
+
+use crate::compression::{compress_or_copy, AnyCodec, Kind};
+use crate::Mode;
+
+/// The contents of the file in [`one_file`]
+pub const HELLO_CONTENTS: &[u8] = b"hi there\n";
+
+/// An image containing only the file `hello`
+pub fn one_file() -> Vec<u8> {
+    ImageBuilder::new().file("hello", HELLO_CONTENTS).build()
+}
+
+/// An image containing the file `hello`, as in [`one_file`], and the symlink `link` to it
+pub fn file_and_symlink() -> Vec<u8> {
+    ImageBuilder::new()
+        .file("hello", HELLO_CONTENTS)
+        .symlink("link", "hello")
+        .build()
+}
+
+/// An image with `depth` nested directories named `d`, with the file `file` in the deepest
+pub fn deep_dirs(depth: usize) -> Vec<u8> {
+    let mut path = "d/".repeat(depth);
+    path.push_str("file");
+    ImageBuilder::new().file(path, "deep\n").build()
+}
+
+/// An image with one of each kind of inode: `dir`, `file`, `symlink` (to `file`), `block`
+/// (device 8,1), `char` (device 1,3), `fifo` and `socket`
+pub fn all_inode_kinds() -> Vec<u8> {
+    ImageBuilder::new()
+        .dir("dir")
+        .file("file", HELLO_CONTENTS)
+        .symlink("symlink", "file")
+        .block_device("block", 8, 1)
+        .char_device("char", 1, 3)
+        .fifo("fifo")
+        .socket("socket")
+        .build()
+}
+
+/// The compression algorithms images can be built with, which are those enabled by features
+pub fn codecs() -> Vec<Kind> {
+    vec![
+        #[cfg(feature = "gzip")]
+        Kind::ZLib,
+        #[cfg(feature = "lz4")]
+        Kind::Lz4,
+        #[cfg(feature = "xz")]
+        Kind::Xz,
+        #[cfg(feature = "zstd")]
+        Kind::Zstd,
+    ]
+}
+
+/// The contents of `compressible` in [`compressed`]: a few blocks of text
+pub fn compressible_contents() -> Vec<u8> {
+    b"squashfs compresses this line well\n"
+        .iter()
+        .copied()
+        .cycle()
+        .take(3 * repr::BLOCK_SIZE_MIN as usize + 100)
+        .collect()
+}
+
+/// The contents of `incompressible` in [`compressed`]: a block of pseudo-random bytes
+pub fn incompressible_contents() -> Vec<u8> {
+    let mut state = 0x2545_f491_u32;
+    (0..repr::BLOCK_SIZE_MIN)
+        .map(|_| {
+            // xorshift32
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+/// An image compressed with `kind`, containing `compressible` and `incompressible` files, see
+/// [`compressible_contents`] and [`incompressible_contents`]
+///
+/// # Panics
+///
+/// Panics if `kind` isn't one of the [`codecs`]
+pub fn compressed(kind: Kind) -> Vec<u8> {
+    ImageBuilder::new()
+        .compression(kind)
+        .file("compressible", compressible_contents())
+        .file("incompressible", incompressible_contents())
+        .build()
+}
+
+/// An image with extended attributes: the file `file` has `user.comment` and
+/// `security.selinux`, the directory `dir` has `trusted.overlay.opaque`, and `plain` has none
+pub fn xattrs() -> Vec<u8> {
+    ImageBuilder::new()
+        .file("file", HELLO_CONTENTS)
+        .xattr("file", "user.comment", "hello")
+        .xattr("file", "security.selinux", "system_u:object_r:etc_t:s0\0")
+        .dir("dir")
+        .xattr("dir", "trusted.overlay.opaque", "y")
+        .file("plain", "")
+        .build()
+}
+
+/// An image where `a`, `b` and `dir/c` are hard links to the same file
+pub fn hard_links() -> Vec<u8> {
+    ImageBuilder::new()
+        .file("a", HELLO_CONTENTS)
+        .hard_link("b", "a")
+        .hard_link("dir/c", "a")
+        .build()
+}
+
+/// The contents of `sparse` in [`sparse`]: a block of data, two blocks of zeros, then a short
+/// tail
+pub fn sparse_contents() -> Vec<u8> {
+    let block = repr::BLOCK_SIZE_MIN as usize;
+    let mut contents = vec![b'x'; block];
+    contents.resize(3 * block, 0);
+    contents.extend_from_slice(b"end\n");
+    contents
+}
+
+/// An image with sparse files: `sparse`, see [`sparse_contents`], and `zeros`, which is three
+/// blocks of zeros and so is stored without any data
+pub fn sparse() -> Vec<u8> {
+    ImageBuilder::new()
+        .file("sparse", sparse_contents())
+        .file("zeros", vec![0; 3 * repr::BLOCK_SIZE_MIN as usize])
+        .build()
+}
+
+/// A small tree compressed with `kind`, mixing the kinds of contents codecs have to handle:
+/// text, multi-block, incompressible, sparse and empty files, and symlinks
+///
+/// Reading it should always give the same listing, whatever the codec, see
+/// [`CORPUS_LISTING`].
+///
+/// # Panics
+///
+/// Panics if `kind` isn't one of the [`codecs`]
+pub fn corpus(kind: Kind) -> Vec<u8> {
+    ImageBuilder::new()
+        .compression(kind)
+        .file("etc/hostname", "box\n")
+        .file("etc/passwd", "root:x:0:0:root:/root:/bin/sh\n")
+        .file("usr/bin/tool", compressible_contents())
+        .mode("usr/bin/tool", Mode::O755)
+        .file("usr/lib/blob", incompressible_contents())
+        .owner("usr/lib/blob", 1000, 100)
+        .file("var/sparse", sparse_contents())
+        .file("var/empty", "")
+        .symlink("bin", "usr/bin")
+        .symlink("etc/localtime", "/usr/share/zoneinfo/UTC")
+        .build()
+}
+
+/// An entry of [`CORPUS_LISTING`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListedEntry<'a> {
+    pub path: &'a str,
+    /// As displayed by `ls -l`
+    pub mode: &'a str,
+    pub owner: (u32, u32),
+    /// The size of a file, or the length of a symlink's target
+    pub size: u64,
+    /// The xxh3 hash of a file's contents, or of a symlink's target
+    pub hash: u64,
+}
+
+/// The walk of [`corpus`], listing every entry in order
+pub const CORPUS_LISTING: &[ListedEntry<'static>] = &[
+    ListedEntry {
+        path: "/",
+        mode: "drwxr-xr-x",
+        owner: (0, 0),
+        size: 0,
+        hash: 0x0000000000000000,
+    },
+    ListedEntry {
+        path: "/bin",
+        mode: "lrwxrwxrwx",
+        owner: (0, 0),
+        size: 7,
+        hash: 0x0e48f5818b2b137c,
+    },
+    ListedEntry {
+        path: "/etc",
+        mode: "drwxr-xr-x",
+        owner: (0, 0),
+        size: 0,
+        hash: 0x0000000000000000,
+    },
+    ListedEntry {
+        path: "/etc/hostname",
+        mode: "-rw-r--r--",
+        owner: (0, 0),
+        size: 4,
+        hash: 0x7f072aa576d32853,
+    },
+    ListedEntry {
+        path: "/etc/localtime",
+        mode: "lrwxrwxrwx",
+        owner: (0, 0),
+        size: 23,
+        hash: 0xc0ddc03bcac3c055,
+    },
+    ListedEntry {
+        path: "/etc/passwd",
+        mode: "-rw-r--r--",
+        owner: (0, 0),
+        size: 30,
+        hash: 0xfc464dcdc5749f6b,
+    },
+    ListedEntry {
+        path: "/usr",
+        mode: "drwxr-xr-x",
+        owner: (0, 0),
+        size: 0,
+        hash: 0x0000000000000000,
+    },
+    ListedEntry {
+        path: "/usr/bin",
+        mode: "drwxr-xr-x",
+        owner: (0, 0),
+        size: 0,
+        hash: 0x0000000000000000,
+    },
+    ListedEntry {
+        path: "/usr/bin/tool",
+        mode: "-rwxr-xr-x",
+        owner: (0, 0),
+        size: 12388,
+        hash: 0x67899f1f3cd60583,
+    },
+    ListedEntry {
+        path: "/usr/lib",
+        mode: "drwxr-xr-x",
+        owner: (0, 0),
+        size: 0,
+        hash: 0x0000000000000000,
+    },
+    ListedEntry {
+        path: "/usr/lib/blob",
+        mode: "-rw-r--r--",
+        owner: (1000, 100),
+        size: 4096,
+        hash: 0x8d389312a27a6060,
+    },
+    ListedEntry {
+        path: "/var",
+        mode: "drwxr-xr-x",
+        owner: (0, 0),
+        size: 0,
+        hash: 0x0000000000000000,
+    },
+    ListedEntry {
+        path: "/var/empty",
+        mode: "-rw-r--r--",
+        owner: (0, 0),
+        size: 0,
+        hash: 0x2d06800538d394c2,
+    },
+    ListedEntry {
+        path: "/var/sparse",
+        mode: "-rw-r--r--",
+        owner: (0, 0),
+        size: 12292,
+        hash: 0x3512d9979f757c43,
+    },
+];
+
+#[derive(Debug, Clone)]
+enum Data {
+    Dir(BTreeMap<BString, usize>),
+    File(Vec<u8>),
+    Symlink(BString),
+    BlockDevice(u32, u32),
+    CharDevice(u32, u32),
+    Fifo,
+    Socket,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    data: Data,
+    mode: Mode,
+    uid: u32,
+    gid: u32,
+    mtime: u32,
+    xattrs: Vec<(BString, Vec<u8>)>,
+}
+
+impl Node {
+    fn new(data: Data, mode: Mode) -> Self {
+        Node {
+            data,
+            mode,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            xattrs: Vec::new(),
+        }
+    }
+}
+
+/// Describes a small image, to be built in memory
+///
+/// Paths are relative to the root, and missing parent directories are created. Unless set
+/// otherwise, every entry is owned by root, with its mtime at the epoch, and the permissions
+/// `mksquashfs` would usually see: `0755` for directories, `0777` for symlinks, and `0644` for
+/// everything else.
+///
+/// Files are stored in blocks of the minimum block size unless set otherwise, so small
+/// contents are enough to test multi-block files. Blocks of zeros are stored sparse.
+///
+/// # Panics
+///
+/// Methods panic if a path passes through anything but a directory, or doesn't exist when it
+/// must.
+#[derive(Debug, Clone)]
+pub struct ImageBuilder {
+    kind: Kind,
+    compressed: bool,
+    block_size: u32,
+    nodes: Vec<Node>,
+}
+
+impl Default for ImageBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ImageBuilder {
+    /// An image with an empty root directory, compressed with the first of the [`codecs`]
+    pub fn new() -> Self {
+        ImageBuilder {
+            kind: *codecs()
+                .first()
+                .expect("no compression features are enabled"),
+            compressed: true,
+            block_size: repr::BLOCK_SIZE_MIN,
+            nodes: vec![Node::new(Data::Dir(BTreeMap::new()), Mode::O755)],
+        }
+    }
+
+    pub fn compression(&mut self, kind: Kind) -> &mut Self {
+        assert!(codecs().contains(&kind), "{} is not enabled", kind);
+        self.kind = kind;
+        self
+    }
+
+    /// Store every block uncompressed, with the matching superblock flags
+    ///
+    /// This keeps the layout simple to predict, e.g. to corrupt specific structures.
+    pub fn uncompressed(&mut self) -> &mut Self {
+        self.compressed = false;
+        self
+    }
+
+    pub fn block_size(&mut self, block_size: u32) -> &mut Self {
+        assert!(
+            block_size.is_power_of_two()
+                && (repr::BLOCK_SIZE_MIN..=repr::BLOCK_SIZE_MAX).contains(&block_size)
+        );
+        self.block_size = block_size;
+        self
+    }
+
+    pub fn dir<P: AsRef<[u8]>>(&mut self, path: P) -> &mut Self {
+        self.insert(path.as_ref(), Data::Dir(BTreeMap::new()), Mode::O755)
+    }
+
+    pub fn file<P: AsRef<[u8]>, C: Into<Vec<u8>>>(&mut self, path: P, contents: C) -> &mut Self {
+        self.insert(path.as_ref(), Data::File(contents.into()), Mode::O644)
+    }
+
+    pub fn symlink<P: AsRef<[u8]>, T: Into<BString>>(&mut self, path: P, target: T) -> &mut Self {
+        self.insert(path.as_ref(), Data::Symlink(target.into()), Mode::O777)
+    }
+
+    pub fn block_device<P: AsRef<[u8]>>(&mut self, path: P, major: u32, minor: u32) -> &mut Self {
+        self.insert(path.as_ref(), Data::BlockDevice(major, minor), Mode::O644)
+    }
+
+    pub fn char_device<P: AsRef<[u8]>>(&mut self, path: P, major: u32, minor: u32) -> &mut Self {
+        self.insert(path.as_ref(), Data::CharDevice(major, minor), Mode::O644)
+    }
+
+    pub fn fifo<P: AsRef<[u8]>>(&mut self, path: P) -> &mut Self {
+        self.insert(path.as_ref(), Data::Fifo, Mode::O644)
+    }
+
+    pub fn socket<P: AsRef<[u8]>>(&mut self, path: P) -> &mut Self {
+        self.insert(path.as_ref(), Data::Socket, Mode::O644)
+    }
+
+    /// Add `path` as another name for the existing non-directory at `target`
+    pub fn hard_link<P: AsRef<[u8]>, T: AsRef<[u8]>>(&mut self, path: P, target: T) -> &mut Self {
+        let target = self.lookup(target.as_ref());
+        assert!(!matches!(self.nodes[target].data, Data::Dir(_)));
+        let (parent, name) = self.parent(path.as_ref());
+        self.children(parent).insert(name, target);
+        self
+    }
+
+    /// Set the permissions of the existing entry at `path`
+    pub fn mode<P: AsRef<[u8]>>(&mut self, path: P, mode: Mode) -> &mut Self {
+        let node = self.lookup(path.as_ref());
+        self.nodes[node].mode = mode.perm();
+        self
+    }
+
+    /// Set the owner of the existing entry at `path`
+    pub fn owner<P: AsRef<[u8]>>(&mut self, path: P, uid: u32, gid: u32) -> &mut Self {
+        let node = self.lookup(path.as_ref());
+        self.nodes[node].uid = uid;
+        self.nodes[node].gid = gid;
+        self
+    }
+
+    /// Set the modification time of the existing entry at `path`, in seconds since the epoch
+    pub fn mtime<P: AsRef<[u8]>>(&mut self, path: P, mtime: u32) -> &mut Self {
+        let node = self.lookup(path.as_ref());
+        self.nodes[node].mtime = mtime;
+        self
+    }
+
+    /// Add an extended attribute to the existing entry at `path`
+    ///
+    /// The key must be in the `user.`, `trusted.` or `security.` namespace.
+    pub fn xattr<P: AsRef<[u8]>, K: Into<BString>, V: Into<Vec<u8>>>(
+        &mut self,
+        path: P,
+        key: K,
+        value: V,
+    ) -> &mut Self {
+        let key = key.into();
+        xattr_prefix(&key);
+        let node = self.lookup(path.as_ref());
+        self.nodes[node].xattrs.push((key, value.into()));
+        self
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let numbers = self.inode_numbers();
+        let inode_count = numbers.iter().copied().max().unwrap_or_default();
+        let mut links = vec![0; self.nodes.len()];
+        for node in &self.nodes {
+            if let Data::Dir(children) = &node.data {
+                for &child in children.values() {
+                    links[child] += 1;
+                }
+            }
+        }
+
+        let codec = || self.compressed.then(|| AnyCodec::new(self.kind));
+        let mut assembler = Assembler {
+            builder: self,
+            codec: codec(),
+            numbers,
+            links,
+            written: vec![None; self.nodes.len()],
+            data: vec![0; mem::size_of::<repr::superblock::Superblock>()],
+            inodes: Metablocks::new(codec()),
+            dirs: Metablocks::new(codec()),
+            xattrs: Metablocks::new(codec()),
+            xattr_ids: Vec::new(),
+            ids: Vec::new(),
+        };
+        let (root_ref, _) = assembler.write_node(0, inode_count + 1);
+        let Assembler {
+            mut data,
+            inodes,
+            dirs,
+            xattrs,
+            xattr_ids,
+            ids,
+            ..
+        } = assembler;
+
+        let inode_table_start = data.len() as u64;
+        data.extend_from_slice(&inodes.finish());
+        let directory_table_start = data.len() as u64;
+        data.extend_from_slice(&dirs.finish());
+        let id_table_start = lookup_table(&mut data, codec(), ids.as_bytes());
+        let xattr_id_table_start = if xattr_ids.is_empty() {
+            u64::MAX
+        } else {
+            let xattr_table_start = data.len() as u64;
+            data.extend_from_slice(&xattrs.finish());
+            let blocks = metablocks(&mut data, codec(), xattr_ids.as_bytes());
+            let start = data.len() as u64;
+            let header = repr::xattr::LookupTable::new(xattr_table_start, xattr_ids.len() as u32);
+            data.extend_from_slice(header.as_bytes());
+            data.extend_from_slice(blocks.as_bytes());
+            start
+        };
+
+        let mut flags = repr::superblock::Flags::NO_FRAGMENTS;
+        if !self.compressed {
+            flags |= repr::superblock::Flags::UNCOMPRESSED_INODES
+                | repr::superblock::Flags::UNCOMPRESSED_DATA
+                | repr::superblock::Flags::UNCOMPRESSED_FRAGMENTS
+                | repr::superblock::Flags::UNCOMPRESSED_XATTRS
+                | repr::superblock::Flags::UNCOMPRESSED_IDS;
+        }
+        if xattr_ids.is_empty() {
+            flags |= repr::superblock::Flags::NO_XATTRS;
+        }
+        let superblock = repr::superblock::Superblock {
+            magic: repr::superblock::MAGIC,
+            inode_count,
+            modification_time: repr::Time(0),
+            block_size: self.block_size,
+            fragment_entry_count: 0,
+            compression_id: repr::compression::Id(self.kind.id()),
+            block_log: self.block_size.trailing_zeros() as _,
+            flags,
+            id_count: ids.len() as u16,
+            version_major: repr::superblock::VERSION_MAJOR,
+            version_minor: repr::superblock::VERSION_MINOR,
+            root_inode_ref: root_ref,
+            bytes_used: data.len() as u64,
+            id_table_start,
+            xattr_id_table_start,
+            inode_table_start,
+            directory_table_start,
+            fragment_table_start: u64::MAX,
+            export_table_start: u64::MAX,
+        };
+        data[..mem::size_of_val(&superblock)].copy_from_slice(superblock.as_bytes());
+        data
+    }
+
+    fn insert(&mut self, path: &[u8], data: Data, mode: Mode) -> &mut Self {
+        let (parent, name) = self.parent(path);
+        self.add_child(parent, name, data, mode);
+        self
+    }
+
+    fn add_child(&mut self, parent: usize, name: BString, data: Data, mode: Mode) -> usize {
+        let node = self.nodes.len();
+        self.nodes.push(Node::new(data, mode));
+        self.children(parent).insert(name, node);
+        node
+    }
+
+    fn children(&mut self, dir: usize) -> &mut BTreeMap<BString, usize> {
+        match &mut self.nodes[dir].data {
+            Data::Dir(children) => children,
+            _ => panic!("not a directory"),
+        }
+    }
+
+    /// The directory which will contain `path`, creating it if needed, and the name of `path`
+    fn parent(&mut self, path: &[u8]) -> (usize, BString) {
+        let mut components: Vec<&[u8]> = path.split_str("/").filter(|c| !c.is_empty()).collect();
+        let name = components.pop().expect("the root already exists");
+        let mut dir = 0;
+        for component in components {
+            dir = match self.children(dir).get(component.as_bstr()) {
+                Some(&child) => child,
+                None => self.add_child(
+                    dir,
+                    component.into(),
+                    Data::Dir(BTreeMap::new()),
+                    Mode::O755,
+                ),
+            };
+        }
+        // Check the parent is a directory
+        self.children(dir);
+        (dir, name.into())
+    }
+
+    fn lookup(&self, path: &[u8]) -> usize {
+        let mut node = 0;
+        for component in path.split_str("/").filter(|c| !c.is_empty()) {
+            node = match &self.nodes[node].data {
+                Data::Dir(children) => *children
+                    .get(component.as_bstr())
+                    .unwrap_or_else(|| panic!("{:?} doesn't exist", path.as_bstr())),
+                _ => panic!("{:?} passes through a non-directory", path.as_bstr()),
+            };
+        }
+        node
+    }
+
+    /// Number every inode in the order they're written: each directory after its contents
+    fn inode_numbers(&self) -> Vec<u32> {
+        fn visit(nodes: &[Node], node: usize, numbers: &mut Vec<u32>, next: &mut u32) {
+            if numbers[node] != 0 {
+                return;
+            }
+            if let Data::Dir(children) = &nodes[node].data {
+                for &child in children.values() {
+                    visit(nodes, child, numbers, next);
+                }
+            }
+            numbers[node] = *next;
+            *next += 1;
+        }
+        let mut numbers = vec![0; self.nodes.len()];
+        visit(&self.nodes, 0, &mut numbers, &mut 1);
+        numbers
+    }
+}
+
+fn xattr_prefix(key: &[u8]) -> (repr::xattr::Kind, &[u8]) {
+    let prefixes = [
+        (&b"user."[..], repr::xattr::Kind::USER),
+        (&b"trusted."[..], repr::xattr::Kind::TRUSTED),
+        (&b"security."[..], repr::xattr::Kind::SECURITY),
+    ];
+    prefixes
+        .iter()
+        .find_map(|&(prefix, kind)| Some((kind, key.strip_prefix(prefix)?)))
+        .unwrap_or_else(|| panic!("unsupported xattr namespace: {:?}", key.as_bstr()))
+}
+
+struct Assembler<'a> {
+    builder: &'a ImageBuilder,
+    codec: Option<AnyCodec>,
+    numbers: Vec<u32>,
+    links: Vec<u32>,
+    /// The inode reference and type of each node, once it's written
+    written: Vec<Option<(repr::inode::Ref, InodeKind)>>,
+    data: Vec<u8>,
+    inodes: Metablocks,
+    dirs: Metablocks,
+    xattrs: Metablocks,
+    xattr_ids: Vec<repr::xattr::LookupEntry>,
+    ids: Vec<u32>,
+}
+
+impl Assembler<'_> {
+    /// Write the contents and inode of `node`, after those of everything beneath it
+    fn write_node(&mut self, node: usize, parent: u32) -> (repr::inode::Ref, InodeKind) {
+        if let Some(written) = self.written[node] {
+            return written;
+        }
+        let builder = self.builder;
+        let Node {
+            data,
+            mode,
+            uid,
+            gid,
+            mtime,
+            xattrs,
+        } = &builder.nodes[node];
+        let number = self.numbers[node];
+        let links = self.links[node];
+        let xattr_idx = self.write_xattrs(xattrs);
+        let extended = xattr_idx.is_some();
+
+        let mut body = Vec::new();
+        let kind = match data {
+            Data::Dir(children) => {
+                let entries: Vec<_> = children
+                    .iter()
+                    .map(|(name, &child)| {
+                        let (inode_ref, kind) = self.write_node(child, number);
+                        (name, inode_ref, kind, self.numbers[child])
+                    })
+                    .collect();
+                let subdirs = children
+                    .values()
+                    .filter(|&&child| matches!(builder.nodes[child].data, Data::Dir(_)))
+                    .count() as u32;
+                let hard_link_count = repr::inode::dir_hardlink_count(0, subdirs);
+                let (start, size) = self.write_listing(&entries);
+                let file_size = repr::inode::dir_stored_size(size);
+                if extended || file_size > u16::MAX.into() {
+                    body.extend_from_slice(
+                        repr::inode::ExtendedDir {
+                            hard_link_count,
+                            file_size,
+                            dir_block_start: start.block_start(),
+                            parent_inode_number: repr::inode::Idx(parent),
+                            index_count: 0,
+                            block_offset: start.start_offset(),
+                            xattr_idx,
+                        }
+                        .as_bytes(),
+                    );
+                    InodeKind::EXT_DIR
+                } else {
+                    body.extend_from_slice(
+                        repr::inode::BasicDir {
+                            dir_block_start: start.block_start(),
+                            hard_link_count,
+                            file_size: file_size as u16,
+                            block_offset: start.start_offset(),
+                            parent_inode_number: repr::inode::Idx(parent),
+                        }
+                        .as_bytes(),
+                    );
+                    InodeKind::BASIC_DIR
+                }
+            }
+            Data::File(contents) => {
+                let blocks_start = self.data.len() as u64;
+                let (sizes, sparse) = self.write_blocks(contents);
+                let kind = if extended || links > 1 || sparse > 0 {
+                    body.extend_from_slice(
+                        repr::inode::ExtendedFile {
+                            blocks_start: repr::datablock::Ref(blocks_start),
+                            file_size: contents.len() as u64,
+                            sparse,
+                            hard_link_count: links,
+                            fragment_block_index: repr::fragment::Idx(u32::MAX),
+                            block_offset: 0,
+                            xattr_idx,
+                        }
+                        .as_bytes(),
+                    );
+                    InodeKind::EXT_FILE
+                } else {
+                    body.extend_from_slice(
+                        repr::inode::BasicFile {
+                            blocks_start: blocks_start as u32,
+                            fragment_block_index: repr::fragment::Idx(u32::MAX),
+                            block_offset: 0,
+                            file_size: contents.len() as u32,
+                        }
+                        .as_bytes(),
+                    );
+                    InodeKind::BASIC_FILE
+                };
+                body.extend_from_slice(sizes.as_bytes());
+                kind
+            }
+            Data::Symlink(target) => {
+                body.extend_from_slice(
+                    repr::inode::Symlink {
+                        hard_link_count: links,
+                        target_size: target.len() as u32,
+                    }
+                    .as_bytes(),
+                );
+                body.extend_from_slice(target);
+                if extended {
+                    body.extend_from_slice(xattr_idx.as_bytes());
+                    InodeKind::EXT_SYMLINK
+                } else {
+                    InodeKind::BASIC_SYMLINK
+                }
+            }
+            &Data::BlockDevice(major, minor) | &Data::CharDevice(major, minor) => {
+                let device = repr::inode::DeviceNumber::new(major, minor).unwrap();
+                let block = matches!(data, Data::BlockDevice(..));
+                if extended {
+                    body.extend_from_slice(
+                        repr::inode::ExtendedDevice {
+                            hard_link_count: links,
+                            device,
+                            xattr_idx,
+                        }
+                        .as_bytes(),
+                    );
+                    if block {
+                        InodeKind::EXT_BLOCK_DEV
+                    } else {
+                        InodeKind::EXT_CHAR_DEV
+                    }
+                } else {
+                    body.extend_from_slice(
+                        repr::inode::BasicDevice {
+                            hard_link_count: links,
+                            device,
+                        }
+                        .as_bytes(),
+                    );
+                    if block {
+                        InodeKind::BASIC_BLOCK_DEV
+                    } else {
+                        InodeKind::BASIC_CHAR_DEV
+                    }
+                }
+            }
+            Data::Fifo | Data::Socket => {
+                let fifo = matches!(data, Data::Fifo);
+                if extended {
+                    body.extend_from_slice(
+                        repr::inode::ExtendedIpc {
+                            hard_link_count: links,
+                            xattr_idx,
+                        }
+                        .as_bytes(),
+                    );
+                    if fifo {
+                        InodeKind::EXT_FIFO
+                    } else {
+                        InodeKind::EXT_SOCKET
+                    }
+                } else {
+                    body.extend_from_slice(
+                        repr::inode::BasicIpc {
+                            hard_link_count: links,
+                        }
+                        .as_bytes(),
+                    );
+                    if fifo {
+                        InodeKind::BASIC_FIFO
+                    } else {
+                        InodeKind::BASIC_SOCKET
+                    }
+                }
+            }
+        };
+
+        let header = repr::inode::Header {
+            inode_type: kind,
+            permissions: *mode,
+            uid_idx: self.id_idx(*uid),
+            gid_idx: self.id_idx(*gid),
+            modified_time: repr::Time(*mtime),
+            inode_number: repr::inode::Idx(number),
+        };
+        let inode_ref = self.inodes.position();
+        self.inodes.write(header.as_bytes());
+        self.inodes.write(&body);
+        self.written[node] = Some((inode_ref, kind));
+        (inode_ref, kind)
+    }
+
+    fn id_idx(&mut self, id: u32) -> repr::uid_gid::Idx {
+        let idx = match self.ids.iter().position(|&existing| existing == id) {
+            Some(idx) => idx,
+            None => {
+                self.ids.push(id);
+                self.ids.len() - 1
+            }
+        };
+        repr::uid_gid::Idx(idx as u16)
+    }
+
+    /// Write a file's data blocks, returning their sizes and the number of bytes left sparse
+    fn write_blocks(&mut self, contents: &[u8]) -> (Vec<repr::datablock::Size>, u64) {
+        let mut sizes = Vec::new();
+        let mut sparse = 0;
+        for block in contents.chunks(self.builder.block_size as usize) {
+            if block.iter().all(|&byte| byte == 0) {
+                sizes.push(repr::datablock::Size::ZERO);
+                sparse += block.len() as u64;
+                continue;
+            }
+            let (data, compressed) = compress(&mut self.codec, block);
+            self.data.extend_from_slice(&data);
+            sizes.push(repr::datablock::Size::new(data.len() as u32, !compressed));
+        }
+        (sizes, sparse)
+    }
+
+    /// Write a directory listing, returning its position and size
+    fn write_listing(
+        &mut self,
+        entries: &[(&BString, repr::inode::Ref, InodeKind, u32)],
+    ) -> (repr::directory::Ref, u32) {
+        let mut listing = Vec::new();
+        let mut rest = entries;
+        while let Some(&(_, first_ref, _, first_number)) = rest.first() {
+            // Entries share a header if their inodes are in the same block, and their inode
+            // numbers are close enough to store as offsets
+            let len = rest
+                .iter()
+                .take(256)
+                .take_while(|&&(_, inode_ref, _, number)| {
+                    inode_ref.block_start() == first_ref.block_start()
+                        && i16::try_from(i64::from(number) - i64::from(first_number)).is_ok()
+                })
+                .count();
+            let (group, tail) = rest.split_at(len);
+            let header = repr::directory::Header {
+                count: len as u32 - 1,
+                start: first_ref.block_start(),
+                inode_number: repr::inode::Idx(first_number),
+            };
+            listing.extend_from_slice(header.as_bytes());
+            for &(name, inode_ref, kind, number) in group {
+                let entry = repr::directory::Entry {
+                    offset: inode_ref.start_offset(),
+                    inode_offset: (i64::from(number) - i64::from(first_number)) as i16,
+                    kind: kind.to_basic(),
+                    name_size: name.len() as u16 - 1,
+                };
+                listing.extend_from_slice(entry.as_bytes());
+                listing.extend_from_slice(name);
+            }
+            rest = tail;
+        }
+        let start = self.dirs.position();
+        self.dirs.write(&listing);
+        (start, listing.len() as u32)
+    }
+
+    fn write_xattrs(&mut self, xattrs: &[(BString, Vec<u8>)]) -> repr::xattr::Idx {
+        if xattrs.is_empty() {
+            return repr::xattr::Idx::NONE;
+        }
+        let start = self.xattrs.position();
+        let mut size = 0;
+        for (key, value) in xattrs {
+            let (kind, name) = xattr_prefix(key);
+            let mut pair = Vec::new();
+            let key = repr::xattr::Key {
+                kind,
+                name_size: name.len() as u16,
+            };
+            pair.extend_from_slice(key.as_bytes());
+            pair.extend_from_slice(name);
+            let value_size = repr::xattr::Value {
+                value_size: value.len() as u32,
+            };
+            pair.extend_from_slice(value_size.as_bytes());
+            pair.extend_from_slice(value);
+            self.xattrs.write(&pair);
+            size += pair.len() as u32;
+        }
+        self.xattr_ids.push(repr::xattr::LookupEntry {
+            xattr_ref: start,
+            count: xattrs.len() as u32,
+            size,
+        });
+        repr::xattr::Idx(self.xattr_ids.len() as u32 - 1)
+    }
+}
+
+/// Compress a block if that makes it smaller, returning the data to store, and whether it's
+/// compressed
+fn compress(codec: &mut Option<AnyCodec>, block: &[u8]) -> (Vec<u8>, bool) {
+    let codec = match codec {
+        Some(codec) => codec,
+        None => return (block.to_vec(), false),
+    };
+    let mut data = vec![0; block.len()];
+    let (len, compressed) = compress_or_copy(codec, block, &mut data);
+    data.truncate(len);
+    (data, compressed)
+}
+
+/// A table of metadata blocks, written sequentially
+struct Metablocks {
+    codec: Option<AnyCodec>,
+    output: Vec<u8>,
+    current: Vec<u8>,
+}
+
+impl Metablocks {
+    fn new(codec: Option<AnyCodec>) -> Self {
+        Metablocks {
+            codec,
+            output: Vec::new(),
+            current: Vec::new(),
+        }
+    }
+
+    /// The position the next write will start at
+    fn position(&mut self) -> repr::metablock::Ref {
+        if self.current.len() == repr::metablock::SIZE {
+            self.flush();
+        }
+        repr::metablock::Ref::new(self.output.len() as u32, self.current.len() as u16)
+    }
+
+    fn write(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.current.len() == repr::metablock::SIZE {
+                self.flush();
+            }
+            let len = data.len().min(repr::metablock::SIZE - self.current.len());
+            self.current.extend_from_slice(&data[..len]);
+            data = &data[len..];
+        }
+    }
+
+    fn flush(&mut self) {
+        let (data, compressed) = compress(&mut self.codec, &self.current);
+        let header = repr::metablock::Header::new(data.len() as u16, compressed);
+        self.output.extend_from_slice(header.as_bytes());
+        self.output.extend_from_slice(&data);
+        self.current.clear();
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if !self.current.is_empty() {
+            self.flush();
+        }
+        self.output
+    }
+}
+
+/// Write `items` in metadata blocks, returning the position of each block
+fn metablocks(data: &mut Vec<u8>, codec: Option<AnyCodec>, items: &[u8]) -> Vec<u64> {
+    let start = data.len() as u64;
+    let mut table = Metablocks::new(codec);
+    let blocks = items
+        .chunks(repr::metablock::SIZE)
+        .map(|chunk| {
+            let block = start + u64::from(table.position().block_start());
+            table.write(chunk);
+            block
+        })
+        .collect();
+    data.extend_from_slice(&table.finish());
+    blocks
+}
+
+/// Write a lookup table of `items`, such as the id table, returning its start
+fn lookup_table(data: &mut Vec<u8>, codec: Option<AnyCodec>, items: &[u8]) -> u64 {
+    let blocks = metablocks(data, codec, items);
+    let start = data.len() as u64;
+    data.extend_from_slice(blocks.as_bytes());
+    start
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read::{Archive, InodeData};
+    use crate::FileType;
+
+    fn open(image: Vec<u8>) -> Archive<Vec<u8>> {
+        let archive = Archive::new(image).unwrap();
+        assert_eq!(archive.lint().unwrap(), []);
+        archive
+    }
+
+    fn file(archive: &Archive<Vec<u8>>, name: &str) -> crate::read::Inode {
+        let root = archive.root().unwrap();
+        let entry = archive
+            .read_dir(&root)
+            .unwrap()
+            .into_iter()
+            .find(|entry| entry.name() == name)
+            .unwrap();
+        archive.inode(entry.inode_ref()).unwrap()
+    }
+
+    #[test]
+    fn deterministic() {
+        assert_eq!(all_inode_kinds(), all_inode_kinds());
+        assert_eq!(xattrs(), xattrs());
+    }
+
+    #[test]
+    fn one_file_image() {
+        let archive = open(one_file());
+        let hello = file(&archive, "hello");
+        assert_eq!(archive.read_file(&hello).unwrap(), HELLO_CONTENTS);
+        assert_eq!({ archive.superblock().inode_count }, 2);
+    }
+
+    #[test]
+    fn deep_dirs_image() {
+        let archive = open(deep_dirs(100));
+        let deepest = archive
+            .walk()
+            .map(Result::unwrap)
+            .max_by_key(|entry| entry.depth())
+            .unwrap();
+        assert_eq!(deepest.depth(), 101);
+        assert_eq!(deepest.path().len(), 100 * 2 + "/file".len());
+        assert_eq!(archive.read_file(deepest.inode()).unwrap(), b"deep\n");
+    }
+
+    #[test]
+    fn all_kinds() {
+        let archive = open(all_inode_kinds());
+        let types: Vec<_> = archive
+            .read_dir(&archive.root().unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.name().to_string(), entry.file_type()))
+            .collect();
+        assert_eq!(
+            types,
+            [
+                ("block".to_string(), FileType::BlockDev),
+                ("char".to_string(), FileType::CharDev),
+                ("dir".to_string(), FileType::Dir),
+                ("fifo".to_string(), FileType::Fifo),
+                ("file".to_string(), FileType::File),
+                ("socket".to_string(), FileType::Socket),
+                ("symlink".to_string(), FileType::Symlink),
+            ]
+        );
+        match file(&archive, "char").data() {
+            InodeData::CharDevice(device) => assert_eq!((device.major(), device.minor()), (1, 3)),
+            other => panic!("unexpected inode {:?}", other),
+        }
+        assert_eq!(file(&archive, "symlink").symlink_target().unwrap(), "file");
+    }
+
+    #[test]
+    fn all_codecs() {
+        for kind in codecs() {
+            let archive = open(compressed(kind));
+            assert_eq!(archive.compression().kind(), kind);
+            let compressible = file(&archive, "compressible");
+            assert_eq!(
+                archive.read_file(&compressible).unwrap(),
+                compressible_contents()
+            );
+            let incompressible = file(&archive, "incompressible");
+            assert_eq!(
+                archive.read_file(&incompressible).unwrap(),
+                incompressible_contents()
+            );
+        }
+    }
+
+    #[test]
+    fn corpus_listings() {
+        use xxhash_rust::xxh3::xxh3_64;
+
+        for kind in codecs() {
+            let archive = open(corpus(kind));
+            let entries: Vec<_> = archive.walk().map(Result::unwrap).collect();
+            let modes: Vec<_> = entries
+                .iter()
+                .map(|e| e.inode().mode().to_string())
+                .collect();
+            let listing: Vec<_> = entries
+                .iter()
+                .zip(&modes)
+                .map(|(entry, mode)| {
+                    let inode = entry.inode();
+                    let (size, hash) = match inode.data() {
+                        InodeData::File(file) => {
+                            let contents = archive.read_file(inode).unwrap();
+                            assert_eq!(contents.len() as u64, file.file_size);
+                            (file.file_size, xxh3_64(&contents))
+                        }
+                        InodeData::Symlink(target) => (target.len() as u64, xxh3_64(target)),
+                        _ => (0, 0),
+                    };
+                    ListedEntry {
+                        path: entry.path().to_str().unwrap(),
+                        mode,
+                        owner: (inode.uid(), inode.gid()),
+                        size,
+                        hash,
+                    }
+                })
+                .collect();
+            assert_eq!(listing, CORPUS_LISTING, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn uncompressed() {
+        let image = ImageBuilder::new()
+            .uncompressed()
+            .file("file", compressible_contents())
+            .build();
+        let archive = open(image.clone());
+        let file = file(&archive, "file");
+        assert_eq!(archive.read_file(&file).unwrap(), compressible_contents());
+        let contents = compressible_contents();
+        assert!(image
+            .windows(contents.len())
+            .any(|window| window == contents));
+    }
+
+    #[test]
+    fn xattr_image() {
+        let archive = open(xattrs());
+        assert!(file(&archive, "file").xattr_idx().is_some());
+        assert!(file(&archive, "dir").xattr_idx().is_some());
+        assert!(!file(&archive, "plain").xattr_idx().is_some());
+        assert_ne!({ archive.superblock().xattr_id_table_start }, u64::MAX);
+    }
+
+    #[test]
+    fn hard_link_image() {
+        let archive = open(hard_links());
+        let a = file(&archive, "a");
+        let b = file(&archive, "b");
+        assert_eq!(a.inode_number(), b.inode_number());
+        assert_eq!(a.hard_link_count(), 3);
+        let links: Vec<_> = archive
+            .walk()
+            .map(Result::unwrap)
+            .filter_map(|entry| Some(entry.hard_link_target()?.to_string()))
+            .collect();
+        assert_eq!(links, ["/a", "/a"]);
+    }
+
+    #[test]
+    fn sparse_image() {
+        let archive = open(sparse());
+        let sparse = file(&archive, "sparse");
+        assert_eq!(archive.read_file(&sparse).unwrap(), sparse_contents());
+        let block = u64::from(repr::BLOCK_SIZE_MIN);
+        assert_eq!(archive.seek_hole(&sparse, 0).unwrap(), Some(block));
+        assert_eq!(archive.seek_data(&sparse, block).unwrap(), Some(3 * block));
+
+        let zeros = file(&archive, "zeros");
+        assert_eq!(
+            archive.read_file(&zeros).unwrap(),
+            vec![0; 3 * block as usize]
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use std::io;
 use std::thread;
 
 #[derive(Debug)]
@@ -42,3 +43,34 @@ impl<T> Drop for Joiner<T> {
         }
     }
 }
+
+/// Restrict the current thread to running on `cpus`, or leave it alone if `cpus` is empty
+#[cfg(target_os = "linux")]
+pub(crate) fn pin_current(cpus: &[usize]) -> io::Result<()> {
+    if cpus.is_empty() {
+        return Ok(());
+    }
+    // SAFETY: cpu_set_t is a plain bitmask, CPU_SET only writes within it, and the size passed
+    // to sched_setaffinity is its own.
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus {
+            if cpu >= libc::CPU_SETSIZE as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("CPU {cpu} is beyond the largest supported"),
+                ));
+            }
+            libc::CPU_SET(cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn pin_current(_cpus: &[usize]) -> io::Result<()> {
+    Ok(())
+}
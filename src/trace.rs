@@ -0,0 +1,200 @@
+//! Laying out an image in the order its files are first read
+//!
+//! When an image is read from slow or high latency storage, as an appliance image is on first
+//! boot, files read one after another but stored far apart each cost a seek, and the readahead
+//! past the end of each is wasted. An [`AccessTrace`] lists files in the order they were first
+//! read. An archive built with one as its
+//! [`access_trace`](crate::write::ArchiveBuilder::access_trace) writes the data blocks and
+//! fragments of those files first, in that order, so booting reads the image front to back.
+//! The files the trace doesn't list follow in the usual order.
+//!
+//! An [`AccessRecorder`] captures a trace: open an image with
+//! [`OpenOptions::record_accesses`](crate::read::OpenOptions::record_accesses), serve it from a
+//! FUSE server built on the reader while the system boots from it, and turn the recorded
+//! accesses into paths.
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use std::sync::Arc;
+//! use sqfs::read::OpenOptions;
+//! use sqfs::trace::AccessRecorder;
+//!
+//! let recorder = Arc::new(AccessRecorder::new());
+//! let archive = OpenOptions::new()
+//!     .record_accesses(recorder.clone())
+//!     .open("image.sqfs")?;
+//! // ... serve the archive while the system boots ...
+//! recorder.trace(&archive)?.save("boot.trace")?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+use bstr::{BString, ByteSlice};
+
+use crate::errors::{Result, WriteError};
+use crate::read::{escape_into, unescape, Archive, ReadAt};
+
+/// The paths of files, in the order they were first read
+///
+/// A trace is saved as text, with one absolute path per line. Backslashes and newlines in
+/// paths are escaped as `\\` and `\n`, so any list of paths without either can be loaded as a
+/// trace, whichever tool produced it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessTrace {
+    paths: Vec<BString>,
+}
+
+impl AccessTrace {
+    pub fn new<I, P>(paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<BString>,
+    {
+        AccessTrace {
+            paths: paths.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    pub fn paths(&self) -> &[BString] {
+        &self.paths
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::parse(&fs::read(path)?)
+    }
+
+    /// Parse a trace from its text, skipping blank lines
+    pub fn parse(text: &[u8]) -> Result<Self> {
+        // Not `lines`, which would also strip a carriage return ending a path
+        let paths = text
+            .split_str("\n")
+            .enumerate()
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(i, line)| {
+                unescape(line).ok_or_else(|| WriteError::InvalidAccessTrace { line: i + 1 }.into())
+            })
+            .collect::<Result<_>>()?;
+        Ok(AccessTrace { paths })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut text = Vec::new();
+        for path in &self.paths {
+            escape_into(path, &mut text);
+            text.push(b'\n');
+        }
+        text
+    }
+}
+
+/// Records which files are read from an archive, and in what order
+///
+/// Only the first read of each file is recorded. Recording is cheap, but takes a lock, which
+/// readers on many threads share.
+#[derive(Debug, Default)]
+pub struct AccessRecorder {
+    accesses: Mutex<Accesses>,
+}
+
+#[derive(Debug, Default)]
+struct Accesses {
+    seen: HashSet<u32>,
+    order: Vec<u32>,
+}
+
+impl AccessRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Note a read of the contents of the file with inode number `inode_number`
+    pub(crate) fn record(&self, inode_number: u32) {
+        let mut accesses = self.accesses.lock().unwrap();
+        if accesses.seen.insert(inode_number) {
+            accesses.order.push(inode_number);
+        }
+    }
+
+    /// The inode numbers of the files read so far, in the order they were first read
+    pub fn inode_numbers(&self) -> Vec<u32> {
+        self.accesses.lock().unwrap().order.clone()
+    }
+
+    /// The trace of the files read so far from `archive`, which must be the archive they were
+    /// recorded from
+    ///
+    /// A hard linked file is listed under the first of its paths which
+    /// [`Archive::walk`] produces.
+    pub fn trace<R: ReadAt>(&self, archive: &Archive<R>) -> Result<AccessTrace> {
+        let order = self.inode_numbers();
+        let wanted: HashSet<u32> = order.iter().copied().collect();
+        let mut paths = HashMap::with_capacity(order.len());
+        for entry in archive.walk() {
+            let entry = entry?;
+            let inode_number = entry.inode().inode_number();
+            if wanted.contains(&inode_number) {
+                paths
+                    .entry(inode_number)
+                    .or_insert_with(|| entry.path().to_owned());
+            }
+        }
+        Ok(AccessTrace {
+            paths: order
+                .iter()
+                .filter_map(|inode_number| paths.remove(inode_number))
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read::OpenOptions;
+    use crate::testing::ImageBuilder;
+    use std::sync::Arc;
+
+    #[test]
+    fn text() {
+        let trace = AccessTrace::new(vec!["/etc/os-release", "/odd\\name\n"]);
+        let text = trace.to_bytes();
+        assert_eq!(text, b"/etc/os-release\n/odd\\\\name\\n\n".as_bytes());
+        assert_eq!(AccessTrace::parse(&text).unwrap(), trace);
+        assert_eq!(
+            AccessTrace::parse(b"/a\r\n\n/b\n").unwrap().paths(),
+            ["/a\r", "/b"]
+        );
+        assert!(AccessTrace::parse(b"/a\n/b\\x\n").is_err());
+    }
+
+    #[test]
+    fn record() {
+        let image = ImageBuilder::new()
+            .file("a", "a")
+            .file("b/c", "c")
+            .file("d", "d")
+            .build();
+        let recorder = Arc::new(AccessRecorder::new());
+        let archive = OpenOptions::new()
+            .record_accesses(recorder.clone())
+            .open_source(image)
+            .unwrap();
+        for path in ["d", "b/c", "d", "a"] {
+            archive.read_file(&archive.lookup(path).unwrap()).unwrap();
+        }
+        assert_eq!(
+            recorder.trace(&archive).unwrap().paths(),
+            ["/d", "/b/c", "/a"]
+        );
+    }
+}
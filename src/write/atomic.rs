@@ -0,0 +1,120 @@
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A file which only appears at its destination once it is completely written
+///
+/// Data is written to a temporary file in the destination's directory, which is renamed over
+/// the destination by [`commit`](AtomicFile::commit). If the file is dropped without being
+/// committed, the temporary file is deleted, so readers never observe partial output.
+#[derive(Debug)]
+pub struct AtomicFile {
+    file: File,
+    temp_path: PathBuf,
+    dest: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    pub fn create<P: AsRef<Path>>(dest: P) -> io::Result<Self> {
+        Self::_create(dest.as_ref())
+    }
+
+    fn _create(dest: &Path) -> io::Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let file_name = dest
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "missing file name"))?;
+        let mut temp_name = OsString::from(".");
+        temp_name.push(file_name);
+        temp_name.push(format!(
+            ".{}-{}.tmp",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let temp_path = dest.with_file_name(temp_name);
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)?;
+        Ok(Self {
+            file,
+            temp_path,
+            dest: dest.to_owned(),
+            committed: false,
+        })
+    }
+
+    /// The path the file will have once committed
+    pub fn dest(&self) -> &Path {
+        &self.dest
+    }
+
+    pub(crate) fn sync_data(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// Move the file to its destination, replacing any existing file
+    pub fn commit(&mut self) -> io::Result<()> {
+        if self.committed {
+            return Ok(());
+        }
+        fs::rename(&self.temp_path, &self.dest)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl io::Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for AtomicFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn commit_and_abandon() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("image.sqfs");
+
+        let mut file = AtomicFile::create(&dest).unwrap();
+        file.write_all(b"partial").unwrap();
+        assert!(!dest.exists());
+        drop(file);
+        assert!(!dest.exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        let mut file = AtomicFile::create(&dest).unwrap();
+        file.write_all(b"complete").unwrap();
+        file.commit().unwrap();
+        drop(file);
+        assert_eq!(fs::read(&dest).unwrap(), b"complete");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,47 @@
+use std::collections::HashMap;
+use std::io;
+
+use super::tree::{Entry, Tree};
+use super::Archive;
+use crate::cpio::Header;
+use crate::errors::Result;
+use crate::Mode;
+
+/// Add the contents of a newc (or crc) cpio archive to `archive`, and set it as the root
+///
+/// Entries may appear in any order. Directories missing from the cpio archive are created with
+/// default metadata, and a `.` entry sets the metadata of the root directory.
+/// Regular files sharing an inode number are stored as hard links.
+pub fn from_cpio<R: io::Read, W: io::Write>(archive: &mut Archive<W>, mut reader: R) -> Result<()> {
+    let mut tree = Tree::new();
+    let mut links = HashMap::new();
+    while let Some((header, data)) = Header::read_entry(&mut reader)? {
+        let mode = Mode::from_unix(header.mode);
+        let mut link = None;
+        if mode.ty() == Mode::TYPE_FILE && header.nlink > 1 {
+            let key = (header.dev_major, header.dev_minor, header.ino);
+            let id = *links.entry(key).or_insert_with(|| tree.new_link());
+            // Only one of the links is expected to carry the file contents
+            if !tree.has_link_contents(id) {
+                tree.set_link_contents(id, data.clone());
+            }
+            link = Some(id);
+        }
+        let entry = Entry {
+            uid: header.uid,
+            gid: header.gid,
+            mode,
+            mtime: header.mtime,
+            contents: if link.is_some() { Vec::new() } else { data },
+            device: (header.rdev_major, header.rdev_minor),
+            link,
+            provider: None,
+            xattrs: Vec::new(),
+        };
+        tree.insert(&header.name, entry)?;
+    }
+
+    let root = tree.build(archive)?;
+    archive.set_root(root);
+    Ok(())
+}
@@ -1,5 +1,5 @@
 use crate::compress_threads::ParallelCompressor;
-use crate::pool;
+use crate::pool::BlockPool;
 use crate::write::{fragments, ReadHoles};
 use crossbeam_channel::Receiver;
 use futures::channel::oneshot;
@@ -54,12 +54,13 @@ struct Response {
 fn handle_file(
     block_size: usize,
     compressor: Option<&ParallelCompressor>,
+    pool: &Arc<BlockPool>,
     mut file: Box<dyn ReadHoles>,
 ) -> io::Result<Response> {
     let mut sizes = Vec::new();
     let mut do_skip = true;
     loop {
-        let mut block = pool::block();
+        let mut block = pool.get();
         if do_skip {
             let mut hole_size = match file.skip_hole() {
                 Ok(size) => size,
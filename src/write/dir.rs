@@ -1,25 +1,46 @@
 use crate::compression::Compressor;
+use crate::pool::BlockPool;
 use crate::write::metablock_writer::MetablockWriter;
+use repr::offset::UncompressedOffset;
 use std::convert::TryInto;
 use std::mem;
+use std::sync::Arc;
 use zerocopy::AsBytes;
 
 pub struct DirectoryInfo {
-    header_refs: Vec<repr::directory::Ref>,
-    uncompressed_size: u32,
+    /// The position of the directory's first header
+    pub start: repr::directory::Ref,
+    pub index: Vec<IndexEntry>,
+    pub uncompressed_size: u32,
+}
+
+/// An entry of a directory's index, stored in its extended inode, which lets a lookup skip
+/// straight to the metablock which may hold a name
+///
+/// The index has an entry for the first header in each metablock after the first one the
+/// directory starts in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// The offset of the header from the directory's first header, as if the directory table
+    /// were uncompressed
+    pub offset: u32,
+    /// The position in the directory table of the metablock holding the header
+    pub block_start: u32,
+    /// The name of the first entry after the header
+    pub name: Vec<u8>,
 }
 
 pub struct Table<Comp> {
     writer: MetablockWriter<Comp>,
-    // TODO: Is this correct, or should this be u64?
-    total_size: usize,
+    /// The size of the table so far, before compression
+    total_size: UncompressedOffset,
 }
 
 impl<Comp: Compressor> Table<Comp> {
-    pub fn new(compressor: Option<Comp>) -> Self {
+    pub fn new(compressor: Option<Comp>, pool: &Arc<BlockPool>) -> Self {
         Self {
-            writer: MetablockWriter::new(compressor),
-            total_size: 0,
+            writer: MetablockWriter::new(compressor, pool),
+            total_size: UncompressedOffset(0),
         }
     }
 
@@ -41,13 +62,23 @@ impl<Comp: Compressor> Table<Comp> {
         IntoIt: IntoIterator<Item = Entry>,
     {
         let start_size = self.total_size;
+        let start = self.writer.position();
 
         let mut builder = self.start_dir();
-        let mut header_refs = Vec::new();
+        let mut index = Vec::new();
+        let mut indexed_block = start.block_start();
 
         for entry in contents {
-            if let Some(header_ref) = builder.add_entry(entry) {
-                header_refs.push(header_ref);
+            let name = entry.name.clone();
+            if let Some((header_ref, header_offset)) = builder.add_entry(entry) {
+                if header_ref.block_start() != indexed_block {
+                    indexed_block = header_ref.block_start();
+                    index.push(IndexEntry {
+                        offset: (header_offset - start_size).try_into().unwrap(),
+                        block_start: indexed_block,
+                        name,
+                    });
+                }
             }
         }
 
@@ -55,12 +86,13 @@ impl<Comp: Compressor> Table<Comp> {
 
         let end_size = self.total_size;
         DirectoryInfo {
-            header_refs,
+            start,
+            index,
             uncompressed_size: (end_size - start_size).try_into().unwrap(),
         }
     }
 
-    pub fn finish(self) -> (usize, Vec<u8>) {
+    pub fn finish(self) -> (UncompressedOffset, Vec<u8>) {
         (self.total_size, self.writer.finish())
     }
 }
@@ -94,10 +126,14 @@ const MIN_INODE_NUM_REF: repr::inode::Idx = repr::inode::Idx(i16::MIN.unsigned_a
 const MAX_INODE_NUM_REF: repr::inode::Idx = repr::inode::Idx(u32::MAX - i16::MAX as u32);
 
 impl<Comp: Compressor> DirBuilder<'_, Comp> {
-    /// Add a dir entry, returning the header pos, if this required a new header
-    pub fn add_entry(&mut self, entry: Entry) -> Option<repr::directory::Ref> {
+    /// Add a dir entry, returning the position of the header, and its offset in the
+    /// uncompressed table, if this required a new header
+    pub fn add_entry(
+        &mut self,
+        entry: Entry,
+    ) -> Option<(repr::directory::Ref, UncompressedOffset)> {
         let need_header = self.crossed_metablock
-            || self.header.count >= 256
+            || self.header.count >= repr::limits::MAX_DIR_ENTRIES_PER_HEADER
             || self.header.start != entry.inode.block_start()
             || inode_diff(self.header.inode_number, entry.inode_num).is_none();
 
@@ -108,12 +144,12 @@ impl<Comp: Compressor> DirBuilder<'_, Comp> {
             // Don't set the reference num lower than a ref num which can go all the way to zero, or higher than one
             // which can go to the max
             self.header.inode_number = entry.inode_num.clamp(MIN_INODE_NUM_REF, MAX_INODE_NUM_REF);
-            Some(self.table.writer.position())
+            Some((self.table.writer.position(), self.table.total_size))
         } else {
             None
         };
 
-        let prev_metablock = self.total_size() / repr::metablock::SIZE;
+        let prev_metablock = self.total_size().metablock_index();
         self.header.count += 1;
 
         let name_len: u16 = entry.name.len().try_into().unwrap();
@@ -127,21 +163,27 @@ impl<Comp: Compressor> DirBuilder<'_, Comp> {
         self.entries.extend_from_slice(raw_entry.as_bytes());
         self.entries.extend_from_slice(&entry.name);
 
-        let current_metablock = self.total_size() / repr::metablock::SIZE;
+        let current_metablock = self.total_size().metablock_index();
         if current_metablock != prev_metablock {
             self.crossed_metablock = true;
         }
         header_pos
     }
 
-    fn total_size(&self) -> usize {
-        self.table.total_size + mem::size_of_val(&self.header) + self.entries.len()
+    fn total_size(&self) -> UncompressedOffset {
+        let pending = mem::size_of_val(&self.header) + self.entries.len();
+        self.table.total_size + pending as u64
     }
 
     fn flush(&mut self) {
         if self.header.count != 0 {
             self.table.total_size = self.total_size();
-            self.table.writer.write(&self.header);
+            // The stored count is one less than the number of entries
+            let header = repr::directory::Header {
+                count: self.header.count - 1,
+                ..self.header
+            };
+            self.table.writer.write(&header);
             self.table.writer.write_raw(&self.entries);
 
             self.entries.clear();
@@ -162,17 +204,64 @@ mod tests {
     #[test]
     fn simple() {
         let compressor = crate::compression::AnyCodec::new(crate::compression::Kind::default());
-        let mut table = Table::new(Some(compressor));
+        let pool = Arc::new(BlockPool::new(0, 1));
+        let mut table = Table::new(Some(compressor), &pool);
         let entries = (0..1000).map(|i| Entry {
             inode: repr::inode::Ref::new(i / 100, i as _),
             inode_num: repr::inode::Idx(i * 50),
             inode_kind: repr::inode::Kind::BASIC_FILE,
             name: format!("b{:03}", i).into_bytes(),
         });
-        let header_refs = table.dir(entries);
+        let info = table.dir(entries);
+        assert_eq!(info.start, repr::directory::Ref::new(0, 0));
 
         let (uncompressed_size, data) = table.finish();
-        assert!(data.len() < uncompressed_size);
+        assert_eq!(u64::from(info.uncompressed_size), uncompressed_size.0);
+        assert!((data.len() as u64) < uncompressed_size.0);
+    }
+
+    #[test]
+    fn index() {
+        let pool = Arc::new(BlockPool::new(0, 1));
+        let mut table = Table::<crate::compression::AnyCodec>::new(None, &pool);
+        // Another directory first, so offsets are relative to the second one's first header
+        table.dir(vec![Entry {
+            inode: repr::inode::Ref::new(0, 0),
+            inode_num: repr::inode::Idx(1),
+            inode_kind: repr::inode::Kind::BASIC_DIR,
+            name: b"other".to_vec(),
+        }]);
+        let entries: Vec<_> = (0..2000)
+            .map(|i| Entry {
+                inode: repr::inode::Ref::new(0, i as _),
+                inode_num: repr::inode::Idx(i + 2),
+                inode_kind: repr::inode::Kind::BASIC_FILE,
+                name: format!("entry{:04}", i).into_bytes(),
+            })
+            .collect();
+        let info = table.dir(entries);
+        let (_, data) = table.finish();
+
+        // Uncompressed metablocks are 2 bytes bigger than their contents
+        let metablocks = (data.len() + repr::metablock::SIZE - 1) / (repr::metablock::SIZE + 2);
+        assert!(metablocks > 2);
+        assert_eq!(info.index.len(), metablocks - 1);
+        let first_header = 12 + 8 + "other".len();
+        for (i, entry) in info.index.iter().enumerate() {
+            let block_start = (i + 1) * (repr::metablock::SIZE + 2);
+            assert_eq!(entry.block_start as usize, block_start);
+            // The header is the first thing in the metablock, or follows the end of the last
+            // header's entries in it
+            let offset = entry.offset as usize + first_header;
+            assert!(offset >= (i + 1) * repr::metablock::SIZE);
+            assert!(entry.name.starts_with(b"entry"));
+
+            let header_at = block_start + 2 + offset - (i + 1) * repr::metablock::SIZE;
+            let header: repr::directory::Header = repr::read(&data[header_at..]).unwrap();
+            let name_at = header_at + 12 + 8;
+            assert_eq!(&data[name_at..name_at + entry.name.len()], &entry.name[..]);
+            assert!(header.count > 0);
+        }
     }
 
     #[test]
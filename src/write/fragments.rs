@@ -1,31 +1,35 @@
 use crate::compression::AnyCodec;
+use crate::pool::BlockPool;
 use crate::write::two_level;
+use std::sync::Arc;
 
 pub struct Table {
     inner: two_level::Table<repr::fragment::Entry, AnyCodec>,
-    count: usize,
+    /// Kept so a planned build can record them, see [`entries`](Self::entries)
+    entries: Vec<repr::fragment::Entry>,
 }
 
 impl Table {
-    pub fn new(compressor: Option<AnyCodec>) -> Self {
+    pub fn new(compressor: Option<AnyCodec>, pool: &Arc<BlockPool>) -> Self {
         Self {
-            inner: two_level::Table::new(compressor),
-            count: 0,
+            inner: two_level::Table::new(compressor, pool),
+            entries: Vec::new(),
         }
     }
 
     pub fn add_fragment(&mut self, location: repr::datablock::Ref, size: repr::datablock::Size) {
-        let entry = repr::fragment::Entry {
-            start: location,
-            size,
-            _unused: 0,
-        };
+        let entry = repr::fragment::Entry::new(location, size);
         self.inner.write(&entry);
-        self.count += 1;
+        self.entries.push(entry);
     }
 
     pub fn count(&self) -> usize {
-        self.count
+        self.entries.len()
+    }
+
+    /// Every fragment block added so far, in order
+    pub fn entries(&self) -> &[repr::fragment::Entry] {
+        &self.entries
     }
 
     pub fn finish(self) -> (Vec<u8>, Vec<u32>) {
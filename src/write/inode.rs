@@ -1,20 +1,29 @@
-use super::metablock_writer::MetablockWriter;
+use super::dir::IndexEntry;
+use super::metablock_writer::{Mark, MetablockWriter};
+use crate::compress_threads::ParallelCompressor;
 use crate::compression::Compressor;
+use crate::pool::BlockPool;
 use crate::Mode;
 use std::convert::TryInto;
 use std::io;
+use std::sync::Arc;
 
 #[derive(Debug, Default)]
 pub struct Table<Comp> {
     writer: MetablockWriter<Comp>,
-    count: u32,
 }
 
 impl<Comp: Compressor> Table<Comp> {
-    pub fn new(compressor: Option<Comp>) -> Self {
+    pub fn new(compressor: Option<Comp>, pool: &Arc<BlockPool>) -> Self {
         Self {
-            writer: MetablockWriter::new(compressor),
-            count: 0,
+            writer: MetablockWriter::new(compressor, pool),
+        }
+    }
+
+    /// A table compressed by `parallel`, see [`MetablockWriter::with_parallel`]
+    pub fn with_parallel(parallel: Arc<ParallelCompressor>, pool: &Arc<BlockPool>) -> Self {
+        Self {
+            writer: MetablockWriter::with_parallel(parallel, pool),
         }
     }
 
@@ -23,12 +32,18 @@ impl<Comp: Compressor> Table<Comp> {
     }
 
     pub fn add(&mut self, entry: Entry) -> io::Result<repr::inode::Ref> {
-        let result = self.writer.position();
+        let mark = self.add_deferred(entry)?;
+        Ok(self.resolve(mark))
+    }
 
-        let extended = entry.needs_ext();
+    /// Add an inode, returning a mark to [`resolve`](Self::resolve) into its reference later
+    ///
+    /// Resolving waits for every block before the inode to be compressed, so adding all the
+    /// inodes of a directory before resolving any lets them be compressed in parallel.
+    pub fn add_deferred(&mut self, entry: Entry) -> io::Result<Mark> {
+        let result = self.writer.mark();
 
-        let inode_number = repr::inode::Idx(self.count);
-        self.count += 1;
+        let extended = entry.needs_ext();
 
         let header = repr::inode::Header {
             inode_type: entry.data.inode_kind(extended),
@@ -36,7 +51,7 @@ impl<Comp: Compressor> Table<Comp> {
             uid_idx: entry.common.uid_idx,
             gid_idx: entry.common.gid_idx,
             modified_time: entry.common.modified_time,
-            inode_number,
+            inode_number: entry.common.inode_number,
         };
 
         self.writer.write(&header);
@@ -78,6 +93,10 @@ impl<Comp: Compressor> Table<Comp> {
         Ok(result)
     }
 
+    pub fn resolve(&mut self, mark: Mark) -> repr::inode::Ref {
+        self.writer.resolve(mark)
+    }
+
     fn write_basic_dir(&mut self, common: &Common, data: &DirData) {
         let body = repr::inode::BasicDir {
             dir_block_start: data.dir_ref.block_start(),
@@ -108,17 +127,23 @@ impl<Comp: Compressor> Table<Comp> {
             file_size: repr::inode::dir_stored_size(data.dir_size),
             dir_block_start: data.dir_ref.block_start(),
             parent_inode_number: data.parent_inode_num,
-            index_count: data
-                .header_locations
-                .as_ref()
-                .map_or(0, |locations| locations.len().try_into().unwrap()),
+            index_count: data.index.len().try_into().unwrap(),
             block_offset: data.dir_ref.start_offset(),
             xattr_idx: common.xattr_idx,
         };
 
         self.writer.write(&body);
 
-        todo!("Need to write header locations")
+        for entry in &data.index {
+            let name_size: u32 = entry.name.len().try_into().unwrap();
+            let index = repr::directory::Index {
+                index: entry.offset,
+                start: entry.block_start,
+                name_size: name_size - 1,
+            };
+            self.writer.write(&index);
+            self.writer.write_raw(&entry.name);
+        }
     }
 
     fn write_basic_file(&mut self, common: &Common, data: &FileData) {
@@ -224,6 +249,9 @@ pub struct Common {
     pub modified_time: repr::Time,
     pub hardlink_count: u32,
     pub xattr_idx: repr::xattr::Idx,
+    /// The number of the inode, from 1, which directory entries and the export table refer to
+    /// it by
+    pub inode_number: repr::inode::Idx,
     /// Force extended type of inode
     pub force_ext: bool,
 }
@@ -237,7 +265,7 @@ impl Entry {
         match &self.data {
             Data::Directory(data) => {
                 let stored_dir_size = repr::inode::dir_stored_size(data.dir_size);
-                data.header_locations.is_some() || stored_dir_size > u16::MAX.into()
+                !data.index.is_empty() || stored_dir_size > u16::MAX.into()
             }
             Data::File(data) => {
                 self.common.hardlink_count > 1
@@ -290,8 +318,8 @@ pub struct DirData {
     pub dir_size: u32,
     pub parent_inode_num: repr::inode::Idx,
     pub child_count: u32,
-    // TODO: This is the wrong type, need index as if it were uncompressed
-    pub header_locations: Option<Vec<repr::directory::Ref>>,
+    /// The directory's index, which is only stored in an extended inode
+    pub index: Vec<IndexEntry>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -323,7 +351,8 @@ mod tests {
 
     #[test]
     fn add_entries() {
-        let mut table = Table::<AnyCodec>::new(None);
+        let pool = Arc::new(BlockPool::new(0, 1));
+        let mut table = Table::<AnyCodec>::new(None, &pool);
 
         let common = Common {
             permissions: Default::default(),
@@ -332,6 +361,7 @@ mod tests {
             modified_time: repr::Time(0),
             hardlink_count: 1,
             xattr_idx: repr::xattr::Idx::default(),
+            inode_number: repr::inode::Idx(1),
             force_ext: false,
         };
         let entry = Entry {
@@ -341,7 +371,10 @@ mod tests {
         table.add(entry).unwrap();
 
         let entry = Entry {
-            common,
+            common: Common {
+                inode_number: repr::inode::Idx(2),
+                ..common
+            },
             data: Data::Symlink(SymlinkData {
                 target_path: b"abcdef".to_vec(),
             }),
@@ -355,7 +388,10 @@ mod tests {
         );
 
         let entry = Entry {
-            common,
+            common: Common {
+                inode_number: repr::inode::Idx(3),
+                ..common
+            },
             data: Data::File(FileData {
                 blocks_start: repr::datablock::Ref(0),
                 file_size: 10,
@@ -378,15 +414,57 @@ mod tests {
         );
 
         let data = table.finish();
-        assert_eq!(
-            data,
-            concat!(
-                "\x56\0\x07\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x01\0\0\0\x03\0\0\0",
-                "\0\0\0\0\0\0\0\0\x01\0\0\0\x01\0\0\0\x06\0\0\0abcdef\x02\0\0",
-                "\0\0\0\0\0\0\0\0\0\x02\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x0A\0\0",
-                "\0\x0A\0\0\0",
-            )
-            .as_bytes()
-        );
+        let expected: &[&[u8]] = &[
+            b"\x56\x80\x07\0\0\0\0\0\0\0\0\0\0\0\x01\0\0\0\x01\0\0\0\x03\0\0\0",
+            b"\0\0\0\0\0\0\0\0\x02\0\0\0\x01\0\0\0\x06\0\0\0abcdef\x02\0\0",
+            b"\0\0\0\0\0\0\0\0\0\x03\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x0A\0\0",
+            b"\0\x0A\0\0\0",
+        ];
+        assert_eq!(data, expected.concat());
+    }
+
+    #[test]
+    fn ext_dir_index() {
+        let pool = Arc::new(BlockPool::new(0, 1));
+        let mut table = Table::<AnyCodec>::new(None, &pool);
+        let entry = Entry {
+            common: Common {
+                permissions: Default::default(),
+                uid_idx: repr::uid_gid::Idx(0),
+                gid_idx: repr::uid_gid::Idx(0),
+                modified_time: repr::Time(0),
+                hardlink_count: 2,
+                xattr_idx: repr::xattr::Idx::default(),
+                inode_number: repr::inode::Idx(1),
+                force_ext: false,
+            },
+            data: Data::Directory(DirData {
+                dir_ref: repr::directory::Ref::new(0, 0),
+                dir_size: 9000,
+                parent_inode_num: repr::inode::Idx(1),
+                child_count: 300,
+                index: vec![IndexEntry {
+                    offset: 8190,
+                    block_start: 4000,
+                    name: b"name".to_vec(),
+                }],
+            }),
+        };
+        table.add(entry).unwrap();
+
+        let data = table.finish();
+        // Skip the metablock header
+        let mut rest = &data[2..];
+        let header: raw::Header = repr::read(rest).unwrap();
+        assert_eq!({ header.inode_type }, raw::Kind::EXT_DIR);
+        rest = &rest[mem::size_of::<raw::Header>()..];
+        let dir: raw::ExtendedDir = repr::read(rest).unwrap();
+        assert_eq!({ dir.index_count }, 1);
+        rest = &rest[mem::size_of::<raw::ExtendedDir>()..];
+        let index: repr::directory::Index = repr::read(rest).unwrap();
+        assert_eq!({ index.index }, 8190);
+        assert_eq!({ index.start }, 4000);
+        assert_eq!({ index.name_size }, 3);
+        assert_eq!(&rest[mem::size_of::<repr::directory::Index>()..], b"name");
     }
 }
@@ -0,0 +1,47 @@
+//! Where the parts of a finished image lie in its output
+//!
+//! Images are often stacked under dm-verity or dm-crypt, whose tools need to know how much of
+//! the file is payload: `veritysetup format` takes the number of data blocks, and where the
+//! hash tree starts if it's appended to the same file. Padding the output to the device's
+//! block size with [`ArchiveBuilder::padding`](super::ArchiveBuilder::padding) makes the
+//! payload a whole number of blocks, and [`Layout`] gives the exact boundaries, so scripts
+//! don't have to work them out from the superblock.
+
+use std::ops::Range;
+
+/// The layout of a written image, see [`Archive::layout`](super::Archive::layout)
+///
+/// The output is the archive itself, then the signature trailer if there is one, then zeros up
+/// to a multiple of the padding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    /// The number of bytes of the archive itself, which start the output
+    pub bytes_used: u64,
+    /// Where the signature trailer is in the output, if the archive was signed with a trailer
+    pub signature_trailer: Option<Range<u64>>,
+    /// The multiple the output was padded to
+    pub padding: u64,
+    /// The total size of the output, including the padding
+    pub file_size: u64,
+}
+
+impl Layout {
+    /// The end of everything written before the padding
+    pub fn payload_end(&self) -> u64 {
+        match &self.signature_trailer {
+            Some(trailer) => trailer.end,
+            None => self.bytes_used,
+        }
+    }
+
+    /// The number of `block_size` blocks the output consists of, as `veritysetup` takes for
+    /// `--data-blocks`, or `None` if the output isn't a whole number of blocks
+    ///
+    /// An appended hash tree starts at [`file_size`](Self::file_size), the `--hash-offset`.
+    pub fn data_blocks(&self, block_size: u64) -> Option<u64> {
+        match self.file_size.checked_rem(block_size) {
+            Some(0) => Some(self.file_size / block_size),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::errors::Result;
+use crate::read;
+
+/// A shared in-memory buffer an archive can be written to
+///
+/// Clones share the same buffer, so one clone can be given to the archive while another is kept
+/// to retrieve the written bytes, see [`Archive::in_memory`](super::Archive::in_memory).
+#[derive(Debug, Clone, Default)]
+pub struct InMemory {
+    data: Arc<Mutex<Vec<u8>>>,
+}
+
+impl InMemory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<u8>> {
+        // The buffer is always left in a valid state, even if a writer panicked
+        self.data.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// A copy of everything written so far
+    pub fn bytes(&self) -> Vec<u8> {
+        self.lock().clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    /// Open the written archive for reading
+    pub fn open(&self) -> Result<read::Archive<Vec<u8>>> {
+        read::Archive::new(self.bytes())
+    }
+}
+
+impl io::Write for InMemory {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn shared_buffer() {
+        let handle = InMemory::new();
+        let mut writer = handle.clone();
+        assert!(handle.is_empty());
+        writer.write_all(b"hello").unwrap();
+        writer.write_all(b" there").unwrap();
+        assert_eq!(handle.len(), 11);
+        assert_eq!(handle.bytes(), b"hello there");
+    }
+}
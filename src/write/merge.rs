@@ -0,0 +1,247 @@
+use std::io;
+
+use bstr::{BString, ByteSlice};
+
+use super::tree::Tree;
+use super::Archive;
+use crate::config::ConflictPolicy;
+use crate::errors::{Result, WriteError};
+use crate::read::{self, child_path, ReadAt};
+use crate::Mode;
+
+/// How [`merge`] combines two archives
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeOptions {
+    /// The directory the root of the second archive is grafted onto, `/` to combine the roots
+    ///
+    /// Missing parents are created with default metadata.
+    pub prefix: BString,
+    pub conflicts: ConflictPolicy,
+}
+
+impl Default for MergeOptions {
+    fn default() -> Self {
+        MergeOptions {
+            prefix: "/".into(),
+            conflicts: ConflictPolicy::default(),
+        }
+    }
+}
+
+/// Add the contents of `first`, and of `second` under `options.prefix`, to `archive`, and set
+/// the merged tree as the root
+///
+/// File contents are streamed from their source archive a block at a time as they're added,
+/// so neither is extracted or held in memory. Extended attributes, and hard links within each
+/// archive, are kept.
+///
+/// ```no_run
+/// use sqfs::read;
+/// use sqfs::write::{self, MergeOptions};
+///
+/// # fn main() -> sqfs::Result<()> {
+/// let base = read::Archive::open("base.sqfs")?;
+/// let overlay = read::Archive::open("overlay.sqfs")?;
+/// let mut archive = write::Archive::from_writer(std::fs::File::create("merged.sqfs")?);
+/// let options = MergeOptions {
+///     prefix: "/opt/overlay".into(),
+///     ..MergeOptions::default()
+/// };
+/// write::merge(&mut archive, &base, &overlay, &options)?;
+/// archive.flush()?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn merge<R1, R2, W>(
+    archive: &mut Archive<W>,
+    first: &read::Archive<R1>,
+    second: &read::Archive<R2>,
+    options: &MergeOptions,
+) -> Result<()>
+where
+    R1: ReadAt + 'static,
+    R2: ReadAt + 'static,
+    W: io::Write,
+{
+    let root = merged_tree(first, second, options)?.build(archive)?;
+    archive.set_root(root);
+    Ok(())
+}
+
+fn merged_tree<R1, R2>(
+    first: &read::Archive<R1>,
+    second: &read::Archive<R2>,
+    options: &MergeOptions,
+) -> Result<Tree>
+where
+    R1: ReadAt + 'static,
+    R2: ReadAt + 'static,
+{
+    let mut tree = Tree::new();
+    tree.add_archive(first, |tree, path, entry| tree.insert(&path, entry))?;
+
+    // A directory of the second archive left out by the conflict policy, whose contents are
+    // left out too. The walk produces them right after it.
+    let mut skipped: Option<BString> = None;
+    tree.add_archive(second, |tree, path, entry| {
+        let path = graft(&options.prefix, &path);
+        if let Some(dir) = &skipped {
+            if path.starts_with(dir) && path.get(dir.len()) == Some(&b'/') {
+                return Ok(());
+            }
+            skipped = None;
+        }
+        let existing_dir = match tree.get_mut(&path)? {
+            Some(node) => node.is_dir(),
+            None => return tree.insert(&path, entry),
+        };
+        let is_dir = entry.mode.ty() == Mode::TYPE_DIR;
+        match options.conflicts {
+            _ if existing_dir && is_dir => {
+                if options.conflicts == ConflictPolicy::KeepSecond {
+                    tree.insert(&path, entry)?;
+                }
+                Ok(())
+            }
+            ConflictPolicy::Error => Err(WriteError::MergeConflict(path).into()),
+            ConflictPolicy::KeepFirst => {
+                if is_dir {
+                    skipped = Some(path);
+                }
+                Ok(())
+            }
+            ConflictPolicy::KeepSecond => tree.insert(&path, entry),
+        }
+    })?;
+    Ok(tree)
+}
+
+/// The path of an entry of the second archive once it's grafted onto `prefix`
+fn graft(prefix: &[u8], path: &[u8]) -> BString {
+    let relative = path.trim_start_with(|c| c == '/');
+    if relative.is_empty() {
+        prefix.into()
+    } else {
+        child_path(prefix, relative)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ImageBuilder;
+
+    fn open(builder: &ImageBuilder) -> read::Archive<Vec<u8>> {
+        read::Archive::new(builder.build()).unwrap()
+    }
+
+    fn paths(tree: &mut Tree, paths: &[&str]) -> Vec<Option<Mode>> {
+        paths
+            .iter()
+            .map(|path| {
+                let node = tree.get_mut(path.as_bytes()).unwrap()?;
+                Some(node.entry.as_ref().unwrap().mode.ty())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn merge_trees() {
+        let first = open(ImageBuilder::new().file("a", "first").file("dir/b", "b"));
+        let second = open(
+            ImageBuilder::new()
+                .file("dir/c", "c")
+                .dir("a/sub")
+                .file("a/sub/d", "d"),
+        );
+
+        let options = MergeOptions::default();
+        let err = merged_tree(&first, &second, &options).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            r#"Write error: "/a" exists in both archives being merged"#
+        );
+
+        let options = MergeOptions {
+            conflicts: ConflictPolicy::KeepFirst,
+            ..MergeOptions::default()
+        };
+        let mut tree = merged_tree(&first, &second, &options).unwrap();
+        assert_eq!(
+            paths(&mut tree, &["a", "dir/b", "dir/c", "a/sub"]),
+            [
+                Some(Mode::TYPE_FILE),
+                Some(Mode::TYPE_FILE),
+                Some(Mode::TYPE_FILE),
+                None
+            ]
+        );
+
+        let options = MergeOptions {
+            conflicts: ConflictPolicy::KeepSecond,
+            ..MergeOptions::default()
+        };
+        let mut tree = merged_tree(&first, &second, &options).unwrap();
+        assert_eq!(
+            paths(&mut tree, &["a", "dir/b", "a/sub/d"]),
+            [
+                Some(Mode::TYPE_DIR),
+                Some(Mode::TYPE_FILE),
+                Some(Mode::TYPE_FILE)
+            ]
+        );
+    }
+
+    #[test]
+    fn merged_image() {
+        let big: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let first = open(ImageBuilder::new().file("big", big.clone()).xattr(
+            "big",
+            "user.first",
+            "1",
+        ));
+        let second = open(ImageBuilder::new().symlink("link", "big").xattr(
+            "link",
+            "security.selinux",
+            "label",
+        ));
+        let mut image = Vec::new();
+        let mut archive = Archive::from_writer(&mut image);
+        merge(&mut archive, &first, &second, &MergeOptions::default()).unwrap();
+        archive.flush().unwrap();
+        drop(archive);
+
+        let merged = read::Archive::new(image).unwrap();
+        let file = merged.lookup("big").unwrap();
+        assert_eq!(merged.read_file(&file).unwrap(), big);
+        let xattrs = |path: &str| -> Vec<_> {
+            let inode = merged.lookup(path).unwrap();
+            merged.xattrs(&inode).unwrap().map(Result::unwrap).collect()
+        };
+        assert_eq!(xattrs("big"), [("user.first".into(), b"1".to_vec())]);
+        assert_eq!(
+            xattrs("link"),
+            [("security.selinux".into(), b"label".to_vec())]
+        );
+    }
+
+    #[test]
+    fn graft_under_prefix() {
+        let first = open(ImageBuilder::new().file("a", "first"));
+        let second = open(ImageBuilder::new().file("a", "second"));
+        let options = MergeOptions {
+            prefix: "/opt/second".into(),
+            ..MergeOptions::default()
+        };
+        let mut tree = merged_tree(&first, &second, &options).unwrap();
+        assert_eq!(
+            paths(&mut tree, &["a", "opt/second", "opt/second/a"]),
+            [
+                Some(Mode::TYPE_FILE),
+                Some(Mode::TYPE_DIR),
+                Some(Mode::TYPE_FILE)
+            ]
+        );
+        assert!(tree.get_mut(b"opt").unwrap().unwrap().entry.is_none());
+    }
+}
@@ -1,35 +1,89 @@
+use crate::compress_threads::{ParallelCompressor, Pending};
 use crate::compression::{compress_or_copy, Compressor};
-use crate::pool;
+use crate::pool::BlockPool;
+use std::collections::VecDeque;
 use std::convert::TryInto;
 use std::fmt::{Debug, Formatter};
 use std::mem;
+use std::sync::Arc;
 use zerocopy::AsBytes;
 
 #[derive(Default)]
 pub struct MetablockWriter<Comp> {
     compressor: Option<Comp>,
+    /// Compresses full blocks on other threads instead of `compressor`, see
+    /// [`with_parallel`](Self::with_parallel)
+    parallel: Option<Arc<ParallelCompressor>>,
+    /// Blocks submitted to `parallel` which haven't been written to `output` yet, in order
+    pending: VecDeque<Pending>,
+    /// The position in `output` following each block written
+    block_starts: Vec<u32>,
     output: Vec<u8>,
     current_block: Vec<u8>,
 }
 
+/// A position in the uncompressed stream of a [`MetablockWriter`], which becomes a
+/// [`repr::metablock::Ref`] once the blocks before it have been compressed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Mark {
+    /// The index of the metablock
+    block: usize,
+    offset: u16,
+}
+
 impl<Comp: Compressor> MetablockWriter<Comp> {
-    pub fn new(compressor: Option<Comp>) -> Self {
-        Self::with_capacity(compressor, 0)
+    pub fn new(compressor: Option<Comp>, pool: &Arc<BlockPool>) -> Self {
+        Self::with_capacity(compressor, 0, pool)
     }
 
-    pub fn with_capacity(compressor: Option<Comp>, cap: usize) -> Self {
+    pub fn with_capacity(compressor: Option<Comp>, cap: usize, pool: &Arc<BlockPool>) -> Self {
         Self {
             compressor,
+            parallel: None,
+            pending: VecDeque::new(),
+            block_starts: Vec::new(),
             output: Vec::with_capacity(cap),
-            current_block: pool::block().detach(),
+            current_block: pool.get().detach(),
         }
     }
 
-    pub fn position(&self) -> repr::metablock::Ref {
-        repr::metablock::Ref::new(
-            self.output.len().try_into().unwrap(),
-            self.current_block.len().try_into().unwrap(),
-        )
+    /// Compress blocks with `parallel`, so a table of many blocks is compressed on all its
+    /// threads at once
+    ///
+    /// Full blocks are submitted as soon as they are written, and only waited for when a
+    /// position after them is [resolved](Self::resolve), so positions should be [marked]
+    /// (Self::mark) and resolved as late as possible.
+    pub fn with_parallel(parallel: Arc<ParallelCompressor>, pool: &Arc<BlockPool>) -> Self {
+        Self {
+            parallel: Some(parallel),
+            ..Self::new(None, pool)
+        }
+    }
+
+    /// The position of the next byte written, resolving it immediately
+    pub fn position(&mut self) -> repr::metablock::Ref {
+        let mark = self.mark();
+        self.resolve(mark)
+    }
+
+    /// The position of the next byte written, without waiting for any blocks to be compressed
+    pub fn mark(&self) -> Mark {
+        Mark {
+            block: self.block_starts.len() + self.pending.len(),
+            offset: self.current_block.len().try_into().unwrap(),
+        }
+    }
+
+    /// The position of `mark` in the output, waiting for the blocks before it to be compressed
+    pub fn resolve(&mut self, mark: Mark) -> repr::metablock::Ref {
+        while self.block_starts.len() < mark.block {
+            self.write_pending();
+        }
+        let block_start = match mark.block {
+            0 => 0,
+            block => self.block_starts[block - 1],
+        };
+        repr::metablock::Ref::new(block_start, mark.offset)
     }
 
     pub fn write<T: AsBytes>(&mut self, item: &T) {
@@ -48,10 +102,21 @@ impl<Comp: Compressor> MetablockWriter<Comp> {
 
     pub fn finish(mut self) -> Vec<u8> {
         self.flush();
+        while !self.pending.is_empty() {
+            self.write_pending();
+        }
         mem::take(&mut self.output)
     }
 
     fn flush(&mut self) {
+        if let Some(parallel) = &self.parallel {
+            let block = mem::replace(
+                &mut self.current_block,
+                Vec::with_capacity(repr::metablock::SIZE),
+            );
+            self.pending.push_back(parallel.submit(block));
+            return;
+        }
         if let Some(compressor) = &mut self.compressor {
             // TODO: 8k on the stack vs on the heap? Uninitialized?
             let mut dst = [0; repr::metablock::SIZE];
@@ -62,6 +127,23 @@ impl<Comp: Compressor> MetablockWriter<Comp> {
             Self::write_output(&mut self.output, &self.current_block, false);
         }
         self.current_block.clear();
+        self.block_written();
+    }
+
+    /// Write the oldest block submitted to the parallel compressor, waiting for it
+    fn write_pending(&mut self) {
+        let response = self
+            .pending
+            .pop_front()
+            .expect("resolved a position in a block which hasn't been written")
+            .wait();
+        Self::write_output(&mut self.output, &response.data, response.compressed);
+        self.block_written();
+    }
+
+    fn block_written(&mut self) {
+        self.block_starts
+            .push(self.output.len().try_into().unwrap());
     }
 
     fn write_output(output: &mut Vec<u8>, data: &[u8], compressed: bool) {
@@ -78,8 +160,12 @@ impl<Comp> Debug for MetablockWriter<Comp> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("MetablockWriter")
             .field("finished_output_size", &self.output.len())
+            .field("pending_blocks", &self.pending.len())
             .field("current_block_size", &self.current_block.len())
-            .field("is_compressed", &self.compressor.is_some())
+            .field(
+                "is_compressed",
+                &(self.compressor.is_some() || self.parallel.is_some()),
+            )
             .finish()
     }
 }
@@ -88,8 +174,13 @@ impl<Comp> Debug for MetablockWriter<Comp> {
 mod tests {
     use super::*;
     use crate::compression::{AnyCodec, Kind};
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use zerocopy::AsBytes;
 
+    fn pool() -> Arc<BlockPool> {
+        Arc::new(BlockPool::new(0, 1))
+    }
+
     fn pos(pos: repr::metablock::Ref) -> (u32, u16) {
         (pos.block_start(), pos.start_offset())
     }
@@ -104,7 +195,7 @@ mod tests {
 
         let compressor = AnyCodec::new(Kind::ZLib);
 
-        let mut writer = MetablockWriter::new(Some(compressor));
+        let mut writer = MetablockWriter::new(Some(compressor), &pool());
 
         let big_t = BigT { data: [0; 1000] };
         // Write 9 * 1000 bytes so the next one will start in the second metablock
@@ -135,7 +226,7 @@ mod tests {
             data: [u8; GIANT_SIZE],
         }
 
-        let mut writer = MetablockWriter::<AnyCodec>::new(None);
+        let mut writer = MetablockWriter::<AnyCodec>::new(None, &pool());
 
         let big_t = GiantT {
             data: [0; GIANT_SIZE],
@@ -154,4 +245,46 @@ mod tests {
 
         let result = writer.finish();
     }
+
+    #[test]
+    fn boxed_compressor() {
+        struct Count(Arc<AtomicUsize>);
+
+        impl Compressor for Count {
+            fn compress(&mut self, src: &[u8], dst: &mut [u8]) -> std::io::Result<usize> {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                Err(std::io::ErrorKind::UnexpectedEof.into())
+            }
+        }
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let compressor: Box<dyn Compressor + Send> = Box::new(Count(Arc::clone(&calls)));
+        let mut writer = MetablockWriter::new(Some(compressor), &pool());
+        writer.write_raw(&[1; repr::metablock::SIZE + 1]);
+        let output = writer.finish();
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        assert_eq!(output.len(), 2 * 2 + repr::metablock::SIZE + 1);
+    }
+
+    #[test]
+    fn parallel() {
+        let compressor = AnyCodec::new(Kind::default());
+        let parallel = Arc::new(ParallelCompressor::with_threads(compressor.clone(), 2));
+        let mut serial = MetablockWriter::new(Some(compressor), &pool());
+        let mut writer = MetablockWriter::<AnyCodec>::with_parallel(parallel, &pool());
+
+        let mut marks = Vec::new();
+        for i in 0..5000u32 {
+            let item = (i % 7).to_le_bytes();
+            marks.push((serial.position(), writer.mark()));
+            serial.write(&item);
+            writer.write(&item);
+        }
+        // Nothing has been waited for yet
+        assert!(writer.pending.len() > 1);
+        for (expected, mark) in marks {
+            assert_eq!(writer.resolve(mark), expected);
+        }
+        assert_eq!(writer.finish(), serial.finish());
+    }
 }
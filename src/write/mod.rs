@@ -1,56 +1,177 @@
 //mod datablocks;
+mod atomic;
+mod cpio;
 mod dir;
 mod fragments;
 mod inode;
+mod layout;
+mod memory;
+mod merge;
 mod metablock_writer;
+mod numbering;
+mod plan;
+mod report;
+mod source;
+mod split;
+mod staged;
+mod stream;
+mod strip;
+mod sync_writer;
+pub(crate) mod tree;
 mod two_level;
 mod uid_gid;
+#[cfg(feature = "watch")]
+mod watch;
+mod xattr;
 
 use chrono::{DateTime, Utc};
+use std::io::Read as _;
 use std::path::Path;
 use std::{fmt, mem, ptr};
 use std::{fs, io};
 
 use bstr::BString;
 
-use crate::config::FragmentMode;
-
+pub use atomic::AtomicFile;
+pub use cpio::from_cpio;
+pub use layout::Layout;
+pub use memory::InMemory;
+pub use merge::{merge, MergeOptions};
+pub use report::{Issue, Report};
+pub use source::{ContentProvider, Contents, SourceEntry, SourceKind};
+pub use split::SplitFile;
+pub use staged::{BuildPlan, BuildState, PlannedEntry};
+pub use strip::{strip, StripOptions};
+#[cfg(feature = "watch")]
+pub use watch::{watch, WatchOptions};
+
+use crate::config::{
+    CpuAffinity, DeviceNumberPolicy, FragmentMode, LimitPolicy, Limits, LoggingConfig,
+    MemoryBudget, SpecialFilePolicy, SymlinkRewrite, SyncPolicy,
+};
+
+use crate::compress_threads::ParallelCompressor;
 use crate::compression;
-use crate::errors::Result;
+use crate::compression::adaptive::SkipIncompressible;
+use crate::compression::verify::VerifyWrites;
+use crate::compression::{AnyCodec, BlockCodec};
+use crate::digest::{Digest, DigestAlgorithm, HashingReader};
+use crate::errors::{Result, WriteError};
+use crate::metrics::NoMetrics;
+use crate::pool::BlockPool;
+use crate::signature::{SignaturePlacement, Signer};
+use crate::trace::AccessTrace;
 use crate::Mode;
 use slog::Logger;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryInto;
 use std::fs::File;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 use swiss_reader::SparseRead;
 
+use numbering::InodeNumbers;
+use plan::Plan;
+use repr::datablock::Size;
+use repr::offset::ArchiveOffset;
+use sync_writer::{SeekHook, SyncWriter, WriterHook};
+use xattr::Xattrs;
+use zerocopy::{AsBytes, FromBytes};
+
+/// The unit of block devices, which [`ArchiveBuilder::padding`] must be a multiple of
+pub const SECTOR_SIZE: u64 = 512;
+
 const MODE_DEFAULT_DIRECTORY: Mode = Mode::O755;
 const MODE_DEFAULT_FILE: Mode = Mode::O644;
+const MODE_DEFAULT_SYMLINK: Mode = Mode::O777;
+
+/// The search (execute) permissions matching the read permissions of `mode`
+fn search_bits(mode: Mode) -> Mode {
+    Mode::from_bits_truncate((mode.bits() & 0o444) >> 2)
+}
 
 pub struct Archive<W: io::Write> {
-    file: W,
+    file: SyncWriter<W>,
+    /// Called once the archive has been completely written
+    commit: Option<WriterHook<W>>,
+    /// The multiple the output is padded to, see [`ArchiveBuilder::padding`]
+    padding: u64,
+    /// Where everything was written, once the archive is finished
+    layout: Option<Layout>,
+    /// Whether [`flush`](Self::flush) has started
+    flushing: bool,
+    signing: Option<Signing>,
+    /// The digest and signature of the image, once it's finished
+    image_digest: Option<Digest>,
+    signature: Option<Vec<u8>>,
     mtime: DateTime<Utc>,
     block_size: u32,
+    memory: MemoryBudget,
+    /// Buffers for blocks, shared by the metadata writers and compression threads
+    pool: Arc<BlockPool>,
+    /// The image laid out so far, with the data blocks of every file finished
+    plan: Plan,
+    compressor_kind: compression::Kind,
+    /// A codec configured with the archive's compression options, for metadata
+    codec: AnyCodec,
+    compressor: Arc<ParallelCompressor>,
+    /// The number of blocks which failed verification, if they're verified, see
+    /// [`ArchiveBuilder::verify_writes`]
+    verify_failures: Option<Arc<AtomicU64>>,
+    /// The number of blocks of a file read ahead while earlier ones are compressed
+    read_ahead: usize,
+    compressed_data: bool,
+    compressed_fragments: bool,
+    fragment_mode: FragmentMode,
+    /// The tails of files waiting to fill a fragment block
+    fragment: Vec<u8>,
+    fragments: fragments::Table,
 
     flags: repr::superblock::Flags,
+    /// Whether the extended attributes of items are stored, see [`ArchiveBuilder::xattrs`]
+    store_xattrs: bool,
+    /// Whether to write an export table, see [`ArchiveBuilder::exportable`]
+    exportable: bool,
+    special_files: SpecialFilePolicy,
+    symlink_rewrite: SymlinkRewrite,
+    device_numbers: DeviceNumberPolicy,
+    limits: Limits,
+    limit_policy: LimitPolicy,
+    access_trace: Option<AccessTrace>,
+    /// Issues found so far, returned by [`Archive::flush`]
+    report: Report,
     items: Vec<Item>,
     root: ItemRef,
 
     uid_gids: uid_gid::Table,
 
+    file_digests: Option<DigestAlgorithm>,
+    /// The digests of the contents of file items, by item index
+    digests: HashMap<u32, Digest>,
+
     logger: Logger,
 }
 
 impl<W: io::Write> Archive<W> {
-    pub fn create_file_contents<R>(&self, file: R)
+    /// Write the contents of a file before adding it, see
+    /// [`FileBuilder::finish_with_contents`]
+    ///
+    /// The contents are read to the end, and written like those of
+    /// [`FileBuilder::finish`]. Files given the same contents share their data blocks, without
+    /// being hard links.
+    pub fn create_file_contents<R>(&mut self, mut file: R) -> Result<FileContents>
     where
         R: SparseRead + Send,
     {
-        todo!()
+        self.write_file(&[], &mut file).map(FileContents)
     }
 }
 
+/// The contents of a file, written by [`Archive::create_file_contents`]
+#[derive(Debug, Clone)]
+pub struct FileContents(WrittenFile);
+
 pub struct SubdirBuilder;
 
 impl SubdirBuilder {
@@ -79,10 +200,11 @@ struct Item {
     gid: repr::uid_gid::Id,
     mode: repr::Mode,
     mtime: DateTime<Utc>,
+    explicit: Explicit,
 
     inode: Option<repr::inode::Ref>,
 
-    // TODO: xattrs
+    xattrs: Xattrs,
     data: Data,
 }
 
@@ -92,10 +214,20 @@ impl Item {
 
         match self.data {
             Data::Directory { .. } => Kind::BASIC_DIR,
-            _ => todo!(),
+            Data::Symlink { .. } => Kind::BASIC_SYMLINK,
+            Data::BlockDev(_) => Kind::BASIC_BLOCK_DEV,
+            Data::CharDev(_) => Kind::BASIC_CHAR_DEV,
+            Data::Fifo => Kind::BASIC_FIFO,
+            Data::Socket => Kind::BASIC_SOCKET,
+            Data::File { .. } => Kind::BASIC_FILE,
         }
     }
 
+    /// The full mode of the item, including the file type bits matching its data
+    pub(crate) fn full_mode(&self) -> repr::Mode {
+        self.mode.perm() | self.data.mode_type()
+    }
+
     pub(crate) fn children_refs(&self) -> Option<impl Iterator<Item = ItemRef> + '_> {
         match &self.data {
             Data::Directory { entries } => Some(entries.iter().map(|(_, &item_ref)| item_ref)),
@@ -104,6 +236,30 @@ impl Item {
     }
 }
 
+/// Which metadata of an item was set on its builder, or inherited from a directory, and so
+/// isn't replaced by the child defaults of the directories containing it
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+struct Explicit {
+    uid: bool,
+    gid: bool,
+    mode: bool,
+}
+
+/// Metadata for the items in a directory which don't set their own, see
+/// [`DirBuilder::default_child_mode`]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+struct ChildDefaults {
+    uid: Option<repr::uid_gid::Id>,
+    gid: Option<repr::uid_gid::Id>,
+    mode: Option<repr::Mode>,
+}
+
+impl ChildDefaults {
+    fn is_empty(&self) -> bool {
+        *self == ChildDefaults::default()
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct ItemRef(u32);
 
@@ -115,8 +271,21 @@ enum Data {
     CharDev(repr::inode::DeviceNumber),
     Fifo,
     Socket,
-    // TODO
-    File {},
+    File { file: inode::FileData },
+}
+
+impl Data {
+    fn mode_type(&self) -> repr::Mode {
+        match self {
+            Data::Symlink { .. } => Mode::TYPE_LINK,
+            Data::Directory { .. } => Mode::TYPE_DIR,
+            Data::BlockDev(_) => Mode::TYPE_BLOCK,
+            Data::CharDev(_) => Mode::TYPE_CHAR,
+            Data::Fifo => Mode::TYPE_FIFO,
+            Data::Socket => Mode::TYPE_SOCKET,
+            Data::File { .. } => Mode::TYPE_FILE,
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -128,7 +297,10 @@ pub struct DirBuilder {
     gid: repr::uid_gid::Id,
     mode: repr::Mode,
     mtime: DateTime<Utc>,
+    explicit: Explicit,
+    xattrs: Xattrs,
     entries: BTreeMap<BString, ItemRef>,
+    child_defaults: ChildDefaults,
     logger: Logger,
 }
 
@@ -139,23 +311,33 @@ impl DirBuilder {
             gid: repr::uid_gid::Id(0),
             mode: MODE_DEFAULT_DIRECTORY,
             mtime: Utc::now(),
+            explicit: Explicit::default(),
+            xattrs: Xattrs::new(),
             entries: BTreeMap::new(),
+            child_defaults: ChildDefaults::default(),
             logger,
         }
     }
 
     pub fn set_uid(&mut self, id: u32) -> &mut Self {
         self.uid = repr::uid_gid::Id(id);
+        self.explicit.uid = true;
         self
     }
 
     pub fn set_gid(&mut self, id: u32) -> &mut Self {
         self.gid = repr::uid_gid::Id(id);
+        self.explicit.gid = true;
         self
     }
 
+    /// Set the permissions of the item
+    ///
+    /// Only the permission bits of `mode` are honored: any file type bits are replaced by the
+    /// type of the item being built.
     pub fn set_mode(&mut self, mode: crate::Mode) -> &mut Self {
-        self.mode = mode;
+        self.mode = mode.perm();
+        self.explicit.mode = true;
         self
     }
 
@@ -164,6 +346,49 @@ impl DirBuilder {
         self
     }
 
+    /// Set an extended attribute of the item, replacing any with the same name
+    ///
+    /// Squashfs can only store attributes in the `user.`, `trusted.` and `security.`
+    /// namespaces, so others, such as `system.posix_acl_access`, are rejected. Attributes
+    /// are dropped when the archive is built without [`xattrs`](ArchiveBuilder::xattrs).
+    pub fn set_xattr<N, V>(&mut self, name: N, value: V) -> Result<&mut Self>
+    where
+        N: Into<BString>,
+        V: Into<Vec<u8>>,
+    {
+        let (name, value) = (name.into(), value.into());
+        xattr::check(&name, &value)?;
+        self.xattrs.insert(name, value);
+        Ok(self)
+    }
+
+    /// Set the uid of every item in the directory, and in its subdirectories, which doesn't set
+    /// its own
+    ///
+    /// Defaults are applied when the directory is finished. An item's own builder, or the
+    /// defaults of a nearer directory, take precedence.
+    pub fn default_child_uid(&mut self, id: u32) -> &mut Self {
+        self.child_defaults.uid = Some(repr::uid_gid::Id(id));
+        self
+    }
+
+    /// Set the gid of every item beneath the directory which doesn't set its own, like
+    /// [`default_child_uid`](Self::default_child_uid)
+    pub fn default_child_gid(&mut self, id: u32) -> &mut Self {
+        self.child_defaults.gid = Some(repr::uid_gid::Id(id));
+        self
+    }
+
+    /// Set the permissions of every item beneath the directory which doesn't set its own, like
+    /// [`default_child_uid`](Self::default_child_uid)
+    ///
+    /// Directories are also given search permission wherever `mode` gives read permission, so
+    /// `0o644` gives files `0o644` and directories `0o755`. Symlinks keep their permissions.
+    pub fn default_child_mode(&mut self, mode: crate::Mode) -> &mut Self {
+        self.child_defaults.mode = Some(mode.perm());
+        self
+    }
+
     pub fn add_item<S: Into<BString>>(&mut self, name: S, item: ItemRef) -> &mut Self {
         self._add_item(name.into(), item);
         self
@@ -174,14 +399,22 @@ impl DirBuilder {
     }
 
     pub fn finish<W: io::Write>(self, archive: &mut Archive<W>) -> ItemRef {
+        if !self.child_defaults.is_empty() {
+            for &child in self.entries.values() {
+                archive.inherit(child, &self.child_defaults);
+            }
+        }
         // This is safe because self will not be dropped
         let entries = unsafe { ptr::read(&self.entries) };
+        let xattrs = unsafe { ptr::read(&self.xattrs) };
         let item = Item {
             uid: self.uid,
             gid: self.gid,
             mode: self.mode,
             mtime: self.mtime,
+            explicit: self.explicit,
             inode: None,
+            xattrs,
             data: Data::Directory { entries },
         };
         mem::forget(self);
@@ -200,27 +433,377 @@ impl Drop for DirBuilder {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum IpcKind {
+    Fifo,
+    Socket,
+}
+
+impl IpcKind {
+    fn name(self) -> &'static str {
+        match self {
+            IpcKind::Fifo => "fifo",
+            IpcKind::Socket => "socket",
+        }
+    }
+}
+
+/// Builder for a fifo or a socket
+///
+/// Whether the item is actually stored depends on the archive's
+/// [`SpecialFilePolicy`](crate::config::SpecialFilePolicy)
+#[derive(Debug)]
+pub struct IpcBuilder {
+    uid: repr::uid_gid::Id,
+    gid: repr::uid_gid::Id,
+    mode: repr::Mode,
+    mtime: DateTime<Utc>,
+    explicit: Explicit,
+    xattrs: Xattrs,
+    kind: IpcKind,
+}
+
+impl IpcBuilder {
+    fn new(kind: IpcKind) -> Self {
+        IpcBuilder {
+            uid: repr::uid_gid::Id(0),
+            gid: repr::uid_gid::Id(0),
+            mode: MODE_DEFAULT_FILE,
+            mtime: Utc::now(),
+            explicit: Explicit::default(),
+            xattrs: Xattrs::new(),
+            kind,
+        }
+    }
+
+    pub fn set_uid(&mut self, id: u32) -> &mut Self {
+        self.uid = repr::uid_gid::Id(id);
+        self.explicit.uid = true;
+        self
+    }
+
+    pub fn set_gid(&mut self, id: u32) -> &mut Self {
+        self.gid = repr::uid_gid::Id(id);
+        self.explicit.gid = true;
+        self
+    }
+
+    /// Set the permissions of the item
+    ///
+    /// Only the permission bits of `mode` are honored: any file type bits are replaced by the
+    /// type of the item being built.
+    pub fn set_mode(&mut self, mode: crate::Mode) -> &mut Self {
+        self.mode = mode.perm();
+        self.explicit.mode = true;
+        self
+    }
+
+    pub fn set_modified_time(&mut self, date_time: DateTime<Utc>) -> &mut Self {
+        self.mtime = date_time;
+        self
+    }
+
+    /// Set an extended attribute of the item, like [`DirBuilder::set_xattr`]
+    pub fn set_xattr<N, V>(&mut self, name: N, value: V) -> Result<&mut Self>
+    where
+        N: Into<BString>,
+        V: Into<Vec<u8>>,
+    {
+        let (name, value) = (name.into(), value.into());
+        xattr::check(&name, &value)?;
+        self.xattrs.insert(name, value);
+        Ok(self)
+    }
+
+    /// Add the item to the archive
+    ///
+    /// Returns `Ok(None)` if the archive is configured to skip special files, and an error if it
+    /// is configured to reject them.
+    pub fn finish<W: io::Write>(self, archive: &mut Archive<W>) -> Result<Option<ItemRef>> {
+        self.finish_at(archive, None)
+    }
+
+    /// Add the item to the archive, naming `path` in the report if it's skipped
+    pub(crate) fn finish_at<W: io::Write>(
+        self,
+        archive: &mut Archive<W>,
+        path: Option<&[u8]>,
+    ) -> Result<Option<ItemRef>> {
+        match archive.special_files {
+            SpecialFilePolicy::Store => {}
+            SpecialFilePolicy::Skip => {
+                let issue = Issue::SkippedSpecialFile {
+                    path: path.map(BString::from),
+                    kind: self.kind.name(),
+                };
+                archive.report.note(&archive.logger, issue);
+                return Ok(None);
+            }
+            SpecialFilePolicy::Error => {
+                return Err(WriteError::SpecialFile {
+                    kind: self.kind.name(),
+                }
+                .into());
+            }
+        }
+
+        let data = match self.kind {
+            IpcKind::Fifo => Data::Fifo,
+            IpcKind::Socket => Data::Socket,
+        };
+        let item = Item {
+            uid: self.uid,
+            gid: self.gid,
+            mode: self.mode,
+            mtime: self.mtime,
+            explicit: self.explicit,
+            inode: None,
+            xattrs: self.xattrs,
+            data,
+        };
+        Ok(Some(archive.add_item(item)))
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum DeviceKind {
+    Block,
+    Char,
+}
+
+/// Builder for a block or character device
+#[derive(Debug)]
+pub struct DeviceBuilder {
+    uid: repr::uid_gid::Id,
+    gid: repr::uid_gid::Id,
+    mode: repr::Mode,
+    mtime: DateTime<Utc>,
+    explicit: Explicit,
+    xattrs: Xattrs,
+    kind: DeviceKind,
+    major: u32,
+    minor: u32,
+}
+
+impl DeviceBuilder {
+    fn new(kind: DeviceKind) -> Self {
+        DeviceBuilder {
+            uid: repr::uid_gid::Id(0),
+            gid: repr::uid_gid::Id(0),
+            mode: MODE_DEFAULT_FILE,
+            mtime: Utc::now(),
+            explicit: Explicit::default(),
+            xattrs: Xattrs::new(),
+            kind,
+            major: 0,
+            minor: 0,
+        }
+    }
+
+    pub fn set_uid(&mut self, id: u32) -> &mut Self {
+        self.uid = repr::uid_gid::Id(id);
+        self.explicit.uid = true;
+        self
+    }
+
+    pub fn set_gid(&mut self, id: u32) -> &mut Self {
+        self.gid = repr::uid_gid::Id(id);
+        self.explicit.gid = true;
+        self
+    }
+
+    /// Set the permissions of the item
+    ///
+    /// Only the permission bits of `mode` are honored: any file type bits are replaced by the
+    /// type of the item being built.
+    pub fn set_mode(&mut self, mode: crate::Mode) -> &mut Self {
+        self.mode = mode.perm();
+        self.explicit.mode = true;
+        self
+    }
+
+    pub fn set_modified_time(&mut self, date_time: DateTime<Utc>) -> &mut Self {
+        self.mtime = date_time;
+        self
+    }
+
+    /// Set an extended attribute of the item, like [`DirBuilder::set_xattr`]
+    pub fn set_xattr<N, V>(&mut self, name: N, value: V) -> Result<&mut Self>
+    where
+        N: Into<BString>,
+        V: Into<Vec<u8>>,
+    {
+        let (name, value) = (name.into(), value.into());
+        xattr::check(&name, &value)?;
+        self.xattrs.insert(name, value);
+        Ok(self)
+    }
+
+    pub fn set_device(&mut self, major: u32, minor: u32) -> &mut Self {
+        self.major = major;
+        self.minor = minor;
+        self
+    }
+
+    /// Add the device to the archive
+    ///
+    /// If the device number does not fit in squashfs, this either fails or clamps the device
+    /// number, depending on the archive's
+    /// [`DeviceNumberPolicy`](crate::config::DeviceNumberPolicy)
+    pub fn finish<W: io::Write>(self, archive: &mut Archive<W>) -> Result<ItemRef> {
+        let device = match repr::inode::DeviceNumber::new(self.major, self.minor) {
+            Ok(device) => device,
+            Err(e) => match archive.device_numbers {
+                DeviceNumberPolicy::Error => return Err(WriteError::from(e).into()),
+                DeviceNumberPolicy::Clamp => {
+                    let issue = Issue::ClampedDevice {
+                        major: e.major,
+                        minor: e.minor,
+                    };
+                    archive.report.note(&archive.logger, issue);
+                    repr::inode::DeviceNumber::new_clamped(e.major, e.minor)
+                }
+            },
+        };
+
+        let data = match self.kind {
+            DeviceKind::Block => Data::BlockDev(device),
+            DeviceKind::Char => Data::CharDev(device),
+        };
+        let item = Item {
+            uid: self.uid,
+            gid: self.gid,
+            mode: self.mode,
+            mtime: self.mtime,
+            explicit: self.explicit,
+            inode: None,
+            xattrs: self.xattrs,
+            data,
+        };
+        Ok(archive.add_item(item))
+    }
+}
+
+/// Builder for a symbolic link
+#[derive(Debug)]
+pub struct SymlinkBuilder {
+    uid: repr::uid_gid::Id,
+    gid: repr::uid_gid::Id,
+    mode: repr::Mode,
+    mtime: DateTime<Utc>,
+    explicit: Explicit,
+    xattrs: Xattrs,
+    target: BString,
+}
+
+impl SymlinkBuilder {
+    fn new(target: BString) -> Self {
+        SymlinkBuilder {
+            uid: repr::uid_gid::Id(0),
+            gid: repr::uid_gid::Id(0),
+            mode: MODE_DEFAULT_SYMLINK,
+            mtime: Utc::now(),
+            explicit: Explicit::default(),
+            xattrs: Xattrs::new(),
+            target,
+        }
+    }
+
+    pub fn set_uid(&mut self, id: u32) -> &mut Self {
+        self.uid = repr::uid_gid::Id(id);
+        self.explicit.uid = true;
+        self
+    }
+
+    pub fn set_gid(&mut self, id: u32) -> &mut Self {
+        self.gid = repr::uid_gid::Id(id);
+        self.explicit.gid = true;
+        self
+    }
+
+    /// Set the permissions of the item
+    ///
+    /// Only the permission bits of `mode` are honored: any file type bits are replaced by the
+    /// type of the item being built.
+    pub fn set_mode(&mut self, mode: crate::Mode) -> &mut Self {
+        self.mode = mode.perm();
+        self.explicit.mode = true;
+        self
+    }
+
+    pub fn set_modified_time(&mut self, date_time: DateTime<Utc>) -> &mut Self {
+        self.mtime = date_time;
+        self
+    }
+
+    /// Set an extended attribute of the item, like [`DirBuilder::set_xattr`]
+    pub fn set_xattr<N, V>(&mut self, name: N, value: V) -> Result<&mut Self>
+    where
+        N: Into<BString>,
+        V: Into<Vec<u8>>,
+    {
+        let (name, value) = (name.into(), value.into());
+        xattr::check(&name, &value)?;
+        self.xattrs.insert(name, value);
+        Ok(self)
+    }
+
+    pub fn finish<W: io::Write>(self, archive: &mut Archive<W>) -> ItemRef {
+        let item = Item {
+            uid: self.uid,
+            gid: self.gid,
+            mode: self.mode,
+            mtime: self.mtime,
+            explicit: self.explicit,
+            inode: None,
+            xattrs: self.xattrs,
+            data: Data::Symlink {
+                target: self.target,
+            },
+        };
+        archive.add_item(item)
+    }
+}
+
 pub struct FileBuilder {
     uid: repr::uid_gid::Id,
     gid: repr::uid_gid::Id,
     mode: repr::Mode,
     mtime: DateTime<Utc>,
+    explicit: Explicit,
+    xattrs: Xattrs,
+    raw_blocks: Vec<RawData>,
     contents: Box<dyn io::Read>,
 }
 
+/// A block added by [`FileBuilder::add_raw_block`]
+#[derive(Debug)]
+struct RawData {
+    data: Vec<u8>,
+    size: Size,
+    len: u32,
+}
+
 impl FileBuilder {
     pub fn set_uid(&mut self, id: u32) -> &mut Self {
         self.uid = repr::uid_gid::Id(id);
+        self.explicit.uid = true;
         self
     }
 
     pub fn set_gid(&mut self, id: u32) -> &mut Self {
         self.gid = repr::uid_gid::Id(id);
+        self.explicit.gid = true;
         self
     }
 
+    /// Set the permissions of the item
+    ///
+    /// Only the permission bits of `mode` are honored: any file type bits are replaced by the
+    /// type of the item being built.
     pub fn set_mode(&mut self, mode: crate::Mode) -> &mut Self {
-        self.mode = mode;
+        self.mode = mode.perm();
+        self.explicit.mode = true;
         self
     }
 
@@ -229,13 +812,109 @@ impl FileBuilder {
         self
     }
 
+    /// Set an extended attribute of the item, like [`DirBuilder::set_xattr`]
+    pub fn set_xattr<N, V>(&mut self, name: N, value: V) -> Result<&mut Self>
+    where
+        N: Into<BString>,
+        V: Into<Vec<u8>>,
+    {
+        let (name, value) = (name.into(), value.into());
+        xattr::check(&name, &value)?;
+        self.xattrs.insert(name, value);
+        Ok(self)
+    }
+
+    /// Read the file's contents from `contents`
+    ///
+    /// The length doesn't need to be known in advance, so contents can be streamed from a pipe
+    /// or stdin. Only one block is buffered at a time.
     pub fn set_contents(&mut self, contents: Box<dyn io::Read>) -> &mut Self {
         self.contents = contents;
         self
     }
 
-    pub fn finish<W: io::Write>(self, archive: &mut Archive<W>) -> ItemRef {
-        todo!()
+    /// Start the file with a block which is already compressed, which is copied to the
+    /// archive as is
+    ///
+    /// `data` is the stored bytes of the block, `size` its entry in a block list, and `len`
+    /// the number of bytes of the file it holds once decompressed. A sparse block has no
+    /// stored bytes and a size of [`Size::ZERO`]. Raw blocks come before the
+    /// [contents](Self::set_contents), so all but the last block of a file must be full, and
+    /// a file ending in a partial raw block can't have any other contents.
+    ///
+    /// The block must have been compressed with the archive's codec, at the archive's block
+    /// size: it's the caller's job to check, as the writer can't without decompressing it.
+    /// Blocks read with [`read::Archive::read_raw_block`](crate::read::Archive::read_raw_block)
+    /// can be copied from one archive to another this way.
+    pub fn add_raw_block(&mut self, data: Vec<u8>, size: Size, len: u32) -> &mut Self {
+        self.raw_blocks.push(RawData { data, size, len });
+        self
+    }
+
+    /// Write the file's contents to the archive, and add the file
+    ///
+    /// The contents are read to the end, and written as data blocks, with the end of the file
+    /// in a fragment as the archive's [`fragment_mode`](ArchiveBuilder::fragment_mode)
+    /// allows.
+    pub fn finish<W: io::Write>(mut self, archive: &mut Archive<W>) -> Result<ItemRef> {
+        let mut contents = mem::replace(&mut self.contents, Box::new(io::empty()));
+        let file = archive.write_file(&self.raw_blocks, &mut contents)?;
+        Ok(self.finish_written(archive, file))
+    }
+
+    /// Add the file, with `contents` already written to `archive`
+    ///
+    /// Any contents or raw blocks set on the builder are ignored.
+    pub fn finish_with_contents<W: io::Write>(
+        self,
+        archive: &mut Archive<W>,
+        contents: &FileContents,
+    ) -> ItemRef {
+        self.finish_written(archive, contents.0.clone())
+    }
+
+    /// Add the file, whose contents were already written as `file`
+    fn finish_written<W: io::Write>(self, archive: &mut Archive<W>, file: WrittenFile) -> ItemRef {
+        let WrittenFile { data: file, digest } = file;
+        let item = Item {
+            uid: self.uid,
+            gid: self.gid,
+            mode: self.mode,
+            mtime: self.mtime,
+            explicit: self.explicit,
+            inode: None,
+            xattrs: self.xattrs,
+            data: Data::File { file },
+        };
+        let item_ref = archive.add_item(item);
+        if let Some(digest) = digest {
+            archive.record_digest(item_ref, digest);
+        }
+        item_ref
+    }
+}
+
+/// The contents of a file written to an archive, before the file itself is added
+#[derive(Debug, Clone)]
+pub(crate) struct WrittenFile {
+    data: inode::FileData,
+    /// The digest of the contents, if the archive computes them
+    digest: Option<Digest>,
+}
+
+impl From<inode::FileData> for WrittenFile {
+    fn from(data: inode::FileData) -> Self {
+        WrittenFile { data, digest: None }
+    }
+}
+
+impl Archive<InMemory> {
+    /// Create an archive which is written to memory
+    ///
+    /// The returned handle shares the archive's buffer, and can be used to retrieve the
+    /// written bytes once the archive has been flushed.
+    pub fn in_memory() -> (Self, InMemory) {
+        ArchiveBuilder::new().build_in_memory()
     }
 }
 
@@ -253,7 +932,36 @@ impl<W: io::Write> Archive<W> {
     }
 
     pub fn create_file(&self) -> FileBuilder {
-        todo!()
+        FileBuilder {
+            uid: repr::uid_gid::Id(0),
+            gid: repr::uid_gid::Id(0),
+            mode: MODE_DEFAULT_FILE,
+            mtime: Utc::now(),
+            explicit: Explicit::default(),
+            xattrs: Xattrs::new(),
+            raw_blocks: Vec::new(),
+            contents: Box::new(io::empty()),
+        }
+    }
+
+    pub fn create_symlink<S: Into<BString>>(&self, target: S) -> SymlinkBuilder {
+        SymlinkBuilder::new(target.into())
+    }
+
+    pub fn create_block_device(&self) -> DeviceBuilder {
+        DeviceBuilder::new(DeviceKind::Block)
+    }
+
+    pub fn create_char_device(&self) -> DeviceBuilder {
+        DeviceBuilder::new(DeviceKind::Char)
+    }
+
+    pub fn create_fifo(&self) -> IpcBuilder {
+        IpcBuilder::new(IpcKind::Fifo)
+    }
+
+    pub fn create_socket(&self) -> IpcBuilder {
+        IpcBuilder::new(IpcKind::Socket)
     }
 
     fn get(&self, item_ref: ItemRef) -> &Item {
@@ -265,6 +973,11 @@ impl<W: io::Write> Archive<W> {
     }
 
     fn add_item(&mut self, item: Item) -> ItemRef {
+        debug_assert_eq!(
+            item.mode.ty(),
+            Mode::NONE,
+            "file type bits must come from the item's data"
+        );
         self.uid_gids.add(item.uid);
         self.uid_gids.add(item.gid);
 
@@ -273,25 +986,385 @@ impl<W: io::Write> Archive<W> {
         item_ref
     }
 
+    /// Apply a directory's child defaults to `item_ref`, and everything beneath it, wherever
+    /// they weren't already set
+    fn inherit(&mut self, item_ref: ItemRef, defaults: &ChildDefaults) {
+        let item = self.get_mut(item_ref);
+        let mut ids = Vec::new();
+        if let (Some(uid), false) = (defaults.uid, item.explicit.uid) {
+            item.uid = uid;
+            item.explicit.uid = true;
+            ids.push(uid);
+        }
+        if let (Some(gid), false) = (defaults.gid, item.explicit.gid) {
+            item.gid = gid;
+            item.explicit.gid = true;
+            ids.push(gid);
+        }
+        if let (Some(mode), false) = (defaults.mode, item.explicit.mode) {
+            match item.data {
+                Data::Symlink { .. } => {}
+                Data::Directory { .. } => item.mode = mode | search_bits(mode),
+                _ => item.mode = mode,
+            }
+            item.explicit.mode = true;
+        }
+        let children: Vec<ItemRef> = item.children_refs().into_iter().flatten().collect();
+        for id in ids {
+            self.uid_gids.add(id);
+        }
+        for child in children {
+            self.inherit(child, defaults);
+        }
+    }
+
+    /// Write the contents of a file like [`write_contents`](Self::write_contents), also
+    /// computing their digest if the archive records them
+    fn write_file(&mut self, raw: &[RawData], contents: &mut dyn io::Read) -> Result<WrittenFile> {
+        match self.file_digests {
+            Some(algorithm) => {
+                let mut reader = HashingReader::new(contents, algorithm);
+                let data = self.write_contents(raw, &mut reader)?;
+                Ok(WrittenFile {
+                    data,
+                    digest: Some(reader.finish()),
+                })
+            }
+            None => Ok(self.write_contents(raw, contents)?.into()),
+        }
+    }
+
+    /// Write the data blocks of a file, copying `raw` and then reading `contents`, and put
+    /// its tail in a fragment, returning the file's inode data
+    fn write_contents(
+        &mut self,
+        raw: &[RawData],
+        contents: &mut dyn io::Read,
+    ) -> Result<inode::FileData> {
+        let file = self.write_blocks(raw, contents)?;
+        Ok(self.write_tail(file)?)
+    }
+
+    /// Write the data blocks of a file, leaving its tail to [`write_tail`](Self::write_tail)
+    ///
+    /// Until the tail's written, the blocks can still be discarded by truncating the data
+    /// blocks back to where they started.
+    fn write_blocks(
+        &mut self,
+        raw: &[RawData],
+        contents: &mut dyn io::Read,
+    ) -> Result<stream::StreamedFile> {
+        let blocks_start = self.plan.data_position();
+        // Checked before copying any, so a bad block doesn't leave a partial file behind
+        for (i, block) in raw.iter().enumerate() {
+            self.check_raw(block, i + 1 == raw.len())?;
+        }
+        // A partial block must end the file, which takes reading a byte of the contents to know
+        if raw.last().is_some_and(|block| block.len < self.block_size)
+            && stream::read_block(contents, &mut [0])? != 0
+        {
+            return Err(WriteError::InvalidRawBlock("contents follow a partial block").into());
+        }
+        let mut raw_sizes = Vec::with_capacity(raw.len());
+        let (mut raw_len, mut raw_sparse) = (0, 0);
+        for block in raw {
+            if !block.data.is_empty() {
+                self.plan.write_block(&mut self.file, &block.data)?;
+            } else {
+                raw_sparse += u64::from(block.len);
+            }
+            raw_sizes.push(block.size);
+            raw_len += u64::from(block.len);
+        }
+
+        let compressor = if self.compressed_data {
+            Some(&*self.compressor)
+        } else {
+            None
+        };
+        let contents_start = self.plan.data_position();
+        let mut file = stream::write_blocks_parallel(
+            contents,
+            &mut self.plan.data(&mut self.file),
+            contents_start,
+            self.block_size,
+            compressor,
+            self.read_ahead,
+            self.fragment_mode != FragmentMode::Never,
+        )?;
+        file.blocks_start = blocks_start;
+        file.file_size += raw_len;
+        file.sparse_bytes += raw_sparse;
+        raw_sizes.append(&mut file.block_sizes);
+        file.block_sizes = raw_sizes;
+        Ok(file)
+    }
+
+    /// Store the tail of a file written by [`write_blocks`](Self::write_blocks), returning the
+    /// file's inode data
+    fn write_tail(&mut self, mut file: stream::StreamedFile) -> io::Result<inode::FileData> {
+        let tail = mem::take(&mut file.tail);
+        if tail.is_empty() {
+            return Ok(file.file_data(None));
+        }
+        if self.fragment_mode == FragmentMode::SmallFiles && !file.block_sizes.is_empty() {
+            // Only small files go in fragments, the tail of a larger file is its last block
+            if tail.iter().all(|&b| b == 0) {
+                file.sparse_bytes += tail.len() as u64;
+                file.block_sizes.push(Size::ZERO);
+            } else {
+                let (_, size) = self.write_data_block(tail, self.compressed_data)?;
+                file.block_sizes.push(size);
+            }
+            return Ok(file.file_data(None));
+        }
+        let fragment = self.add_fragment(&tail)?;
+        Ok(file.file_data(Some(fragment)))
+    }
+
+    /// Append a data or fragment block to the image, returning its offset and stored size
+    fn write_data_block(
+        &mut self,
+        data: Vec<u8>,
+        compress: bool,
+    ) -> io::Result<(ArchiveOffset, Size)> {
+        if compress {
+            let response = self.compressor.submit(data).wait();
+            let start = self.plan.write_block(&mut self.file, &response.data)?;
+            let len = response.data.len().try_into().unwrap();
+            Ok((start, Size::new(len, !response.compressed)))
+        } else {
+            let start = self.plan.write_block(&mut self.file, &data)?;
+            Ok((start, Size::new(data.len().try_into().unwrap(), true)))
+        }
+    }
+
+    /// Check a raw block's size matches its stored bytes, and that it's a full block unless
+    /// it's the `last` of a file
+    fn check_raw(&self, block: &RawData, last: bool) -> Result<()> {
+        let problem = if block.data.len() != block.size.size() as usize {
+            "size doesn't match the stored bytes"
+        } else if block.size.size() > self.block_size {
+            "larger than the block size"
+        } else if block.len > self.block_size || block.len == 0 {
+            "length out of range"
+        } else if block.len < self.block_size && !last {
+            "only the last block can be partial"
+        } else {
+            return Ok(());
+        };
+        Err(WriteError::InvalidRawBlock(problem).into())
+    }
+
+    /// Add the tail of a file to the current fragment block, returning the index of the block
+    /// and the tail's offset in it
+    ///
+    /// The current block is written first if the tail doesn't fit.
+    fn add_fragment(&mut self, tail: &[u8]) -> io::Result<(repr::fragment::Idx, u32)> {
+        if self.fragment.len() + tail.len() > self.block_size as usize {
+            self.flush_fragment()?;
+        }
+        let index = repr::fragment::Idx(self.fragments.count().try_into().unwrap());
+        let offset = self.fragment.len().try_into().unwrap();
+        self.fragment.extend_from_slice(tail);
+        Ok((index, offset))
+    }
+
+    /// Write the current fragment block, if it holds any tails
+    fn flush_fragment(&mut self) -> io::Result<()> {
+        if self.fragment.is_empty() {
+            return Ok(());
+        }
+        let block = mem::take(&mut self.fragment);
+        let (start, size) = self.write_data_block(block, self.compressed_fragments)?;
+        self.fragments
+            .add_fragment(repr::datablock::Ref(start.0), size);
+        Ok(())
+    }
+
+    /// Record the digest of the contents of the file `item_ref`
+    fn record_digest(&mut self, item_ref: ItemRef, digest: Digest) {
+        self.digests.insert(item_ref.0, digest);
+    }
+
+    /// The digests of every regular file reachable from the root, by absolute path
+    ///
+    /// Digests are only computed when [`ArchiveBuilder::file_digests`] is set, while each
+    /// file's contents are read to be compressed, so no second pass over the files is needed.
+    /// Hard linked files appear under each of their paths.
+    pub fn file_digests(&self) -> BTreeMap<BString, Digest> {
+        let mut digests = BTreeMap::new();
+        if self.root.0 == u32::MAX {
+            return digests;
+        }
+        let mut stack = vec![(BString::from("/"), self.root)];
+        while let Some((path, item_ref)) = stack.pop() {
+            match &self.get(item_ref).data {
+                Data::Directory { entries } => {
+                    for (name, &child) in entries {
+                        stack.push((crate::read::child_path(&path, name), child));
+                    }
+                }
+                Data::File { .. } => {
+                    if let Some(&digest) = self.digests.get(&item_ref.0) {
+                        digests.insert(path, digest);
+                    }
+                }
+                _ => {}
+            }
+        }
+        digests
+    }
+
+    pub(crate) fn logger(&self) -> &Logger {
+        &self.logger
+    }
+
+    /// Pad, flush and sync the written archive, then commit it if it's being written atomically
+    ///
+    /// `bytes_used` must be the number of bytes written for the archive itself.
+    fn finish_output(&mut self, bytes_used: u64) -> Result<()> {
+        debug_assert_eq!(self.file.written(), bytes_used);
+        self.image_digest = self.file.take_digest();
+        let mut signature_trailer = None;
+        if let (Some(signing), Some(digest)) = (&self.signing, &self.image_digest) {
+            let signature = signing.signer.sign(digest)?;
+            if signing.placement == SignaturePlacement::Trailer {
+                io::Write::write_all(&mut self.file, &crate::signature::trailer(&signature)?)?;
+                signature_trailer = Some(bytes_used..self.file.written());
+            }
+            self.signature = Some(signature);
+        }
+        let written = self.file.written();
+        let padding = match written % self.padding {
+            0 => 0,
+            rem => self.padding - rem,
+        };
+        io::copy(&mut io::repeat(0).take(padding), &mut self.file)?;
+        self.file.finish()?;
+        if let Some(commit) = self.commit {
+            commit(self.file.get_mut())?;
+        }
+        self.layout = Some(Layout {
+            bytes_used,
+            signature_trailer,
+            padding: self.padding,
+            file_size: self.file.written(),
+        });
+        Ok(())
+    }
+
+    /// The SHA-256 digest of the image, covering the first [`bytes_used`](Self::bytes_used)
+    /// bytes of the output
+    ///
+    /// This is only computed if [`ArchiveBuilder::image_digest`] was set or a signer was given,
+    /// and is only known once the archive has been completely written.
+    pub fn image_digest(&self) -> Option<Digest> {
+        self.image_digest
+    }
+
+    /// The signature of the image, if a signer was given and the archive has been completely
+    /// written
+    pub fn signature(&self) -> Option<&[u8]> {
+        self.signature.as_deref()
+    }
+
+    /// The number of bytes used by the archive, not including padding
+    ///
+    /// This is only known once the archive has been completely written.
+    pub fn bytes_used(&self) -> Option<u64> {
+        self.layout.as_ref().map(|layout| layout.bytes_used)
+    }
+
+    /// The total size of the output, including padding to a multiple of
+    /// [`ArchiveBuilder::padding`]
+    ///
+    /// This is only known once the archive has been completely written.
+    pub fn file_size(&self) -> Option<u64> {
+        self.layout.as_ref().map(|layout| layout.file_size)
+    }
+
+    /// Where the archive, its signature trailer, and the padding are in the output, see
+    /// [`Layout`]
+    ///
+    /// This is only known once the archive has been completely written.
+    pub fn layout(&self) -> Option<&Layout> {
+        self.layout.as_ref()
+    }
+
+    /// The issues found so far while writing the archive
+    pub fn report(&self) -> &Report {
+        &self.report
+    }
+
     pub fn set_root(&mut self, item_ref: ItemRef) {
         assert!(matches!(self.get(item_ref).data, Data::Directory { .. }));
         self.root = item_ref;
     }
 
-    pub fn flush(&mut self) -> Result<()> {
-        let mut superblock = repr::superblock::Superblock {
+    /// Write the archive
+    ///
+    /// The last fragment block and every table are added to the image, which is then written
+    /// front to back, padded to a multiple of [`ArchiveBuilder::padding`] bytes,
+    /// and synced and committed as the writer requires. Flushing again once it has succeeded
+    /// does nothing.
+    ///
+    /// Returns a report of the issues which didn't stop the archive being written, but which
+    /// may have changed its contents, such as skipped entries or clamped times.
+    pub fn flush(&mut self) -> Result<Report> {
+        if self.layout.is_some() {
+            return Ok(self.report.clone());
+        }
+        if self.root.0 == u32::MAX {
+            return Err(WriteError::NoRoot.into());
+        }
+        // Items are given inodes as they're written, so a failed flush can't be retried
+        if mem::replace(&mut self.flushing, true) {
+            return Err(WriteError::FlushFailed.into());
+        }
+
+        self.flush_fragment()?;
+        // Every data and fragment block has been compressed by now
+        let failed = self
+            .verify_failures
+            .as_ref()
+            .map_or(0, |f| f.load(Ordering::Relaxed));
+        if failed > 0 {
+            let issue = Issue::FailedVerification { blocks: failed };
+            self.report.note(&self.logger, issue);
+        }
+        let fragment_entry_count = self.fragments.count().try_into().unwrap();
+        let inode_numbers = InodeNumbers::new(&self.items, self.root);
+        if !self.store_xattrs {
+            let dropped = (self.items.iter().enumerate())
+                .filter(|(idx, item)| {
+                    !item.xattrs.is_empty() && inode_numbers.get(ItemRef(*idx as u32)).is_some()
+                })
+                .count();
+            if dropped > 0 {
+                let issue = Issue::DroppedXattrs { items: dropped };
+                self.report.note(&self.logger, issue);
+            }
+        }
+        let root_inode_ref = self.write_metadata(&inode_numbers)?;
+        let mut plan = mem::replace(
+            &mut self.plan,
+            Plan::new(repr::superblock::Superblock::new_zeroed(), Vec::new()),
+        );
+        plan.superblock = repr::superblock::Superblock {
             magic: repr::superblock::MAGIC,
-            inode_count: self.items.len().try_into().expect("too many items"),
-            modification_time: date_time_to_mtime(self.mtime, &self.logger),
+            inode_count: inode_numbers.count(),
+            modification_time: date_time_to_mtime(self.mtime).0,
             block_size: self.block_size,
-            fragment_entry_count: 0,                     // TODO
-            compression_id: repr::compression::Id::GZIP, // TODO
+            fragment_entry_count,
+            compression_id: repr::compression::Id(self.compressor_kind.id()),
             block_log: self.block_size.trailing_zeros() as _,
             flags: self.flags,
             id_count: self.uid_gids.len(),
             version_major: repr::superblock::VERSION_MAJOR,
             version_minor: repr::superblock::VERSION_MINOR,
-            root_inode_ref: repr::inode::Ref::default(), // TODO
+            root_inode_ref,
+            // The rest are filled in once the image is laid out
             bytes_used: 0,
             id_table_start: u64::MAX,
             xattr_id_table_start: u64::MAX,
@@ -300,11 +1373,144 @@ impl<W: io::Write> Archive<W> {
             fragment_table_start: u64::MAX,
             export_table_start: u64::MAX,
         };
-        // TODO: Compression options
-        // TODO: data blocks
-        superblock.inode_table_start = mem::size_of_val(&superblock).try_into().unwrap();
+        let bytes_used = plan.emit(&mut self.file, |file, superblock| {
+            file.write_at(0, superblock)
+        })?;
+        self.finish_output(bytes_used)?;
+        Ok(self.report.clone())
+    }
 
-        todo!()
+    /// Write the inode, directory and lookup tables into the plan, returning the reference of
+    /// the root inode
+    ///
+    /// Like `mksquashfs`, everything beneath a directory is written before the directory, so
+    /// its listing can refer to the inodes of its entries.
+    fn write_metadata(&mut self, numbers: &InodeNumbers) -> Result<repr::inode::Ref> {
+        use repr::superblock::Flags;
+
+        let (configured, flags) = (self.codec.clone(), self.flags);
+        let codec = |uncompressed: Flags| {
+            if flags.contains(uncompressed) {
+                None
+            } else {
+                Some(configured.clone())
+            }
+        };
+        let mut inodes = inode::Table::new(codec(Flags::UNCOMPRESSED_INODES), &self.pool);
+        let mut dirs = dir::Table::new(codec(Flags::UNCOMPRESSED_INODES), &self.pool);
+        let mut xattrs = xattr::Table::new(codec(Flags::UNCOMPRESSED_XATTRS), &self.pool);
+        // Indexed by inode number - 1
+        let mut inode_refs = vec![repr::inode::Ref::default(); numbers.count() as usize];
+
+        // The number of directory entries referring to each item
+        let mut links = vec![0; self.items.len()];
+        for (idx, item) in self.items.iter().enumerate() {
+            if numbers.get(ItemRef(idx as u32)).is_some() {
+                for child in item.children_refs().into_iter().flatten() {
+                    links[child.0 as usize] += 1;
+                }
+            }
+        }
+
+        // The parent of the root is one past the last inode
+        let mut stack = vec![(self.root, numbers.count() + 1, false)];
+        while let Some((item_ref, parent, children_written)) = stack.pop() {
+            let item = self.get(item_ref);
+            if item.inode.is_some() {
+                continue;
+            }
+            let number = numbers.get(item_ref).unwrap();
+            if let (Some(children), false) = (item.children_refs(), children_written) {
+                stack.push((item_ref, parent, true));
+                let children: Vec<_> = children.collect();
+                stack.extend(
+                    children
+                        .into_iter()
+                        .rev()
+                        .map(|child| (child, number, false)),
+                );
+                continue;
+            }
+
+            let data = match &item.data {
+                Data::Directory { entries } => {
+                    let listing = entries.iter().map(|(name, &child)| {
+                        let child_item = self.get(child);
+                        dir::Entry {
+                            inode: child_item.inode.unwrap(),
+                            inode_num: repr::inode::Idx(numbers.get(child).unwrap()),
+                            inode_kind: child_item.kind(),
+                            name: name.to_vec(),
+                        }
+                    });
+                    let info = dirs.dir(listing);
+                    let subdirs = entries
+                        .values()
+                        .filter(|&&child| matches!(self.get(child).data, Data::Directory { .. }))
+                        .count();
+                    inode::Data::Directory(inode::DirData {
+                        dir_ref: info.start,
+                        dir_size: info.uncompressed_size,
+                        parent_inode_num: repr::inode::Idx(parent),
+                        child_count: subdirs.try_into().unwrap(),
+                        index: info.index,
+                    })
+                }
+                Data::File { file } => inode::Data::File(file.clone()),
+                Data::Symlink { target } => inode::Data::Symlink(inode::SymlinkData {
+                    target_path: target.to_vec(),
+                }),
+                &Data::BlockDev(device) => inode::Data::BlockDev(inode::DeviceData { device }),
+                &Data::CharDev(device) => inode::Data::CharDev(inode::DeviceData { device }),
+                Data::Fifo => inode::Data::Fifo,
+                Data::Socket => inode::Data::Socket,
+            };
+            let common = inode::Common {
+                permissions: item.mode,
+                uid_idx: self.uid_gids.get(item.uid),
+                gid_idx: self.uid_gids.get(item.gid),
+                modified_time: date_time_to_mtime(item.mtime).0,
+                // A directory's link count is worked out from its subdirectories
+                hardlink_count: match item.data {
+                    Data::Directory { .. } => 0,
+                    _ => links[item_ref.0 as usize],
+                },
+                xattr_idx: match self.store_xattrs {
+                    true => xattrs.add(&item.xattrs),
+                    false => repr::xattr::Idx::NONE,
+                },
+                inode_number: repr::inode::Idx(number),
+                force_ext: false,
+            };
+            let inode_ref = inodes.add(inode::Entry { common, data })?;
+            self.get_mut(item_ref).inode = Some(inode_ref);
+            inode_refs[number as usize - 1] = inode_ref;
+        }
+
+        let plan = &mut self.plan;
+        plan.inode_table = inodes.finish();
+        plan.dir_table = dirs.finish().1;
+        if self.fragments.count() != 0 {
+            let fragments =
+                mem::replace(&mut self.fragments, fragments::Table::new(None, &self.pool));
+            plan.fragment_table = Some(fragments.finish().0);
+        }
+        if self.exportable {
+            let mut export = two_level::Table::new(codec(Flags::UNCOMPRESSED_INODES), &self.pool);
+            for inode_ref in &inode_refs {
+                export.write(inode_ref);
+            }
+            plan.export_table = Some(export.finish().0);
+        }
+        plan.id_table = self
+            .uid_gids
+            .metablocks(codec(Flags::UNCOMPRESSED_IDS), &self.pool);
+        plan.xattr_tables = xattrs.finish();
+        self.flags
+            .set(Flags::NO_XATTRS, plan.xattr_tables.is_none());
+        self.flags
+            .set(Flags::EXPORTABLE, plan.export_table.is_some());
+        Ok(self.get(self.root).inode.unwrap())
     }
 }
 
@@ -323,6 +1529,11 @@ impl<W: io::Write> fmt::Debug for Archive<W> {
             .field("mtime", &self.mtime)
             .field("block_size", &self.block_size)
             .field("flags", &self.flags)
+            .field("special_files", &self.special_files)
+            .field("symlink_rewrite", &self.symlink_rewrite)
+            .field("device_numbers", &self.device_numbers)
+            .field("limits", &self.limits)
+            .field("limit_policy", &self.limit_policy)
             .finish()
     }
 }
@@ -337,12 +1548,68 @@ pub struct ArchiveBuilder {
     pub compressed_xattrs: bool,
     pub compressed_ids: bool,
     pub find_duplicates: bool,
+    /// Write an export table, mapping every inode number to its inode, so the image can be
+    /// exported over NFS
     pub exportable: bool,
     pub fragment_mode: FragmentMode,
+    pub special_files: SpecialFilePolicy,
+    /// How the targets of absolute symlinks added from a tree are rewritten, see
+    /// [`SymlinkRewrite`]
+    pub symlink_rewrite: SymlinkRewrite,
+    pub device_numbers: DeviceNumberPolicy,
+    /// Limits on the entries added from a tree, see [`Limits`]
+    pub limits: Limits,
+    pub limit_policy: LimitPolicy,
+    pub sync_policy: SyncPolicy,
+    /// Compute a digest of each file's contents, see [`Archive::file_digests`]
+    pub file_digests: Option<DigestAlgorithm>,
+    /// Compute a digest of the whole image while it is written, see [`Archive::image_digest`]
+    pub image_digest: bool,
     pub compressor_kind: compression::Kind,
+    /// Options for the compressor, instead of the defaults of its kind
+    ///
+    /// They must be for the [`compressor_kind`](Self::compressor_kind). Options other than
+    /// the defaults are stored in the image, as lz4's always are, since readers require them.
+    pub compression_options: Option<compression::Options>,
+    /// Store blocks which look incompressible without running the compressor over them, if
+    /// they measure at least this many bits of entropy per byte, see [`SkipIncompressible`]
+    ///
+    /// Saves most of the time spent compressing trees of already compressed files. 7.9 is a
+    /// good threshold, which random bytes reach but compressible data doesn't.
+    pub skip_incompressible: Option<f64>,
+    pub memory_budget: MemoryBudget,
+    /// The CPUs compression threads run on, see [`CpuAffinity`]
+    pub cpu_affinity: CpuAffinity,
+    /// Pad the output with zeros to a multiple of this many bytes, which must be a multiple of
+    /// [`SECTOR_SIZE`]
+    ///
+    /// Defaults to [`PADDING`](repr::superblock::PADDING), as `mksquashfs` pads. A larger
+    /// multiple, like the block size of a dm-verity or dm-crypt device the image will be
+    /// stacked under, makes the output a whole number of its blocks. See [`Layout`].
+    pub padding: u64,
+    /// Write the contents of the files in the trace before any others, in its order, when
+    /// entries are added from a tree, see [`trace`](crate::trace)
+    pub access_trace: Option<AccessTrace>,
 
     modified_time: DateTime<Utc>,
-    logger: Option<Logger>,
+    logging: Option<LoggingConfig>,
+    signing: Option<Signing>,
+    verify_writes: bool,
+}
+
+/// How to sign an archive once it has been written
+#[derive(Clone)]
+struct Signing {
+    signer: Arc<dyn Signer>,
+    placement: SignaturePlacement,
+}
+
+impl fmt::Debug for Signing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Signing")
+            .field("placement", &self.placement)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Default for ArchiveBuilder {
@@ -358,25 +1625,92 @@ impl Default for ArchiveBuilder {
             find_duplicates: true,
             exportable: true,
             fragment_mode: FragmentMode::default(),
+            special_files: SpecialFilePolicy::default(),
+            symlink_rewrite: SymlinkRewrite::default(),
+            device_numbers: DeviceNumberPolicy::default(),
+            limits: Limits::default(),
+            limit_policy: LimitPolicy::default(),
+            sync_policy: SyncPolicy::default(),
+            file_digests: None,
+            image_digest: false,
             compressor_kind: compression::Kind::default(),
+            compression_options: None,
+            skip_incompressible: None,
+            memory_budget: MemoryBudget::default(),
+            cpu_affinity: CpuAffinity::default(),
+            padding: repr::superblock::PADDING,
+            access_trace: None,
             modified_time: Utc::now(),
-            logger: None,
+            logging: None,
+            signing: None,
+            verify_writes: false,
         }
     }
 }
 
 impl ArchiveBuilder {
-    fn validate(&self) {
+    /// Check the options for values and combinations which make no sense, before anything is
+    /// written
+    ///
+    /// Options which can't be used at all are errors, and options which have no effect because
+    /// of another option are returned as [`Issue::IgnoredOption`] warnings. Every `build`
+    /// method validates the builder, the path based ones and [`try_build`](Self::try_build)
+    /// return the error, while [`build`](Self::build) panics, and any warnings are added to the
+    /// archive's report.
+    pub fn validate(&self) -> Result<Vec<Issue>> {
         if self.block_size < repr::BLOCK_SIZE_MIN
             || self.block_size > repr::BLOCK_SIZE_MAX
             || !self.block_size.is_power_of_two()
         {
-            panic!(
-                "block size must be a power of two between {} and {}",
-                repr::BLOCK_SIZE_MIN,
-                repr::BLOCK_SIZE_MAX
-            );
+            return Err(WriteError::InvalidBlockSize(self.block_size).into());
+        }
+        if !self.compressor_kind.supported() {
+            return Err(WriteError::DisabledCompression(self.compressor_kind).into());
+        }
+        if let Some(options) = &self.compression_options {
+            if options.kind() != self.compressor_kind {
+                return Err(WriteError::MismatchedCompressionOptions {
+                    options: options.kind(),
+                    compressor: self.compressor_kind,
+                }
+                .into());
+            }
+            AnyCodec::from_options(options)?;
+        }
+        self.cpu_affinity
+            .check()
+            .map_err(WriteError::InvalidCpuAffinity)?;
+        if self.padding == 0 || !self.padding.is_multiple_of(SECTOR_SIZE) {
+            return Err(WriteError::InvalidPadding(self.padding).into());
+        }
+
+        let mut warnings = Vec::new();
+        if !self.compressed_fragments && self.fragment_mode == FragmentMode::Never {
+            warnings.push(Issue::IgnoredOption {
+                option: "compressed_fragments",
+                reason: "no fragments are written",
+            });
+        }
+        if !self.compressed_xattrs && !self.xattrs {
+            warnings.push(Issue::IgnoredOption {
+                option: "compressed_xattrs",
+                reason: "xattrs are not stored",
+            });
+        }
+        if self.skip_incompressible.is_some() && !self.compressed_data && !self.compressed_fragments
+        {
+            warnings.push(Issue::IgnoredOption {
+                option: "skip_incompressible",
+                reason: "data blocks are not compressed",
+            });
+        }
+        if self.image_digest && self.signing.is_some() {
+            warnings.push(Issue::IgnoredOption {
+                option: "image_digest",
+                reason: "signed images always compute a digest",
+            });
         }
+        Ok(warnings)
     }
 
     pub fn new() -> Self {
@@ -388,28 +1722,242 @@ impl ArchiveBuilder {
         self
     }
 
+    /// Log every subsystem to `logger`
     pub fn set_logger(&mut self, logger: Logger) -> &mut Self {
-        self.logger = Some(logger);
+        self.logging = Some(LoggingConfig::new(&logger));
+        self
+    }
+
+    /// Use separate loggers for each subsystem
+    pub fn set_logging(&mut self, logging: LoggingConfig) -> &mut Self {
+        self.logging = Some(logging);
         self
     }
 
+    /// Sign the image with `signer` once it has been written, see
+    /// [`Archive::signature`] and the [`signature`](crate::signature) module
+    pub fn set_signer(
+        &mut self,
+        signer: Arc<dyn Signer>,
+        placement: SignaturePlacement,
+    ) -> &mut Self {
+        self.signing = Some(Signing { signer, placement });
+        self
+    }
+
+    /// Decompress every block after compressing it, and check it matches the original, see
+    /// [`VerifyWrites`]
+    ///
+    /// This catches a broken codec or corrupted memory before the image ships, at the cost of
+    /// decompressing everything written. Blocks which fail the check are stored uncompressed,
+    /// and counted in an [`Issue::FailedVerification`] when the archive is flushed.
+    pub fn verify_writes(&mut self, verify: bool) -> &mut Self {
+        self.verify_writes = verify;
+        self
+    }
+
+    /// A codec for the archive's blocks, which skips blocks that look incompressible if
+    /// [`skip_incompressible`](Self::skip_incompressible) is set, and checks every block if
+    /// [`verify_writes`](Self::verify_writes) is, counting failures in `verify_failures`
+    pub(crate) fn block_codec(
+        &self,
+        verify_failures: Option<&Arc<AtomicU64>>,
+    ) -> Box<dyn BlockCodec> {
+        let codec = self.codec();
+        match self.skip_incompressible {
+            Some(max_entropy) => {
+                verified(SkipIncompressible { codec, max_entropy }, verify_failures)
+            }
+            None => verified(codec, verify_failures),
+        }
+    }
+
+    /// A codec for the archive's compressor and options, which must have been validated
+    fn codec(&self) -> AnyCodec {
+        // The defaults of some kinds, like xz's dictionary size, depend on the block size
+        let options = self
+            .compression_options
+            .or_else(|| compression::Options::default_for(self.compressor_kind, self.block_size));
+        match &options {
+            Some(options) => AnyCodec::from_options(options).expect("options were validated"),
+            None => AnyCodec::new(self.compressor_kind),
+        }
+    }
+
+    /// The compression options to store in the image, if any
+    fn stored_options(&self) -> Option<compression::Options> {
+        let defaults = compression::Options::default_for(self.compressor_kind, self.block_size);
+        let options = self.compression_options.or(defaults)?;
+        // Readers refuse lz4 images without options, as `mksquashfs` always stores them
+        if self.compressor_kind == compression::Kind::Lz4 || Some(options) != defaults {
+            Some(options)
+        } else {
+            None
+        }
+    }
+
+    /// The superblock flags describing the options
+    fn flags(&self) -> repr::superblock::Flags {
+        use repr::superblock::Flags;
+
+        // Cleared once there are extended attributes to write
+        let mut flags = Flags::NO_XATTRS;
+        let uncompressed = [
+            (self.compressed_inodes, Flags::UNCOMPRESSED_INODES),
+            (self.compressed_data, Flags::UNCOMPRESSED_DATA),
+            (self.compressed_fragments, Flags::UNCOMPRESSED_FRAGMENTS),
+            (self.compressed_xattrs, Flags::UNCOMPRESSED_XATTRS),
+            (self.compressed_ids, Flags::UNCOMPRESSED_IDS),
+        ];
+        for (compressed, flag) in uncompressed {
+            flags.set(flag, !compressed);
+        }
+        match self.fragment_mode {
+            FragmentMode::Never => flags |= Flags::NO_FRAGMENTS,
+            FragmentMode::SmallFiles => {}
+            FragmentMode::Always => flags |= Flags::ALWAYS_FRAGMENTS,
+        }
+        flags.set(Flags::COMPRESSOR_OPTIONS, self.stored_options().is_some());
+        flags
+    }
+
+    /// Build an archive writing to `writer`
+    ///
+    /// The image is written front to back without seeking, so `writer` can be a pipe, such as
+    /// stdout, at the cost of spooling the data blocks until the superblock is known.
+    /// Arbitrary writers can't be synced, so the [`sync_policy`](Self::sync_policy) is
+    /// ignored.
+    ///
+    /// The files of [`build_path`](Self::build_path), [`build_split`](Self::build_split) and
+    /// [`build_path_atomic`](Self::build_path_atomic) can seek, so the data blocks are written
+    /// to them directly, and the superblock is written last, unless the image is hashed for an
+    /// [`image_digest`](Self::image_digest) or a signature.
+    ///
+    /// Panics if the builder is invalid, see [`try_build`](Self::try_build).
     pub fn build<W: io::Write>(self, writer: W) -> Archive<W> {
-        self.validate();
+        self.build_with_hooks(writer, None, None, None)
+    }
+
+    /// Like [`build`](Self::build), returning an error if the builder is invalid
+    pub fn try_build<W: io::Write>(self, writer: W) -> Result<Archive<W>> {
+        self.validate()?;
+        Ok(self.build_with_hooks(writer, None, None, None))
+    }
 
-        let logger = self.logger.unwrap_or_else(crate::default_logger);
+    /// Build an archive which is written to memory, see [`Archive::in_memory`]
+    pub fn build_in_memory(self) -> (Archive<InMemory>, InMemory) {
+        let handle = InMemory::new();
+        (self.build(handle.clone()), handle)
+    }
 
-        let modification_time = date_time_to_mtime(self.modified_time, &logger);
+    fn build_with_hooks<W: io::Write>(
+        self,
+        writer: W,
+        sync: Option<WriterHook<W>>,
+        commit: Option<WriterHook<W>>,
+        seek: Option<SeekHook<W>>,
+    ) -> Archive<W> {
+        let warnings = self
+            .validate()
+            .unwrap_or_else(|e| panic!("invalid archive builder: {}", e));
+
+        let pool = Arc::new(BlockPool::with_budget(&self.memory_budget));
+        let flags = self.flags();
+        let threads = num_cpus::get();
+        // Shared by the codecs of every thread
+        let verify_failures = self.verify_writes.then(Arc::default);
+        let compressor = ParallelCompressor::with_affinity(
+            || self.block_codec(verify_failures.as_ref()),
+            threads,
+            Arc::new(NoMetrics),
+            Arc::clone(&pool),
+            &self.memory_budget,
+            &self.cpu_affinity,
+        );
+        let codec = self.codec();
+        let fragments = fragments::Table::new(Some(codec.clone()), &pool);
+        // Stored as a single uncompressed metablock
+        let compression_options = match self.stored_options() {
+            Some(options) => {
+                let bytes = options.as_bytes();
+                let header = repr::metablock::Header::new(bytes.len().try_into().unwrap(), false);
+                [header.as_bytes(), bytes].concat()
+            }
+            None => Vec::new(),
+        };
+
+        let logging = self.logging.unwrap_or_default();
+        let logger = logging.write.clone();
+        slog::debug!(logging.compression, "Creating archive"; "compression" => %self.compressor_kind);
+        if sync.is_none() && self.sync_policy != SyncPolicy::Never {
+            slog::warn!(logger, "Ignoring sync policy, the writer can't be synced"; "policy" => ?self.sync_policy);
+        }
+
+        let mut report = Report::default();
+        for warning in warnings {
+            report.note(&logger, warning);
+        }
+        let (modification_time, clamped) = date_time_to_mtime(self.modified_time);
+        if clamped {
+            let issue = Issue::ClampedTime {
+                date: self.modified_time,
+            };
+            report.note(&logger, issue);
+        }
 
         let uid_gids = uid_gid::Table::new();
+        let mut file = SyncWriter::new(writer, self.sync_policy, sync);
+        let mut plan = Plan::new(
+            repr::superblock::Superblock::new_zeroed(),
+            compression_options,
+        );
+        if self.image_digest || self.signing.is_some() {
+            // The digest is computed as the image is written, so it must be written in order
+            file.hash_output();
+        } else if let Some(seek) = seek {
+            file.set_seek(seek);
+            plan.write_in_place();
+        }
         Archive {
-            file: writer,
+            file,
+            commit,
+            padding: self.padding,
+            layout: None,
+            flushing: false,
+            signing: self.signing,
+            image_digest: None,
+            signature: None,
             mtime: self.modified_time,
             block_size: self.block_size,
+            memory: self.memory_budget,
+            pool,
+            plan,
+            compressor_kind: self.compressor_kind,
+            codec,
+            compressor: Arc::new(compressor),
+            verify_failures,
+            read_ahead: threads,
+            compressed_data: self.compressed_data,
+            compressed_fragments: self.compressed_fragments,
+            fragment_mode: self.fragment_mode,
+            fragment: Vec::new(),
+            fragments,
             root: ItemRef(u32::MAX),
             uid_gids,
             items: Vec::new(),
 
-            flags: repr::superblock::Flags::default(),
+            flags,
+            store_xattrs: self.xattrs,
+            exportable: self.exportable,
+            special_files: self.special_files,
+            symlink_rewrite: self.symlink_rewrite,
+            device_numbers: self.device_numbers,
+            limits: self.limits,
+            limit_policy: self.limit_policy,
+            access_trace: self.access_trace,
+            report,
+            file_digests: self.file_digests,
+            digests: HashMap::new(),
             logger,
         }
     }
@@ -419,25 +1967,683 @@ impl ArchiveBuilder {
     }
 
     fn _build_path(mut self, path: &Path) -> Result<Archive<File>> {
-        let logger = self.logger.take().unwrap_or_else(crate::default_logger);
-        let path_str = path.display().to_string();
-        self.logger = Some(logger.new(slog::o!("file" => path_str)));
+        let logging = self.logging.take().unwrap_or_default();
+        self.logging = Some(logging.for_file(path));
 
+        self.validate()?;
         let file = fs::File::create(path)?;
-        Ok(self.build(file))
+        Ok(self.build_with_hooks(
+            file,
+            Some(|file: &mut File| file.sync_data()),
+            None,
+            Some(<File as io::Seek>::seek),
+        ))
+    }
+
+    /// Build an archive which is written to a temporary file next to `path`, and only renamed
+    /// to `path` once it has been completely written
+    ///
+    /// If writing fails, or the archive is dropped before it is finished, the temporary file
+    /// is deleted and any existing file at `path` is left untouched.
+    pub fn build_path_atomic<P: AsRef<Path>>(self, path: P) -> Result<Archive<AtomicFile>> {
+        self._build_path_atomic(path.as_ref())
+    }
+
+    /// Build an archive which is split into parts of at most `part_size` bytes, named after
+    /// `base` (see [`SplitFile`])
+    pub fn build_split<P: AsRef<Path>>(
+        self,
+        base: P,
+        part_size: u64,
+    ) -> Result<Archive<SplitFile>> {
+        self.validate()?;
+        let file = SplitFile::create(base, part_size)?;
+        Ok(self.build_with_hooks(
+            file,
+            Some(SplitFile::sync_data),
+            None,
+            Some(<SplitFile as io::Seek>::seek),
+        ))
+    }
+
+    fn _build_path_atomic(mut self, path: &Path) -> Result<Archive<AtomicFile>> {
+        let logging = self.logging.take().unwrap_or_default();
+        self.logging = Some(logging.for_file(path));
+
+        self.validate()?;
+        let file = AtomicFile::create(path)?;
+        Ok(self.build_with_hooks(
+            file,
+            Some(AtomicFile::sync_data),
+            Some(AtomicFile::commit),
+            Some(<AtomicFile as io::Seek>::seek),
+        ))
     }
 }
 
-fn date_time_to_mtime(date_time: DateTime<Utc>, logger: &Logger) -> repr::Time {
+/// Convert a time to squashfs's unsigned seconds, returning whether it was out of range and had
+/// to be clamped
+/// Wrap `codec` in [`VerifyWrites`] counting failures in `failures`, if blocks are verified
+fn verified<C: BlockCodec + 'static>(
+    codec: C,
+    failures: Option<&Arc<AtomicU64>>,
+) -> Box<dyn BlockCodec> {
+    match failures {
+        Some(failures) => Box::new(VerifyWrites::counting(codec, Arc::clone(failures))),
+        None => Box::new(codec),
+    }
+}
+
+fn date_time_to_mtime(date_time: DateTime<Utc>) -> (repr::Time, bool) {
     let mtime = date_time.timestamp();
-    let underlying_time = if mtime > u32::MAX.into() {
-        slog::warn!(logger, "Modification time is out of range for squashfs"; "date" => %date_time);
-        u32::MAX
-    } else if mtime < u32::MIN.into() {
-        slog::warn!(logger, "Modification time is out of range for squashfs"; "date" => %date_time);
-        u32::MIN
-    } else {
-        mtime as u32
-    };
-    repr::Time(underlying_time)
+    let underlying_time = mtime.clamp(u32::MIN.into(), u32::MAX.into()) as u32;
+    (
+        repr::Time(underlying_time),
+        i64::from(underlying_time) != mtime,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn child_defaults() {
+        let mut archive = ArchiveBuilder::new().build(Vec::new());
+        let file = archive.create_fifo().finish(&mut archive).unwrap().unwrap();
+        let mut own = archive.create_fifo();
+        own.set_uid(5).set_mode(Mode::from_bits_truncate(0o600));
+        let own = own.finish(&mut archive).unwrap().unwrap();
+        let symlink = archive.create_symlink("file").finish(&mut archive);
+
+        let mut inner = archive.create_dir();
+        inner.default_child_uid(2);
+        inner.add_item("file", file).add_item("own", own);
+        let inner = inner.finish(&mut archive);
+
+        let mut outer = archive.create_dir();
+        outer
+            .default_child_uid(1)
+            .default_child_gid(1)
+            .default_child_mode(Mode::O644);
+        outer.add_item("inner", inner).add_item("link", symlink);
+        outer.finish(&mut archive);
+
+        let meta = |item_ref| {
+            let item = archive.get(item_ref);
+            (item.uid.0, item.gid.0, item.mode)
+        };
+        assert_eq!(meta(file), (2, 1, Mode::O644));
+        assert_eq!(meta(own), (5, 1, Mode::from_bits_truncate(0o600)));
+        assert_eq!(meta(symlink), (1, 1, Mode::O777));
+        assert_eq!(meta(inner), (1, 1, Mode::O755));
+        assert_eq!(archive.uid_gids.len(), 4);
+    }
+
+    fn add_file(archive: &mut Archive<Vec<u8>>, contents: &[u8]) -> inode::FileData {
+        let mut file = archive.create_file();
+        file.set_contents(Box::new(io::Cursor::new(contents.to_vec())));
+        let item_ref = file.finish(archive).unwrap();
+        match &archive.get(item_ref).data {
+            Data::File { file } => file.clone(),
+            data => panic!("{:?} isn't a file", data),
+        }
+    }
+
+    #[test]
+    fn file_contents() {
+        let mut builder = ArchiveBuilder::new();
+        builder.block_size = 4096;
+        builder.file_digests = Some(DigestAlgorithm::Sha256);
+        let mut archive = builder.build(Vec::new());
+        let start = archive.plan.data_position();
+
+        let mut contents = crate::testing::compressible_contents();
+        contents[4096..8192].fill(0);
+        let large = add_file(&mut archive, &contents);
+        assert_eq!({ large.blocks_start.0 }, start.0);
+        assert_eq!(large.file_size, contents.len() as u64);
+        assert_eq!(large.sparse_bytes, 4096);
+        assert_eq!(large.block_sizes.len(), contents.len() / 4096);
+        assert_eq!(large.block_sizes[1], 0);
+        assert_eq!({ large.fragment_block_idx.0 }, 0);
+        assert_eq!(large.fragment_offset, 0);
+        let stored: u64 = large
+            .block_sizes
+            .iter()
+            .map(|&size| u64::from(Size(size).size()))
+            .sum();
+        assert_eq!(archive.plan.data_position().0, start.0 + stored);
+
+        // Tails share a fragment block until it's full
+        let tail = (contents.len() % 4096) as u32;
+        let small = add_file(&mut archive, b"small");
+        assert!(small.block_sizes.is_empty());
+        assert_eq!({ small.fragment_block_idx.0 }, 0);
+        assert_eq!(small.fragment_offset, tail);
+        let full = add_file(&mut archive, &[1; 4000]);
+        assert_eq!({ full.fragment_block_idx.0 }, 1);
+        assert_eq!(full.fragment_offset, 0);
+        assert_eq!(archive.fragments.count(), 1);
+
+        let empty = add_file(&mut archive, b"");
+        assert_eq!(empty.file_size, 0);
+        assert_eq!({ empty.fragment_block_idx.0 }, u32::MAX);
+        assert_eq!(archive.digests.len(), 4);
+    }
+
+    #[test]
+    fn small_file_fragments() {
+        let mut builder = ArchiveBuilder::new();
+        builder.block_size = 4096;
+        builder.fragment_mode = FragmentMode::SmallFiles;
+        builder.compressed_data = false;
+        let mut archive = builder.build(Vec::new());
+        let start = archive.plan.data_position();
+
+        let large = add_file(&mut archive, &[1; 4096 + 10]);
+        assert_eq!(
+            large.block_sizes,
+            [Size::new(4096, true).0, Size::new(10, true).0]
+        );
+        assert_eq!({ large.fragment_block_idx.0 }, u32::MAX);
+        assert_eq!(archive.plan.data_position().0, start.0 + 4096 + 10);
+
+        let small = add_file(&mut archive, b"small");
+        assert!(small.block_sizes.is_empty());
+        assert_eq!({ small.fragment_block_idx.0 }, 0);
+        assert_eq!(archive.fragment, b"small");
+    }
+
+    #[test]
+    fn verify_failures() {
+        let mut builder = ArchiveBuilder::new();
+        builder.verify_writes(true);
+        let (mut archive, _) = builder.build_in_memory();
+        let mut file = archive.create_file();
+        file.set_contents(Box::new(io::Cursor::new(b"verified".to_vec())));
+        let file = file.finish(&mut archive).unwrap();
+        let mut root = archive.create_dir();
+        root.add_item("file", file);
+        let root = root.finish(&mut archive);
+        archive.set_root(root);
+        // As a codec on any of the threads would count a block failing the check
+        let failures = archive.verify_failures.clone().unwrap();
+        failures.fetch_add(2, Ordering::Relaxed);
+        let report = archive.flush().unwrap();
+        assert_eq!(report.issues(), [Issue::FailedVerification { blocks: 2 }]);
+    }
+
+    #[test]
+    fn skip_incompressible() {
+        let contents = crate::testing::compressible_contents();
+        for skip in [None, Some(0.0)] {
+            let mut builder = ArchiveBuilder::new();
+            builder.block_size = 4096;
+            builder.skip_incompressible = skip;
+            builder.verify_writes(true);
+            let (mut archive, image) = builder.build_in_memory();
+            let mut file = archive.create_file();
+            file.set_contents(Box::new(io::Cursor::new(contents.clone())));
+            let file = file.finish(&mut archive).unwrap();
+            let sizes = match &archive.get(file).data {
+                Data::File { file } => file.block_sizes.clone(),
+                data => panic!("{:?} isn't a file", data),
+            };
+            // With a threshold of 0, every block looks incompressible
+            let uncompressed = sizes.iter().all(|&size| Size(size).uncompressed());
+            assert_eq!(uncompressed, skip.is_some());
+
+            let mut root = archive.create_dir();
+            root.add_item("file", file);
+            let root = root.finish(&mut archive);
+            archive.set_root(root);
+            archive.flush().unwrap();
+            let read = image.open().unwrap();
+            let file = read.lookup("file").unwrap();
+            assert_eq!(read.read_file(&file).unwrap(), contents);
+        }
+    }
+
+    #[test]
+    fn shared_contents() {
+        let contents = crate::testing::compressible_contents();
+        let (mut archive, image) = Archive::in_memory();
+        let shared = archive
+            .create_file_contents(io::Cursor::new(&contents))
+            .unwrap();
+        let mut root = archive.create_dir();
+        for (name, uid) in [("a", 1), ("b", 2)] {
+            let mut file = archive.create_file();
+            file.set_uid(uid);
+            let file = file.finish_with_contents(&mut archive, &shared);
+            root.add_item(name, file);
+        }
+        let root = root.finish(&mut archive);
+        archive.set_root(root);
+        archive.flush().unwrap();
+
+        let read = image.open().unwrap();
+        let (a, b) = (read.lookup("a").unwrap(), read.lookup("b").unwrap());
+        assert_ne!(a.inode_number(), b.inode_number());
+        assert_eq!((a.uid(), b.uid()), (1, 2));
+        assert_eq!(read.read_file(&a).unwrap(), contents);
+        assert_eq!(read.read_file(&b).unwrap(), contents);
+    }
+
+    #[test]
+    fn raw_blocks() {
+        let source = crate::read::Archive::new(crate::testing::sparse()).unwrap();
+        let inode = source.lookup("sparse").unwrap();
+        let expected = crate::testing::sparse_contents();
+        let mut builder = ArchiveBuilder::new();
+        builder.block_size = source.block_size();
+        let mut archive = builder.build(Vec::new());
+        let start = archive.plan.data_position();
+
+        let map = source.block_map(&inode).unwrap();
+        let mut file = archive.create_file();
+        let mut copied = 0;
+        for block in map.raw_blocks() {
+            let data = source.read_raw_block(&block).unwrap();
+            file.add_raw_block(data, block.size(), block.uncompressed_size);
+            copied += block.uncompressed_size as usize;
+        }
+        file.set_contents(Box::new(io::Cursor::new(expected[copied..].to_vec())));
+        let item_ref = file.finish(&mut archive).unwrap();
+        let data = match &archive.get(item_ref).data {
+            Data::File { file } => file.clone(),
+            data => panic!("{:?} isn't a file", data),
+        };
+        assert_eq!({ data.blocks_start.0 }, start.0);
+        assert_eq!(data.file_size, expected.len() as u64);
+        let sizes: Vec<u32> = map.raw_blocks().map(|block| block.size().0).collect();
+        assert_eq!(data.block_sizes, sizes);
+        let stored: u64 = map
+            .raw_blocks()
+            .map(|block| u64::from(block.stored_size))
+            .sum();
+        assert_eq!(archive.plan.data_position().0, start.0 + stored);
+        assert_eq!(archive.fragment, expected[copied..]);
+
+        let mut bad = archive.create_file();
+        bad.add_raw_block(vec![1; 10], Size::new(20, true), 20);
+        assert!(bad.finish(&mut archive).is_err());
+        let mut partial = archive.create_file();
+        partial
+            .add_raw_block(vec![1; 10], Size::new(10, true), 10)
+            .set_contents(Box::new(&b"more"[..]));
+        let position = archive.plan.data_position();
+        assert!(partial.finish(&mut archive).is_err());
+        assert_eq!(
+            archive.plan.data_position(),
+            position,
+            "nothing was written"
+        );
+    }
+
+    #[test]
+    fn flush_round_trip() {
+        let mut builder = ArchiveBuilder::new();
+        builder.block_size = 4096;
+        let (mut archive, image) = builder.build_in_memory();
+        let contents = crate::testing::compressible_contents();
+        let add = |archive: &mut Archive<InMemory>, contents: &[u8]| {
+            let mut file = archive.create_file();
+            file.set_contents(Box::new(io::Cursor::new(contents.to_vec())))
+                .set_uid(1000);
+            file.finish(archive).unwrap()
+        };
+        let large = add(&mut archive, &contents);
+        let small = add(&mut archive, b"small");
+        let empty = add(&mut archive, b"");
+        let sparse = add(&mut archive, &[0; 3 * 4096]);
+        let link = archive.create_symlink("large").finish(&mut archive);
+        let fifo = archive.create_fifo().finish(&mut archive).unwrap().unwrap();
+
+        let mut many = archive.create_dir();
+        for i in 0..500 {
+            many.add_item(format!("entry-with-a-long-name-{:04}", i), small);
+        }
+        let many = many.finish(&mut archive);
+        let mut sub = archive.create_dir();
+        sub.add_item("small", small).add_item("many", many);
+        let sub = sub.finish(&mut archive);
+        let mut root = archive.create_dir();
+        root.add_item("large", large)
+            .add_item("empty", empty)
+            .add_item("sparse", sparse)
+            .add_item("link", link)
+            .add_item("fifo", fifo)
+            .add_item("sub", sub);
+        let root = root.finish(&mut archive);
+        archive.set_root(root);
+        archive.flush().unwrap();
+        assert!(archive.flush().is_ok());
+        assert_eq!(archive.file_size(), Some(image.len() as u64));
+        assert_eq!(image.len() % 4096, 0);
+
+        let read = image.open().unwrap();
+        assert_eq!(
+            { read.superblock().inode_count },
+            9,
+            "hard links share an inode"
+        );
+        assert_eq!({ read.superblock().fragment_entry_count }, 1);
+        let file = |path| read.lookup(path).unwrap();
+        assert_eq!(read.read_file(&file("large")).unwrap(), contents);
+        assert_eq!(file("large").uid(), 1000);
+        assert_eq!(read.read_file(&file("sub/small")).unwrap(), b"small");
+        assert_eq!(file("sub/small").hard_link_count(), 501);
+        assert!(read.read_file(&file("empty")).unwrap().is_empty());
+        assert_eq!(read.read_file(&file("sparse")).unwrap(), [0; 3 * 4096]);
+        assert_eq!(file("link").symlink_target().unwrap(), "large");
+        assert_eq!(file("fifo").file_type(), crate::FileType::Fifo);
+        assert_eq!(file("sub").hard_link_count(), 3);
+        let many = read.open_dir("sub/many").unwrap();
+        assert_eq!(many.len(), 500);
+        assert_eq!(
+            read.lookup("sub/many/entry-with-a-long-name-0499")
+                .unwrap()
+                .inode_number(),
+            file("sub/small").inode_number()
+        );
+    }
+
+    #[test]
+    fn in_place_outputs() {
+        fn fill<W: io::Write>(mut archive: Archive<W>, contents: &[u8]) {
+            let mut root = archive.create_dir();
+            if !contents.is_empty() {
+                let mut file = archive.create_file();
+                file.set_contents(Box::new(io::Cursor::new(contents.to_vec())));
+                let file = file.finish(&mut archive).unwrap();
+                root.add_item("file", file);
+            }
+            let root = root.finish(&mut archive);
+            archive.set_root(root);
+            archive.flush().unwrap();
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let (path, atomic) = (dir.path().join("file"), dir.path().join("atomic"));
+        for contents in [crate::testing::compressible_contents(), Vec::new()] {
+            let mut builder = ArchiveBuilder::new();
+            builder.block_size = 4096;
+            builder.set_modification_time(Utc.timestamp_opt(1_600_000_000, 0).unwrap());
+            let (archive, image) = builder.clone().build_in_memory();
+            fill(archive, &contents);
+            fill(builder.clone().build_path(&path).unwrap(), &contents);
+            fill(builder.build_path_atomic(&atomic).unwrap(), &contents);
+            assert_eq!(fs::read(&path).unwrap(), image.bytes());
+            assert_eq!(fs::read(&atomic).unwrap(), image.bytes());
+        }
+    }
+
+    #[test]
+    fn inode_numbers() {
+        let (mut archive, image) = Archive::in_memory();
+        let file = archive.create_file().finish(&mut archive).unwrap();
+        let link = archive.create_symlink("file").finish(&mut archive);
+        let mut deeper = archive.create_dir();
+        deeper.add_item("file", file);
+        let deeper = deeper.finish(&mut archive);
+        let mut sub = archive.create_dir();
+        sub.add_item("deeper", deeper).add_item("link", link);
+        let sub = sub.finish(&mut archive);
+        let mut root = archive.create_dir();
+        root.add_item("sub", sub).add_item("file", file);
+        let root = root.finish(&mut archive);
+        archive.set_root(root);
+        archive.flush().unwrap();
+
+        // The numbers in the inodes must be the ones directory entries refer to them by
+        let read = image.open().unwrap();
+        let mut numbers = std::collections::BTreeSet::new();
+        for dir in ["", "sub", "sub/deeper"] {
+            for entry in read.open_dir(dir).unwrap().dir_entries() {
+                let path = format!("{}/{}", dir, entry.name());
+                let inode = read.lookup(&path).unwrap();
+                assert_eq!(inode.inode_number(), entry.inode_number(), "{}", path);
+                numbers.insert(entry.inode_number());
+            }
+        }
+        numbers.insert(read.root().unwrap().inode_number());
+        let count = read.superblock().inode_count;
+        assert_eq!(numbers, (1..=count).collect());
+    }
+
+    #[test]
+    fn flush_xattrs() {
+        let (mut archive, image) = Archive::in_memory();
+        let mut file = archive.create_file();
+        file.set_xattr("security.selinux", "system_u:object_r:etc_t:s0")
+            .unwrap()
+            .set_xattr("user.note", "hi")
+            .unwrap();
+        assert!(file.set_xattr("system.posix_acl_access", "").is_err());
+        let file = file.finish(&mut archive).unwrap();
+        let mut link = archive.create_symlink("file");
+        link.set_xattr("user.note", "hi")
+            .unwrap()
+            .set_xattr("security.selinux", "system_u:object_r:etc_t:s0")
+            .unwrap();
+        let link = link.finish(&mut archive);
+        let mut root = archive.create_dir();
+        root.add_item("file", file).add_item("link", link);
+        let root = root.finish(&mut archive);
+        archive.set_root(root);
+        archive.flush().unwrap();
+
+        let read = image.open().unwrap();
+        let superblock = read.superblock();
+        assert!(!{ superblock.flags }.contains(repr::superblock::Flags::NO_XATTRS));
+        let idx = |path| read.lookup(path).unwrap().xattr_idx();
+        assert_eq!(idx("file"), repr::xattr::Idx(0));
+        assert_eq!(
+            idx("link"),
+            repr::xattr::Idx(0),
+            "identical sets are shared"
+        );
+        assert_eq!(read.root().unwrap().xattr_idx(), repr::xattr::Idx::NONE);
+
+        let bytes = image.bytes();
+        let start = superblock.xattr_id_table_start as usize;
+        let header: repr::xattr::LookupTable = repr::read(&bytes[start..start + 16]).unwrap();
+        assert_eq!({ header.xattr_entry_count }, 1);
+        assert!({ header.xattr_table_start } < start as u64);
+        assert_eq!(start + 16 + 8, superblock.bytes_used as usize);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_options() {
+        use crate::compression::{Kind, Options};
+        use repr::compression::options::{Lz4, Lz4Flags};
+
+        let contents = crate::testing::compressible_contents();
+        let write = |options| {
+            let mut builder = ArchiveBuilder::new();
+            builder.compressor_kind = Kind::Lz4;
+            builder.compression_options = options;
+            let (mut archive, image) = builder.build_in_memory();
+            let mut file = archive.create_file();
+            file.set_contents(Box::new(io::Cursor::new(contents.clone())));
+            let file = file.finish(&mut archive).unwrap();
+            let mut root = archive.create_dir();
+            root.add_item("file", file);
+            let root = root.finish(&mut archive);
+            archive.set_root(root);
+            archive.flush().unwrap();
+            image.open().unwrap()
+        };
+
+        // Stored even when they're the defaults
+        let read = write(None);
+        assert!({ read.superblock().flags }.contains(repr::superblock::Flags::COMPRESSOR_OPTIONS));
+        assert_eq!(read.compression(), Options::Lz4(Lz4::default()));
+        assert_eq!(
+            read.read_file(&read.lookup("file").unwrap()).unwrap(),
+            contents
+        );
+
+        let high = Options::Lz4(Lz4 {
+            version: 1,
+            flags: Lz4Flags::HIGH_COMPRESSION,
+        });
+        let read = write(Some(high));
+        assert_eq!(read.compression(), high);
+        assert_eq!(
+            read.read_file(&read.lookup("file").unwrap()).unwrap(),
+            contents
+        );
+
+        let mut builder = ArchiveBuilder::new();
+        builder.compression_options = Some(high);
+        assert!(builder.validate().is_err());
+    }
+
+    #[cfg(feature = "xz")]
+    #[test]
+    fn xz_options() {
+        use crate::compression::{Kind, Options};
+        use repr::compression::options::{Xz, XzFilters};
+
+        let contents = crate::testing::compressible_contents();
+        let write = |options| {
+            let mut builder = ArchiveBuilder::new();
+            builder.block_size = 1 << 16;
+            builder.compressor_kind = Kind::Xz;
+            builder.compression_options = options;
+            let (mut archive, image) = builder.build_in_memory();
+            let mut file = archive.create_file();
+            file.set_contents(Box::new(io::Cursor::new(contents.clone())));
+            let file = file.finish(&mut archive).unwrap();
+            let mut root = archive.create_dir();
+            root.add_item("file", file);
+            let root = root.finish(&mut archive);
+            archive.set_root(root);
+            archive.flush().unwrap();
+            image.open().unwrap()
+        };
+
+        // The default dictionary is the block size, which isn't stored
+        let read = write(None);
+        assert!(!{ read.superblock().flags }.contains(repr::superblock::Flags::COMPRESSOR_OPTIONS));
+        assert_eq!(
+            read.compression(),
+            Options::Xz(Xz {
+                dictionary_size: 1 << 16,
+                executable_filters: XzFilters::empty(),
+            })
+        );
+        assert_eq!(
+            read.read_file(&read.lookup("file").unwrap()).unwrap(),
+            contents
+        );
+
+        let filtered = Options::Xz(Xz {
+            dictionary_size: 1 << 14,
+            executable_filters: XzFilters::X86 | XzFilters::ARM_THUMB,
+        });
+        let read = write(Some(filtered));
+        assert_eq!(read.compression(), filtered);
+        assert_eq!(
+            read.read_file(&read.lookup("file").unwrap()).unwrap(),
+            contents
+        );
+    }
+
+    #[test]
+    fn export_table() {
+        let build = |exportable| {
+            let mut builder = ArchiveBuilder::new();
+            builder.exportable = exportable;
+            builder.compressed_inodes = false;
+            let (mut archive, image) = builder.build_in_memory();
+            let file = archive.create_file().finish(&mut archive).unwrap();
+            let mut sub = archive.create_dir();
+            sub.add_item("file", file);
+            let sub = sub.finish(&mut archive);
+            let mut root = archive.create_dir();
+            root.add_item("sub", sub).add_item("link", file);
+            let root = root.finish(&mut archive);
+            archive.set_root(root);
+            archive.flush().unwrap();
+            image
+        };
+
+        let read = build(false).open().unwrap();
+        assert!(!read.flags().contains(repr::superblock::Flags::EXPORTABLE));
+        assert_eq!({ read.superblock().export_table_start }, u64::MAX);
+
+        let image = build(true);
+        let read = image.open().unwrap();
+        assert!(read.flags().contains(repr::superblock::Flags::EXPORTABLE));
+        let bytes = image.bytes();
+        let start = read.superblock().export_table_start as usize;
+        let metablock = u64::from_le_bytes(bytes[start..start + 8].try_into().unwrap()) as usize;
+        // An uncompressed metablock of one inode reference per inode
+        let count = read.superblock().inode_count as usize;
+        let refs = &bytes[metablock + 2..metablock + 2 + 8 * count];
+        for (i, inode_ref) in refs.chunks(8).enumerate() {
+            let inode_ref = repr::inode::Ref(u64::from_le_bytes(inode_ref.try_into().unwrap()));
+            let inode = read.inode(inode_ref).unwrap();
+            assert_eq!(inode.inode_number() as usize, i + 1);
+        }
+    }
+
+    #[test]
+    fn flush_without_root() {
+        let (mut archive, image) = Archive::in_memory();
+        assert!(archive.flush().is_err());
+        let root = archive.create_dir().finish(&mut archive);
+        archive.set_root(root);
+        archive.flush().unwrap();
+        assert!(image.open().unwrap().root().unwrap().is_dir());
+    }
+
+    #[test]
+    fn padding_layout() {
+        struct FixedSigner;
+        impl Signer for FixedSigner {
+            fn sign(&self, _digest: &Digest) -> io::Result<Vec<u8>> {
+                Ok(vec![1; 64])
+            }
+        }
+
+        let mut builder = ArchiveBuilder::new();
+        builder.padding = 1 << 16;
+        builder.set_signer(Arc::new(FixedSigner), SignaturePlacement::Trailer);
+        let (mut archive, image) = builder.build_in_memory();
+        let root = archive.create_dir().finish(&mut archive);
+        archive.set_root(root);
+        archive.flush().unwrap();
+
+        let layout = archive.layout().unwrap().clone();
+        let bytes_used = archive.bytes_used().unwrap();
+        let trailer_len = (crate::signature::TRAILER_HEADER_SIZE + 64) as u64;
+        assert_eq!(layout.bytes_used, bytes_used);
+        assert_eq!(
+            layout.signature_trailer,
+            Some(bytes_used..bytes_used + trailer_len)
+        );
+        assert_eq!(layout.payload_end(), bytes_used + trailer_len);
+        assert_eq!(layout.file_size, 1 << 16);
+        assert_eq!(image.len() as u64, layout.file_size);
+        assert_eq!(layout.data_blocks(4096), Some(16));
+        assert_eq!(layout.data_blocks(3000), None);
+        assert_eq!(layout.data_blocks(0), None);
+        assert_eq!(
+            { image.open().unwrap().superblock().bytes_used },
+            bytes_used
+        );
+
+        let mut builder = ArchiveBuilder::new();
+        builder.padding = 1000;
+        assert!(builder.validate().is_err());
+        builder.padding = 0;
+        assert!(builder.validate().is_err());
+    }
 }
@@ -0,0 +1,127 @@
+//! Assigning inode numbers to the items of an archive
+//!
+//! Items don't get inode numbers until the archive is written, so trees from several sources,
+//! such as merged archives, can't collide. Every item reachable from the root gets exactly one
+//! number, however many directory entries refer to it, so hard links keep sharing an inode.
+//! Items which aren't reachable, such as a directory replaced while merging, get none.
+
+use super::{Item, ItemRef};
+
+/// The inode number of each item, see the [module docs](self)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct InodeNumbers {
+    /// Indexed by item, 0 for unreachable items
+    numbers: Vec<u32>,
+    count: u32,
+}
+
+impl InodeNumbers {
+    /// Number the items reachable from `root`
+    ///
+    /// Like `mksquashfs`, the contents of a directory are numbered before the directory, in
+    /// name order, so the root is numbered last. Nothing is numbered if the root hasn't been
+    /// set.
+    pub fn new(items: &[Item], root: ItemRef) -> Self {
+        let mut numbers = vec![0; items.len()];
+        let mut visited = vec![false; items.len()];
+        let mut next = 1;
+        // Each item is pushed a second time once its children are, to be numbered after them
+        let mut stack = Vec::new();
+        if (root.0 as usize) < items.len() {
+            stack.push((root, false));
+        }
+        while let Some((item_ref, children_pushed)) = stack.pop() {
+            let idx = item_ref.0 as usize;
+            if children_pushed {
+                numbers[idx] = next;
+                next += 1;
+                continue;
+            }
+            if visited[idx] {
+                continue;
+            }
+            visited[idx] = true;
+            stack.push((item_ref, true));
+            if let Some(children) = items[idx].children_refs() {
+                let children: Vec<_> = children.collect();
+                stack.extend(children.into_iter().rev().map(|child| (child, false)));
+            }
+        }
+        InodeNumbers {
+            numbers,
+            count: next - 1,
+        }
+    }
+
+    /// The inode number of `item_ref`, `None` if it isn't reachable from the root
+    pub fn get(&self, item_ref: ItemRef) -> Option<u32> {
+        match self.numbers[item_ref.0 as usize] {
+            0 => None,
+            number => Some(number),
+        }
+    }
+
+    /// The number of inodes, which is also the largest inode number
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write::inode::FileData;
+    use crate::write::Data;
+    use chrono::Utc;
+    use std::collections::BTreeMap;
+
+    fn item(data: Data) -> Item {
+        Item {
+            uid: repr::uid_gid::Id(0),
+            gid: repr::uid_gid::Id(0),
+            mode: crate::Mode::O644,
+            mtime: Utc::now(),
+            explicit: Default::default(),
+            inode: None,
+            xattrs: Default::default(),
+            data,
+        }
+    }
+
+    fn dir(entries: &[(&str, u32)]) -> Item {
+        let entries: BTreeMap<_, _> = entries
+            .iter()
+            .map(|&(name, idx)| (name.into(), ItemRef(idx)))
+            .collect();
+        item(Data::Directory { entries })
+    }
+
+    #[test]
+    fn merged_trees() {
+        // Two trees sharing a hard linked file, grafted under a new root, leaving the root of
+        // the second tree unreachable
+        let items = vec![
+            item(Data::File {
+                file: FileData {
+                    blocks_start: repr::datablock::Ref(96),
+                    file_size: 0,
+                    sparse_bytes: 0,
+                    fragment_block_idx: repr::fragment::Idx(u32::MAX),
+                    fragment_offset: 0,
+                    block_sizes: Vec::new(),
+                },
+            }),
+            dir(&[("x", 0)]),
+            dir(&[("y", 0), ("z", 3)]),
+            item(Data::Symlink { target: "x".into() }),
+            dir(&[("b", 2)]),
+            dir(&[("a", 1), ("b", 2)]),
+        ];
+        let numbers = InodeNumbers::new(&items, ItemRef(5));
+        let all: Vec<_> = (0..items.len() as u32)
+            .map(|idx| numbers.get(ItemRef(idx)))
+            .collect();
+        assert_eq!(all, [Some(1), Some(2), Some(4), Some(3), None, Some(5)]);
+        assert_eq!(numbers.count(), 5);
+    }
+}
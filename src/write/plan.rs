@@ -0,0 +1,423 @@
+//! Laying out a whole image before writing any of it
+//!
+//! The superblock comes first in an image, but records where every table starts, which depends
+//! on the size of everything written before the table, data blocks included. When the output
+//! can seek, the data blocks are written straight to it, after space left for the superblock,
+//! which is written over that space once everything else is. Otherwise the data blocks are
+//! spooled (in memory while small, then to a temporary file), so once everything has been
+//! added the offsets are all known, and the image is written front to back. Either way the
+//! tables are kept in memory until the end, and any `io::Write`, such as a pipe to stdout, can
+//! hold an image.
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+
+use repr::offset::ArchiveOffset;
+use repr::superblock::Superblock;
+use tempfile::SpooledTempFile;
+use zerocopy::{AsBytes, FromBytes};
+
+/// The data blocks spooled in memory before moving to a temporary file
+const SPOOL_MEMORY: usize = 32 << 20;
+
+/// The contents of an image, waiting to be written
+pub(crate) struct Plan {
+    /// The superblock, whose table locations and size are filled in by [`emit`](Self::emit)
+    pub superblock: Superblock,
+    /// The compression options, encoded as a metablock, or empty for the defaults
+    pub compression_options: Vec<u8>,
+    /// The data and fragment blocks, in the order of their offsets
+    data: Spool,
+    data_len: u64,
+    pub inode_table: Vec<u8>,
+    pub dir_table: Vec<u8>,
+    /// The metablocks of the lookup tables, the lists of their locations which follow them in
+    /// the image are added when it's written
+    pub fragment_table: Option<Vec<u8>>,
+    pub export_table: Option<Vec<u8>>,
+    pub id_table: Vec<u8>,
+    pub xattr_tables: Option<XattrTables>,
+}
+
+/// The metablocks of the xattr key value pairs, and of the xattr id table which refers to them
+///
+/// The list of the id table's metablock locations follows them in the image, after a header
+/// with the number of ids and the start of the key value pairs.
+pub(crate) struct XattrTables {
+    pub pairs: Vec<u8>,
+    pub ids: Vec<u8>,
+    pub count: u32,
+}
+
+impl Plan {
+    pub fn new(superblock: Superblock, compression_options: Vec<u8>) -> Self {
+        Plan {
+            superblock,
+            compression_options,
+            data: Spool::Temporary(tempfile::spooled_tempfile(SPOOL_MEMORY)),
+            data_len: 0,
+            inode_table: Vec::new(),
+            dir_table: Vec::new(),
+            fragment_table: None,
+            export_table: None,
+            id_table: Vec::new(),
+            xattr_tables: None,
+        }
+    }
+
+    /// The offset in the image of the next data block written
+    ///
+    /// Inodes refer to their blocks by offset, and since the data blocks directly follow the
+    /// superblock and compression options, these are known as soon as the blocks are written.
+    pub fn data_position(&self) -> ArchiveOffset {
+        let start = mem::size_of::<Superblock>() + self.compression_options.len();
+        ArchiveOffset(start as u64 + self.data_len)
+    }
+
+    /// Write the data blocks straight to the output instead of spooling them, which must be
+    /// able to seek back to write the superblock, see [`emit`](Self::emit)
+    ///
+    /// Nothing can have been written yet.
+    pub fn write_in_place(&mut self) {
+        debug_assert_eq!(self.data_len, 0, "data was already spooled");
+        self.data = Spool::Output { started: false };
+    }
+
+    /// Spool the data blocks to `file` instead, which is kept once the image is written, and
+    /// already holds the first `len` bytes of them
+    ///
+    /// Anything in `file` past `len` is discarded. Nothing can have been written yet.
+    pub fn persist_data(&mut self, mut file: File, len: u64) -> io::Result<()> {
+        debug_assert_eq!(self.data_len, 0, "data was already spooled");
+        file.set_len(len)?;
+        file.seek(SeekFrom::End(0))?;
+        self.data = Spool::Persistent(file);
+        self.data_len = len;
+        Ok(())
+    }
+
+    /// The number of bytes of data blocks written so far
+    pub fn data_len(&self) -> u64 {
+        self.data_len
+    }
+
+    /// Make sure the data blocks written so far survive a crash, if they're spooled to a
+    /// [persistent](Self::persist_data) file
+    pub fn sync_data(&mut self) -> io::Result<()> {
+        match &self.data {
+            Spool::Persistent(file) => file.sync_data(),
+            Spool::Temporary(_) | Spool::Output { .. } => Ok(()),
+        }
+    }
+
+    /// Discard the data blocks written after the first `len` bytes of them
+    ///
+    /// Blocks written [in place](Self::write_in_place) can't be discarded.
+    pub fn truncate_data(&mut self, len: u64) -> io::Result<()> {
+        debug_assert!(len <= self.data_len, "truncating past the end of the data");
+        match &mut self.data {
+            Spool::Temporary(spool) => {
+                spool.set_len(len)?;
+                spool.seek(SeekFrom::End(0))?;
+            }
+            Spool::Persistent(file) => {
+                file.set_len(len)?;
+                file.seek(SeekFrom::End(0))?;
+            }
+            Spool::Output { .. } => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "data blocks written in place can't be discarded",
+                ))
+            }
+        }
+        self.data_len = len;
+        Ok(())
+    }
+
+    /// Append a data or fragment block, returning its offset in the image
+    ///
+    /// `out` is the output, which is only written to if the blocks are written
+    /// [in place](Self::write_in_place).
+    pub fn write_block(&mut self, out: &mut dyn Write, block: &[u8]) -> io::Result<ArchiveOffset> {
+        let position = self.data_position();
+        self.data(out).write_all(block)?;
+        Ok(position)
+    }
+
+    /// A writer appending to the data blocks, so files can be streamed straight into them, see
+    /// [`write_block`](Self::write_block)
+    pub fn data<'a>(&'a mut self, out: &'a mut dyn Write) -> DataWriter<'a> {
+        DataWriter { plan: self, out }
+    }
+
+    /// Write the rest of the image to `out`, returning its size
+    ///
+    /// If the data blocks were spooled, nothing has been written to `out` yet, and the image
+    /// is written front to back. If they were written in place, `out` already holds everything
+    /// up to their end, and once the tables are written `patch` is called to write the
+    /// complete superblock over the start of the output, so an image which is cut short has
+    /// no valid superblock. The image isn't padded.
+    pub fn emit<W, F>(mut self, out: &mut W, patch: F) -> io::Result<u64>
+    where
+        W: Write + ?Sized,
+        F: FnOnce(&mut W, &[u8]) -> io::Result<()>,
+    {
+        let mut layout = Layout(self.data_position().0);
+        let sb = &mut self.superblock;
+        sb.inode_table_start = layout.add(self.inode_table.len());
+        sb.directory_table_start = layout.add(self.dir_table.len());
+        let mut lookup_tables = Vec::new();
+        sb.fragment_table_start = match &self.fragment_table {
+            Some(table) => layout.add_lookup_table(table, &mut lookup_tables)?,
+            None => u64::MAX,
+        };
+        sb.export_table_start = match &self.export_table {
+            Some(table) => layout.add_lookup_table(table, &mut lookup_tables)?,
+            None => u64::MAX,
+        };
+        sb.id_table_start = layout.add_lookup_table(&self.id_table, &mut lookup_tables)?;
+        // The xattr id table's header comes between its metablocks and their locations
+        let mut xattr_lookup = None;
+        sb.xattr_id_table_start = match &self.xattr_tables {
+            Some(tables) => {
+                let pairs_start = layout.add(tables.pairs.len());
+                let ids_start = layout.add(tables.ids.len());
+                let lookup: Vec<u64> = metablock_starts(&tables.ids)?
+                    .into_iter()
+                    .map(|offset| ids_start + offset)
+                    .collect();
+                let header = repr::xattr::LookupTable::new(pairs_start, tables.count);
+                let start =
+                    layout.add(mem::size_of_val(&header) + lookup.len() * mem::size_of::<u64>());
+                xattr_lookup = Some((header, lookup));
+                start
+            }
+            None => u64::MAX,
+        };
+        sb.bytes_used = layout.0;
+
+        let in_place = matches!(self.data, Spool::Output { started: true });
+        if !in_place {
+            out.write_all(self.superblock.as_bytes())?;
+            out.write_all(&self.compression_options)?;
+            let copied = match &mut self.data {
+                Spool::Temporary(spool) => copy_from_start(spool, out)?,
+                Spool::Persistent(file) => copy_from_start(file, out)?,
+                Spool::Output { .. } => 0,
+            };
+            debug_assert_eq!(copied, self.data_len);
+        }
+        out.write_all(&self.inode_table)?;
+        out.write_all(&self.dir_table)?;
+        let tables = [
+            self.fragment_table.as_deref(),
+            self.export_table.as_deref(),
+            Some(&self.id_table[..]),
+        ];
+        for (metablocks, lookup) in tables.iter().flatten().zip(&lookup_tables) {
+            out.write_all(metablocks)?;
+            for offset in lookup {
+                out.write_all(&offset.to_le_bytes())?;
+            }
+        }
+        if let (Some(tables), Some((header, lookup))) = (&self.xattr_tables, xattr_lookup) {
+            out.write_all(&tables.pairs)?;
+            out.write_all(&tables.ids)?;
+            out.write_all(header.as_bytes())?;
+            for offset in lookup {
+                out.write_all(&offset.to_le_bytes())?;
+            }
+        }
+        if in_place {
+            patch(out, self.superblock.as_bytes())?;
+        }
+        Ok(self.superblock.bytes_used)
+    }
+}
+
+/// Copy all of `spool` to `out`, returning the number of bytes copied
+fn copy_from_start<S, W>(spool: &mut S, out: &mut W) -> io::Result<u64>
+where
+    S: Read + Seek,
+    W: Write + ?Sized,
+{
+    spool.seek(SeekFrom::Start(0))?;
+    io::copy(spool, out)
+}
+
+/// Appends to the data blocks of a plan, see [`Plan::data`]
+pub(crate) struct DataWriter<'a> {
+    plan: &'a mut Plan,
+    out: &'a mut dyn Write,
+}
+
+impl Write for DataWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let plan = &mut *self.plan;
+        let written = match &mut plan.data {
+            Spool::Temporary(spool) => spool.write(buf)?,
+            Spool::Persistent(file) => file.write(buf)?,
+            Spool::Output { started } => {
+                // Leave space for the superblock, the compression options are already known
+                if !*started && !buf.is_empty() {
+                    let superblock = Superblock::new_zeroed();
+                    self.out.write_all(superblock.as_bytes())?;
+                    self.out.write_all(&plan.compression_options)?;
+                    *started = true;
+                }
+                self.out.write(buf)?
+            }
+        };
+        plan.data_len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.plan.data {
+            Spool::Temporary(spool) => spool.flush(),
+            Spool::Persistent(file) => file.flush(),
+            Spool::Output { .. } => self.out.flush(),
+        }
+    }
+}
+
+/// Where the data blocks are written
+enum Spool {
+    Temporary(SpooledTempFile),
+    /// See [`Plan::persist_data`]
+    Persistent(File),
+    /// Straight to the output, see [`Plan::write_in_place`], once `started` after the space
+    /// left for the superblock and the compression options
+    Output {
+        started: bool,
+    },
+}
+
+/// The offset of the end of the image laid out so far
+struct Layout(u64);
+
+impl Layout {
+    /// Add a table of `len` bytes, returning where it starts
+    fn add(&mut self, len: usize) -> u64 {
+        let start = self.0;
+        self.0 += len as u64;
+        start
+    }
+
+    /// Add the metablocks of a lookup table, then the list of their locations, which is pushed
+    /// to `lookup_tables`, returning where the list starts
+    fn add_lookup_table(
+        &mut self,
+        metablocks: &[u8],
+        lookup_tables: &mut Vec<Vec<u64>>,
+    ) -> io::Result<u64> {
+        let start = self.add(metablocks.len());
+        let lookup: Vec<u64> = metablock_starts(metablocks)?
+            .into_iter()
+            .map(|offset| start + offset)
+            .collect();
+        let lookup_start = self.add(lookup.len() * mem::size_of::<u64>());
+        lookup_tables.push(lookup);
+        Ok(lookup_start)
+    }
+}
+
+/// The offset of each metablock in `metablocks`, which holds whole metablocks back to back
+fn metablock_starts(metablocks: &[u8]) -> io::Result<Vec<u64>> {
+    let mut starts = Vec::new();
+    let mut offset = 0;
+    while offset < metablocks.len() {
+        let header = metablocks
+            .get(offset..offset + 2)
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        let header = repr::metablock::Header(u16::from_le_bytes(header.try_into().unwrap()));
+        starts.push(offset as u64);
+        offset += 2 + usize::from(header.size());
+    }
+    if offset != metablocks.len() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(starts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::AnyCodec;
+    use crate::pool::BlockPool;
+    use crate::write::metablock_writer::MetablockWriter;
+
+    fn metablocks(len: usize) -> Vec<u8> {
+        let pool = std::sync::Arc::new(BlockPool::new(1, 1));
+        let mut writer = MetablockWriter::<AnyCodec>::new(None, &pool);
+        writer.write_raw(&vec![7; len]);
+        writer.finish()
+    }
+
+    #[test]
+    fn layout() {
+        let mut out = Vec::new();
+        let mut plan = Plan::new(Superblock::new_zeroed(), Vec::new());
+        let block = plan.write_block(&mut out, &[1; 100]).unwrap();
+        assert_eq!(block, ArchiveOffset(96));
+        let block = plan.write_block(&mut out, &[2; 50]).unwrap();
+        assert_eq!(block, ArchiveOffset(196));
+        assert!(out.is_empty(), "data blocks are spooled");
+        plan.inode_table = metablocks(10);
+        plan.dir_table = metablocks(20);
+        // Two metablocks
+        plan.id_table = metablocks(repr::metablock::SIZE + 4);
+
+        let bytes_used = plan.emit(&mut out, |_, _| unreachable!()).unwrap();
+        assert_eq!(bytes_used, out.len() as u64);
+        let superblock: Superblock = repr::read(&out[..96]).unwrap();
+        assert_eq!({ superblock.inode_table_start }, 246);
+        assert_eq!({ superblock.directory_table_start }, 246 + 12);
+        assert_eq!({ superblock.fragment_table_start }, u64::MAX);
+
+        let id_metablocks = 246 + 12 + 22;
+        let id_table_start = id_metablocks + (2 + repr::metablock::SIZE) + (2 + 4);
+        assert_eq!({ superblock.id_table_start }, id_table_start as u64);
+        let lookup = &out[id_table_start..];
+        assert_eq!(lookup.len(), 16);
+        assert_eq!(lookup[..8], (id_metablocks as u64).to_le_bytes());
+        assert_eq!(
+            lookup[8..],
+            ((id_metablocks + 2 + repr::metablock::SIZE) as u64).to_le_bytes()
+        );
+        assert_eq!(out[96..196], [1; 100]);
+    }
+
+    #[test]
+    fn in_place() {
+        let fill = |plan: &mut Plan, out: &mut io::Cursor<Vec<u8>>| {
+            plan.write_block(out, &[1; 100]).unwrap();
+            plan.write_block(out, &[2; 50]).unwrap();
+            plan.inode_table = metablocks(10);
+            plan.dir_table = metablocks(20);
+            plan.id_table = metablocks(repr::metablock::SIZE + 4);
+        };
+        let mut spooled = io::Cursor::new(Vec::new());
+        let mut plan = Plan::new(Superblock::new_zeroed(), vec![3; 4]);
+        fill(&mut plan, &mut spooled);
+        plan.emit(&mut spooled, |_, _| unreachable!()).unwrap();
+
+        let mut out = io::Cursor::new(Vec::new());
+        let mut plan = Plan::new(Superblock::new_zeroed(), vec![3; 4]);
+        plan.write_in_place();
+        fill(&mut plan, &mut out);
+        assert_eq!(out.get_ref().len(), 96 + 4 + 150);
+        assert_eq!(out.get_ref()[100..], spooled.get_ref()[100..250]);
+        let bytes_used = plan
+            .emit(&mut out, |out, superblock| {
+                out.seek(SeekFrom::Start(0))?;
+                out.write_all(superblock)?;
+                out.seek(SeekFrom::End(0)).map(drop)
+            })
+            .unwrap();
+        assert_eq!(bytes_used, out.get_ref().len() as u64);
+        assert_eq!(out.into_inner(), spooled.into_inner());
+    }
+}
@@ -0,0 +1,240 @@
+use std::fmt;
+
+use bstr::BString;
+use chrono::{DateTime, Utc};
+use slog::Logger;
+
+/// A problem which didn't stop an archive being written, but which may have changed its contents
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Issue {
+    /// A time outside the range squashfs can store, which was clamped to the nearest time it
+    /// can
+    ClampedTime { date: DateTime<Utc> },
+    /// A device number too large for squashfs, which was clamped, see
+    /// [`DeviceNumberPolicy`](crate::config::DeviceNumberPolicy)
+    ClampedDevice { major: u32, minor: u32 },
+    /// A socket or fifo left out of the archive, see
+    /// [`SpecialFilePolicy`](crate::config::SpecialFilePolicy)
+    ///
+    /// The path is only known for entries added from a tree, not items built directly.
+    SkippedSpecialFile {
+        path: Option<BString>,
+        kind: &'static str,
+    },
+    /// An entry left out of the archive for exceeding its [`Limits`](crate::config::Limits)
+    SkippedEntry {
+        path: BString,
+        what: &'static str,
+        actual: usize,
+        max: usize,
+    },
+    /// Extended attributes set on items, which were left out because the archive doesn't store
+    /// xattrs, see [`ArchiveBuilder::xattrs`](super::ArchiveBuilder::xattrs)
+    DroppedXattrs { items: usize },
+    /// An [`ArchiveBuilder`](super::ArchiveBuilder) option which has no effect because of
+    /// another, see [`ArchiveBuilder::validate`](super::ArchiveBuilder::validate)
+    IgnoredOption {
+        option: &'static str,
+        reason: &'static str,
+    },
+    /// Compressed blocks which didn't decompress to the original data, and were stored
+    /// uncompressed instead, see
+    /// [`ArchiveBuilder::verify_writes`](super::ArchiveBuilder::verify_writes)
+    ///
+    /// Any at all suggest the codec or the machine can't be trusted.
+    FailedVerification { blocks: u64 },
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::ClampedTime { date } => write!(f, "clamped out of range time {}", date),
+            Issue::ClampedDevice { major, minor } => {
+                write!(f, "clamped out of range device {},{}", major, minor)
+            }
+            Issue::SkippedSpecialFile { path: None, kind } => write!(f, "skipped {}", kind),
+            Issue::SkippedSpecialFile {
+                path: Some(path),
+                kind,
+            } => write!(f, "skipped {} {:?}", kind, path),
+            Issue::SkippedEntry {
+                path,
+                what,
+                actual,
+                max,
+            } => write!(
+                f,
+                "skipped {:?}: {} of {} exceeds the limit of {}",
+                path, what, actual, max
+            ),
+            Issue::DroppedXattrs { items } => {
+                write!(
+                    f,
+                    "dropped the xattrs of {} items, xattrs are not stored",
+                    items
+                )
+            }
+            Issue::IgnoredOption { option, reason } => {
+                write!(f, "ignored option {}: {}", option, reason)
+            }
+            Issue::FailedVerification { blocks } => write!(
+                f,
+                "{} compressed blocks failed verification, and were stored uncompressed",
+                blocks
+            ),
+        }
+    }
+}
+
+/// The issues found while writing an archive, see [`Archive::flush`](super::Archive::flush)
+///
+/// Each issue is also logged as a warning when it happens. Pipelines which treat policy
+/// violations as failures can check that the report is empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    issues: Vec<Issue>,
+}
+
+impl Report {
+    pub fn issues(&self) -> &[Issue] {
+        &self.issues
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// Log and record an issue
+    pub(crate) fn note(&mut self, logger: &Logger, issue: Issue) {
+        slog::warn!(logger, "{}", issue);
+        self.issues.push(issue);
+    }
+}
+
+impl IntoIterator for Report {
+    type Item = Issue;
+    type IntoIter = std::vec::IntoIter<Issue>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.issues.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn note_issues() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let mut report = Report::default();
+        assert!(report.is_empty());
+        report.note(
+            &logger,
+            Issue::SkippedSpecialFile {
+                path: None,
+                kind: "fifo",
+            },
+        );
+        report.note(
+            &logger,
+            Issue::SkippedSpecialFile {
+                path: Some("/run/socket".into()),
+                kind: "socket",
+            },
+        );
+        report.note(
+            &logger,
+            Issue::SkippedEntry {
+                path: "/a".into(),
+                what: "name length",
+                actual: 300,
+                max: 255,
+            },
+        );
+        assert!(!report.is_empty());
+        let messages: Vec<_> = report.into_iter().map(|issue| issue.to_string()).collect();
+        assert_eq!(
+            messages,
+            [
+                "skipped fifo",
+                "skipped socket \"/run/socket\"",
+                "skipped \"/a\": name length of 300 exceeds the limit of 255"
+            ]
+        );
+    }
+
+    #[test]
+    fn clamped_times() {
+        use super::super::date_time_to_mtime;
+        use chrono::TimeZone;
+        let time = |secs| Utc.timestamp_opt(secs, 0).unwrap();
+        assert_eq!(date_time_to_mtime(time(5)), (repr::Time(5), false));
+        assert_eq!(date_time_to_mtime(time(-5)), (repr::Time(0), true));
+        assert_eq!(
+            date_time_to_mtime(time(1 << 33)),
+            (repr::Time(u32::MAX), true)
+        );
+    }
+
+    #[test]
+    fn builder_validation() {
+        use super::super::ArchiveBuilder;
+        use crate::config::FragmentMode;
+
+        let mut builder = ArchiveBuilder::new();
+        assert_eq!(builder.validate().unwrap(), []);
+
+        builder.fragment_mode = FragmentMode::Never;
+        builder.compressed_fragments = false;
+        let warnings: Vec<_> = builder
+            .validate()
+            .unwrap()
+            .iter()
+            .map(|issue| issue.to_string())
+            .collect();
+        assert_eq!(
+            warnings,
+            ["ignored option compressed_fragments: no fragments are written"]
+        );
+
+        builder.cpu_affinity = crate::config::CpuAffinity::Cores(Vec::new());
+        let err = builder.validate().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Write error: Invalid CPU affinity: no cores"
+        );
+        builder.cpu_affinity = Default::default();
+
+        builder.block_size = 3000;
+        let err = builder.validate().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Write error: Block size 3000 invalid: must be a power of two between 4096 and 1048576"
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.sqfs");
+        assert!(builder.clone().build_path(&path).is_err());
+        assert!(!path.exists());
+        assert!(builder.try_build(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn dropped_xattrs() {
+        use super::super::ArchiveBuilder;
+
+        let mut builder = ArchiveBuilder::new();
+        builder.xattrs = false;
+        let (mut archive, _) = builder.build_in_memory();
+        let mut file = archive.create_file();
+        file.set_xattr("user.comment", "hello").unwrap();
+        let file = file.finish(&mut archive).unwrap();
+        let mut root = archive.create_dir();
+        root.add_item("file", file);
+        let root = root.finish(&mut archive);
+        archive.set_root(root);
+        let report = archive.flush().unwrap();
+        assert_eq!(report.issues(), [Issue::DroppedXattrs { items: 1 }]);
+    }
+}
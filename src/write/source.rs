@@ -0,0 +1,240 @@
+//! Adding entries from arbitrary sources, such as databases or virtual filesystems
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use bstr::BString;
+use chrono::{DateTime, Utc};
+
+use super::tree::{Entry, Tree};
+use super::Archive;
+use crate::errors::Result;
+use crate::Mode;
+
+/// Provides the contents of a regular file from a [`SourceEntry`]
+///
+/// The contents are only opened once the file is added to the archive, so a source with many
+/// files doesn't need to hold all of them open, or in memory, at once.
+pub trait ContentProvider {
+    fn open(&self) -> io::Result<Box<dyn io::Read>>;
+}
+
+impl ContentProvider for Vec<u8> {
+    fn open(&self) -> io::Result<Box<dyn io::Read>> {
+        Ok(Box::new(io::Cursor::new(self.clone())))
+    }
+}
+
+/// The contents of the file at the path
+impl ContentProvider for PathBuf {
+    fn open(&self) -> io::Result<Box<dyn io::Read>> {
+        Ok(Box::new(File::open(self)?))
+    }
+}
+
+impl<F: Fn() -> io::Result<Box<dyn io::Read>>> ContentProvider for F {
+    fn open(&self) -> io::Result<Box<dyn io::Read>> {
+        self()
+    }
+}
+
+/// A shared [`ContentProvider`]
+#[derive(Clone)]
+pub struct Contents(Arc<dyn ContentProvider>);
+
+impl Contents {
+    pub fn new<P: ContentProvider + 'static>(provider: P) -> Self {
+        Contents(Arc::new(provider))
+    }
+
+    pub fn open(&self) -> io::Result<Box<dyn io::Read>> {
+        self.0.open()
+    }
+}
+
+impl fmt::Debug for Contents {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Contents").finish_non_exhaustive()
+    }
+}
+
+/// The type of a [`SourceEntry`], and what it contains
+#[derive(Debug, Clone)]
+pub enum SourceKind {
+    Dir,
+    File(Contents),
+    Symlink(BString),
+    BlockDevice { major: u32, minor: u32 },
+    CharDevice { major: u32, minor: u32 },
+    Fifo,
+    Socket,
+}
+
+/// An entry to add with [`Archive::add_entries`]
+#[derive(Debug, Clone)]
+pub struct SourceEntry {
+    /// The path of the entry within the archive, `/` or an empty path for the root directory
+    pub path: BString,
+    pub kind: SourceKind,
+    /// The permissions of the entry, any file type bits are replaced by those of `kind`
+    pub mode: Mode,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: DateTime<Utc>,
+}
+
+impl SourceEntry {
+    /// An entry with the default permissions for its kind, owned by root
+    pub fn new<S: Into<BString>>(path: S, kind: SourceKind) -> Self {
+        let mode = match kind {
+            SourceKind::Dir => super::MODE_DEFAULT_DIRECTORY,
+            SourceKind::Symlink(_) => super::MODE_DEFAULT_SYMLINK,
+            _ => super::MODE_DEFAULT_FILE,
+        };
+        SourceEntry {
+            path: path.into(),
+            kind,
+            mode,
+            uid: 0,
+            gid: 0,
+            mtime: Utc::now(),
+        }
+    }
+
+    pub fn dir<S: Into<BString>>(path: S) -> Self {
+        Self::new(path, SourceKind::Dir)
+    }
+
+    pub fn file<S: Into<BString>, P: ContentProvider + 'static>(path: S, contents: P) -> Self {
+        Self::new(path, SourceKind::File(Contents::new(contents)))
+    }
+
+    pub fn symlink<S: Into<BString>, T: Into<BString>>(path: S, target: T) -> Self {
+        Self::new(path, SourceKind::Symlink(target.into()))
+    }
+
+    fn into_tree_entry(self) -> Entry {
+        let mut entry = Entry {
+            uid: self.uid,
+            gid: self.gid,
+            mode: self.mode.perm(),
+            // The format can't store times before 1970 or after 2106
+            mtime: self.mtime.timestamp().clamp(0, u32::MAX.into()) as u32,
+            contents: Vec::new(),
+            device: (0, 0),
+            link: None,
+            provider: None,
+            xattrs: Vec::new(),
+        };
+        let ty = match self.kind {
+            SourceKind::Dir => Mode::TYPE_DIR,
+            SourceKind::File(contents) => {
+                entry.provider = Some(contents);
+                Mode::TYPE_FILE
+            }
+            SourceKind::Symlink(target) => {
+                entry.contents = target.into();
+                Mode::TYPE_LINK
+            }
+            SourceKind::BlockDevice { major, minor } => {
+                entry.device = (major, minor);
+                Mode::TYPE_BLOCK
+            }
+            SourceKind::CharDevice { major, minor } => {
+                entry.device = (major, minor);
+                Mode::TYPE_CHAR
+            }
+            SourceKind::Fifo => Mode::TYPE_FIFO,
+            SourceKind::Socket => Mode::TYPE_SOCKET,
+        };
+        entry.mode |= ty;
+        entry
+    }
+}
+
+/// Collect `entries` into a tree, see [`Archive::add_entries`]
+pub(super) fn into_tree<I: IntoIterator<Item = SourceEntry>>(entries: I) -> Result<Tree> {
+    let mut tree = Tree::new();
+    for entry in entries {
+        let path = entry.path.clone();
+        tree.insert(&path, entry.into_tree_entry())?;
+    }
+    Ok(tree)
+}
+
+impl<W: io::Write> Archive<W> {
+    /// Add `entries` to the archive, and set the tree they form as the root
+    ///
+    /// Entries may appear in any order. Directories which aren't given entries of their own are
+    /// created with default metadata. A later entry with the same path replaces an earlier one.
+    ///
+    /// ```no_run
+    /// use sqfs::write::{Archive, SourceEntry};
+    ///
+    /// # fn main() -> sqfs::Result<()> {
+    /// let (mut archive, _output) = Archive::in_memory();
+    /// archive.add_entries(vec![
+    ///     SourceEntry::file("etc/hostname", b"box\n".to_vec()),
+    ///     SourceEntry::symlink("bin", "usr/bin"),
+    /// ])?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn add_entries<I: IntoIterator<Item = SourceEntry>>(&mut self, entries: I) -> Result<()> {
+        let root = into_tree(entries)?.build(self)?;
+        self.set_root(root);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::io::Read;
+
+    #[test]
+    fn tree_entries() {
+        let mut file = SourceEntry::file("a/b", b"contents".to_vec());
+        file.mode = Mode::TYPE_DIR | Mode::USER_READ | Mode::USER_WRITE;
+        file.mtime = Utc.timestamp_opt(-5, 0).unwrap();
+        let entry = file.into_tree_entry();
+        assert_eq!(
+            entry.mode,
+            Mode::TYPE_FILE | Mode::USER_READ | Mode::USER_WRITE
+        );
+        assert_eq!(entry.mtime, 0);
+        let mut contents = Vec::new();
+        entry
+            .provider
+            .unwrap()
+            .open()
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents, b"contents");
+
+        let entry = SourceEntry::symlink("link", "target").into_tree_entry();
+        assert_eq!(entry.mode, Mode::TYPE_LINK | Mode::O777);
+        assert_eq!(entry.contents, b"target");
+
+        let device = SourceKind::CharDevice { major: 1, minor: 3 };
+        let entry = SourceEntry::new("dev/null", device).into_tree_entry();
+        assert_eq!(entry.mode, Mode::TYPE_CHAR | Mode::O644);
+        assert_eq!(entry.device, (1, 3));
+    }
+
+    #[test]
+    fn lazy_contents() {
+        let contents = Contents::new(|| -> io::Result<Box<dyn io::Read>> {
+            Err(io::Error::new(io::ErrorKind::NotFound, "unavailable"))
+        });
+        // Creating the entry doesn't open the contents
+        let entry = SourceEntry::new("file", SourceKind::File(contents)).into_tree_entry();
+        let err = entry.provider.unwrap().open().err().unwrap();
+        assert_eq!(err.to_string(), "unavailable");
+    }
+}
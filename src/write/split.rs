@@ -0,0 +1,116 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::split::part_path;
+
+/// A writer which splits its output into files of at most `part_size` bytes
+///
+/// Parts are named after a base path with a numeric extension: `image.000`, `image.001`, and so
+/// on. Use [`read::Chain::open_parts`](crate::read::Chain::open_parts) to read the parts back
+/// as a single archive.
+#[derive(Debug)]
+pub struct SplitFile {
+    base: PathBuf,
+    part_size: u64,
+    parts: Vec<File>,
+    /// The length of the last part
+    last_len: u64,
+    pos: u64,
+}
+
+impl SplitFile {
+    /// Create a split file, with parts named after `base`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `part_size` is zero
+    pub fn create<P: AsRef<Path>>(base: P, part_size: u64) -> io::Result<Self> {
+        assert!(part_size > 0, "part size must not be zero");
+        let mut split = Self {
+            base: base.as_ref().to_owned(),
+            part_size,
+            parts: Vec::new(),
+            last_len: 0,
+            pos: 0,
+        };
+        split.add_part()?;
+        Ok(split)
+    }
+
+    /// The paths of all parts written so far
+    pub fn part_paths(&self) -> Vec<PathBuf> {
+        (0..self.parts.len())
+            .map(|i| part_path(&self.base, i))
+            .collect()
+    }
+
+    fn add_part(&mut self) -> io::Result<()> {
+        let file = File::create(part_path(&self.base, self.parts.len()))?;
+        self.parts.push(file);
+        self.last_len = 0;
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        (self.parts.len() as u64 - 1) * self.part_size + self.last_len
+    }
+
+    /// Flush all parts to durable storage
+    pub fn sync_data(&mut self) -> io::Result<()> {
+        self.parts.iter().try_for_each(File::sync_data)
+    }
+}
+
+impl Write for SplitFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let index = usize::try_from(self.pos / self.part_size).unwrap();
+        let offset = self.pos % self.part_size;
+        while self.parts.len() <= index {
+            // Fill the current last part before moving on, in case we seeked past it
+            let last = self.parts.last_mut().unwrap();
+            last.set_len(self.part_size)?;
+            self.add_part()?;
+        }
+
+        let len = buf.len().min((self.part_size - offset) as usize);
+        let part = &mut self.parts[index];
+        part.seek(SeekFrom::Start(offset))?;
+        let written = part.write(&buf[..len])?;
+        self.pos += written as u64;
+        if index == self.parts.len() - 1 {
+            self.last_len = self.last_len.max(offset + written as u64);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.parts.iter_mut().try_for_each(File::flush)
+    }
+}
+
+impl Seek for SplitFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => add_signed(self.len(), delta),
+            SeekFrom::Current(delta) => add_signed(self.pos, delta),
+        };
+        self.pos = new_pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+fn add_signed(base: u64, delta: i64) -> Option<u64> {
+    if delta >= 0 {
+        base.checked_add(delta as u64)
+    } else {
+        base.checked_sub(delta.unsigned_abs())
+    }
+}
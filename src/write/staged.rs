@@ -0,0 +1,629 @@
+//! Building an image in two stages, so an interrupted build can resume
+//!
+//! Writing the data blocks of a large tree can take hours, and starting over after a crash
+//! wastes all of them. Instead, a [`BuildPlan`] reads every entry once up front, and can be
+//! saved, then [`Archive::add_planned`] writes the data blocks of the planned files, recording
+//! its progress in a [`BuildState`] directory, before adding the entries. Building again with
+//! the same plan and state skips every file written before the last checkpoint.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use bstr::{BString, ByteSlice};
+use slog::Logger;
+use xxhash_rust::xxh3::Xxh3;
+
+use super::inode::FileData;
+use super::source::{self, SourceEntry};
+use super::tree::{Entry, Node, Tree};
+use super::Archive;
+use crate::errors::{Result, WriteError};
+use crate::read::{child_path, escape_into, unescape};
+use crate::Mode;
+
+/// The first line of a plan file
+const PLAN_HEADER: &str = "sqfs-build-plan 1";
+
+/// The first line of a state log, followed by the fingerprint of the build it's for
+const STATE_HEADER: &str = "sqfs-build-state 1";
+
+/// The contents of a build, worked out before any of it is written
+///
+/// Every entry is recorded with its path, the inode number it will be given, and the size and
+/// hash of a file's contents. A saved plan is text: a header line, then a line per entry, in
+/// the order of their inode numbers, which is also the order the files' contents are written
+/// in:
+///
+/// ```text
+/// sqfs-build-plan 1
+/// 1 5 0123456789abcdef0123456789abcdef /etc/hostname
+/// 2 0 - /etc
+/// 3 0 - /
+/// ```
+///
+/// Files have their size and 128 bit xxh3 hash, other entries `-`. Paths are escaped as in an
+/// [extraction journal](crate::read::ExtractJournal).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildPlan {
+    entries: Vec<PlannedEntry>,
+}
+
+/// An entry of a [`BuildPlan`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedEntry {
+    /// The absolute path of the entry in the archive
+    pub path: BString,
+    pub inode_number: u32,
+    /// The size of a regular file's contents, 0 for other entries
+    pub size: u64,
+    /// The 128 bit xxh3 hash of a regular file's contents, `None` for other entries
+    pub hash: Option<u128>,
+}
+
+/// Where a planned build records its progress, see [`Archive::add_planned`]
+///
+/// The state directory holds the data blocks written so far, in place of the temporary file
+/// they're usually spooled to, and a log of the files and fragment blocks written. Every
+/// [`checkpoint_interval`](Self::checkpoint_interval) bytes, the current fragment block is
+/// written, both are synced, and a checkpoint is logged. Anything after the last checkpoint is
+/// discarded when resuming, so a file is only skipped if its blocks, and those of every file
+/// before it, are known to be on disk. A file is only logged once all of it is written, so one
+/// interrupted part way through is written again from its start.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildState {
+    /// The state directory, which is created if it doesn't exist
+    pub dir: PathBuf,
+    /// How many bytes of data blocks are written between checkpoints
+    pub checkpoint_interval: u64,
+}
+
+impl BuildState {
+    /// Keep the state in `dir`, checkpointing every 64 MiB
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        BuildState {
+            dir: dir.into(),
+            checkpoint_interval: 64 << 20,
+        }
+    }
+}
+
+impl BuildPlan {
+    /// Plan the build of `entries`, reading the contents of every file to hash it
+    ///
+    /// Entries are combined into a tree as [`Archive::add_entries`] does.
+    pub fn new<I: IntoIterator<Item = SourceEntry>>(entries: I) -> Result<Self> {
+        let tree = source::into_tree(entries)?;
+        let mut planned = Vec::new();
+        for (number, (path, node)) in (1..).zip(nodes(&tree)) {
+            let (size, hash) = match file_entry(node) {
+                Some(entry) => {
+                    let mut contents = HashingReader::new(open(entry)?);
+                    io::copy(&mut contents, &mut io::sink())?;
+                    (contents.len, Some(contents.hasher.digest128()))
+                }
+                None => (0, None),
+            };
+            planned.push(PlannedEntry {
+                path,
+                inode_number: number,
+                size,
+                hash,
+            });
+        }
+        Ok(BuildPlan { entries: planned })
+    }
+
+    /// Every entry, in the order of their inode numbers
+    pub fn entries(&self) -> &[PlannedEntry] {
+        &self.entries
+    }
+
+    /// Read a plan saved with [`save`](Self::save)
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let text = fs::read(path)?;
+        // Not `lines`, which would also strip a carriage return ending a path
+        let mut lines = text.split_str("\n");
+        if lines.next() != Some(PLAN_HEADER.as_bytes()) {
+            return Err(WriteError::InvalidBuildPlan { line: 1 }.into());
+        }
+        let entries = lines
+            .enumerate()
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(i, line)| {
+                parse_entry(line).ok_or_else(|| WriteError::InvalidBuildPlan { line: i + 2 }.into())
+            })
+            .collect::<Result<_>>()?;
+        Ok(BuildPlan { entries })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut text = format!("{}\n", PLAN_HEADER).into_bytes();
+        for entry in &self.entries {
+            let fields = match entry.hash {
+                Some(hash) => format!("{} {} {:032x} ", entry.inode_number, entry.size, hash),
+                None => format!("{} {} - ", entry.inode_number, entry.size),
+            };
+            text.extend_from_slice(fields.as_bytes());
+            escape_into(&entry.path, &mut text);
+            text.push(b'\n');
+        }
+        text
+    }
+}
+
+fn parse_entry(line: &[u8]) -> Option<PlannedEntry> {
+    let mut fields = line.splitn_str(4, " ");
+    let mut number = || -> Option<u64> { fields.next()?.to_str().ok()?.parse().ok() };
+    let inode_number = number()?.try_into().ok()?;
+    let size = number()?;
+    let hash = match fields.next()? {
+        b"-" => None,
+        hash => Some(u128::from_str_radix(hash.to_str().ok()?, 16).ok()?),
+    };
+    Some(PlannedEntry {
+        path: unescape(fields.next()?)?,
+        inode_number,
+        size,
+        hash,
+    })
+}
+
+impl<W: io::Write> Archive<W> {
+    /// Add `entries` to the archive as planned by `plan`, recording the progress of writing
+    /// the files' contents in `state`, so an interrupted build can resume where it left off
+    ///
+    /// The entries must be the ones the plan was made from, and the archive must be empty.
+    /// Each file is checked against the plan as it's written, so files whose contents have
+    /// changed since are reported as a mismatch, and their blocks discarded, leaving the data
+    /// of the files before them. The state directory can be removed once the archive is
+    /// flushed.
+    ///
+    /// Entries skipped by the archive's [`LimitPolicy`](crate::config::LimitPolicy) are skipped
+    /// after their contents are written, and leave the other entries with different inode
+    /// numbers than planned.
+    pub fn add_planned<I>(&mut self, plan: &BuildPlan, entries: I, state: &BuildState) -> Result<()>
+    where
+        I: IntoIterator<Item = SourceEntry>,
+    {
+        if !self.items.is_empty() || self.plan.data_len() != 0 {
+            return Err(WriteError::PlanNotFirst.into());
+        }
+        let tree = source::into_tree(entries)?;
+        let nodes = nodes(&tree);
+        for (i, (path, node)) in nodes.iter().enumerate() {
+            let matches = plan.entries.get(i).is_some_and(|planned| {
+                planned.path == *path && planned.hash.is_some() == file_entry(node).is_some()
+            });
+            if !matches {
+                return Err(WriteError::PlanMismatch(path.clone()).into());
+            }
+        }
+        if let Some(extra) = plan.entries.get(nodes.len()) {
+            return Err(WriteError::PlanMismatch(extra.path.clone()).into());
+        }
+
+        let mut fingerprint = Xxh3::new();
+        fingerprint.update(&plan.to_bytes());
+        fingerprint.update(format!("{} {:?}", self.block_size, self.compressor_kind).as_bytes());
+        // The options come before the data blocks, so they move every block with them
+        fingerprint.update(&self.plan.compression_options);
+        let (mut log, data, fragments) =
+            StateLog::open(state, fingerprint.digest128(), &self.logger)?;
+        self.plan.persist_data(data, log.checkpointed)?;
+        for entry in fragments {
+            self.fragments.add_fragment(entry.start, entry.size);
+        }
+
+        let mut written = HashMap::new();
+        let files = nodes
+            .into_iter()
+            .zip(&plan.entries)
+            .filter_map(|((path, node), planned)| Some((path, file_entry(node)?, planned)));
+        for (i, (path, entry, planned)) in files.enumerate() {
+            if let Some(file) = log.files.get(i) {
+                written.insert(path, file.clone().into());
+                continue;
+            }
+            // Reading one byte more than planned is enough to tell the file has grown
+            let mut contents = HashingReader::new(open(entry)?.take(planned.size + 1));
+            let data_len = self.plan.data_len();
+            let blocks = self.write_blocks(&[], &mut contents)?;
+            if contents.len != planned.size || Some(contents.hasher.digest128()) != planned.hash {
+                // Its tail isn't in a fragment yet, so only its blocks need discarding
+                self.plan.truncate_data(data_len)?;
+                return Err(WriteError::PlanMismatch(path).into());
+            }
+            let file = self.write_tail(blocks)?;
+            log.files.push(file.clone());
+            written.insert(path, file.into());
+            if self.plan.data_len() - log.checkpointed >= state.checkpoint_interval {
+                self.checkpoint(&mut log)?;
+            }
+        }
+        self.checkpoint(&mut log)?;
+
+        let root = tree.build_written(self, written)?;
+        self.set_root(root);
+        Ok(())
+    }
+
+    /// Write the current fragment block, and record everything written so far as complete
+    fn checkpoint(&mut self, log: &mut StateLog) -> Result<()> {
+        self.flush_fragment()?;
+        self.plan.sync_data()?;
+        log.checkpoint(self.fragments.entries(), self.plan.data_len())
+    }
+}
+
+/// The log of a planned build's progress, see [`BuildState`]
+struct StateLog {
+    file: File,
+    /// The data of every file written, in the order they're planned
+    files: Vec<FileData>,
+    /// The number of files and fragment blocks logged
+    logged_files: usize,
+    logged_fragments: usize,
+    /// The length of the data blocks at the last checkpoint
+    checkpointed: u64,
+}
+
+impl StateLog {
+    /// Open the state in `state`, returning the log, the file the data blocks are spooled to,
+    /// and the fragment blocks already written
+    ///
+    /// The log is rewritten with only what the last checkpoint covered. State left by a build
+    /// with a different `fingerprint` is discarded.
+    fn open(
+        state: &BuildState,
+        fingerprint: u128,
+        logger: &Logger,
+    ) -> Result<(Self, File, Vec<repr::fragment::Entry>)> {
+        fs::create_dir_all(&state.dir)?;
+        let log_path = state.dir.join("state");
+        let header = format!("{} {:032x}", STATE_HEADER, fingerprint);
+        let (mut files, mut fragments, mut checkpointed) = (Vec::new(), Vec::new(), 0);
+        match fs::read(&log_path) {
+            Ok(text) if text.split_str("\n").next() == Some(header.as_bytes()) => {
+                (files, fragments, checkpointed) = read_log(&text)?;
+            }
+            Ok(_) => slog::info!(logger, "Discarding the state of a different build"),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let data = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(state.dir.join("data"))?;
+        if data.metadata()?.len() < checkpointed {
+            slog::warn!(logger, "Data blocks are missing, starting over");
+            files.clear();
+            fragments.clear();
+            checkpointed = 0;
+        }
+        if !files.is_empty() {
+            slog::info!(logger, "Resuming build"; "files" => files.len());
+        }
+
+        // Rewritten in full, so records past the last checkpoint can't be mistaken for new ones
+        let mut log = StateLog {
+            file: File::create(state.dir.join("state.new"))?,
+            files,
+            logged_files: 0,
+            logged_fragments: 0,
+            checkpointed: 0,
+        };
+        writeln!(log.file, "{}", header)?;
+        log.checkpoint(&fragments, checkpointed)?;
+        fs::rename(state.dir.join("state.new"), &log_path)?;
+        Ok((log, data, fragments))
+    }
+
+    /// Log the files and fragment blocks written since the last checkpoint, which have been
+    /// synced, along with `data_len`, the length of the data blocks
+    fn checkpoint(&mut self, fragments: &[repr::fragment::Entry], data_len: u64) -> Result<()> {
+        let mut text = String::new();
+        for entry in &fragments[self.logged_fragments..] {
+            text += &format!("fragment {} {}\n", { entry.start.0 }, { entry.size.0 });
+        }
+        for file in &self.files[self.logged_files..] {
+            text += &format!(
+                "file {} {} {} {} {} ",
+                { file.blocks_start.0 },
+                file.file_size,
+                file.sparse_bytes,
+                { file.fragment_block_idx.0 },
+                file.fragment_offset
+            );
+            if file.block_sizes.is_empty() {
+                text += "-";
+            }
+            let sizes: Vec<_> = file.block_sizes.iter().map(u32::to_string).collect();
+            text += &sizes.join(",");
+            text += "\n";
+        }
+        text += &format!("checkpoint {}\n", data_len);
+        self.file.write_all(text.as_bytes())?;
+        self.file.sync_data()?;
+        self.logged_fragments = fragments.len();
+        self.logged_files = self.files.len();
+        self.checkpointed = data_len;
+        Ok(())
+    }
+}
+
+type Checkpointed = (Vec<FileData>, Vec<repr::fragment::Entry>, u64);
+
+/// Read the files and fragment blocks of a log up to its last checkpoint, and the length of
+/// the data blocks then
+fn read_log(text: &[u8]) -> Result<Checkpointed> {
+    let (mut files, mut fragments) = (Vec::new(), Vec::new());
+    let mut checkpoint = (0, 0, 0);
+    let mut lines: Vec<_> = text.split_str("\n").collect();
+    // A crash can leave the last line unfinished, and it isn't needed without a checkpoint after
+    lines.pop();
+    for (i, line) in lines.into_iter().enumerate().skip(1) {
+        let invalid = || WriteError::InvalidBuildState { line: i + 1 };
+        let mut fields = line.split_str(" ");
+        let kind = fields.next();
+        let mut number = || -> Option<u64> { fields.next()?.to_str().ok()?.parse().ok() };
+        match kind {
+            Some(b"fragment") => {
+                let (start, size) = number().zip(number()).ok_or_else(invalid)?;
+                let size = repr::datablock::Size(size.try_into().map_err(|_| invalid())?);
+                fragments.push(repr::fragment::Entry::new(
+                    repr::datablock::Ref(start),
+                    size,
+                ));
+            }
+            Some(b"file") => files.push(parse_file(line).ok_or_else(invalid)?),
+            Some(b"checkpoint") => {
+                let data_len = number().ok_or_else(invalid)?;
+                checkpoint = (files.len(), fragments.len(), data_len);
+            }
+            _ => return Err(invalid().into()),
+        }
+    }
+    files.truncate(checkpoint.0);
+    fragments.truncate(checkpoint.1);
+    Ok((files, fragments, checkpoint.2))
+}
+
+fn parse_file(line: &[u8]) -> Option<FileData> {
+    let mut fields = line.split_str(" ").skip(1);
+    let mut number = || -> Option<u64> { fields.next()?.to_str().ok()?.parse().ok() };
+    let blocks_start = repr::datablock::Ref(number()?);
+    let file_size = number()?;
+    let sparse_bytes = number()?;
+    let fragment_block_idx = repr::fragment::Idx(number()?.try_into().ok()?);
+    let fragment_offset = number()?.try_into().ok()?;
+    let block_sizes = match line.split_str(" ").nth(6)? {
+        b"-" => Vec::new(),
+        sizes => sizes
+            .split_str(",")
+            .map(|size| size.to_str().ok()?.parse().ok())
+            .collect::<Option<_>>()?,
+    };
+    Some(FileData {
+        blocks_start,
+        file_size,
+        sparse_bytes,
+        fragment_block_idx,
+        fragment_offset,
+        block_sizes,
+    })
+}
+
+/// Every node of `tree` with its path, in the order they're numbered: the contents of each
+/// directory in name order, then the directory
+fn nodes(tree: &Tree) -> Vec<(BString, &Node)> {
+    fn visit<'a>(path: BString, node: &'a Node, nodes: &mut Vec<(BString, &'a Node)>) {
+        for (name, child) in &node.children {
+            visit(child_path(&path, name), child, nodes);
+        }
+        nodes.push((path, node));
+    }
+    let mut nodes = Vec::new();
+    visit("/".into(), tree.root(), &mut nodes);
+    nodes
+}
+
+/// The entry of `node`, if it's a regular file
+fn file_entry(node: &Node) -> Option<&Entry> {
+    node.entry
+        .as_ref()
+        .filter(|entry| entry.mode.ty() == Mode::TYPE_FILE)
+}
+
+fn open(entry: &Entry) -> io::Result<Box<dyn io::Read>> {
+    match &entry.provider {
+        Some(provider) => provider.open(),
+        None => Ok(Box::new(io::Cursor::new(entry.contents.clone()))),
+    }
+}
+
+/// Hashes and counts everything read through it
+struct HashingReader<R> {
+    inner: R,
+    hasher: Xxh3,
+    len: u64,
+}
+
+impl<R> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            hasher: Xxh3::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.len += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write::{ArchiveBuilder, Contents, SourceKind};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn entries(contents: &[(&str, &[u8])]) -> Vec<SourceEntry> {
+        contents
+            .iter()
+            .map(|&(path, contents)| SourceEntry::file(path, contents.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn plan() {
+        let dir = tempfile::tempdir().unwrap();
+        let plan = BuildPlan::new(entries(&[("b/c", b"c"), ("a", b"aa"), ("b/\nd", b"")])).unwrap();
+        let summary: Vec<_> = plan
+            .entries()
+            .iter()
+            .map(|entry| (entry.inode_number, entry.path.to_str().unwrap(), entry.size))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (1, "/a", 2),
+                (2, "/b/\nd", 0),
+                (3, "/b/c", 1),
+                (4, "/b", 0),
+                (5, "/", 0)
+            ]
+        );
+        assert_eq!(
+            plan.entries()[0].hash,
+            Some(xxhash_rust::xxh3::xxh3_128(b"aa"))
+        );
+        assert_eq!(plan.entries()[3].hash, None);
+
+        let path = dir.path().join("plan");
+        plan.save(&path).unwrap();
+        assert_eq!(BuildPlan::load(&path).unwrap(), plan);
+        fs::write(&path, "sqfs-build-plan 1\n1 2 - /a\nnonsense\n").unwrap();
+        let err = BuildPlan::load(&path).unwrap_err().to_string();
+        assert!(err.contains("line 3"), "{}", err);
+    }
+
+    #[test]
+    fn resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = BuildState::new(dir.path());
+        state.checkpoint_interval = 1;
+        let big = crate::testing::compressible_contents();
+        let opened = Arc::new(AtomicUsize::new(0));
+        let fail = Arc::new(AtomicBool::new(true));
+        let source = || {
+            let (big, opened, fail) = (big.clone(), opened.clone(), fail.clone());
+            let a = move || -> io::Result<Box<dyn io::Read>> {
+                opened.fetch_add(1, Ordering::SeqCst);
+                Ok(Box::new(io::Cursor::new(big.clone())))
+            };
+            let c = move || -> io::Result<Box<dyn io::Read>> {
+                match fail.load(Ordering::SeqCst) {
+                    true => Err(io::Error::other("interrupted")),
+                    false => Ok(Box::new(io::Cursor::new(b"c".to_vec()))),
+                }
+            };
+            vec![
+                SourceEntry::new("a", SourceKind::File(Contents::new(a))),
+                SourceEntry::file("b", b"small".to_vec()),
+                SourceEntry::new("c", SourceKind::File(Contents::new(c))),
+            ]
+        };
+        fail.store(false, Ordering::SeqCst);
+        let plan = BuildPlan::new(source()).unwrap();
+        fail.store(true, Ordering::SeqCst);
+        opened.store(0, Ordering::SeqCst);
+
+        let mut builder = ArchiveBuilder::new();
+        builder.block_size = 4096;
+        let (mut archive, _) = builder.clone().build_in_memory();
+        let err = archive.add_planned(&plan, source(), &state).unwrap_err();
+        assert!(err.to_string().contains("interrupted"), "{}", err);
+        drop(archive);
+        assert_eq!(opened.load(Ordering::SeqCst), 1);
+
+        fail.store(false, Ordering::SeqCst);
+        let (mut archive, image) = builder.build_in_memory();
+        archive.add_planned(&plan, source(), &state).unwrap();
+        archive.flush().unwrap();
+        // The contents of `a` were written before the crash, and not read again
+        assert_eq!(opened.load(Ordering::SeqCst), 1);
+
+        let image = image.open().unwrap();
+        for (path, contents) in [("a", &big[..]), ("b", &b"small"[..]), ("c", &b"c"[..])] {
+            let inode = image.lookup(path).unwrap();
+            assert_eq!(image.read_file(&inode).unwrap(), contents, "{}", path);
+        }
+        for planned in plan.entries() {
+            let inode = image.lookup(&planned.path).unwrap();
+            assert_eq!(
+                inode.inode_number(),
+                planned.inode_number,
+                "{}",
+                planned.path
+            );
+        }
+    }
+
+    #[test]
+    fn changed_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = BuildState::new(dir.path());
+        let plan = BuildPlan::new(entries(&[("a", b"before")])).unwrap();
+        let (mut archive, _) = crate::write::Archive::in_memory();
+        let err = archive
+            .add_planned(&plan, entries(&[("a", b"after!")]), &state)
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("\"/a\" doesn't match the build plan"));
+        assert_eq!(archive.plan.data_len(), 0, "nothing was written");
+        assert!(archive.fragment.is_empty());
+
+        // The blocks of a larger file are discarded once it's found to have changed
+        let big: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let mut changed = big.clone();
+        changed[1000] ^= 1;
+        let plan = BuildPlan::new(entries(&[("a", b"before"), ("b", &big)])).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let state = BuildState::new(dir.path());
+        let (mut archive, _) = crate::write::Archive::in_memory();
+        let err = archive
+            .add_planned(&plan, entries(&[("a", b"before"), ("b", &changed)]), &state)
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("\"/b\" doesn't match the build plan"));
+        assert_eq!(archive.plan.data_len(), 0, "the blocks of b were discarded");
+        assert_eq!(archive.fragment, b"before", "a is kept");
+        let (mut archive, _) = crate::write::Archive::in_memory();
+        let err = archive
+            .add_planned(&plan, entries(&[("b", b"before")]), &state)
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("\"/b\" doesn't match the build plan"));
+    }
+}
@@ -0,0 +1,337 @@
+//! Writing the data blocks of a file read from a stream
+//!
+//! The contents of a [`FileBuilder`](super::FileBuilder) can be any `io::Read`, including pipes
+//! and stdin, whose length isn't known until they end. Each block is sent to be compressed as
+//! soon as it is full, so only the blocks being compressed are buffered, however long the
+//! stream. What can only be known once the stream ends, the file size, the number of sparse
+//! bytes and the tail left over for a fragment, is collected in a [`StreamedFile`], and decides
+//! whether the file needs an extended inode.
+
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+
+use repr::datablock::Size;
+use repr::offset::ArchiveOffset;
+
+use super::inode::FileData;
+use crate::compress_threads::{ParallelCompressor, Response};
+
+/// The data blocks of a file, once its stream has ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct StreamedFile {
+    pub blocks_start: ArchiveOffset,
+    pub file_size: u64,
+    /// The number of bytes in blocks which were all zeros, and weren't written
+    pub sparse_bytes: u64,
+    pub block_sizes: Vec<Size>,
+    /// The end of the file which didn't fill a block, to store in a fragment
+    pub tail: Vec<u8>,
+}
+
+impl StreamedFile {
+    /// The number of bytes the data blocks take in the output
+    pub fn stored_size(&self) -> u64 {
+        self.block_sizes
+            .iter()
+            .map(|size| u64::from(size.size()))
+            .sum()
+    }
+
+    /// The file's inode data, with its tail at `offset` in the fragment block `fragment`, or
+    /// without a fragment if it has no tail
+    pub fn file_data(&self, fragment: Option<(repr::fragment::Idx, u32)>) -> FileData {
+        let (fragment_block_idx, fragment_offset) =
+            fragment.unwrap_or((repr::fragment::Idx(u32::MAX), 0));
+        FileData {
+            blocks_start: repr::datablock::Ref(self.blocks_start.0),
+            file_size: self.file_size,
+            sparse_bytes: self.sparse_bytes,
+            fragment_block_idx,
+            fragment_offset,
+            block_sizes: self.block_sizes.iter().map(|size| size.0).collect(),
+        }
+    }
+}
+
+/// Write the contents of `reader` to `out` as data blocks of `block_size` bytes, starting at
+/// the offset `blocks_start` of the archive
+///
+/// Blocks are compressed on the threads of `compressor`, or stored uncompressed without one.
+/// Blocks of all zeros are left out as sparse blocks. If `fragment_tail` is set, a last block
+/// shorter than `block_size` is returned as the tail rather than written.
+///
+/// Up to `read_ahead` blocks are read while earlier blocks are compressed, so a single large
+/// file keeps every thread busy: at least the number of threads is a good choice. Blocks are
+/// still written in order.
+pub(crate) fn write_blocks_parallel<R, W>(
+    reader: &mut R,
+    out: &mut W,
+    blocks_start: ArchiveOffset,
+    block_size: u32,
+    compressor: Option<&ParallelCompressor>,
+    read_ahead: usize,
+    fragment_tail: bool,
+) -> io::Result<StreamedFile>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+{
+    let block_size = block_size as usize;
+    let mut file = StreamedFile {
+        blocks_start,
+        file_size: 0,
+        sparse_bytes: 0,
+        block_sizes: Vec::new(),
+        tail: Vec::new(),
+    };
+    // Without a compressor, blocks are written as they're read
+    let mut ordered = compressor.map(ParallelCompressor::ordered);
+    for seq in 0.. {
+        let mut block = vec![0; block_size];
+        let len = read_block(reader, &mut block)?;
+        if len == 0 {
+            break;
+        }
+        block.truncate(len);
+        file.file_size += len as u64;
+        if len < block_size && fragment_tail {
+            file.tail = block;
+            break;
+        }
+
+        let sparse = block.iter().all(|&b| b == 0);
+        if sparse {
+            file.sparse_bytes += len as u64;
+        }
+        match &mut ordered {
+            Some(ordered) => {
+                if sparse {
+                    ordered.skip(seq);
+                } else {
+                    ordered.submit(seq, block);
+                }
+                if ordered.pending() > read_ahead {
+                    let (_, response) = ordered.drain().next().unwrap();
+                    write_response(out, &mut file, response)?;
+                }
+            }
+            None if sparse => file.block_sizes.push(Size::ZERO),
+            None => {
+                out.write_all(&block)?;
+                file.block_sizes
+                    .push(Size::new(len.try_into().unwrap(), true));
+            }
+        }
+        if len < block_size {
+            break;
+        }
+    }
+    if let Some(ordered) = &mut ordered {
+        for (_, response) in ordered.drain() {
+            write_response(out, &mut file, response)?;
+        }
+    }
+    Ok(file)
+}
+
+/// Write a block returned by [`Ordered::drain`](crate::compress_threads::Ordered::drain) to
+/// `out`, and record its size in `file`, or record a sparse block if it was skipped
+fn write_response<W: Write + ?Sized>(
+    out: &mut W,
+    file: &mut StreamedFile,
+    response: Option<Response>,
+) -> io::Result<()> {
+    let size = match response {
+        Some(response) => {
+            out.write_all(&response.data)?;
+            Size::new(
+                response.data.len().try_into().unwrap(),
+                !response.compressed,
+            )
+        }
+        None => Size::ZERO,
+    };
+    file.block_sizes.push(size);
+    Ok(())
+}
+
+/// Fill `block` from `reader`, returning less than its length only at the end of the stream
+///
+/// Pipes return whatever has been written to them so far, so a single read is often short.
+pub(crate) fn read_block<R: Read + ?Sized>(reader: &mut R, block: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < block.len() {
+        match reader.read(&mut block[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::{compress_or_copy, AnyCodec, Compressor};
+
+    /// The blocks [`write_blocks_parallel`] should write, compressing one block at a time on this
+    /// thread
+    fn write_blocks<R, W, C>(
+        reader: &mut R,
+        out: &mut W,
+        blocks_start: ArchiveOffset,
+        block_size: u32,
+        mut compressor: Option<&mut C>,
+        fragment_tail: bool,
+    ) -> io::Result<StreamedFile>
+    where
+        R: Read + ?Sized,
+        W: Write + ?Sized,
+        C: Compressor + ?Sized,
+    {
+        let block_size = block_size as usize;
+        let mut file = StreamedFile {
+            blocks_start,
+            file_size: 0,
+            sparse_bytes: 0,
+            block_sizes: Vec::new(),
+            tail: Vec::new(),
+        };
+        let mut block = vec![0; block_size];
+        let mut compressed = vec![0; block_size];
+        loop {
+            let len = read_block(reader, &mut block)?;
+            if len == 0 {
+                break;
+            }
+            let data = &block[..len];
+            file.file_size += len as u64;
+            if len < block_size && fragment_tail {
+                file.tail = data.to_vec();
+                break;
+            }
+
+            if data.iter().all(|&b| b == 0) {
+                file.sparse_bytes += len as u64;
+                file.block_sizes.push(Size::ZERO);
+            } else {
+                let (stored, is_compressed) = match &mut compressor {
+                    Some(compressor) => compress_or_copy(&mut **compressor, data, &mut compressed),
+                    None => {
+                        compressed[..len].copy_from_slice(data);
+                        (len, false)
+                    }
+                };
+                out.write_all(&compressed[..stored])?;
+                file.block_sizes
+                    .push(Size::new(stored.try_into().unwrap(), !is_compressed));
+            }
+            if len < block_size {
+                break;
+            }
+        }
+        Ok(file)
+    }
+
+    /// Returns at most 100 bytes from each read, like a pipe
+    struct Trickle<R>(R);
+
+    impl<R: Read> Read for Trickle<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(100);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn short_reads() {
+        let mut contents = crate::testing::compressible_contents();
+        contents[4096..8192].fill(0);
+        let mut out = Vec::new();
+        let codec = AnyCodec::new(crate::compression::Kind::default());
+        let compressor = ParallelCompressor::with_threads(codec, 2);
+        let mut reader = Trickle(&contents[..]);
+        let file = write_blocks_parallel(
+            &mut reader,
+            &mut out,
+            ArchiveOffset(96),
+            4096,
+            Some(&compressor),
+            2,
+            true,
+        )
+        .unwrap();
+
+        assert_eq!(file.file_size, contents.len() as u64);
+        assert_eq!(file.sparse_bytes, 4096);
+        assert_eq!(file.block_sizes.len(), 3);
+        assert_eq!(file.block_sizes[1], Size::ZERO);
+        assert!(!file.block_sizes[0].uncompressed());
+        assert_eq!(file.tail, contents[3 * 4096..]);
+        assert_eq!(file.stored_size(), out.len() as u64);
+
+        let data = file.file_data(None);
+        assert_eq!({ data.blocks_start.0 }, 96);
+        assert_eq!({ data.fragment_block_idx.0 }, u32::MAX);
+    }
+
+    #[test]
+    fn uncompressed_tail_block() {
+        let contents = vec![1; 4096 + 10];
+        let mut out = Vec::new();
+        let mut reader = &contents[..];
+        let file = write_blocks_parallel(
+            &mut reader,
+            &mut out,
+            ArchiveOffset(0),
+            4096,
+            None,
+            1,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            file.block_sizes,
+            [Size::new(4096, true), Size::new(10, true)]
+        );
+        assert!(file.tail.is_empty());
+        assert_eq!(out, contents);
+    }
+
+    #[test]
+    fn parallel_matches() {
+        let mut contents = crate::testing::compressible_contents();
+        contents[4096..8192].fill(0);
+        let codec = AnyCodec::new(crate::compression::Kind::default());
+        let compressor = ParallelCompressor::with_threads(codec.clone(), 2);
+
+        let mut out = Vec::new();
+        let mut reader = Trickle(&contents[..]);
+        let file = write_blocks_parallel(
+            &mut reader,
+            &mut out,
+            ArchiveOffset(96),
+            4096,
+            Some(&compressor),
+            2,
+            true,
+        )
+        .unwrap();
+
+        let mut serial_out = Vec::new();
+        let mut codec = codec;
+        let serial = write_blocks(
+            &mut &contents[..],
+            &mut serial_out,
+            ArchiveOffset(96),
+            4096,
+            Some(&mut codec),
+            true,
+        )
+        .unwrap();
+        assert_eq!(file, serial);
+        assert_eq!(out, serial_out);
+    }
+}
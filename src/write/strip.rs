@@ -0,0 +1,161 @@
+use std::io;
+
+use chrono::{TimeZone, Utc};
+
+use super::tree::Tree;
+use super::{Archive, ArchiveBuilder};
+use crate::compression;
+use crate::errors::Result;
+use crate::read::{self, ReadAt};
+
+/// What [`strip`] removes from an archive
+///
+/// Extended attributes are always removed. By default everything else is removed too, and the
+/// compression is kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StripOptions {
+    /// Set every time to the epoch, including the archive's modification time
+    pub times: bool,
+    /// Make everything owned by root
+    pub ids: bool,
+    /// Recompress with this algorithm, instead of the one the source archive uses
+    pub compression: Option<compression::Kind>,
+}
+
+impl Default for StripOptions {
+    fn default() -> Self {
+        StripOptions {
+            times: true,
+            ids: true,
+            compression: None,
+        }
+    }
+}
+
+impl StripOptions {
+    /// A builder for the stripped copy of `source`, with the same block size
+    pub fn archive_builder<R: ReadAt>(&self, source: &read::Archive<R>) -> ArchiveBuilder {
+        let mut builder = ArchiveBuilder::new();
+        builder.block_size = source.block_size();
+        builder.compressor_kind = self
+            .compression
+            .unwrap_or_else(|| source.compression().kind());
+        let mtime = if self.times {
+            0
+        } else {
+            source.superblock().modification_time.0
+        };
+        builder.set_modification_time(Utc.timestamp_opt(mtime.into(), 0).unwrap());
+        builder
+    }
+}
+
+/// Add the contents of `source` to `archive`, without the metadata `options` strips, and set it
+/// as the root
+///
+/// File contents are read from `source` as they're added. Hard links are kept.
+///
+/// ```no_run
+/// use sqfs::read;
+/// use sqfs::write::{self, StripOptions};
+///
+/// # fn main() -> sqfs::Result<()> {
+/// let source = read::Archive::open("image.sqfs")?;
+/// let options = StripOptions::default();
+/// let mut archive = options.archive_builder(&source).build(std::fs::File::create("stripped.sqfs")?);
+/// write::strip(&mut archive, &source, &options)?;
+/// archive.flush()?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn strip<R, W>(
+    archive: &mut Archive<W>,
+    source: &read::Archive<R>,
+    options: &StripOptions,
+) -> Result<()>
+where
+    R: ReadAt + 'static,
+    W: io::Write,
+{
+    let root = stripped_tree(source, options)?.build(archive)?;
+    archive.set_root(root);
+    Ok(())
+}
+
+fn stripped_tree<R: ReadAt + 'static>(
+    source: &read::Archive<R>,
+    options: &StripOptions,
+) -> Result<Tree> {
+    let mut tree = Tree::new();
+    tree.add_archive(source, |tree, path, mut entry| {
+        if options.ids {
+            entry.uid = 0;
+            entry.gid = 0;
+        }
+        if options.times {
+            entry.mtime = 0;
+        }
+        tree.insert(&path, entry)
+    })?;
+    Ok(tree)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    use crate::testing::ImageBuilder;
+    use crate::Mode;
+
+    #[test]
+    fn strip_metadata() {
+        let image = ImageBuilder::new()
+            .file("a", "contents")
+            .owner("a", 1000, 100)
+            .mtime("a", 1_600_000_000)
+            .hard_link("dir/b", "a")
+            .char_device("null", 1, 3)
+            .build();
+        let source = read::Archive::new(image).unwrap();
+
+        let mut tree = stripped_tree(&source, &StripOptions::default()).unwrap();
+        let a = tree.get_mut(b"a").unwrap().unwrap().entry.clone().unwrap();
+        assert_eq!((a.uid, a.gid, a.mtime), (0, 0, 0));
+        let b = tree
+            .get_mut(b"dir/b")
+            .unwrap()
+            .unwrap()
+            .entry
+            .clone()
+            .unwrap();
+        assert_eq!(a.link, b.link);
+        assert!(a.link.is_some());
+        let mut contents = String::new();
+        a.provider
+            .unwrap()
+            .open()
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "contents");
+        let null = tree
+            .get_mut(b"null")
+            .unwrap()
+            .unwrap()
+            .entry
+            .clone()
+            .unwrap();
+        assert_eq!(null.mode, Mode::TYPE_CHAR | Mode::O644);
+        assert_eq!(null.device, (1, 3));
+
+        let options = StripOptions {
+            times: false,
+            ids: false,
+            compression: None,
+        };
+        let mut tree = stripped_tree(&source, &options).unwrap();
+        let a = tree.get_mut(b"a").unwrap().unwrap().entry.clone().unwrap();
+        assert_eq!((a.uid, a.gid, a.mtime), (1000, 100, 1_600_000_000));
+    }
+}
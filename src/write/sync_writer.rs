@@ -0,0 +1,175 @@
+use std::io::{self, SeekFrom};
+
+use crate::config::SyncPolicy;
+use crate::digest::{Digest, Sha256};
+
+/// An operation on the underlying writer, such as syncing its contents to durable storage
+pub(crate) type WriterHook<W> = fn(&mut W) -> io::Result<()>;
+
+/// Moves the underlying writer to another offset, for writers which can seek
+pub(crate) type SeekHook<W> = fn(&mut W, SeekFrom) -> io::Result<u64>;
+
+/// A writer which syncs its contents according to a [`SyncPolicy`]
+pub(crate) struct SyncWriter<W> {
+    inner: W,
+    policy: SyncPolicy,
+    sync: Option<WriterHook<W>>,
+    seek: Option<SeekHook<W>>,
+    unsynced: u64,
+    written: u64,
+    hasher: Option<Sha256>,
+}
+
+impl<W: io::Write> SyncWriter<W> {
+    /// Wrap `inner`, syncing with `sync`, or never if it is `None`
+    pub fn new(inner: W, policy: SyncPolicy, sync: Option<WriterHook<W>>) -> Self {
+        Self {
+            inner,
+            policy,
+            sync,
+            seek: None,
+            unsynced: 0,
+            written: 0,
+            hasher: None,
+        }
+    }
+
+    /// Hash everything written from now on, until [`take_digest`](Self::take_digest)
+    pub fn hash_output(&mut self) {
+        self.hasher = Some(Sha256::new());
+    }
+
+    /// The digest of everything written since [`hash_output`](Self::hash_output) was called
+    pub fn take_digest(&mut self) -> Option<Digest> {
+        self.hasher.take().map(Sha256::finish)
+    }
+
+    /// Allow writing over earlier output with [`write_at`](Self::write_at), seeking with `seek`
+    pub fn set_seek(&mut self, seek: SeekHook<W>) {
+        self.seek = Some(seek);
+    }
+
+    /// Write `buf` over the output at `offset`, which must already have been written, then
+    /// carry on from the end
+    ///
+    /// The bytes aren't hashed or counted as written.
+    pub fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let seek = self
+            .seek
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "the writer can't seek"))?;
+        debug_assert!(offset + buf.len() as u64 <= self.written);
+        seek(&mut self.inner, SeekFrom::Start(offset))?;
+        self.inner.write_all(buf)?;
+        seek(&mut self.inner, SeekFrom::Start(self.written))?;
+        Ok(())
+    }
+
+    /// The total number of bytes written so far
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if let Some(sync) = self.sync {
+            self.inner.flush()?;
+            sync(&mut self.inner)?;
+        }
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Flush all written data, and sync it unless the policy is [`SyncPolicy::Never`]
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self.policy {
+            SyncPolicy::Never => self.inner.flush(),
+            SyncPolicy::OnFinish | SyncPolicy::Periodic(_) => self.sync(),
+        }
+    }
+}
+
+impl<W: io::Write> io::Write for SyncWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..written]);
+        }
+        self.unsynced += written as u64;
+        self.written += written as u64;
+        if let SyncPolicy::Periodic(period) = self.policy {
+            if self.unsynced >= period {
+                self.sync()?;
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[derive(Default)]
+    struct Counting {
+        data: Vec<u8>,
+        syncs: Vec<usize>,
+    }
+
+    impl io::Write for Counting {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.data.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn sync(counting: &mut Counting) -> io::Result<()> {
+        counting.syncs.push(counting.data.len());
+        Ok(())
+    }
+
+    #[test]
+    fn sync_policies() {
+        let mut writer = SyncWriter::new(Counting::default(), SyncPolicy::Never, Some(sync));
+        writer.write_all(&[0; 100]).unwrap();
+        writer.finish().unwrap();
+        assert!(writer.inner.syncs.is_empty());
+
+        let mut writer = SyncWriter::new(Counting::default(), SyncPolicy::OnFinish, Some(sync));
+        writer.write_all(&[0; 100]).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(writer.inner.syncs, [100]);
+
+        let mut writer = SyncWriter::new(Counting::default(), SyncPolicy::Periodic(40), Some(sync));
+        for _ in 0..5 {
+            writer.write_all(&[0; 20]).unwrap();
+        }
+        writer.finish().unwrap();
+        assert_eq!(writer.inner.syncs, [40, 80, 100]);
+        assert_eq!(writer.written(), 100);
+
+        let mut writer = SyncWriter::new(Counting::default(), SyncPolicy::Never, None);
+        writer.hash_output();
+        writer.write_all(b"abc").unwrap();
+        assert_eq!(
+            writer.take_digest().unwrap().to_string(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(writer.take_digest(), None);
+
+        let mut writer = SyncWriter::new(Counting::default(), SyncPolicy::Periodic(40), None);
+        writer.write_all(&[0; 100]).unwrap();
+        writer.finish().unwrap();
+        assert!(writer.inner.syncs.is_empty());
+    }
+}
@@ -0,0 +1,665 @@
+//! An in-memory directory tree, used to collect entries from sources which may list them in any
+//! order before adding them to an archive
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+
+use bstr::{BString, ByteSlice, ByteVec};
+use chrono::{TimeZone, Utc};
+
+use super::source::Contents;
+use super::{Archive, Issue, ItemRef, WrittenFile};
+use crate::config::{LimitPolicy, Limits};
+use crate::errors::{Result, WriteError};
+use crate::read::{self, child_path, InodeData, ReadAt};
+use crate::Mode;
+
+/// Identifies a group of hard links to the same file
+pub(crate) type LinkId = u64;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Entry {
+    pub uid: u32,
+    pub gid: u32,
+    /// The full mode, including the file type bits
+    pub mode: Mode,
+    pub mtime: u32,
+    /// The contents of a regular file, or the target of a symlink
+    pub contents: Vec<u8>,
+    /// The `(major, minor)` device number of a device
+    pub device: (u32, u32),
+    /// Regular files sharing a link id are stored as hard links, with the contents from
+    /// [`Tree::set_link_contents`]
+    pub link: Option<LinkId>,
+    /// Opens the contents of a regular file when it's added, instead of `contents`
+    pub provider: Option<Contents>,
+    /// The extended attributes, by name
+    pub xattrs: Vec<(BString, Vec<u8>)>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Node {
+    /// `None` for directories which were only implied as parents of other entries
+    pub entry: Option<Entry>,
+    pub children: BTreeMap<BString, Node>,
+}
+
+impl Node {
+    pub fn is_dir(&self) -> bool {
+        match &self.entry {
+            Some(entry) => entry.mode.ty() == Mode::TYPE_DIR,
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Tree {
+    root: Node,
+    link_contents: HashMap<LinkId, Vec<u8>>,
+    next_link: LinkId,
+}
+
+/// Split a path into its components, ignoring empty and `.` components
+fn components(path: &[u8]) -> Result<Vec<&[u8]>> {
+    let mut result = Vec::new();
+    for component in path.split_str("/") {
+        match component {
+            b"" | b"." => {}
+            b".." => return Err(WriteError::InvalidPath(path.into()).into()),
+            _ => result.push(component),
+        }
+    }
+    Ok(result)
+}
+
+impl Tree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the node at `path`, creating it and any missing parents
+    pub fn get_or_insert(&mut self, path: &[u8]) -> Result<&mut Node> {
+        let mut node = &mut self.root;
+        for component in components(path)? {
+            if !node.is_dir() {
+                return Err(WriteError::InvalidPath(path.into()).into());
+            }
+            node = node.children.entry(component.into()).or_default();
+        }
+        Ok(node)
+    }
+
+    pub fn get_mut(&mut self, path: &[u8]) -> Result<Option<&mut Node>> {
+        let mut node = &mut self.root;
+        for component in components(path)? {
+            node = match node.children.get_mut(component.as_bstr()) {
+                Some(child) => child,
+                None => return Ok(None),
+            };
+        }
+        Ok(Some(node))
+    }
+
+    /// Set the entry at `path`
+    ///
+    /// The children of an existing directory are kept if the new entry is also a directory.
+    pub fn insert(&mut self, path: &[u8], entry: Entry) -> Result<()> {
+        let node = self.get_or_insert(path)?;
+        if entry.mode.ty() != Mode::TYPE_DIR {
+            node.children.clear();
+        }
+        node.entry = Some(entry);
+        Ok(())
+    }
+
+    /// Remove the node at `path` and all its children, returns whether anything was removed
+    pub fn remove(&mut self, path: &[u8]) -> Result<bool> {
+        let components = components(path)?;
+        let (last, parents) = match components.split_last() {
+            Some(split) => split,
+            None => return Err(WriteError::InvalidPath(path.into()).into()),
+        };
+        let mut node = &mut self.root;
+        for &component in parents {
+            node = match node.children.get_mut(component.as_bstr()) {
+                Some(child) => child,
+                None => return Ok(false),
+            };
+        }
+        Ok(node.children.remove(last.as_bstr()).is_some())
+    }
+
+    pub fn root(&self) -> &Node {
+        &self.root
+    }
+
+    /// Allocate a new, unused link id
+    pub fn new_link(&mut self) -> LinkId {
+        let id = self.next_link;
+        self.next_link += 1;
+        id
+    }
+
+    /// Set the contents shared by all files with the link id `link`
+    pub fn set_link_contents(&mut self, link: LinkId, contents: Vec<u8>) {
+        self.link_contents.insert(link, contents);
+    }
+
+    pub fn has_link_contents(&self, link: LinkId) -> bool {
+        matches!(self.link_contents.get(&link), Some(contents) if !contents.is_empty())
+    }
+
+    /// Convert every entry of `source` in walk order, passing each to `insert` with its path
+    ///
+    /// File contents are only read from `source` when they're added to an archive, and are
+    /// streamed rather than read into memory. Hard linked files share a new link id.
+    pub fn add_archive<R, F>(&mut self, source: &read::Archive<R>, insert: F) -> Result<()>
+    where
+        R: ReadAt + 'static,
+        F: FnMut(&mut Tree, BString, Entry) -> Result<()>,
+    {
+        self.add_walk(source, source.walk(), insert)
+    }
+
+    /// Like [`add_archive`](Self::add_archive), for the entries of a walk of part of `source`
+    pub fn add_walk<R, F>(
+        &mut self,
+        source: &read::Archive<R>,
+        walk: read::Walk<'_, R>,
+        mut insert: F,
+    ) -> Result<()>
+    where
+        R: ReadAt + 'static,
+        F: FnMut(&mut Tree, BString, Entry) -> Result<()>,
+    {
+        let mut links = HashMap::new();
+        for walk_entry in walk {
+            let walk_entry = walk_entry?;
+            let inode = walk_entry.inode();
+            let mut entry = Entry {
+                uid: inode.uid(),
+                gid: inode.gid(),
+                mode: inode.mode(),
+                mtime: inode.mtime(),
+                contents: Vec::new(),
+                device: (0, 0),
+                link: None,
+                provider: None,
+                xattrs: Vec::new(),
+            };
+            for xattr in source.xattrs(inode)? {
+                let (name, value) = xattr?;
+                entry
+                    .xattrs
+                    .push((Vec::from_os_str_lossy(&name).into_owned().into(), value));
+            }
+            match inode.data() {
+                InodeData::File(_) => {
+                    if inode.hard_link_count() > 1 {
+                        let number = inode.inode_number();
+                        entry.link = Some(*links.entry(number).or_insert_with(|| self.new_link()));
+                    }
+                    let (source, inode) = (source.clone(), inode.clone());
+                    let contents = move || -> io::Result<Box<dyn io::Read>> {
+                        let reader = source.clone().into_file_reader(&inode);
+                        Ok(Box::new(reader.map_err(io::Error::other)?))
+                    };
+                    entry.provider = Some(Contents::new(contents));
+                }
+                InodeData::Symlink(target) => entry.contents = target.to_vec(),
+                InodeData::BlockDevice(device) | InodeData::CharDevice(device) => {
+                    entry.device = (device.major(), device.minor());
+                }
+                InodeData::Directory(_) | InodeData::Fifo | InodeData::Socket => {}
+            }
+            insert(self, walk_entry.path().into(), entry)?;
+        }
+        Ok(())
+    }
+
+    /// Add every entry of the tree to `archive`, returning the root directory
+    ///
+    /// Symlink targets are rewritten according to the archive's
+    /// [`SymlinkRewrite`](crate::config::SymlinkRewrite). Entries exceeding the archive's [`Limits`] are handled according to its
+    /// [`LimitPolicy`], except for the root, which is never skipped. The contents of the files
+    /// in the archive's [`AccessTrace`](crate::trace::AccessTrace) are written first.
+    pub fn build<W: io::Write>(self, archive: &mut Archive<W>) -> Result<ItemRef> {
+        self.build_reusing(archive, HashMap::new())
+    }
+
+    /// Like [`build`](Self::build), but the files at the paths in `written` have already had
+    /// their contents written to `archive`, before any traced files
+    pub fn build_reusing<W: io::Write>(
+        mut self,
+        archive: &mut Archive<W>,
+        mut written: HashMap<BString, WrittenFile>,
+    ) -> Result<ItemRef> {
+        if let Some(trace) = archive.access_trace.take() {
+            let result = self.write_traced(archive, trace.paths(), &mut written);
+            archive.access_trace = Some(trace);
+            result?;
+        }
+        self.build_written(archive, written)
+    }
+
+    /// Write the contents of the regular files at `paths`, in order, adding them to `written`
+    /// by absolute path
+    ///
+    /// Paths which aren't in the tree, or aren't regular files, are ignored, as are hard
+    /// links, whose contents are written with the first link in the tree. So are files which
+    /// exceed the archive's limits, whichever the policy, leaving them to be handled as usual,
+    /// and files already in `written`.
+    fn write_traced<W: io::Write>(
+        &mut self,
+        archive: &mut Archive<W>,
+        paths: &[BString],
+        written: &mut HashMap<BString, WrittenFile>,
+    ) -> Result<()> {
+        for path in paths {
+            let components = match components(path) {
+                Ok(components) => components,
+                Err(_) => continue,
+            };
+            let depth = components.len();
+            let path = components
+                .into_iter()
+                .fold(BString::from("/"), |path, name| child_path(&path, name));
+            if written.contains_key(&path) {
+                continue;
+            }
+            let node = match self.get_mut(&path)? {
+                Some(node) => &*node,
+                None => continue,
+            };
+            let entry = match &node.entry {
+                Some(entry) if entry.mode.ty() == Mode::TYPE_FILE && entry.link.is_none() => entry,
+                _ => continue,
+            };
+            if exceeded(&archive.limits, &path, depth, node).is_some() {
+                continue;
+            }
+            let mut contents = match &entry.provider {
+                Some(provider) => provider.open()?,
+                None => Box::new(io::Cursor::new(entry.contents.clone())),
+            };
+            let file = archive.write_file(&[], &mut contents)?;
+            written.insert(path, file);
+        }
+        Ok(())
+    }
+
+    /// Like [`build`](Self::build), but the files at the paths in `written` have already had
+    /// their contents written to `archive`, and are added with the given data instead
+    pub fn build_written<W: io::Write>(
+        self,
+        archive: &mut Archive<W>,
+        written: HashMap<BString, WrittenFile>,
+    ) -> Result<ItemRef> {
+        let mut builder = Builder {
+            archive,
+            link_contents: self.link_contents,
+            links: HashMap::new(),
+            written,
+        };
+        let root = builder.build("/".into(), 0, self.root)?;
+        Ok(root.expect("the root is always a directory"))
+    }
+}
+
+struct Builder<'a, W: io::Write> {
+    archive: &'a mut Archive<W>,
+    link_contents: HashMap<LinkId, Vec<u8>>,
+    links: HashMap<LinkId, ItemRef>,
+    written: HashMap<BString, WrittenFile>,
+}
+
+macro_rules! set_metadata {
+    ($builder:expr, $entry:expr) => {
+        if let Some(entry) = $entry {
+            $builder
+                .set_uid(entry.uid)
+                .set_gid(entry.gid)
+                .set_mode(entry.mode)
+                .set_modified_time(Utc.timestamp_opt(entry.mtime.into(), 0).unwrap());
+            for (name, value) in &entry.xattrs {
+                $builder.set_xattr(name.clone(), value.clone())?;
+            }
+        }
+    };
+}
+
+impl<W: io::Write> Builder<'_, W> {
+    /// Add `node` and its children to the archive
+    ///
+    /// Returns `None` for entries skipped by the archive's policies
+    fn build(&mut self, path: BString, depth: usize, mut node: Node) -> Result<Option<ItemRef>> {
+        if let Some(entry) = &mut node.entry {
+            if entry.mode.ty() == Mode::TYPE_LINK {
+                let target = self.archive.symlink_rewrite.rewrite(&path, &entry.contents);
+                entry.contents = target.into();
+            }
+        }
+        if let Some((what, actual, max)) = exceeded(&self.archive.limits, &path, depth, &node) {
+            match self.archive.limit_policy {
+                LimitPolicy::Skip if depth > 0 => {
+                    let issue = Issue::SkippedEntry {
+                        path,
+                        what,
+                        actual,
+                        max,
+                    };
+                    let archive = &mut *self.archive;
+                    archive.report.note(&archive.logger, issue);
+                    return Ok(None);
+                }
+                _ => {
+                    return Err(WriteError::LimitExceeded {
+                        path,
+                        what,
+                        actual,
+                        max,
+                    }
+                    .into())
+                }
+            }
+        }
+
+        let entry = match node.entry {
+            Some(entry) => entry,
+            None => {
+                let mut dir = self.archive.create_dir();
+                self.add_children(&mut dir, &path, depth, node.children)?;
+                return Ok(Some(dir.finish(self.archive)));
+            }
+        };
+
+        let ty = entry.mode.ty();
+        let item_ref = match ty {
+            Mode::TYPE_DIR => {
+                let mut dir = self.archive.create_dir();
+                set_metadata!(dir, Some(&entry));
+                self.add_children(&mut dir, &path, depth, node.children)?;
+                dir.finish(self.archive)
+            }
+            Mode::TYPE_FILE => {
+                if let Some(&item_ref) = entry.link.and_then(|link| self.links.get(&link)) {
+                    return Ok(Some(item_ref));
+                }
+                let contents = match entry.link.and_then(|link| self.link_contents.remove(&link)) {
+                    Some(contents) => contents,
+                    None => entry.contents.clone(),
+                };
+                let mut file = self.archive.create_file();
+                set_metadata!(file, Some(&entry));
+                if let Some(data) = self.written.remove(&path) {
+                    return Ok(Some(file.finish_written(self.archive, data)));
+                }
+                match &entry.provider {
+                    Some(provider) => file.set_contents(provider.open()?),
+                    None => file.set_contents(Box::new(io::Cursor::new(contents))),
+                };
+                let item_ref = file.finish(self.archive)?;
+                if let Some(link) = entry.link {
+                    self.links.insert(link, item_ref);
+                }
+                item_ref
+            }
+            Mode::TYPE_LINK => {
+                let mut symlink = self.archive.create_symlink(entry.contents.clone());
+                set_metadata!(symlink, Some(&entry));
+                symlink.finish(self.archive)
+            }
+            Mode::TYPE_BLOCK | Mode::TYPE_CHAR => {
+                let mut device = if ty == Mode::TYPE_BLOCK {
+                    self.archive.create_block_device()
+                } else {
+                    self.archive.create_char_device()
+                };
+                set_metadata!(device, Some(&entry));
+                device.set_device(entry.device.0, entry.device.1);
+                device.finish(self.archive)?
+            }
+            Mode::TYPE_FIFO | Mode::TYPE_SOCKET => {
+                let mut ipc = if ty == Mode::TYPE_FIFO {
+                    self.archive.create_fifo()
+                } else {
+                    self.archive.create_socket()
+                };
+                set_metadata!(ipc, Some(&entry));
+                return ipc.finish_at(self.archive, Some(&path));
+            }
+            _ => return Err(WriteError::UnknownFileType(entry.mode.bits()).into()),
+        };
+        Ok(Some(item_ref))
+    }
+
+    fn add_children(
+        &mut self,
+        dir: &mut super::DirBuilder,
+        path: &[u8],
+        depth: usize,
+        children: BTreeMap<BString, Node>,
+    ) -> Result<()> {
+        for (name, child) in children {
+            let child_path = child_path(path, &name);
+            if let Some(child) = self.build(child_path, depth + 1, child)? {
+                dir.add_item(name, child);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Find the first of `limits` exceeded by `node` at `path` and `depth`, returning a description
+/// of the limit, the actual value, and the limit
+fn exceeded(
+    limits: &Limits,
+    path: &[u8],
+    depth: usize,
+    node: &Node,
+) -> Option<(&'static str, usize, usize)> {
+    let name_len = match path.rfind_byte(b'/') {
+        Some(slash) => path.len() - slash - 1,
+        None => path.len(),
+    };
+    let symlink_len = match &node.entry {
+        Some(entry) if entry.mode.ty() == Mode::TYPE_LINK => entry.contents.len(),
+        _ => 0,
+    };
+    let checks = [
+        ("depth", depth, limits.max_depth),
+        ("name length", name_len, limits.max_name_len),
+        ("symlink target length", symlink_len, limits.max_symlink_len),
+        (
+            "directory entry count",
+            node.children.len(),
+            limits.max_dir_entries,
+        ),
+    ];
+    checks
+        .iter()
+        .copied()
+        .find(|&(_, actual, max)| actual > max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file() -> Entry {
+        Entry {
+            uid: 0,
+            gid: 0,
+            mode: Mode::TYPE_FILE | Mode::O644,
+            mtime: 0,
+            contents: Vec::new(),
+            device: (0, 0),
+            link: None,
+            provider: None,
+            xattrs: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn insert_paths() {
+        let mut tree = Tree::new();
+        tree.insert(b"./etc/hosts", file()).unwrap();
+        tree.insert(b"/etc//passwd", file()).unwrap();
+        tree.insert(b"bin", file()).unwrap();
+        assert_eq!(tree.get_or_insert(b".").unwrap().children.len(), 2);
+        tree.insert(b"etc/../../escape", file()).unwrap_err();
+        tree.insert(b"bin/sh", file()).unwrap_err();
+
+        let etc = tree.get_mut(b"etc").unwrap().unwrap();
+        assert!(etc.entry.is_none());
+        assert_eq!(etc.children.keys().collect::<Vec<_>>(), ["hosts", "passwd"]);
+
+        assert!(tree.remove(b"etc/hosts").unwrap());
+        assert!(!tree.remove(b"etc/hosts").unwrap());
+        assert!(tree.get_mut(b"etc/hosts").unwrap().is_none());
+    }
+
+    #[test]
+    fn limits() {
+        let limits = Limits {
+            max_depth: 2,
+            max_name_len: 4,
+            max_symlink_len: 3,
+            max_dir_entries: 1,
+        };
+        let node = |entry: Entry| Node {
+            entry: Some(entry),
+            children: BTreeMap::new(),
+        };
+        assert_eq!(exceeded(&limits, b"/a/b", 2, &node(file())), None);
+        assert_eq!(
+            exceeded(&limits, b"/a/b/c", 3, &node(file())),
+            Some(("depth", 3, 2))
+        );
+        assert_eq!(
+            exceeded(&limits, b"/a/hello", 2, &node(file())),
+            Some(("name length", 5, 4))
+        );
+
+        let symlink = Entry {
+            mode: Mode::TYPE_LINK | Mode::O777,
+            contents: b"/etc".to_vec(),
+            ..file()
+        };
+        assert_eq!(
+            exceeded(&limits, b"/link", 1, &node(symlink)),
+            Some(("symlink target length", 4, 3))
+        );
+
+        let mut tree = Tree::new();
+        tree.insert(b"a", file()).unwrap();
+        tree.insert(b"b", file()).unwrap();
+        assert_eq!(
+            exceeded(&limits, b"/", 0, &tree.root),
+            Some(("directory entry count", 2, 1))
+        );
+    }
+
+    #[test]
+    fn rewritten_symlinks() {
+        let mut builder = super::super::ArchiveBuilder::new();
+        builder.symlink_rewrite = crate::config::SymlinkRewrite::Relative;
+        let (mut archive, image) = builder.build_in_memory();
+        let symlink = |target: &str| Entry {
+            mode: Mode::TYPE_LINK | Mode::O777,
+            contents: target.into(),
+            ..file()
+        };
+        let mut tree = Tree::new();
+        tree.insert(b"usr/bin/cc", symlink("/usr/lib/gcc")).unwrap();
+        tree.insert(b"lib", symlink("usr/lib")).unwrap();
+        let root = tree.build(&mut archive).unwrap();
+        archive.set_root(root);
+        archive.flush().unwrap();
+
+        let read = image.open().unwrap();
+        let target = |path| read.lookup(path).unwrap().symlink_target().cloned();
+        assert_eq!(target("lib").unwrap(), "usr/lib");
+        assert_eq!(target("usr/bin/cc").unwrap(), "../lib/gcc");
+    }
+
+    #[test]
+    fn special_files() {
+        use crate::config::SpecialFilePolicy;
+        use crate::write::Issue;
+
+        let build = |policy| {
+            let mut builder = super::super::ArchiveBuilder::new();
+            builder.special_files = policy;
+            let (mut archive, image) = builder.build_in_memory();
+            let special = |ty| Entry {
+                mode: ty | Mode::O644,
+                ..file()
+            };
+            let mut tree = Tree::new();
+            tree.insert(b"dir/fifo", special(Mode::TYPE_FIFO)).unwrap();
+            tree.insert(b"socket", special(Mode::TYPE_SOCKET)).unwrap();
+            let root = tree.build(&mut archive)?;
+            archive.set_root(root);
+            let report = archive.flush()?;
+            Ok::<_, crate::Error>((image.open().unwrap(), report))
+        };
+
+        let (read, report) = build(SpecialFilePolicy::Store).unwrap();
+        assert!(report.is_empty());
+        assert!(read.lookup("dir/fifo").is_ok());
+        assert!(read.lookup("socket").is_ok());
+
+        let (read, report) = build(SpecialFilePolicy::Skip).unwrap();
+        assert!(read.lookup("dir").is_ok());
+        assert!(read.lookup("dir/fifo").is_err());
+        assert!(read.lookup("socket").is_err());
+        assert_eq!(
+            report.issues(),
+            [
+                Issue::SkippedSpecialFile {
+                    path: Some("/dir/fifo".into()),
+                    kind: "fifo",
+                },
+                Issue::SkippedSpecialFile {
+                    path: Some("/socket".into()),
+                    kind: "socket",
+                },
+            ]
+        );
+
+        assert!(build(SpecialFilePolicy::Error).is_err());
+    }
+
+    #[test]
+    fn traced_files_first() {
+        let mut builder = super::super::ArchiveBuilder::new();
+        builder.block_size = 4096;
+        builder.access_trace = Some(crate::trace::AccessTrace::new(vec![
+            "c", "/missing", "/dir", "./a", "/c",
+        ]));
+        let (mut archive, image) = builder.build_in_memory();
+        let mut tree = Tree::new();
+        for (name, byte) in [("a", b'a'), ("b", b'b'), ("c", b'c')] {
+            let contents = vec![byte; 4096];
+            tree.insert(name.as_bytes(), Entry { contents, ..file() })
+                .unwrap();
+        }
+        tree.insert(b"dir/d", file()).unwrap();
+        let root = tree.build(&mut archive).unwrap();
+        assert!(archive.access_trace.is_some());
+        archive.set_root(root);
+        archive.flush().unwrap();
+
+        // Files are added in name order, but c and a were written first
+        let read = image.open().unwrap();
+        let start = |path: &str| {
+            let inode = read.lookup(path).unwrap();
+            let file = inode.as_file().unwrap();
+            assert_eq!(read.read_file(&inode).unwrap(), [path.as_bytes()[0]; 4096]);
+            file.blocks_start
+        };
+        assert!(start("c") < start("a") && start("a") < start("b"));
+    }
+}
@@ -1,6 +1,8 @@
 use super::metablock_writer::MetablockWriter;
 use crate::compression::Compressor;
+use crate::pool::BlockPool;
 use std::marker::PhantomData;
+use std::sync::Arc;
 use std::{fmt, mem};
 use zerocopy::AsBytes;
 
@@ -13,17 +15,17 @@ pub struct Table<T, Comp> {
 impl<T: AsBytes, Comp: Compressor> Table<T, Comp> {
     const _T_SIZE_ASSERT: () = assert!(repr::metablock::SIZE % mem::size_of::<T>() == 0);
 
-    pub fn new(compressor: Option<Comp>) -> Self {
-        Self::with_capacity(compressor, 0)
+    pub fn new(compressor: Option<Comp>, pool: &Arc<BlockPool>) -> Self {
+        Self::with_capacity(compressor, 0, pool)
     }
 
-    pub fn with_capacity(compressor: Option<Comp>, cap: usize) -> Self {
+    pub fn with_capacity(compressor: Option<Comp>, cap: usize, pool: &Arc<BlockPool>) -> Self {
         assert_eq!(repr::metablock::SIZE % mem::size_of::<T>(), 0);
         assert!(mem::size_of::<T>() < repr::metablock::SIZE);
 
         let index_size = cap * mem::size_of::<T>() / repr::metablock::SIZE;
         Self {
-            data_writer: MetablockWriter::with_capacity(compressor, cap),
+            data_writer: MetablockWriter::with_capacity(compressor, cap, pool),
             index: Vec::with_capacity(index_size),
             _phantom: PhantomData,
         }
@@ -1,8 +1,9 @@
 use crate::compression::AnyCodec;
+use crate::pool::BlockPool;
 use crate::write::two_level;
 use indexmap::IndexSet;
 use std::convert::TryInto;
-use std::io;
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub struct Table {
@@ -18,6 +19,11 @@ impl Table {
 
     pub fn add(&mut self, id: repr::uid_gid::Id) -> repr::uid_gid::Idx {
         let (idx, _) = self.ids.insert_full(id);
+        assert!(
+            idx < repr::limits::MAX_IDS,
+            "more than {} distinct uids and gids",
+            repr::limits::MAX_IDS
+        );
 
         repr::uid_gid::Idx(idx as u16)
     }
@@ -32,24 +38,13 @@ impl Table {
         repr::uid_gid::Idx(idx.try_into().unwrap())
     }
 
-    pub fn write_at<W: io::Write>(
-        &mut self,
-        mut writer: W,
-        start_offset: u64,
-        compressor: Option<AnyCodec>,
-    ) -> io::Result<()> {
-        let mut table = two_level::Table::with_capacity(compressor, self.ids.len());
+    /// The ids as the metablocks of an id table, see [`Plan::id_table`](super::plan::Plan)
+    pub fn metablocks(&self, compressor: Option<AnyCodec>, pool: &Arc<BlockPool>) -> Vec<u8> {
+        let mut table = two_level::Table::with_capacity(compressor, self.ids.len(), pool);
         for id in &self.ids {
             table.write(id);
         }
-        let (data_table, indexes) = table.finish();
-
-        writer.write_all(&data_table)?;
-        for &idx in &indexes {
-            let block_offset = start_offset + u64::from(idx);
-            writer.write_all(&block_offset.to_le_bytes())?;
-        }
-
-        Ok(())
+        let (metablocks, _) = table.finish();
+        metablocks
     }
 }
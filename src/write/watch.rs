@@ -0,0 +1,428 @@
+//! Rebuilding an image whenever a directory changes
+//!
+//! Meant for development loops where the image is mounted in an emulator or VM: edit files,
+//! and a fresh image appears at the output path. The directory is scanned once, then only the
+//! paths reported as changed are scanned again. Files whose size and modification time haven't
+//! changed since the last image have their data blocks copied from it, so only what changed is
+//! compressed again. Each image is written with
+//! [`build_path_atomic`](ArchiveBuilder::build_path_atomic), so a half written image is never
+//! seen at the output path.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime};
+
+use bstr::{BString, ByteSlice, ByteVec};
+use chrono::{DateTime, Utc};
+use notify::{RecursiveMode, Watcher};
+
+use super::{Archive, ArchiveBuilder, RawData, Report, SourceEntry, SourceKind, WrittenFile};
+use crate::errors::Result;
+use crate::read::{self, child_path, verify::disk_mode};
+use crate::Mode;
+
+/// How [`watch`] waits for changes
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct WatchOptions {
+    /// How long the directory must be left unchanged before rebuilding, so saving many files
+    /// at once only rebuilds once
+    pub debounce: Duration,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        WatchOptions {
+            debounce: Duration::from_millis(250),
+        }
+    }
+}
+
+/// Build an image of the directory `source` at `output`, then rebuild it every time something
+/// in the directory changes
+///
+/// `on_build` is called with the outcome of every build, and watching stops once it returns
+/// `false`. A failed build doesn't stop watching, so mistakes can be fixed in place. `output`
+/// shouldn't be inside `source`, or every build would cause another.
+///
+/// Data blocks are only copied from the last image when the builder doesn't compute
+/// [`file_digests`](ArchiveBuilder::file_digests), which need every file read in full.
+///
+/// ```no_run
+/// use sqfs::write::{self, ArchiveBuilder, WatchOptions};
+///
+/// # fn main() -> sqfs::Result<()> {
+/// let builder = ArchiveBuilder::new();
+/// write::watch("rootfs", "rootfs.sqfs", &builder, &WatchOptions::default(), |result| {
+///     match result {
+///         Ok(_) => println!("rebuilt rootfs.sqfs"),
+///         Err(e) => eprintln!("build failed: {}", e),
+///     }
+///     true
+/// })?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn watch<P, Q, F>(
+    source: P,
+    output: Q,
+    builder: &ArchiveBuilder,
+    options: &WatchOptions,
+    mut on_build: F,
+) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    F: FnMut(Result<Report>) -> bool,
+{
+    let source = source.as_ref();
+    let output = output.as_ref();
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).map_err(io::Error::other)?;
+    watcher
+        .watch(source, RecursiveMode::Recursive)
+        .map_err(io::Error::other)?;
+
+    let mut snapshot = Snapshot::scan(source)?;
+    while on_build(snapshot.build(builder, output)) {
+        let mut changed = Vec::new();
+        match rx.recv() {
+            Ok(event) => changed.extend(event.map_err(io::Error::other)?.paths),
+            // The watcher was dropped
+            Err(mpsc::RecvError) => return Ok(()),
+        }
+        while let Ok(event) = rx.recv_timeout(options.debounce) {
+            changed.extend(event.map_err(io::Error::other)?.paths);
+        }
+        for path in changed {
+            snapshot.update(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// The size and modification time of a regular file, which tell whether it changed
+type Stamp = (u64, SystemTime);
+
+/// The entries of a directory tree, as of the last scan of each path
+struct Snapshot {
+    root: PathBuf,
+    /// Keyed by path relative to the root, without a leading `/`, so a directory's contents
+    /// directly follow it
+    entries: BTreeMap<BString, SourceEntry>,
+    /// The stamps of the regular files in `entries`
+    stamps: BTreeMap<BString, Stamp>,
+    /// The stamps of the regular files in the image at the output path, as of the last build
+    /// which succeeded
+    built: BTreeMap<BString, Stamp>,
+}
+
+impl Snapshot {
+    fn scan(root: &Path) -> io::Result<Self> {
+        let mut snapshot = Snapshot {
+            root: root.to_owned(),
+            entries: BTreeMap::new(),
+            stamps: BTreeMap::new(),
+            built: BTreeMap::new(),
+        };
+        snapshot.scan_path(root, BString::from(""))?;
+        Ok(snapshot)
+    }
+
+    /// Scan `disk_path` again after it changed, whether it was created, modified or removed
+    fn update(&mut self, disk_path: &Path) -> io::Result<()> {
+        let path = match disk_path.strip_prefix(&self.root) {
+            Ok(relative) => archive_path(relative),
+            Err(_) => return Ok(()),
+        };
+        self.remove(&path);
+        match self.scan_path(disk_path, path) {
+            Err(e) if is_gone(&e) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Remove `path` and anything beneath it
+    fn remove(&mut self, path: &[u8]) {
+        let mut prefix = BString::from(path);
+        if !prefix.is_empty() {
+            prefix.push(b'/');
+        }
+        self.entries.remove(path.as_bstr());
+        self.stamps.remove(path.as_bstr());
+        let beneath: Vec<BString> = self
+            .entries
+            .range(prefix.clone()..)
+            .map(|(path, _)| path)
+            .take_while(|path| path.starts_with(&prefix))
+            .cloned()
+            .collect();
+        for path in beneath {
+            self.entries.remove(&path);
+            self.stamps.remove(&path);
+        }
+    }
+
+    fn scan_path(&mut self, disk_path: &Path, path: BString) -> io::Result<()> {
+        let metadata = fs::symlink_metadata(disk_path)?;
+        let mode = disk_mode(&metadata);
+        let kind = match mode.ty() {
+            Mode::TYPE_DIR => SourceKind::Dir,
+            Mode::TYPE_FILE => SourceKind::File(super::Contents::new(disk_path.to_owned())),
+            Mode::TYPE_LINK => {
+                let target = fs::read_link(disk_path)?;
+                SourceKind::Symlink(Vec::from_path_lossy(&target).into_owned().into())
+            }
+            Mode::TYPE_FIFO => SourceKind::Fifo,
+            Mode::TYPE_SOCKET => SourceKind::Socket,
+            _ => device_kind(&metadata, mode),
+        };
+        let is_dir = matches!(kind, SourceKind::Dir);
+        let mut entry = SourceEntry::new(path.clone(), kind);
+        entry.mode = mode.perm();
+        if let Ok(modified) = metadata.modified() {
+            entry.mtime = DateTime::<Utc>::from(modified);
+        }
+        set_owner(&mut entry, &metadata);
+        self.entries.insert(path.clone(), entry);
+        if let Some(stamp) = stamp(&metadata) {
+            self.stamps.insert(path.clone(), stamp);
+        }
+
+        if is_dir {
+            for child in fs::read_dir(disk_path)? {
+                let child = child?;
+                let child_path = if path.is_empty() {
+                    BString::from(Vec::from_os_str_lossy(&child.file_name()).into_owned())
+                } else {
+                    let mut child_path = path.clone();
+                    child_path.push(b'/');
+                    child_path.push_str(Vec::from_os_str_lossy(&child.file_name()));
+                    child_path
+                };
+                match self.scan_path(&child.path(), child_path) {
+                    // Removed while scanning, a later event will say so
+                    Err(e) if is_gone(&e) => {}
+                    result => result?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write an image of the snapshot to `output`
+    ///
+    /// Files which haven't changed since the last build are copied from the image it left at
+    /// `output`.
+    fn build(&mut self, builder: &ArchiveBuilder, output: &Path) -> Result<Report> {
+        let previous = match builder.file_digests {
+            Some(_) => None,
+            None if self.built.is_empty() => None,
+            // Without the last image, everything is written again
+            None => read::Archive::open(output).ok(),
+        };
+        let mut archive = builder.clone().build_path_atomic(output)?;
+        let mut copied = HashMap::new();
+        if let Some(previous) = &previous {
+            for (path, stamp) in &self.stamps {
+                if self.built.get(path) != Some(stamp) {
+                    continue;
+                }
+                if let Some(file) = self.copy_file(&mut archive, previous, path, stamp)? {
+                    copied.insert(child_path(b"/", path), file);
+                }
+            }
+        }
+        let tree = super::source::into_tree(self.entries.values().cloned())?;
+        let root = tree.build_reusing(&mut archive, copied)?;
+        archive.set_root(root);
+        let report = archive.flush()?;
+        self.built = self.stamps.clone();
+        Ok(report)
+    }
+
+    /// Write the contents of the file at `path` to `archive`, copying its data blocks from
+    /// `previous` and reading only the end stored in a fragment from disk
+    ///
+    /// Returns `None` if the file isn't in `previous` as it was scanned, or changed on disk
+    /// since.
+    fn copy_file<W: io::Write>(
+        &self,
+        archive: &mut Archive<W>,
+        previous: &read::Archive<File>,
+        path: &BString,
+        scanned: &Stamp,
+    ) -> Result<Option<WrittenFile>> {
+        let disk_path = self.root.join(path.to_path_lossy());
+        let mut file = match File::open(&disk_path) {
+            Ok(file) => file,
+            Err(e) if is_gone(&e) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if stamp(&file.metadata()?).as_ref() != Some(scanned) {
+            return Ok(None);
+        }
+        let inode = match previous.lookup(path) {
+            Ok(inode) if inode.file_size() == Some(scanned.0) => inode,
+            // Skipped by the archive's limits, say
+            _ => return Ok(None),
+        };
+        let map = previous.block_map(&inode)?;
+        let mut raw = Vec::new();
+        let mut copied = 0;
+        for block in map.raw_blocks() {
+            raw.push(RawData {
+                data: previous.read_raw_block(&block)?,
+                size: block.size(),
+                len: block.uncompressed_size,
+            });
+            copied += u64::from(block.uncompressed_size);
+        }
+        file.seek(SeekFrom::Start(copied))?;
+        Ok(Some(archive.write_file(&raw, &mut file)?))
+    }
+}
+
+/// The stamp of a regular file with `metadata`, if its modification time is known
+fn stamp(metadata: &fs::Metadata) -> Option<Stamp> {
+    let modified = metadata.modified().ok()?;
+    if metadata.is_file() {
+        Some((metadata.len(), modified))
+    } else {
+        None
+    }
+}
+
+/// The path within the archive of `relative`, a path relative to the watched directory
+fn archive_path(relative: &Path) -> BString {
+    let mut path = BString::from("");
+    for component in relative.components() {
+        if let Component::Normal(name) = component {
+            if !path.is_empty() {
+                path.push(b'/');
+            }
+            path.push_str(Vec::from_os_str_lossy(name));
+        }
+    }
+    path
+}
+
+/// Whether `e` means the path being scanned no longer exists, because it or a parent was removed
+fn is_gone(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+    )
+}
+
+#[cfg(unix)]
+fn device_kind(metadata: &fs::Metadata, mode: Mode) -> SourceKind {
+    use crate::read::verify::split_device;
+    use std::os::unix::fs::MetadataExt;
+
+    let (major, minor) = split_device(metadata.rdev());
+    if mode.ty() == Mode::TYPE_BLOCK {
+        SourceKind::BlockDevice { major, minor }
+    } else {
+        SourceKind::CharDevice { major, minor }
+    }
+}
+
+#[cfg(not(unix))]
+fn device_kind(metadata: &fs::Metadata, mode: Mode) -> SourceKind {
+    unreachable!("devices are only found on unix")
+}
+
+#[cfg(unix)]
+fn set_owner(entry: &mut SourceEntry, metadata: &fs::Metadata) {
+    use std::os::unix::fs::MetadataExt;
+
+    entry.uid = metadata.uid();
+    entry.gid = metadata.gid();
+}
+
+#[cfg(not(unix))]
+fn set_owner(entry: &mut SourceEntry, metadata: &fs::Metadata) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn paths(snapshot: &Snapshot) -> Vec<&str> {
+        snapshot
+            .entries
+            .keys()
+            .map(|path| path.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn incremental_scan() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        fs::write(dir.path().join("a/b/file"), "file").unwrap();
+        fs::write(dir.path().join("a.txt"), "a").unwrap();
+        let mut snapshot = Snapshot::scan(dir.path()).unwrap();
+        assert_eq!(paths(&snapshot), ["", "a", "a.txt", "a/b", "a/b/file"]);
+        assert!(matches!(
+            snapshot.entries[b"".as_bstr()].kind,
+            SourceKind::Dir
+        ));
+        assert!(matches!(
+            snapshot.entries[b"a/b/file".as_bstr()].kind,
+            SourceKind::File(_)
+        ));
+
+        fs::remove_dir_all(dir.path().join("a")).unwrap();
+        fs::write(dir.path().join("a"), "now a file").unwrap();
+        fs::write(dir.path().join("new"), "new").unwrap();
+        snapshot.update(&dir.path().join("a")).unwrap();
+        snapshot.update(&dir.path().join("a/b/file")).unwrap();
+        snapshot.update(&dir.path().join("new")).unwrap();
+        assert_eq!(paths(&snapshot), ["", "a", "a.txt", "new"]);
+        assert!(matches!(
+            snapshot.entries[b"a".as_bstr()].kind,
+            SourceKind::File(_)
+        ));
+
+        fs::remove_file(dir.path().join("a.txt")).unwrap();
+        snapshot.update(&dir.path().join("a.txt")).unwrap();
+        assert_eq!(paths(&snapshot), ["", "a", "new"]);
+    }
+
+    #[test]
+    fn unchanged_files_copied() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let output = dir.path().join("image.sqfs");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("kept"), vec![1; 8192]).unwrap();
+        fs::write(source.join("changed"), "before").unwrap();
+        let mut builder = ArchiveBuilder::new();
+        builder.block_size = 4096;
+        let mut snapshot = Snapshot::scan(&source).unwrap();
+        snapshot.build(&builder, &output).unwrap();
+
+        // Rewritten with the same size and time, so the blocks of the last image are kept
+        let kept = fs::OpenOptions::new()
+            .write(true)
+            .open(source.join("kept"))
+            .unwrap();
+        let modified = kept.metadata().unwrap().modified().unwrap();
+        (&kept).write_all(&[2; 8192]).unwrap();
+        kept.set_modified(modified).unwrap();
+        fs::write(source.join("changed"), "after").unwrap();
+        snapshot.update(&source.join("kept")).unwrap();
+        snapshot.update(&source.join("changed")).unwrap();
+        snapshot.build(&builder, &output).unwrap();
+
+        let image = read::Archive::open(&output).unwrap();
+        let kept = image.lookup("kept").unwrap();
+        assert_eq!(image.read_file(&kept).unwrap(), vec![1; 8192]);
+        let changed = image.lookup("changed").unwrap();
+        assert_eq!(image.read_file(&changed).unwrap(), b"after");
+    }
+}
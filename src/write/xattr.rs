@@ -0,0 +1,216 @@
+//! Writing extended attributes
+//!
+//! The key value pairs of every inode are written back to back to one table, and each distinct
+//! set of them gets an entry in the xattr id table, which inodes refer to by index. Identical
+//! sets share an entry, and a long value repeated in different sets is only stored once, with
+//! later uses referring back to it, as `mksquashfs` does.
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::{TryFrom, TryInto};
+use std::mem;
+use std::sync::Arc;
+
+use bstr::BString;
+use repr::xattr::Kind;
+
+use crate::compression::AnyCodec;
+use crate::errors::{Result, WriteError};
+use crate::pool::BlockPool;
+use crate::write::metablock_writer::MetablockWriter;
+use crate::write::plan::XattrTables;
+use crate::write::two_level;
+
+/// The extended attributes of an item, by full name, including the namespace prefix
+pub(crate) type Xattrs = BTreeMap<BString, Vec<u8>>;
+
+/// The namespaces squashfs can store, with the id each is stored as
+const PREFIXES: [(&[u8], Kind); 3] = [
+    (b"user.", Kind::USER),
+    (b"trusted.", Kind::TRUSTED),
+    (b"security.", Kind::SECURITY),
+];
+
+/// Split the namespace prefix off the full name of an attribute, returning its id and the rest
+/// of the name, or `None` if squashfs can't store the namespace, or the name is empty
+pub(crate) fn split_prefix(name: &[u8]) -> Option<(Kind, &[u8])> {
+    PREFIXES.iter().find_map(|&(prefix, kind)| {
+        name.strip_prefix(prefix)
+            .filter(|rest| !rest.is_empty())
+            .map(|rest| (kind, rest))
+    })
+}
+
+/// Check an attribute can be stored, see [`DirBuilder::set_xattr`](super::DirBuilder::set_xattr)
+pub(crate) fn check(name: &[u8], value: &[u8]) -> Result<()> {
+    let fits = match split_prefix(name) {
+        Some((_, rest)) => rest.len() <= u16::MAX.into() && u32::try_from(value.len()).is_ok(),
+        None => false,
+    };
+    if !fits {
+        return Err(WriteError::UnsupportedXattr(name.into()).into());
+    }
+    Ok(())
+}
+
+pub struct Table {
+    pairs: MetablockWriter<AnyCodec>,
+    ids: two_level::Table<repr::xattr::LookupEntry, AnyCodec>,
+    count: u32,
+    /// The entry of each set of attributes already written
+    sets: HashMap<Xattrs, repr::xattr::Idx>,
+    /// Where each value stored out of line can be referred to
+    values: HashMap<Vec<u8>, repr::xattr::Ref>,
+}
+
+impl Table {
+    pub fn new(compressor: Option<AnyCodec>, pool: &Arc<BlockPool>) -> Self {
+        Self {
+            pairs: MetablockWriter::new(compressor.clone(), pool),
+            ids: two_level::Table::new(compressor, pool),
+            count: 0,
+            sets: HashMap::new(),
+            values: HashMap::new(),
+        }
+    }
+
+    /// Add the attributes of an inode, returning the index it refers to them by
+    ///
+    /// Every name must have passed [`check`].
+    pub fn add(&mut self, xattrs: &Xattrs) -> repr::xattr::Idx {
+        if xattrs.is_empty() {
+            return repr::xattr::Idx::NONE;
+        }
+        if let Some(&idx) = self.sets.get(xattrs) {
+            return idx;
+        }
+
+        let start = self.pairs.position();
+        let mut size = 0;
+        for (name, value) in xattrs {
+            let (kind, name) = split_prefix(name).expect("xattr names are checked when set");
+            let shared = self.values.get(value).copied();
+            let kind = match shared {
+                Some(_) => Kind(kind.0 | Kind::OUT_OF_LINE.0),
+                None => kind,
+            };
+            self.pairs.write(&repr::xattr::Key {
+                kind,
+                name_size: name.len().try_into().unwrap(),
+            });
+            self.pairs.write_raw(name);
+            size += mem::size_of::<repr::xattr::Key>() + name.len();
+
+            match shared {
+                Some(value_ref) => {
+                    self.pairs.write(&repr::xattr::Value {
+                        value_size: mem::size_of::<repr::xattr::Ref>() as u32,
+                    });
+                    self.pairs.write(&value_ref);
+                    size +=
+                        mem::size_of::<repr::xattr::Value>() + mem::size_of::<repr::xattr::Ref>();
+                }
+                None => {
+                    let value_ref = self.pairs.position();
+                    self.pairs.write(&repr::xattr::Value {
+                        value_size: value.len().try_into().unwrap(),
+                    });
+                    self.pairs.write_raw(value);
+                    size += mem::size_of::<repr::xattr::Value>() + value.len();
+                    // Referring back to a value takes as long as a value of this length
+                    if value.len() > mem::size_of::<repr::xattr::Ref>() {
+                        self.values.insert(value.clone(), value_ref);
+                    }
+                }
+            }
+        }
+
+        self.ids.write(&repr::xattr::LookupEntry {
+            xattr_ref: start,
+            count: xattrs.len().try_into().unwrap(),
+            size: size.try_into().unwrap(),
+        });
+        let idx = repr::xattr::Idx(self.count);
+        self.count += 1;
+        self.sets.insert(xattrs.clone(), idx);
+        idx
+    }
+
+    /// The tables to add to the image, or `None` if no inode has any attributes
+    pub fn finish(self) -> Option<XattrTables> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(XattrTables {
+            pairs: self.pairs.finish(),
+            ids: self.ids.finish().0,
+            count: self.count,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zerocopy::FromBytes;
+
+    fn xattrs(pairs: &[(&str, &[u8])]) -> Xattrs {
+        pairs
+            .iter()
+            .map(|&(name, value)| (name.into(), value.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn prefixes() {
+        assert_eq!(
+            split_prefix(b"security.selinux"),
+            Some((Kind::SECURITY, &b"selinux"[..]))
+        );
+        assert_eq!(split_prefix(b"user.a"), Some((Kind::USER, &b"a"[..])));
+        assert_eq!(split_prefix(b"user."), None);
+        assert_eq!(split_prefix(b"system.posix_acl_access"), None);
+        assert!(check(b"trusted.overlay.opaque", b"y").is_ok());
+        assert!(check(b"system.posix_acl_access", b"").is_err());
+    }
+
+    #[test]
+    fn dedup() {
+        let pool = Arc::new(BlockPool::new(1, 1));
+        let mut table = Table::new(None, &pool);
+        let label: &[u8] = b"system_u:object_r:etc_t:s0";
+        let first = xattrs(&[("security.selinux", label), ("user.a", b"1")]);
+        let second = xattrs(&[("security.selinux", label)]);
+
+        assert_eq!(table.add(&Xattrs::new()), repr::xattr::Idx::NONE);
+        assert_eq!(table.add(&first), repr::xattr::Idx(0));
+        assert_eq!(table.add(&second), repr::xattr::Idx(1));
+        assert_eq!(table.add(&first), repr::xattr::Idx(0));
+
+        let tables = table.finish().unwrap();
+        assert_eq!(tables.count, 2);
+        // Uncompressed metablocks, so the contents follow a two byte header
+        let pairs = &tables.pairs[2..];
+        let ids = &tables.ids[2..];
+        let entries: Vec<_> = ids
+            .chunks(mem::size_of::<repr::xattr::LookupEntry>())
+            .map(|entry| repr::xattr::LookupEntry::read_from(entry).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 2);
+
+        // The first set is stored in order, with its values inline
+        let first_size = (4 + 7 + 4 + label.len()) + (4 + 1 + 4 + 1);
+        assert_eq!({ entries[0].xattr_ref }, repr::xattr::Ref::new(0, 0));
+        assert_eq!({ entries[0].count }, 2);
+        assert_eq!({ entries[0].size } as usize, first_size);
+        assert_eq!(&pairs[..4], [2, 0, 7, 0]);
+        assert_eq!(&pairs[4..11], b"selinux");
+        assert_eq!(&pairs[15..15 + label.len()], label);
+
+        // The second refers back to the label
+        let second = &pairs[first_size..];
+        assert_eq!({ entries[1].size }, 4 + 7 + 4 + 8);
+        assert_eq!(&second[..4], [2, 1, 7, 0]);
+        assert_eq!(&second[11..15], 8u32.to_le_bytes());
+        assert_eq!(&second[15..], 11u64.to_le_bytes());
+    }
+}